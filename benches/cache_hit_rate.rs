@@ -0,0 +1,112 @@
+// Compares the hit rates of the cache eviction policies.
+// Run with `cargo bench --bench cache_hit_rate`.
+
+use std::sync::Arc;
+
+use kvdb::{CachePolicy, Config, KvDb, MemoryStorage};
+
+const KEYS: usize = 10_000;
+const VALUE_SIZE: usize = 100;
+
+// Room for a tenth of the keys
+const CACHE_ENTRIES: usize = KEYS / 10;
+
+const READS: usize = 200_000;
+
+// A full scan of every key after this many reads, in the scanning workload
+const SCAN_INTERVAL: usize = 2_000;
+
+// A small deterministic random number generator (xorshift64*)
+struct Rng(u64);
+
+impl Rng {
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// Draws keys following a Zipf distribution, so a few keys get most of the reads
+struct Zipf {
+    cumulative: Vec<f64>,
+}
+
+impl Zipf {
+    fn new(keys: usize, exponent: f64) -> Self {
+        let mut total = 0.0;
+        let mut cumulative = Vec::with_capacity(keys);
+        for rank in 1..=keys {
+            total += 1.0 / (rank as f64).powf(exponent);
+            cumulative.push(total);
+        }
+        for weight in &mut cumulative {
+            *weight /= total;
+        }
+        Self { cumulative }
+    }
+
+    fn sample(&self, rng: &mut Rng) -> i64 {
+        let point = rng.next_f64();
+        self.cumulative.partition_point(|&weight| weight < point).min(self.cumulative.len() - 1) as i64
+    }
+}
+
+// Run a workload, returning the hit rate of the reads after warming up.
+// The scanning workload reads every key once at regular intervals.
+fn hit_rate(policy: CachePolicy, scans: bool) -> f64 {
+    let db = KvDb::open(Config {
+        storage: Some(Arc::new(MemoryStorage::new())),
+        cache_capacity_bytes: CACHE_ENTRIES * (8 + VALUE_SIZE),
+        cache_policy: policy,
+        ..Config::default()
+    })
+    .unwrap();
+    for key in 0..KEYS as i64 {
+        db.set(key, &"x".repeat(VALUE_SIZE)).unwrap();
+    }
+
+    // Popular keys are spread over the key space
+    let zipf = Zipf::new(KEYS, 0.99);
+    let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+    let mut read = |db: &KvDb| db.get(zipf.sample(&mut rng) * 7919 % KEYS as i64).unwrap();
+
+    for _ in 0..READS / 10 {
+        read(&db);
+    }
+
+    // Only the skewed reads count, not the scans
+    let (mut hits, mut misses) = (0, 0);
+    let mut before = db.cache_stats();
+    for step in 1..=READS {
+        read(&db);
+        if scans && step % SCAN_INTERVAL == 0 {
+            let after = db.cache_stats();
+            hits += after.hits - before.hits;
+            misses += after.misses - before.misses;
+
+            for key in 0..KEYS as i64 {
+                db.get(key).unwrap();
+            }
+            before = db.cache_stats();
+        }
+    }
+    let after = db.cache_stats();
+    hits += after.hits - before.hits;
+    misses += after.misses - before.misses;
+
+    hits as f64 / (hits + misses) as f64
+}
+
+fn main() {
+    println!("{:<10} {:>10} {:>10}", "policy", "skewed", "scanning");
+    for (name, policy) in [("lru", CachePolicy::Lru), ("tinylfu", CachePolicy::TinyLfu)] {
+        println!(
+            "{:<10} {:>9.1}% {:>9.1}%",
+            name,
+            hit_rate(policy, false) * 100.0,
+            hit_rate(policy, true) * 100.0
+        );
+    }
+}
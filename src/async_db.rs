@@ -0,0 +1,437 @@
+use std::io::{self, Read, Write};
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::{AsOf, CacheStats, Config, DbStats, KvDb, KvError, Result, VersionHistory};
+
+// The number of pieces of a streamed value buffered between the I/O thread
+// and the task reading or writing it
+const STREAM_BUFFER: usize = 4;
+
+// A handle to a database for use from async code. Every operation that may
+// block on I/O or locks, including the garbage collection a write can trigger,
+// runs on tokio's blocking thread pool rather than on the runtime's workers.
+// The handle is cheap to clone and must be used from within a tokio runtime.
+#[derive(Clone)]
+pub struct AsyncKvDb {
+    db: Arc<KvDb>,
+}
+
+impl AsyncKvDb {
+    pub fn new(db: Arc<KvDb>) -> Self {
+        AsyncKvDb { db }
+    }
+
+    pub async fn open(config: Config) -> Result<Self> {
+        let db = spawn(move || KvDb::open(config)).await?;
+        Ok(Self::new(Arc::new(db)))
+    }
+
+    // The blocking database this handle runs operations on
+    pub fn db(&self) -> &Arc<KvDb> {
+        &self.db
+    }
+
+    // Run a blocking operation on the database off the runtime's workers
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&KvDb) -> Result<T> + Send + 'static,
+    {
+        let db = self.db.clone();
+        spawn(move || f(&db)).await
+    }
+
+    pub async fn set(&self, key: i64, value: String) -> Result<Option<String>> {
+        self.run(move |db| db.set(key, &value)).await
+    }
+
+    pub async fn set_bytes(&self, key: i64, value: Vec<u8>) -> Result<()> {
+        self.run(move |db| db.set_bytes(key, &value)).await
+    }
+
+    pub async fn set_many(&self, entries: Vec<(i64, String)>) -> Result<Vec<Option<String>>> {
+        self.run(move |db| {
+            let entries: Vec<(i64, &str)> = entries.iter().map(|(key, value)| (*key, value.as_str())).collect();
+            db.set_many(&entries)
+        })
+        .await
+    }
+
+    pub async fn get(&self, key: i64) -> Result<Option<String>> {
+        self.run(move |db| db.get(key)).await
+    }
+
+    pub async fn get_bytes(&self, key: i64) -> Result<Option<Vec<u8>>> {
+        self.run(move |db| db.get_bytes(key)).await
+    }
+
+    pub async fn get_many(&self, keys: Vec<i64>) -> Result<Vec<Option<String>>> {
+        self.run(move |db| db.get_many(&keys)).await
+    }
+
+    pub async fn remove(&self, key: i64) -> Result<Option<String>> {
+        self.run(move |db| db.remove(key)).await
+    }
+
+    pub async fn incr(&self, key: i64, delta: i64) -> Result<i64> {
+        self.run(move |db| db.incr(key, delta)).await
+    }
+
+    pub async fn decr(&self, key: i64, delta: i64) -> Result<i64> {
+        self.run(move |db| db.decr(key, delta)).await
+    }
+
+    pub async fn merge(&self, key: i64, operand: String) -> Result<()> {
+        self.run(move |db| db.merge(key, &operand)).await
+    }
+
+    pub async fn query_by_index(&self, name: &str, value: &str) -> Result<Vec<(i64, String)>> {
+        let (name, value) = (name.to_string(), value.to_string());
+        self.run(move |db| db.query_by_index(&name, &value)).await
+    }
+
+    pub async fn get_at(&self, key: i64, as_of: AsOf) -> Result<Option<String>> {
+        self.run(move |db| db.get_at(key, as_of)).await
+    }
+
+    pub async fn history(&self, key: i64) -> Result<VersionHistory> {
+        self.run(move |db| db.history(key)).await
+    }
+
+    pub async fn scan(&self, range: impl RangeBounds<i64>) -> Result<Vec<(i64, String)>> {
+        let range = owned_range(range);
+        self.run(move |db| db.scan(range)).await
+    }
+
+    pub async fn scan_bytes(&self, range: impl RangeBounds<i64>) -> Result<Vec<(i64, Vec<u8>)>> {
+        let range = owned_range(range);
+        self.run(move |db| db.scan_bytes(range)).await
+    }
+
+    // Read the live entries with keys in the given range in key order, a page at a
+    // time, as `ScanCursor::next_page` does with the limits given
+    pub fn scan_pages(&self, range: impl RangeBounds<i64>, max_entries: usize, max_bytes: usize) -> AsyncScanner {
+        let db = self.db.clone();
+        let range = owned_range(range);
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+
+        // The cursor borrows the database, so it lives on the I/O thread and
+        // sends the pages over as they are read
+        tokio::task::spawn_blocking(move || {
+            let mut cursor = match db.scan_cursor(range) {
+                Ok(cursor) => cursor,
+                Err(err) => {
+                    let _ = tx.blocking_send(Err(err));
+                    return;
+                }
+            };
+            loop {
+                let page = cursor.next_page(max_entries, max_bytes);
+
+                // Stop at the end of the range, or if the scanner was dropped or the read failed
+                let done = page.as_ref().map_or(true, |page| page.is_empty());
+                if tx.blocking_send(page).is_err() || done {
+                    break;
+                }
+            }
+        });
+        AsyncScanner { pages: rx }
+    }
+
+    // Get a reader that streams a value from the database in pieces of at most
+    // `piece_size` bytes, which must be at least 1
+    pub async fn get_reader(&self, key: i64, piece_size: usize) -> Result<Option<AsyncValueReader>> {
+        if piece_size == 0 {
+            return Err(KvError::InvalidArgument("the piece size must be at least 1"));
+        }
+        let db = self.db.clone();
+        let (opened_tx, opened_rx) = oneshot::channel();
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+
+        // The reader borrows the database, so it lives on the I/O thread and
+        // sends the value over as it is read
+        tokio::task::spawn_blocking(move || {
+            let mut reader = match db.get_reader(key) {
+                Ok(Some(reader)) => {
+                    let _ = opened_tx.send(Ok(true));
+                    reader
+                }
+                Ok(None) => {
+                    let _ = opened_tx.send(Ok(false));
+                    return;
+                }
+                Err(err) => {
+                    let _ = opened_tx.send(Err(err));
+                    return;
+                }
+            };
+
+            let mut buffer = vec![0; piece_size];
+            loop {
+                let piece = match reader.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(len) => Ok(buffer[..len].to_vec()),
+                    Err(err) => Err(KvError::Io(err)),
+                };
+
+                // Stop if the reader was dropped or the read failed
+                let failed = piece.is_err();
+                if tx.blocking_send(piece).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        match opened_rx.await {
+            Ok(Ok(true)) => Ok(Some(AsyncValueReader { pieces: rx })),
+            Ok(Ok(false)) => Ok(None),
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(task_failed()),
+        }
+    }
+
+    // Start writing a large value for a key piece by piece.
+    // The value replaces the current one when `AsyncBlobWriter::finish` is called.
+    pub fn blob_writer(&self, key: i64) -> AsyncBlobWriter {
+        let db = self.db.clone();
+        let (tx, mut rx) = mpsc::channel::<Option<Vec<u8>>>(STREAM_BUFFER);
+
+        // Pieces are written on the I/O thread until the writer is finished or dropped
+        let task = tokio::task::spawn_blocking(move || {
+            let mut writer = db.blob_writer(key)?;
+            loop {
+                match rx.blocking_recv() {
+                    Some(Some(data)) => writer.write_all(&data)?,
+                    Some(None) => return writer.finish(),
+                    None => return Ok(()),
+                }
+            }
+        });
+        AsyncBlobWriter { pieces: tx, task: Some(task) }
+    }
+
+    pub async fn warm_cache(&self) -> Result<usize> {
+        self.run(|db| db.warm_cache()).await
+    }
+
+    pub async fn cf(&self, name: &str) -> Result<AsyncKvDb> {
+        let name = name.to_string();
+        let db = self.run(move |db| db.cf(&name)).await?;
+        Ok(Self::new(db))
+    }
+
+    pub async fn existing_cf(&self, name: &str) -> Result<Option<AsyncKvDb>> {
+        let name = name.to_string();
+        let db = self.run(move |db| db.existing_cf(&name)).await?;
+        Ok(db.map(Self::new))
+    }
+
+    pub async fn column_families(&self) -> Result<Vec<String>> {
+        self.run(|db| db.column_families()).await
+    }
+
+    pub async fn open_column_families(&self) -> Result<Vec<(String, AsyncKvDb)>> {
+        let column_families = self.run(|db| Ok(db.open_column_families())).await?;
+        Ok(column_families.into_iter().map(|(name, db)| (name, Self::new(db))).collect())
+    }
+
+    pub async fn len(&self) -> Result<usize> {
+        self.run(|db| Ok(db.len())).await
+    }
+
+    pub async fn is_empty(&self) -> Result<bool> {
+        self.run(|db| Ok(db.is_empty())).await
+    }
+
+    pub async fn cache_stats(&self) -> Result<CacheStats> {
+        self.run(|db| Ok(db.cache_stats())).await
+    }
+
+    pub async fn stats(&self) -> Result<DbStats> {
+        self.run(|db| db.stats()).await
+    }
+
+    pub async fn close(&self) -> Result<()> {
+        self.run(|db| db.close()).await
+    }
+}
+
+// Streams a value out of the database, as returned by `AsyncKvDb::get_reader`
+pub struct AsyncValueReader {
+    pieces: mpsc::Receiver<Result<Vec<u8>>>,
+}
+
+impl AsyncValueReader {
+    // Get the next piece of the value, or None once it has all been read
+    pub async fn read(&mut self) -> Result<Option<Vec<u8>>> {
+        self.pieces.recv().await.transpose()
+    }
+}
+
+// Reads a range of entries a page at a time, as returned by `AsyncKvDb::scan_pages`
+pub struct AsyncScanner {
+    pages: mpsc::Receiver<Result<Vec<(i64, String)>>>,
+}
+
+impl AsyncScanner {
+    // Get the next page of entries, or None once the whole range has been read
+    pub async fn next_page(&mut self) -> Result<Option<Vec<(i64, String)>>> {
+        match self.pages.recv().await {
+            Some(Ok(page)) if page.is_empty() => Ok(None),
+            Some(page) => page.map(Some),
+            None => Err(task_failed()),
+        }
+    }
+}
+
+// Writes a large value into the database as it arrives, as returned by
+// `AsyncKvDb::blob_writer`. Dropping the writer without finishing it discards
+// the value.
+pub struct AsyncBlobWriter {
+    pieces: mpsc::Sender<Option<Vec<u8>>>,
+    // The I/O thread writing the value, until its result has been taken
+    task: Option<JoinHandle<Result<()>>>,
+}
+
+impl AsyncBlobWriter {
+    pub async fn write(&mut self, data: Vec<u8>) -> Result<()> {
+        if self.pieces.send(Some(data)).await.is_err() {
+            // The I/O thread only stops early when writing fails
+            return Err(self.failure().await);
+        }
+        Ok(())
+    }
+
+    // Make the value visible, replacing any previous value for the key
+    pub async fn finish(mut self) -> Result<()> {
+        if self.pieces.send(None).await.is_err() {
+            return Err(self.failure().await);
+        }
+        join(self.task.take().unwrap()).await
+    }
+
+    // The error the I/O thread stopped with
+    async fn failure(&mut self) -> KvError {
+        match self.task.take() {
+            Some(task) => join(task).await.err().unwrap_or_else(task_failed),
+            None => KvError::Io(io::Error::other("an earlier write of the value failed")),
+        }
+    }
+}
+
+// Run a blocking function on tokio's blocking thread pool
+async fn spawn<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    join(tokio::task::spawn_blocking(f)).await
+}
+
+// Wait for a blocking task, passing on a panic in it to the caller
+async fn join<T>(task: JoinHandle<Result<T>>) -> Result<T> {
+    match task.await {
+        Ok(result) => result,
+        Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+        Err(_) => Err(task_failed()),
+    }
+}
+
+// Blocking tasks are only cancelled when the runtime shuts down
+fn task_failed() -> KvError {
+    KvError::Io(io::Error::new(io::ErrorKind::Interrupted, "the runtime is shutting down"))
+}
+
+fn owned_range(range: impl RangeBounds<i64>) -> (Bound<i64>, Bound<i64>) {
+    (range.start_bound().cloned(), range.end_bound().cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EngineKind, LsmOptions, MemoryStorage};
+
+    fn config(engine: EngineKind) -> Config {
+        Config { storage: Some(Arc::new(MemoryStorage::new())), engine, chunk_size: 16, ..Config::default() }
+    }
+
+    #[tokio::test]
+    async fn test_async_db() {
+        for engine in [EngineKind::Log, EngineKind::Lsm(LsmOptions::default())] {
+            let db = AsyncKvDb::open(config(engine)).await.unwrap();
+            assert_eq!(db.set(1, "one".to_string()).await.unwrap(), None);
+            assert_eq!(db.set(1, "uno".to_string()).await.unwrap(), Some("one".to_string()));
+            db.set_many(vec![(2, "two".to_string()), (3, "three".to_string())]).await.unwrap();
+            assert_eq!(db.get(1).await.unwrap(), Some("uno".to_string()));
+            assert_eq!(db.get_many(vec![2, 4]).await.unwrap(), [Some("two".to_string()), None]);
+            assert_eq!(db.incr(5, 3).await.unwrap(), 3);
+            assert_eq!(db.remove(2).await.unwrap(), Some("two".to_string()));
+            let keys: Vec<i64> = db.scan(..4).await.unwrap().into_iter().map(|(key, _)| key).collect();
+            assert_eq!(keys, [1, 3]);
+
+            // Operations run concurrently from many tasks
+            let tasks: Vec<_> = (0..32)
+                .map(|i| {
+                    let db = db.clone();
+                    tokio::spawn(async move { db.set(100 + i, format!("value{}", i)).await })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap().unwrap();
+            }
+            assert_eq!(db.get(131).await.unwrap(), Some("value31".to_string()));
+            assert_eq!(db.len().await.unwrap(), 35);
+            let mut pages = db.scan_pages(..100, 2, usize::MAX);
+            assert_eq!(pages.next_page().await.unwrap().unwrap().len(), 2);
+            assert_eq!(pages.next_page().await.unwrap().unwrap(), [(5, "3".to_string())]);
+            assert_eq!(pages.next_page().await.unwrap(), None);
+            assert!(!db.is_empty().await.unwrap());
+            assert_eq!(db.cache_stats().await.unwrap().hits, db.db().cache_stats().hits);
+
+            let cf = db.cf("users").await.unwrap();
+            cf.set(1, "alice".to_string()).await.unwrap();
+            assert_eq!(cf.get(1).await.unwrap(), Some("alice".to_string()));
+            assert_eq!(db.column_families().await.unwrap(), ["users"]);
+            let open: Vec<String> = db.open_column_families().await.unwrap().into_iter().map(|(name, _)| name).collect();
+            assert_eq!(open, ["users"]);
+
+            db.close().await.unwrap();
+            assert!(matches!(db.get(1).await, Err(KvError::DbClosed)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_blobs() {
+        let db = AsyncKvDb::open(config(EngineKind::Log)).await.unwrap();
+        let value: Vec<u8> = (0..100).collect();
+
+        let mut writer = db.blob_writer(1);
+        for piece in value.chunks(30) {
+            writer.write(piece.to_vec()).await.unwrap();
+        }
+        writer.finish().await.unwrap();
+
+        // A writer dropped without finishing discards the value
+        let mut writer = db.blob_writer(1);
+        writer.write(vec![1, 2, 3]).await.unwrap();
+        drop(writer);
+
+        let mut reader = db.get_reader(1, 40).await.unwrap().unwrap();
+        let mut read = Vec::new();
+        while let Some(piece) = reader.read().await.unwrap() {
+            assert!(piece.len() <= 40);
+            read.extend(piece);
+        }
+        assert_eq!(read, value);
+        assert!(db.get_reader(2, 40).await.unwrap().is_none());
+        assert!(matches!(db.get_reader(1, 0).await, Err(KvError::InvalidArgument(_))));
+
+        // Streaming writes aren't supported by the LSM engine
+        let db = AsyncKvDb::open(config(EngineKind::Lsm(LsmOptions::default()))).await.unwrap();
+        assert!(matches!(db.blob_writer(1).finish().await, Err(KvError::Unsupported(_))));
+    }
+}
@@ -0,0 +1,570 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use kvdb::{Config, EngineKind, KvDb, LsmOptions, MemoryStorage};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tonic::transport::Channel;
+
+// Include the generated proto code
+pub mod kvdb_proto {
+    tonic::include_proto!("kvdb");
+}
+
+use kvdb_proto::{kv_service_client::KvServiceClient, GetRequest, KeyValue, MultiSetRequest, SetRequest};
+
+// The number of records written at a time while loading
+const LOAD_BATCH_SIZE: usize = 1000;
+
+#[derive(Parser)]
+#[clap(author, version, about = "Runs YCSB-style workloads against KVDB")]
+struct Cli {
+    #[clap(subcommand)]
+    target: Target,
+}
+
+#[derive(Subcommand)]
+enum Target {
+    /// Run against a database opened in this process
+    Embedded {
+        /// The database directory (an in-memory database if omitted)
+        #[clap(long)]
+        path: Option<PathBuf>,
+        /// The storage engine
+        #[clap(long, value_enum, default_value_t = Engine::Log)]
+        engine: Engine,
+        #[clap(flatten)]
+        workload: WorkloadArgs,
+    },
+    /// Run against a kvdb-server
+    Remote {
+        /// Server address
+        #[clap(short, long, default_value = "http://[::1]:50051")]
+        server: String,
+        #[clap(flatten)]
+        workload: WorkloadArgs,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Engine {
+    Log,
+    Lsm,
+}
+
+// The YCSB core workloads that only use operations the server offers
+#[derive(Clone, Copy, ValueEnum)]
+enum Preset {
+    /// 50% reads, 50% updates
+    A,
+    /// 95% reads, 5% updates
+    B,
+    /// Reads only
+    C,
+    /// 95% reads of recently inserted keys, 5% inserts
+    D,
+    /// 50% reads, 50% read-modify-writes
+    F,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum KeyDistribution {
+    /// Every key is as likely
+    Uniform,
+    /// A few keys, spread over the key space, get most operations
+    Zipfian,
+    /// The most recently inserted keys get most operations
+    Latest,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum SizeDistribution {
+    /// Every size in the range is as likely
+    Uniform,
+    /// Small sizes are much more likely than large ones
+    Zipfian,
+}
+
+#[derive(Args)]
+struct WorkloadArgs {
+    /// The YCSB core workload to run
+    #[clap(short, long, value_enum, default_value_t = Preset::A)]
+    workload: Preset,
+    /// The share of reads, replacing the workload's mix of operations
+    #[clap(long)]
+    reads: Option<f64>,
+    /// The share of updates of existing keys, replacing the workload's mix
+    #[clap(long)]
+    updates: Option<f64>,
+    /// The share of inserts of new keys, replacing the workload's mix
+    #[clap(long)]
+    inserts: Option<f64>,
+    /// The share of reads followed by an update of the same key, replacing the workload's mix
+    #[clap(long)]
+    read_modify_writes: Option<f64>,
+    /// The keys operations pick (zipfian, or latest for workload d, if omitted)
+    #[clap(long, value_enum)]
+    keys: Option<KeyDistribution>,
+    /// The skew of zipfian keys and value sizes, between 0 and 1
+    #[clap(long, default_value_t = 0.99)]
+    zipf_exponent: f64,
+    /// The number of records loaded before the run
+    #[clap(long, default_value_t = 100_000)]
+    records: usize,
+    /// The number of operations in the run
+    #[clap(long, default_value_t = 100_000)]
+    operations: usize,
+    /// The number of threads, or of concurrent requests when remote
+    #[clap(long, default_value_t = 4)]
+    threads: usize,
+    /// The smallest value size in bytes
+    #[clap(long, default_value_t = 100)]
+    min_value_size: usize,
+    /// The largest value size in bytes (the smallest if omitted)
+    #[clap(long)]
+    max_value_size: Option<usize>,
+    /// How value sizes are spread between the smallest and the largest
+    #[clap(long, value_enum, default_value_t = SizeDistribution::Uniform)]
+    value_sizes: SizeDistribution,
+    /// Skip loading the records, for a database loaded by an earlier run
+    #[clap(long)]
+    skip_load: bool,
+    /// The seed of the random number generators
+    #[clap(long, default_value_t = 1)]
+    seed: u64,
+}
+
+// The kinds of operation, in the order of the shares in `Workload::mix`
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum OpKind {
+    Read,
+    Update,
+    Insert,
+    ReadModifyWrite,
+}
+
+const OP_KINDS: [OpKind; 4] = [OpKind::Read, OpKind::Update, OpKind::Insert, OpKind::ReadModifyWrite];
+
+impl OpKind {
+    fn name(self) -> &'static str {
+        match self {
+            OpKind::Read => "read",
+            OpKind::Update => "update",
+            OpKind::Insert => "insert",
+            OpKind::ReadModifyWrite => "read-modify-write",
+        }
+    }
+}
+
+// An operation to run; the value is only used by writes
+struct Operation {
+    kind: OpKind,
+    key: i64,
+    value: String,
+}
+
+// A small deterministic random number generator (xorshift64*)
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // The state must not be zero
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound.max(1)
+    }
+}
+
+// Draws ranks below a count following a Zipf distribution, so the first few get
+// most draws. This is the closed form of Gray et al. that YCSB uses, which only
+// needs the sum of the weights of the ranks, extended as the count grows.
+struct Zipf {
+    exponent: f64,
+    alpha: f64,
+    // The sum of the weights of the first two ranks
+    zeta2: f64,
+    sums: RwLock<ZipfSums>,
+}
+
+// The constants of a Zipf distribution that depend on the count of ranks
+#[derive(Clone, Copy)]
+struct ZipfSums {
+    count: u64,
+    // The sum of the weights of every rank
+    zeta: f64,
+    eta: f64,
+}
+
+impl Zipf {
+    fn new(count: u64, exponent: f64) -> Self {
+        let zipf = Self {
+            exponent,
+            alpha: 1.0 / (1.0 - exponent),
+            zeta2: 1.0 + 0.5f64.powf(exponent),
+            sums: RwLock::new(ZipfSums { count: 0, zeta: 0.0, eta: 0.0 }),
+        };
+        zipf.sums_for(count.max(1));
+        zipf
+    }
+
+    // Draw a rank below `count`. Counts are expected to grow, so a count below
+    // one seen before draws from the larger one.
+    fn sample(&self, rng: &mut Rng, count: u64) -> u64 {
+        let sums = self.sums_for(count.max(1));
+        let point = rng.next_f64();
+        let weight = point * sums.zeta;
+        if weight < 1.0 {
+            return 0;
+        }
+        if weight < self.zeta2 {
+            return 1;
+        }
+        let rank = sums.count as f64 * (sums.eta * point - sums.eta + 1.0).powf(self.alpha);
+        (rank as u64).min(sums.count - 1)
+    }
+
+    fn sums_for(&self, count: u64) -> ZipfSums {
+        let sums = *self.sums.read().unwrap();
+        if sums.count >= count {
+            return sums;
+        }
+
+        let mut sums = self.sums.write().unwrap();
+        if sums.count < count {
+            sums.zeta += (sums.count + 1..=count).map(|rank| 1.0 / (rank as f64).powf(self.exponent)).sum::<f64>();
+            sums.eta = (1.0 - (2.0 / count as f64).powf(1.0 - self.exponent)) / (1.0 - self.zeta2 / sums.zeta);
+            sums.count = count;
+        }
+        *sums
+    }
+}
+
+// What every thread shares to generate operations
+struct Workload {
+    // The cumulative shares of each kind of operation, in the order of `OP_KINDS`
+    mix: [f64; 4],
+    keys: KeyDistribution,
+    key_zipf: Zipf,
+    min_value_size: usize,
+    max_value_size: usize,
+    size_zipf: Option<Zipf>,
+    // Random text that values are cut from
+    text: String,
+    // The number of keys, which inserts add to
+    key_count: AtomicI64,
+}
+
+impl Workload {
+    fn new(args: &WorkloadArgs) -> Result<Self, String> {
+        let mut mix = match args.workload {
+            Preset::A => [0.5, 0.5, 0.0, 0.0],
+            Preset::B => [0.95, 0.05, 0.0, 0.0],
+            Preset::C => [1.0, 0.0, 0.0, 0.0],
+            Preset::D => [0.95, 0.0, 0.05, 0.0],
+            Preset::F => [0.5, 0.0, 0.0, 0.5],
+        };
+        let overrides = [args.reads, args.updates, args.inserts, args.read_modify_writes];
+        if overrides.iter().any(Option::is_some) {
+            mix = overrides.map(|share| share.unwrap_or(0.0));
+        }
+        let total: f64 = mix.iter().sum();
+        if total <= 0.0 || mix.iter().any(|&share| share < 0.0) {
+            return Err("the shares of operations can't be negative, and must not all be zero".to_string());
+        }
+        let mut sum = 0.0;
+        for share in &mut mix {
+            sum += *share / total;
+            *share = sum;
+        }
+
+        if !(args.zipf_exponent > 0.0 && args.zipf_exponent < 1.0) {
+            return Err("the zipf exponent must be between 0 and 1".to_string());
+        }
+
+        let max_value_size = args.max_value_size.unwrap_or(args.min_value_size);
+        if max_value_size < args.min_value_size {
+            return Err("the largest value size is below the smallest".to_string());
+        }
+        let size_range = max_value_size - args.min_value_size + 1;
+
+        let mut rng = Rng::new(args.seed);
+        let text = (0..max_value_size * 2).map(|_| (b'a' + rng.below(26) as u8) as char).collect();
+        let keys = match (args.keys, args.workload) {
+            (Some(keys), _) => keys,
+            (None, Preset::D) => KeyDistribution::Latest,
+            (None, _) => KeyDistribution::Zipfian,
+        };
+
+        Ok(Self {
+            mix,
+            keys,
+            key_zipf: Zipf::new(args.records as u64, args.zipf_exponent),
+            min_value_size: args.min_value_size,
+            max_value_size,
+            size_zipf: (args.value_sizes == SizeDistribution::Zipfian && size_range > 1).then(|| Zipf::new(size_range as u64, args.zipf_exponent)),
+            text,
+            key_count: AtomicI64::new(args.records as i64),
+        })
+    }
+
+    fn value(&self, rng: &mut Rng) -> String {
+        let range = (self.max_value_size - self.min_value_size + 1) as u64;
+        let size = self.min_value_size
+            + match &self.size_zipf {
+                Some(zipf) => zipf.sample(rng, range),
+                None => rng.below(range),
+            } as usize;
+        let start = rng.below((self.text.len() - size + 1) as u64) as usize;
+        self.text[start..start + size].to_string()
+    }
+
+    // An existing key, picked following the key distribution
+    fn existing_key(&self, rng: &mut Rng) -> i64 {
+        let count = self.key_count.load(Ordering::Relaxed).max(1);
+        match self.keys {
+            KeyDistribution::Uniform => rng.below(count as u64) as i64,
+            // Popular keys are spread over the key space
+            KeyDistribution::Zipfian => (self.key_zipf.sample(rng, count as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) % count as u64) as i64,
+            KeyDistribution::Latest => (count - 1 - self.key_zipf.sample(rng, count as u64) as i64).max(0),
+        }
+    }
+
+    fn next(&self, rng: &mut Rng) -> Operation {
+        let point = rng.next_f64();
+        let kind = OP_KINDS[self.mix.iter().position(|&share| point < share).unwrap_or(OP_KINDS.len() - 1)];
+        let key = match kind {
+            OpKind::Insert => self.key_count.fetch_add(1, Ordering::Relaxed),
+            _ => self.existing_key(rng),
+        };
+        let value = if kind == OpKind::Read { String::new() } else { self.value(rng) };
+        Operation { kind, key, value }
+    }
+}
+
+// The latencies of the operations a thread ran, by kind, and the number that failed
+#[derive(Default)]
+struct Recorder {
+    latencies: [Vec<Duration>; 4],
+    errors: [usize; 4],
+}
+
+impl Recorder {
+    fn record(&mut self, kind: OpKind, latency: Duration, ok: bool) {
+        let i = OP_KINDS.iter().position(|&other| other == kind).unwrap();
+        self.latencies[i].push(latency);
+        if !ok {
+            self.errors[i] += 1;
+        }
+    }
+
+    fn merge(&mut self, other: Recorder) {
+        for (i, latencies) in other.latencies.into_iter().enumerate() {
+            self.latencies[i].extend(latencies);
+            self.errors[i] += other.errors[i];
+        }
+    }
+
+    fn report(mut self, elapsed: Duration) {
+        let total: usize = self.latencies.iter().map(Vec::len).sum();
+        println!(
+            "Run: {} operations in {:.2}s ({:.0} ops/s)",
+            total,
+            elapsed.as_secs_f64(),
+            total as f64 / elapsed.as_secs_f64()
+        );
+        println!(
+            "{:<18} {:>9} {:>7} {:>9} {:>9} {:>9} {:>9} {:>9}",
+            "operation", "count", "errors", "p50 µs", "p95 µs", "p99 µs", "p99.9 µs", "max µs"
+        );
+        for (i, latencies) in self.latencies.iter_mut().enumerate() {
+            if latencies.is_empty() {
+                continue;
+            }
+            latencies.sort_unstable();
+            let micros = |latency: &Duration| latency.as_nanos() as f64 / 1000.0;
+            let percentile = |p: f64| micros(&latencies[((latencies.len() as f64 * p) as usize).min(latencies.len() - 1)]);
+            println!(
+                "{:<18} {:>9} {:>7} {:>9.1} {:>9.1} {:>9.1} {:>9.1} {:>9.1}",
+                OP_KINDS[i].name(),
+                latencies.len(),
+                self.errors[i],
+                percentile(0.5),
+                percentile(0.95),
+                percentile(0.99),
+                percentile(0.999),
+                micros(latencies.last().unwrap())
+            );
+        }
+    }
+}
+
+// The number of operations each of `threads` threads runs
+fn share(operations: usize, threads: usize, thread: usize) -> usize {
+    operations / threads + usize::from(thread < operations % threads)
+}
+
+fn report_load(records: usize, elapsed: Duration) {
+    println!(
+        "Load: {} records in {:.2}s ({:.0} records/s)",
+        records,
+        elapsed.as_secs_f64(),
+        records as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn run_embedded(db: Arc<KvDb>, args: &WorkloadArgs, workload: Arc<Workload>) -> kvdb::Result<()> {
+    if !args.skip_load {
+        let start = Instant::now();
+        let mut rng = Rng::new(args.seed);
+        for batch_start in (0..args.records).step_by(LOAD_BATCH_SIZE) {
+            let batch_end = (batch_start + LOAD_BATCH_SIZE).min(args.records);
+            let values: Vec<(i64, String)> = (batch_start..batch_end).map(|key| (key as i64, workload.value(&mut rng))).collect();
+            let entries: Vec<(i64, &str)> = values.iter().map(|(key, value)| (*key, value.as_str())).collect();
+            db.set_many(&entries)?;
+        }
+        report_load(args.records, start.elapsed());
+    }
+
+    let start = Instant::now();
+    let threads: Vec<_> = (0..args.threads)
+        .map(|thread| {
+            let (db, workload) = (db.clone(), workload.clone());
+            let (operations, seed) = (share(args.operations, args.threads, thread), args.seed + 1 + thread as u64);
+            std::thread::spawn(move || {
+                let mut rng = Rng::new(seed);
+                let mut recorder = Recorder::default();
+                for _ in 0..operations {
+                    let op = workload.next(&mut rng);
+                    let started = Instant::now();
+                    let result = match op.kind {
+                        OpKind::Read => db.get(op.key).map(|_| ()),
+                        OpKind::Update | OpKind::Insert => db.set(op.key, &op.value).map(|_| ()),
+                        OpKind::ReadModifyWrite => db.get(op.key).and_then(|_| db.set(op.key, &op.value)).map(|_| ()),
+                    };
+                    recorder.record(op.kind, started.elapsed(), result.is_ok());
+                }
+                recorder
+            })
+        })
+        .collect();
+
+    let mut recorder = Recorder::default();
+    for thread in threads {
+        recorder.merge(thread.join().unwrap());
+    }
+    recorder.report(start.elapsed());
+    Ok(())
+}
+
+async fn remote_get(client: &mut KvServiceClient<Channel>, key: i64) -> bool {
+    match client.get(GetRequest { key, namespace: String::new() }).await {
+        Ok(response) => response.into_inner().error.is_empty(),
+        Err(_) => false,
+    }
+}
+
+async fn remote_set(client: &mut KvServiceClient<Channel>, key: i64, value: &str) -> bool {
+    match client.set(SetRequest { key, value: value.to_string(), namespace: String::new() }).await {
+        Ok(response) => response.into_inner().success,
+        Err(_) => false,
+    }
+}
+
+// Run an operation against the server, returning whether it succeeded
+async fn run_remote_op(client: &mut KvServiceClient<Channel>, op: &Operation) -> bool {
+    match op.kind {
+        OpKind::Read => remote_get(client, op.key).await,
+        OpKind::Update | OpKind::Insert => remote_set(client, op.key, &op.value).await,
+        OpKind::ReadModifyWrite => remote_get(client, op.key).await && remote_set(client, op.key, &op.value).await,
+    }
+}
+
+async fn run_remote(server: String, args: &WorkloadArgs, workload: Arc<Workload>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = KvServiceClient::connect(server).await?;
+
+    if !args.skip_load {
+        let start = Instant::now();
+        let mut rng = Rng::new(args.seed);
+        for batch_start in (0..args.records).step_by(LOAD_BATCH_SIZE) {
+            let batch_end = (batch_start + LOAD_BATCH_SIZE).min(args.records);
+            let entries = (batch_start..batch_end).map(|key| KeyValue { key: key as i64, value: workload.value(&mut rng) }).collect();
+            let response = client.multi_set(MultiSetRequest { entries, namespace: String::new() }).await?.into_inner();
+            if !response.success {
+                return Err(format!("loading failed: {}", response.error).into());
+            }
+        }
+        report_load(args.records, start.elapsed());
+    }
+
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..args.threads)
+        .map(|thread| {
+            let (mut client, workload) = (client.clone(), workload.clone());
+            let (operations, seed) = (share(args.operations, args.threads, thread), args.seed + 1 + thread as u64);
+            tokio::spawn(async move {
+                let mut rng = Rng::new(seed);
+                let mut recorder = Recorder::default();
+                for _ in 0..operations {
+                    let op = workload.next(&mut rng);
+                    let started = Instant::now();
+                    let ok = run_remote_op(&mut client, &op).await;
+                    recorder.record(op.kind, started.elapsed(), ok);
+                }
+                recorder
+            })
+        })
+        .collect();
+
+    let mut recorder = Recorder::default();
+    for task in tasks {
+        recorder.merge(task.await?);
+    }
+    recorder.report(start.elapsed());
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+    let cli = Cli::parse();
+
+    match cli.target {
+        Target::Embedded { path, engine, workload: args } => {
+            let workload = Arc::new(Workload::new(&args)?);
+            let mut config = Config {
+                engine: match engine {
+                    Engine::Log => EngineKind::Log,
+                    Engine::Lsm => EngineKind::Lsm(LsmOptions::default()),
+                },
+                ..Config::default()
+            };
+            match path {
+                Some(path) => config.path = path,
+                None => config.storage = Some(Arc::new(MemoryStorage::new())),
+            }
+
+            // The threads block, so keep them off the runtime
+            let db = Arc::new(KvDb::open(config)?);
+            tokio::task::spawn_blocking(move || run_embedded(db, &args, workload)).await??;
+        }
+        Target::Remote { server, workload: args } => {
+            let workload = Arc::new(Workload::new(&args)?);
+            run_remote(server, &args, workload).await?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,4 +1,12 @@
 use clap::{Parser, Subcommand};
+use kvdb::{DataFormat, EntryReader, EntryWriter};
+use serde_json::json;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::Request;
 
 // Include the generated proto code
@@ -7,9 +15,20 @@ pub mod kvdb_proto {
 }
 
 use kvdb_proto::{
-    kv_service_client::KvServiceClient, GetRequest, RemoveRequest, SetRequest,
+    kv_service_client::KvServiceClient, DumpRequest, GetBlobRequest, GetRequest, IncrRequest,
+    KeyValue, LoadRequest, MultiGetRequest, MultiSetRequest, OperationStats, PutBlobRequest,
+    QueryByIndexRequest, RemoveRequest, SetRequest, StatsRequest,
 };
 
+// The size of the pieces files are streamed to the server in
+const BLOB_MESSAGE_SIZE: usize = 64 * 1024;
+
+// The most entries in each message sent by load. A batch is also sent once its
+// keys and values add up to this many bytes, to stay well below the 4MB gRPC
+// servers accept by default.
+const LOAD_BATCH_SIZE: usize = 1000;
+const LOAD_BATCH_BYTES: usize = 1024 * 1024;
+
 #[derive(Parser)]
 #[clap(author, version, about = "KVDB Client")]
 struct Cli {
@@ -17,6 +36,10 @@ struct Cli {
     #[clap(short, long, default_value = "http://[::1]:50051")]
     server: String,
 
+    /// The namespace (column family) to use, instead of the default one
+    #[clap(short, long, default_value = "")]
+    namespace: String,
+
     #[clap(subcommand)]
     command: Commands,
 }
@@ -40,6 +63,84 @@ enum Commands {
         /// The key to remove
         key: i64,
     },
+    /// Store the contents of a file as a value, streaming it to the server
+    PutBlob {
+        /// The key (an integer)
+        key: i64,
+        /// The file to read the value from
+        file: PathBuf,
+    },
+    /// Retrieve a value as a stream, writing it to a file or stdout
+    GetBlob {
+        /// The key to look up
+        key: i64,
+        /// The file to write the value to (stdout if omitted)
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Get the values of many keys in one request
+    MultiGet {
+        /// The keys to look up
+        #[clap(required = true)]
+        keys: Vec<i64>,
+    },
+    /// Set many key-value pairs in one request
+    MultiSet {
+        /// The pairs to set, as KEY=VALUE
+        #[clap(required = true, value_parser = parse_key_value)]
+        entries: Vec<KeyValue>,
+    },
+    /// Atomically add to an integer value, starting from zero if the key is missing
+    Incr {
+        /// The key of the counter
+        key: i64,
+        /// The amount to add, which may be negative
+        #[clap(default_value_t = 1, allow_negative_numbers = true)]
+        delta: i64,
+    },
+    /// Find the entries with a field value in a secondary index
+    Query {
+        /// The name of the index
+        index: String,
+        /// The field value to look for
+        value: String,
+    },
+    /// Export every entry, in key order, to a file or stdout
+    Dump {
+        /// The file to write the entries to (stdout if omitted)
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        /// The format to write: jsonl or csv
+        #[clap(short, long, default_value_t = DataFormat::JsonLines)]
+        format: DataFormat,
+    },
+    /// Set the entries in a file, streaming them to the server in batches
+    Load {
+        /// The file to read the entries from
+        file: PathBuf,
+        /// The format of the file: jsonl or csv
+        #[clap(short, long, default_value_t = DataFormat::JsonLines)]
+        format: DataFormat,
+    },
+    /// Print the size of the database and counters for the operations done on it, as JSON
+    Stats,
+}
+
+// Parse a KEY=VALUE argument
+fn parse_key_value(arg: &str) -> Result<KeyValue, String> {
+    let (key, value) = arg.split_once('=').ok_or_else(|| format!("expected KEY=VALUE, got {:?}", arg))?;
+    let key = key.parse().map_err(|err| format!("invalid key {:?}: {}", key, err))?;
+    Ok(KeyValue { key, value: value.to_string() })
+}
+
+// An upper bound on the latency of the given fraction of calls, from the buckets
+fn percentile_micros(operation: &OperationStats, fraction: f64) -> u64 {
+    let rank = (operation.count as f64 * fraction).ceil() as u64;
+    operation
+        .latency_buckets
+        .iter()
+        .find(|bucket| bucket.count >= rank)
+        .map_or(operation.max_latency_micros, |bucket| bucket.le_micros.min(operation.max_latency_micros))
 }
 
 #[tokio::main]
@@ -49,11 +150,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Connect to the server
     let mut client = KvServiceClient::connect(cli.server).await?;
+    let namespace = cli.namespace;
 
     // Execute the appropriate command
     match cli.command {
         Commands::Set { key, value } => {
-            let request = Request::new(SetRequest { key, value });
+            let request = Request::new(SetRequest { key, value, namespace });
             let response = client.set(request).await?;
             let resp = response.into_inner();
 
@@ -68,7 +170,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
         Commands::Get { key } => {
-            let request = Request::new(GetRequest { key });
+            let request = Request::new(GetRequest { key, namespace });
             let response = client.get(request).await?;
             let resp = response.into_inner();
 
@@ -81,7 +183,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
         Commands::Remove { key } => {
-            let request = Request::new(RemoveRequest { key });
+            let request = Request::new(RemoveRequest { key, namespace });
             let response = client.remove(request).await?;
             let resp = response.into_inner();
 
@@ -96,6 +198,275 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 eprintln!("Failed to remove key: {}. Error: {}", key, resp.error);
             }
         }
+        Commands::PutBlob { key, file } => {
+            let mut file = File::open(&file).await?;
+            let (tx, rx) = mpsc::channel(4);
+            
+            // Read the file in pieces while they are being sent
+            tokio::spawn(async move {
+                let mut namespace = namespace;
+                let mut buffer = vec![0; BLOB_MESSAGE_SIZE];
+                while let Ok(len) = file.read(&mut buffer).await {
+                    if len == 0 {
+                        break;
+                    }
+                    // Only the first piece needs the namespace
+                    let piece = PutBlobRequest {
+                        key,
+                        data: buffer[..len].to_vec(),
+                        namespace: std::mem::take(&mut namespace),
+                    };
+                    if tx.send(piece).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            
+            let response = client.put_blob(ReceiverStream::new(rx)).await?;
+            let resp = response.into_inner();
+
+            if resp.success {
+                println!("Successfully stored {} bytes for key: {}", resp.size, key);
+            } else {
+                eprintln!("Failed to store key: {}. Error: {}", key, resp.error);
+            }
+        }
+        Commands::GetBlob { key, output } => {
+            let request = Request::new(GetBlobRequest { key, namespace });
+            let mut stream = match client.get_blob(request).await {
+                Ok(response) => response.into_inner(),
+                Err(status) if status.code() == tonic::Code::NotFound => {
+                    println!("{}", status.message());
+                    return Ok(());
+                }
+                Err(status) => return Err(status.into()),
+            };
+
+            let mut writer: Box<dyn tokio::io::AsyncWrite + Unpin> = match &output {
+                Some(path) => Box::new(File::create(path).await?),
+                None => Box::new(tokio::io::stdout()),
+            };
+            
+            let mut size = 0;
+            loop {
+                match stream.message().await {
+                    Ok(Some(piece)) => {
+                        writer.write_all(&piece.data).await?;
+                        size += piece.data.len();
+                    }
+                    Ok(None) => break,
+                    Err(status) if status.code() == tonic::Code::NotFound => {
+                        println!("Key not found: {}", key);
+                        return Ok(());
+                    }
+                    Err(status) => {
+                        eprintln!("Error retrieving key {}: {}", key, status.message());
+                        return Ok(());
+                    }
+                }
+            }
+            writer.flush().await?;
+            
+            if let Some(path) = output {
+                println!("Wrote {} bytes for key {} to {:?}", size, key, path);
+            }
+        }
+        Commands::MultiGet { keys } => {
+            let request = Request::new(MultiGetRequest { keys: keys.clone(), namespace });
+            let response = client.multi_get(request).await?;
+            let resp = response.into_inner();
+
+            if !resp.error.is_empty() {
+                eprintln!("Error retrieving keys: {}", resp.error);
+            }
+            for (key, value) in keys.iter().zip(resp.values) {
+                if value.exists {
+                    println!("Value for key {}: {}", key, value.value);
+                } else {
+                    println!("Key not found: {}", key);
+                }
+            }
+        }
+        Commands::MultiSet { entries } => {
+            let count = entries.len();
+            let request = Request::new(MultiSetRequest { entries, namespace });
+            let response = client.multi_set(request).await?;
+            let resp = response.into_inner();
+
+            if resp.success {
+                println!("Successfully set {} keys", count);
+            } else {
+                eprintln!("Failed to set keys. Error: {}", resp.error);
+            }
+        }
+        Commands::Incr { key, delta } => {
+            let request = Request::new(IncrRequest { key, delta, namespace });
+            let response = client.incr(request).await?;
+            let resp = response.into_inner();
+
+            if resp.success {
+                println!("Value for key {}: {}", key, resp.value);
+            } else {
+                eprintln!("Failed to update key: {}. Error: {}", key, resp.error);
+            }
+        }
+        Commands::Query { index, value } => {
+            let request = Request::new(QueryByIndexRequest { index, value, namespace });
+            let response = client.query_by_index(request).await?;
+            let resp = response.into_inner();
+
+            if !resp.error.is_empty() {
+                eprintln!("Error querying the index: {}", resp.error);
+            } else if resp.entries.is_empty() {
+                println!("No matching keys");
+            }
+            for entry in resp.entries {
+                println!("Value for key {}: {}", entry.key, entry.value);
+            }
+        }
+        Commands::Dump { output, format } => {
+            let request = Request::new(DumpRequest { namespace });
+            let mut stream = client.dump(request).await?.into_inner();
+
+            let file: Box<dyn std::io::Write> = match &output {
+                Some(path) => Box::new(std::fs::File::create(path)?),
+                None => Box::new(std::io::stdout()),
+            };
+            let mut writer = EntryWriter::new(BufWriter::new(file), format)?;
+            
+            let mut count = 0;
+            let mut failure = None;
+            loop {
+                match stream.message().await {
+                    Ok(Some(batch)) => {
+                        for entry in &batch.entries {
+                            writer.write(entry.key, &entry.value)?;
+                        }
+                        count += batch.entries.len();
+                    }
+                    Ok(None) => break,
+                    Err(status) => {
+                        failure = Some(status);
+                        break;
+                    }
+                }
+            }
+            writer.finish()?;
+            
+            if let Some(status) = failure {
+                eprintln!("Error dumping entries after {}: {}", count, status.message());
+                std::process::exit(1);
+            }
+            
+            if let Some(path) = output {
+                println!("Wrote {} entries to {:?}", count, path);
+            }
+        }
+        Commands::Load { file, format } => {
+            let file = std::fs::File::open(&file)?;
+            let (tx, rx) = mpsc::channel(4);
+            
+            // Parse the file on a thread of its own while the batches are being sent.
+            // Malformed data stops the stream, and the entries before it are kept.
+            let reader = tokio::task::spawn_blocking(move || {
+                let mut namespace = namespace;
+                let mut entries = EntryReader::new(BufReader::new(file), format);
+                loop {
+                    let mut batch = Vec::with_capacity(LOAD_BATCH_SIZE);
+                    let mut size = 0;
+                    let mut result = Ok(());
+                    let mut done = false;
+                    while batch.len() < LOAD_BATCH_SIZE && size < LOAD_BATCH_BYTES {
+                        match entries.next() {
+                            Some(Ok((key, value))) => {
+                                size += std::mem::size_of::<i64>() + value.len();
+                                batch.push(KeyValue { key, value });
+                            }
+                            Some(Err(err)) => {
+                                result = Err(err);
+                                done = true;
+                                break;
+                            }
+                            None => {
+                                done = true;
+                                break;
+                            }
+                        }
+                    }
+                    if !batch.is_empty() {
+                        // Only the first batch needs the namespace
+                        let request = LoadRequest { entries: batch, namespace: std::mem::take(&mut namespace) };
+                        if tx.blocking_send(request).is_err() {
+                            return Ok(());
+                        }
+                    }
+                    if done {
+                        return result;
+                    }
+                }
+            });
+            
+            let response = client.load(ReceiverStream::new(rx)).await?;
+            let resp = response.into_inner();
+            let parsed: kvdb::Result<()> = reader.await?;
+
+            if !resp.success {
+                eprintln!("Failed to load entries after {}. Error: {}", resp.count, resp.error);
+                std::process::exit(1);
+            } else if let Err(err) = parsed {
+                eprintln!("Loaded {} entries, then stopped. Error: {}", resp.count, err);
+                std::process::exit(1);
+            } else {
+                println!("Successfully loaded {} entries", resp.count);
+            }
+        }
+        Commands::Stats => {
+            let request = Request::new(StatsRequest { namespace });
+            let response = client.stats(request).await?;
+            let resp = response.into_inner();
+
+            if !resp.error.is_empty() {
+                eprintln!("Error getting stats: {}", resp.error);
+                return Ok(());
+            }
+            let operations: serde_json::Map<String, serde_json::Value> = resp
+                .operations
+                .iter()
+                .map(|operation| {
+                    let mean = match operation.count {
+                        0 => 0.0,
+                        count => operation.total_latency_micros as f64 / count as f64,
+                    };
+                    let stats = json!({
+                        "count": operation.count,
+                        "errors": operation.errors,
+                        "mean_latency_us": mean,
+                        "p50_latency_us": percentile_micros(operation, 0.5),
+                        "p99_latency_us": percentile_micros(operation, 0.99),
+                        "max_latency_us": operation.max_latency_micros,
+                    });
+                    (operation.name.clone(), stats)
+                })
+                .collect();
+            let stats = json!({
+                "keys": resp.keys,
+                "file_size": resp.file_size,
+                "dead_bytes": resp.dead_bytes_known.then_some(resp.dead_bytes),
+                "cache": {
+                    "hits": resp.cache_hits,
+                    "misses": resp.cache_misses,
+                    "evictions": resp.cache_evictions,
+                    "entries": resp.cache_entries,
+                    "size_bytes": resp.cache_size_bytes,
+                    "hit_rate": resp.cache_hit_rate,
+                },
+                "gc_runs": resp.gc_runs,
+                "compactions": resp.compactions,
+                "compaction_time_us": resp.compaction_time_micros,
+                "operations": operations,
+            });
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        }
     }
 
     Ok(())
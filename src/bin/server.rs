@@ -1,6 +1,15 @@
-use kvdb::{Config, KvDb};
+use kvdb::{AsyncKvDb, Config, DbStats, KvError, SecondaryIndex};
+use metrics::{CountedService, ServerMetrics};
+use std::collections::HashSet;
 use std::sync::Arc;
-use tonic::{transport::Server, Request, Response, Status};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport::Server, Request, Response, Status, Streaming};
+
+#[path = "server/metrics.rs"]
+mod metrics;
 
 // Include the generated proto code
 pub mod kvdb_proto {
@@ -9,18 +18,52 @@ pub mod kvdb_proto {
 
 use kvdb_proto::{
     kv_service_server::{KvService, KvServiceServer},
-    GetRequest, GetResponse, RemoveRequest, RemoveResponse, SetRequest, SetResponse,
+    DumpRequest, DumpResponse, GetBlobRequest, GetBlobResponse, GetRequest, GetResponse,
+    IncrRequest, IncrResponse, KeyValue, LatencyBucket, LoadRequest, LoadResponse,
+    MultiGetRequest, MultiGetResponse, MultiSetRequest, MultiSetResponse, OperationStats,
+    PutBlobRequest, PutBlobResponse, QueryByIndexRequest, QueryByIndexResponse, RemoveRequest,
+    RemoveResponse, SetRequest, SetResponse, StatsRequest, StatsResponse,
 };
 
+// The size of the pieces large values are streamed to clients in
+const BLOB_MESSAGE_SIZE: usize = 64 * 1024;
+
+// The most entries, and bytes of keys and values, in each message of a dump.
+// Messages stay well below the 4MB gRPC clients accept by default.
+const DUMP_BATCH_SIZE: usize = 1000;
+const DUMP_BATCH_BYTES: usize = 1024 * 1024;
+
 // Our KVDB gRPC service implementation
+// Database operations run off the runtime's workers, so a slow disk or a
+// garbage collection doesn't hold up other requests
+// Namespaces are only created for writes to those the server was started
+// with, so clients can't make it open any number of column families.
 struct KvDbService {
-    db: Arc<KvDb>,
+    db: AsyncKvDb,
+    namespaces: HashSet<String>,
 }
 
 impl KvDbService {
-    fn new(db: KvDb) -> Self {
-        Self {
-            db: Arc::new(db),
+    fn new(db: AsyncKvDb, namespaces: HashSet<String>) -> Self {
+        Self { db, namespaces }
+    }
+
+    // The column family named in a request, or the default one if there is no
+    // name. Writes may create one of the namespaces the server was started with.
+    async fn namespace(&self, name: &str, write: bool) -> Result<AsyncKvDb, Status> {
+        if name.is_empty() {
+            return Ok(self.db.clone());
+        }
+        let result = if write && self.namespaces.contains(name) {
+            self.db.cf(name).await.map(Some)
+        } else {
+            self.db.existing_cf(name).await
+        };
+        match result {
+            Ok(Some(db)) => Ok(db),
+            Ok(None) => Err(Status::not_found(format!("Namespace not found: {}", name))),
+            Err(err @ KvError::InvalidColumnFamily(_)) => Err(Status::invalid_argument(format!("{}", err))),
+            Err(err) => Err(Status::internal(format!("{}", err))),
         }
     }
 }
@@ -32,7 +75,9 @@ impl KvService for KvDbService {
         let req = request.into_inner();
         
         // Attempt to set the key-value pair
-        match self.db.set(req.key, &req.value) {
+        let db = self.namespace(&req.namespace, true).await?;
+        let result = db.set(req.key, req.value).await;
+        match result {
             Ok(old_value) => Ok(Response::new(SetResponse {
                 success: true,
                 old_value: old_value.unwrap_or_default(),
@@ -50,7 +95,9 @@ impl KvService for KvDbService {
         let req = request.into_inner();
         
         // Attempt to get the value for the key
-        match self.db.get(req.key) {
+        let db = self.namespace(&req.namespace, false).await?;
+        let result = db.get(req.key).await;
+        match result {
             Ok(value_opt) => {
                 let exists = value_opt.is_some();
                 Ok(Response::new(GetResponse {
@@ -71,7 +118,9 @@ impl KvService for KvDbService {
         let req = request.into_inner();
         
         // Attempt to remove the key
-        match self.db.remove(req.key) {
+        let db = self.namespace(&req.namespace, false).await?;
+        let result = db.remove(req.key).await;
+        match result {
             Ok(old_value) => Ok(Response::new(RemoveResponse {
                 success: true,
                 old_value: old_value.unwrap_or_default(),
@@ -84,6 +133,282 @@ impl KvService for KvDbService {
             })),
         }
     }
+
+    async fn put_blob(&self, request: Request<Streaming<PutBlobRequest>>) -> Result<Response<PutBlobResponse>, Status> {
+        let mut stream = request.into_inner();
+        
+        // The first piece says where the value goes
+        let mut piece = stream.message().await?;
+        let Some(first) = &piece else {
+            return Ok(Response::new(PutBlobResponse {
+                success: false,
+                size: 0,
+                error: "No data received".to_string(),
+            }));
+        };
+        let db = self.namespace(&first.namespace, true).await?;
+        let key = first.key;
+        
+        // Write each piece to the database as it arrives
+        let result: Result<u64, String> = async {
+            let mut writer = db.blob_writer(key);
+            let mut size = 0;
+            
+            while let Some(data) = piece {
+                size += data.data.len() as u64;
+                writer.write(data.data).await.map_err(|err| err.to_string())?;
+                piece = stream.message().await.map_err(|status| status.message().to_string())?;
+            }
+            
+            writer.finish().await.map_err(|err| err.to_string())?;
+            Ok(size)
+        }
+        .await;
+        
+        match result {
+            Ok(size) => Ok(Response::new(PutBlobResponse {
+                success: true,
+                size,
+                error: String::new(),
+            })),
+            Err(error) => Ok(Response::new(PutBlobResponse {
+                success: false,
+                size: 0,
+                error,
+            })),
+        }
+    }
+
+    type GetBlobStream = ReceiverStream<Result<GetBlobResponse, Status>>;
+
+    async fn get_blob(&self, request: Request<GetBlobRequest>) -> Result<Response<Self::GetBlobStream>, Status> {
+        let req = request.into_inner();
+        let db = self.namespace(&req.namespace, false).await?;
+        let mut reader = match db.get_reader(req.key, BLOB_MESSAGE_SIZE).await {
+            Ok(Some(reader)) => reader,
+            Ok(None) => return Err(Status::not_found(format!("Key not found: {}", req.key))),
+            Err(err) => return Err(Status::internal(format!("{}", err))),
+        };
+        let (tx, rx) = mpsc::channel(4);
+        
+        // Pass the value on to the client as it is read
+        tokio::spawn(async move {
+            loop {
+                let message = match reader.read().await {
+                    Ok(Some(data)) => Ok(GetBlobResponse { data }),
+                    Ok(None) => break,
+                    Err(err) => Err(Status::internal(format!("{}", err))),
+                };
+                
+                // Stop if the client went away or the read failed
+                let failed = message.is_err();
+                if tx.send(message).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn multi_get(&self, request: Request<MultiGetRequest>) -> Result<Response<MultiGetResponse>, Status> {
+        let req = request.into_inner();
+        
+        // Look up all the keys at once
+        let db = self.namespace(&req.namespace, false).await?;
+        let result = db.get_many(req.keys).await;
+        match result {
+            Ok(values) => Ok(Response::new(MultiGetResponse {
+                values: values
+                    .into_iter()
+                    .map(|value_opt| GetResponse {
+                        exists: value_opt.is_some(),
+                        value: value_opt.unwrap_or_default(),
+                        error: String::new(),
+                    })
+                    .collect(),
+                error: String::new(),
+            })),
+            Err(err) => Ok(Response::new(MultiGetResponse {
+                values: Vec::new(),
+                error: format!("{}", err),
+            })),
+        }
+    }
+
+    async fn multi_set(&self, request: Request<MultiSetRequest>) -> Result<Response<MultiSetResponse>, Status> {
+        let req = request.into_inner();
+        
+        // Write all the pairs at once
+        let entries: Vec<(i64, String)> = req.entries.into_iter().map(|entry| (entry.key, entry.value)).collect();
+        let db = self.namespace(&req.namespace, true).await?;
+        let result = db.set_many(entries).await;
+        match result {
+            Ok(_) => Ok(Response::new(MultiSetResponse {
+                success: true,
+                error: String::new(),
+            })),
+            Err(err) => Ok(Response::new(MultiSetResponse {
+                success: false,
+                error: format!("{}", err),
+            })),
+        }
+    }
+
+    async fn incr(&self, request: Request<IncrRequest>) -> Result<Response<IncrResponse>, Status> {
+        let req = request.into_inner();
+        
+        // Attempt to update the counter
+        let db = self.namespace(&req.namespace, true).await?;
+        let result = db.incr(req.key, req.delta).await;
+        match result {
+            Ok(value) => Ok(Response::new(IncrResponse {
+                success: true,
+                value,
+                error: String::new(),
+            })),
+            Err(err) => Ok(Response::new(IncrResponse {
+                success: false,
+                value: 0,
+                error: format!("{}", err),
+            })),
+        }
+    }
+
+    async fn query_by_index(&self, request: Request<QueryByIndexRequest>) -> Result<Response<QueryByIndexResponse>, Status> {
+        let req = request.into_inner();
+        
+        // Look up the matching entries in the index
+        let db = self.namespace(&req.namespace, false).await?;
+        let result = db.query_by_index(&req.index, &req.value).await;
+        match result {
+            Ok(entries) => Ok(Response::new(QueryByIndexResponse {
+                entries: entries.into_iter().map(|(key, value)| KeyValue { key, value }).collect(),
+                error: String::new(),
+            })),
+            Err(err) => Ok(Response::new(QueryByIndexResponse {
+                entries: Vec::new(),
+                error: format!("{}", err),
+            })),
+        }
+    }
+
+    type DumpStream = ReceiverStream<Result<DumpResponse, Status>>;
+
+    async fn dump(&self, request: Request<DumpRequest>) -> Result<Response<Self::DumpStream>, Status> {
+        let req = request.into_inner();
+        let db = self.namespace(&req.namespace, false).await?;
+        let mut pages = db.scan_pages(.., DUMP_BATCH_SIZE, DUMP_BATCH_BYTES);
+        let (tx, rx) = mpsc::channel(4);
+        
+        // Send the entries a batch at a time as they are read, stopping if the
+        // client goes away or a read fails
+        tokio::spawn(async move {
+            loop {
+                let batch = match pages.next_page().await {
+                    Ok(Some(page)) => Ok(DumpResponse {
+                        entries: page.into_iter().map(|(key, value)| KeyValue { key, value }).collect(),
+                    }),
+                    Ok(None) => break,
+                    Err(err) => Err(Status::internal(format!("{}", err))),
+                };
+                let failed = batch.is_err();
+                if tx.send(batch).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn load(&self, request: Request<Streaming<LoadRequest>>) -> Result<Response<LoadResponse>, Status> {
+        let mut stream = request.into_inner();
+        
+        // The first batch says where the entries go
+        let mut batch = stream.message().await?;
+        let db = match &batch {
+            Some(first) => self.namespace(&first.namespace, true).await?,
+            None => self.db.clone(),
+        };
+        
+        // Set each batch as it arrives, counting the entries set
+        let mut count = 0;
+        let result: Result<(), String> = async {
+            while let Some(data) = batch {
+                let entries: Vec<(i64, String)> = data.entries.into_iter().map(|entry| (entry.key, entry.value)).collect();
+                let len = entries.len() as u64;
+                db.set_many(entries).await.map_err(|err| err.to_string())?;
+                count += len;
+                batch = stream.message().await.map_err(|status| status.message().to_string())?;
+            }
+            Ok(())
+        }
+        .await;
+        
+        match result {
+            Ok(()) => Ok(Response::new(LoadResponse {
+                success: true,
+                count,
+                error: String::new(),
+            })),
+            Err(error) => Ok(Response::new(LoadResponse {
+                success: false,
+                count,
+                error,
+            })),
+        }
+    }
+
+    async fn stats(&self, request: Request<StatsRequest>) -> Result<Response<StatsResponse>, Status> {
+        let req = request.into_inner();
+        
+        let db = self.namespace(&req.namespace, false).await?;
+        let result = db.stats().await;
+        match result {
+            Ok(stats) => Ok(Response::new(stats_response(stats))),
+            Err(err) => Ok(Response::new(StatsResponse {
+                error: format!("{}", err),
+                ..StatsResponse::default()
+            })),
+        }
+    }
+}
+
+fn stats_response(stats: DbStats) -> StatsResponse {
+    let micros = |latency: Duration| latency.as_micros() as u64;
+    StatsResponse {
+        keys: stats.keys as u64,
+        file_size: stats.file_size,
+        dead_bytes: stats.dead_bytes.unwrap_or(0),
+        dead_bytes_known: stats.dead_bytes.is_some(),
+        cache_hits: stats.cache.hits,
+        cache_misses: stats.cache.misses,
+        cache_evictions: stats.cache.evictions,
+        cache_entries: stats.cache.entries as u64,
+        cache_size_bytes: stats.cache.size_bytes as u64,
+        cache_hit_rate: stats.cache.hit_rate(),
+        gc_runs: stats.gc_runs,
+        compactions: stats.compactions,
+        compaction_time_micros: micros(stats.compaction_time),
+        operations: stats
+            .operations
+            .into_iter()
+            .map(|operation| OperationStats {
+                name: operation.operation.to_string(),
+                count: operation.count,
+                errors: operation.errors,
+                total_latency_micros: micros(operation.total_latency),
+                max_latency_micros: micros(operation.max_latency),
+                latency_buckets: operation
+                    .latency_buckets
+                    .into_iter()
+                    .map(|(bound, count)| LatencyBucket { le_micros: micros(bound), count })
+                    .collect(),
+            })
+            .collect(),
+        error: String::new(),
+    }
 }
 
 #[tokio::main]
@@ -91,9 +416,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize logger
     env_logger::init();
     
-    // Parse command-line arguments
-    let args: Vec<String> = std::env::args().collect();
-    let addr = if args.len() > 1 {
+    // Parse command-line arguments. `--metrics ADDR` serves metrics for
+    // Prometheus over HTTP on another address.
+    let mut args: Vec<String> = std::env::args().collect();
+    let metrics_addr: Option<std::net::SocketAddr> = match args.iter().position(|arg| arg == "--metrics") {
+        Some(i) => {
+            let addr = args.get(i + 1).ok_or("expected an address after --metrics")?.parse()?;
+            args.drain(i..i + 2);
+            Some(addr)
+        }
+        None => None,
+    };
+    
+    // Each `--namespace NAME` lets writes create that namespace. Others can
+    // only be used once they exist.
+    let mut namespaces = HashSet::new();
+    while let Some(i) = args.iter().position(|arg| arg == "--namespace") {
+        let name = args.get(i + 1).ok_or("expected a name after --namespace")?;
+        namespaces.insert(name.clone());
+        args.drain(i..i + 2);
+    }
+    let addr: std::net::SocketAddr = if args.len() > 1 {
         args[1].parse()?
     } else {
         "[::1]:50051".parse()?
@@ -105,22 +448,56 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         std::path::PathBuf::from("db")
     };
     
-    // Configure and open the database
+    // Any further arguments define secondary indexes, as NAME=/json/pointer
+    let secondary_indexes = args
+        .iter()
+        .skip(3)
+        .map(|arg| match arg.split_once('=') {
+            Some((name, path)) => Ok(SecondaryIndex::new(name, path)),
+            None => Err(format!("expected NAME=/json/pointer, got {:?}", arg)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    
+    // Configure and open the database, keeping track of the hot keys
+    // so that the cache can be warmed up after a restart
     let config = Config {
         path: db_path.clone(),
+        hot_keys_interval: Some(Duration::from_secs(60)),
+        secondary_indexes,
         ..Config::default()
     };
     
-    let db = KvDb::open(config)?;
-    let service = KvDbService::new(db);
+    let db = AsyncKvDb::open(config).await?;
+    let metrics = Arc::new(ServerMetrics::default());
+    let service = CountedService::new(KvDbService::new(db.clone(), namespaces), metrics.clone());
+    
+    if let Some(metrics_addr) = metrics_addr {
+        let metrics = metrics.clone();
+        let db = db.clone();
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(metrics_addr, metrics, db).await {
+                log::error!("Metrics server failed: {}", err);
+            }
+        });
+        println!("Serving metrics on http://{}/metrics", metrics_addr);
+    }
+    
+    // Warm up the cache while serving requests
+    tokio::spawn(async move {
+        match db.warm_cache().await {
+            Ok(keys) => log::info!("Warmed up the cache with {} keys", keys),
+            Err(err) => log::warn!("Failed to warm up the cache: {}", err),
+        }
+    });
     
     println!("KVDB Server listening on {}", addr);
     println!("Database path: {:?}", db_path);
     
-    // Start the gRPC server
+    // Start the gRPC server, counting the connections it accepts
+    let listener = TcpListener::bind(addr).await?;
     Server::builder()
         .add_service(KvServiceServer::new(service))
-        .serve(addr)
+        .serve_with_incoming(metrics.incoming(listener))
         .await?;
     
     Ok(())
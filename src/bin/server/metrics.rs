@@ -0,0 +1,425 @@
+// Metrics for Prometheus to scrape, served over HTTP at /metrics: counts and
+// latencies of the RPCs by method and status, open connections, and the stats
+// of every namespace in use
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::{self, Write};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, StatusCode};
+use kvdb::{AsyncKvDb, DbStats, LATENCY_BUCKETS};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::transport::server::{Connected, TcpConnectInfo};
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::kvdb_proto::{
+    kv_service_server::KvService, DumpRequest, DumpResponse, GetBlobRequest, GetBlobResponse,
+    GetRequest, GetResponse, IncrRequest, IncrResponse, LoadRequest, LoadResponse,
+    MultiGetRequest, MultiGetResponse, MultiSetRequest, MultiSetResponse, PutBlobRequest,
+    PutBlobResponse, QueryByIndexRequest, QueryByIndexResponse, RemoveRequest, RemoveResponse,
+    SetRequest, SetResponse, StatsRequest, StatsResponse,
+};
+use crate::KvDbService;
+
+// The content type of the Prometheus text format
+const TEXT_FORMAT: &str = "text/plain; version=0.0.4";
+
+// Latencies of one kind of RPC, counted in `LATENCY_BUCKETS`
+#[derive(Default)]
+struct Histogram {
+    // Calls per bucket; those slower than every bound are only in `count`
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: Duration,
+}
+
+impl Histogram {
+    fn observe(&mut self, latency: Duration) {
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&bound| latency <= bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += latency;
+    }
+}
+
+// The counters the server keeps itself, as opposed to those of the database
+#[derive(Default)]
+pub struct ServerMetrics {
+    // By method and status
+    rpcs: Mutex<BTreeMap<(&'static str, String), Histogram>>,
+    open_connections: AtomicI64,
+}
+
+impl ServerMetrics {
+    // Run an RPC, counting it by its status: "ok", "error" for an error reported
+    // in the response, or the gRPC status code it failed with. The latency of a
+    // streamed response is the time until the stream starts.
+    async fn observe<T: ReportsError>(
+        &self,
+        method: &'static str,
+        rpc: impl Future<Output = Result<Response<T>, Status>>,
+    ) -> Result<Response<T>, Status> {
+        let started = Instant::now();
+        let result = rpc.await;
+        let status = match &result {
+            Ok(response) if response.get_ref().error().is_empty() => "ok".to_string(),
+            Ok(_) => "error".to_string(),
+            Err(status) => format!("{:?}", status.code()),
+        };
+        self.rpcs.lock().unwrap().entry((method, status)).or_default().observe(started.elapsed());
+        result
+    }
+
+    // Accept connections for the gRPC server, keeping count of those open
+    pub fn incoming(self: &Arc<Self>, listener: TcpListener) -> impl Stream<Item = io::Result<CountedStream>> {
+        let metrics = self.clone();
+        TcpListenerStream::new(listener).map(move |stream| Ok(CountedStream::new(stream?, metrics.clone())))
+    }
+
+    // Render the metrics of the server and of the namespaces of the database in
+    // use. Scraping doesn't open namespaces that haven't been used since the start.
+    async fn render(&self, db: &AsyncKvDb) -> String {
+        let mut namespaces = vec![(String::new(), db.clone())];
+        match db.open_column_families().await {
+            Ok(column_families) => namespaces.extend(column_families),
+            Err(err) => log::warn!("Failed to list namespaces for metrics: {}", err),
+        }
+        let mut stats = Vec::with_capacity(namespaces.len());
+        for (name, db) in namespaces {
+            match db.stats().await {
+                Ok(db_stats) => stats.push((name, db_stats)),
+                Err(err) => log::warn!("Failed to get stats of namespace {:?}: {}", name, err),
+            }
+        }
+
+        let mut out = Exposition::default();
+        out.header("kvdb_rpc_requests_total", "counter", "RPCs handled, by method and status");
+        let rpcs = self.rpcs.lock().unwrap();
+        for ((method, status), histogram) in rpcs.iter() {
+            out.sample("kvdb_rpc_requests_total", &[("method", method), ("status", status)], histogram.count);
+        }
+        out.header("kvdb_rpc_duration_seconds", "histogram", "Time taken to handle RPCs, by method and status");
+        for ((method, status), histogram) in rpcs.iter() {
+            let mut below = 0;
+            let buckets = LATENCY_BUCKETS.iter().zip(histogram.buckets).map(|(&bound, count)| {
+                below += count;
+                (bound, below)
+            });
+            let labels = [("method", *method), ("status", status.as_str())];
+            out.histogram("kvdb_rpc_duration_seconds", &labels, buckets, histogram.count, histogram.sum);
+        }
+        drop(rpcs);
+        out.header("kvdb_open_connections", "gauge", "Client connections currently open");
+        out.sample("kvdb_open_connections", &[], self.open_connections.load(Ordering::Relaxed));
+
+        write_db_metrics(&mut out, &stats);
+        out.text
+    }
+}
+
+// A metric of each namespace: its name, help text, and its value in the stats
+type NamespaceMetric<T> = (&'static str, &'static str, fn(&DbStats) -> T);
+
+// Add the stats of each namespace
+fn write_db_metrics(out: &mut Exposition, stats: &[(String, DbStats)]) {
+    let gauges: [NamespaceMetric<Option<f64>>; 5] = [
+        ("kvdb_keys", "Keys in the namespace", |stats| Some(stats.keys as f64)),
+        ("kvdb_file_size_bytes", "Bytes the namespace takes up on disk", |stats| Some(stats.file_size as f64)),
+        ("kvdb_live_bytes", "Bytes of the log holding current values", |stats| {
+            stats.dead_bytes.map(|dead_bytes| (stats.file_size - dead_bytes) as f64)
+        }),
+        ("kvdb_dead_bytes", "Bytes of the log garbage collection would reclaim", |stats| {
+            stats.dead_bytes.map(|dead_bytes| dead_bytes as f64)
+        }),
+        ("kvdb_cache_hit_ratio", "Fraction of cache lookups that found the key", |stats| Some(stats.cache.hit_rate())),
+    ];
+    for (name, help, value) in gauges {
+        out.header(name, "gauge", help);
+        for (namespace, stats) in stats {
+            if let Some(value) = value(stats) {
+                out.sample(name, &[("namespace", namespace)], value);
+            }
+        }
+    }
+
+    let counters: [NamespaceMetric<u64>; 3] = [
+        ("kvdb_cache_hits_total", "Cache lookups that found the key", |stats| stats.cache.hits),
+        ("kvdb_cache_misses_total", "Cache lookups that didn't find the key", |stats| stats.cache.misses),
+        ("kvdb_cache_evictions_total", "Entries evicted from the cache", |stats| stats.cache.evictions),
+    ];
+    for (name, help, value) in counters {
+        out.header(name, "counter", help);
+        for (namespace, stats) in stats {
+            out.sample(name, &[("namespace", namespace)], value(stats));
+        }
+    }
+
+    let name = "kvdb_compaction_duration_seconds";
+    out.header(name, "summary", "Time taken by garbage collections of the log and compactions of the LSM engine");
+    for (namespace, stats) in stats {
+        out.sample(&format!("{}_sum", name), &[("namespace", namespace)], stats.compaction_time.as_secs_f64());
+        out.sample(&format!("{}_count", name), &[("namespace", namespace)], stats.gc_runs + stats.compactions);
+    }
+
+    let name = "kvdb_operation_duration_seconds";
+    out.header(name, "histogram", "Time taken by database operations, by namespace and operation");
+    for (namespace, stats) in stats {
+        for operation in &stats.operations {
+            let labels = [("namespace", namespace.as_str()), ("operation", operation.operation.name())];
+            let buckets = operation.latency_buckets.iter().copied();
+            out.histogram(name, &labels, buckets, operation.count, operation.total_latency);
+        }
+    }
+    let name = "kvdb_operation_errors_total";
+    out.header(name, "counter", "Database operations that failed, by namespace and operation");
+    for (namespace, stats) in stats {
+        for operation in &stats.operations {
+            let labels = [("namespace", namespace.as_str()), ("operation", operation.operation.name())];
+            out.sample(name, &labels, operation.errors);
+        }
+    }
+}
+
+// Writes metrics in the Prometheus text format
+#[derive(Default)]
+struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.text, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.text, "# TYPE {} {}", name, kind).unwrap();
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl fmt::Display) {
+        self.text.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(label, value)| {
+                    let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+                    format!("{}=\"{}\"", label, value)
+                })
+                .collect();
+            write!(self.text, "{{{}}}", labels.join(",")).unwrap();
+        }
+        writeln!(self.text, " {}", value).unwrap();
+    }
+
+    // The buckets hold the number of observations that were at most each bound
+    fn histogram(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        buckets: impl Iterator<Item = (Duration, u64)>,
+        count: u64,
+        sum: Duration,
+    ) {
+        let bucket_name = format!("{}_bucket", name);
+        for (bound, below) in buckets {
+            let bound = bound.as_secs_f64().to_string();
+            self.sample(&bucket_name, &[labels, &[("le", &bound)]].concat(), below);
+        }
+        self.sample(&bucket_name, &[labels, &[("le", "+Inf")]].concat(), count);
+        self.sample(&format!("{}_sum", name), labels, sum.as_secs_f64());
+        self.sample(&format!("{}_count", name), labels, count);
+    }
+}
+
+// Serve the metrics over HTTP until the server fails
+pub async fn serve(addr: SocketAddr, metrics: Arc<ServerMetrics>, db: AsyncKvDb) -> hyper::Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        let db = db.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: hyper::Request<Body>| {
+                let metrics = metrics.clone();
+                let db = db.clone();
+                async move {
+                    let response = match (request.method(), request.uri().path()) {
+                        (&Method::GET, "/metrics") => hyper::Response::builder()
+                            .header(CONTENT_TYPE, TEXT_FORMAT)
+                            .body(Body::from(metrics.render(&db).await)),
+                        _ => hyper::Response::builder().status(StatusCode::NOT_FOUND).body(Body::from("Not found\n")),
+                    };
+                    Ok::<_, Infallible>(response.unwrap())
+                }
+            }))
+        }
+    });
+    hyper::Server::try_bind(&addr)?.serve(make_service).await
+}
+
+// A client connection, counted as open until it is dropped
+pub struct CountedStream {
+    stream: TcpStream,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl CountedStream {
+    fn new(stream: TcpStream, metrics: Arc<ServerMetrics>) -> Self {
+        metrics.open_connections.fetch_add(1, Ordering::Relaxed);
+        Self { stream, metrics }
+    }
+}
+
+impl Drop for CountedStream {
+    fn drop(&mut self) {
+        self.metrics.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Connected for CountedStream {
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> TcpConnectInfo {
+        self.stream.connect_info()
+    }
+}
+
+impl AsyncRead for CountedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CountedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+// An error a response reports in its body rather than as a gRPC status
+trait ReportsError {
+    fn error(&self) -> &str {
+        ""
+    }
+}
+
+macro_rules! reports_error {
+    ($($response:ty),*) => {
+        $(impl ReportsError for $response {
+            fn error(&self) -> &str {
+                &self.error
+            }
+        })*
+    };
+}
+
+reports_error!(
+    SetResponse,
+    GetResponse,
+    RemoveResponse,
+    PutBlobResponse,
+    MultiGetResponse,
+    MultiSetResponse,
+    IncrResponse,
+    QueryByIndexResponse,
+    LoadResponse,
+    StatsResponse
+);
+
+// Streamed responses report errors as gRPC statuses
+impl<T> ReportsError for ReceiverStream<T> {}
+
+// The gRPC service, with each RPC counted in the server metrics
+pub struct CountedService {
+    service: KvDbService,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl CountedService {
+    pub fn new(service: KvDbService, metrics: Arc<ServerMetrics>) -> Self {
+        Self { service, metrics }
+    }
+}
+
+#[tonic::async_trait]
+impl KvService for CountedService {
+    async fn set(&self, request: Request<SetRequest>) -> Result<Response<SetResponse>, Status> {
+        self.metrics.observe("Set", self.service.set(request)).await
+    }
+
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        self.metrics.observe("Get", self.service.get(request)).await
+    }
+
+    async fn remove(&self, request: Request<RemoveRequest>) -> Result<Response<RemoveResponse>, Status> {
+        self.metrics.observe("Remove", self.service.remove(request)).await
+    }
+
+    async fn put_blob(&self, request: Request<Streaming<PutBlobRequest>>) -> Result<Response<PutBlobResponse>, Status> {
+        self.metrics.observe("PutBlob", self.service.put_blob(request)).await
+    }
+
+    type GetBlobStream = ReceiverStream<Result<GetBlobResponse, Status>>;
+
+    async fn get_blob(&self, request: Request<GetBlobRequest>) -> Result<Response<Self::GetBlobStream>, Status> {
+        self.metrics.observe("GetBlob", self.service.get_blob(request)).await
+    }
+
+    async fn multi_get(&self, request: Request<MultiGetRequest>) -> Result<Response<MultiGetResponse>, Status> {
+        self.metrics.observe("MultiGet", self.service.multi_get(request)).await
+    }
+
+    async fn multi_set(&self, request: Request<MultiSetRequest>) -> Result<Response<MultiSetResponse>, Status> {
+        self.metrics.observe("MultiSet", self.service.multi_set(request)).await
+    }
+
+    async fn incr(&self, request: Request<IncrRequest>) -> Result<Response<IncrResponse>, Status> {
+        self.metrics.observe("Incr", self.service.incr(request)).await
+    }
+
+    async fn query_by_index(&self, request: Request<QueryByIndexRequest>) -> Result<Response<QueryByIndexResponse>, Status> {
+        self.metrics.observe("QueryByIndex", self.service.query_by_index(request)).await
+    }
+
+    type DumpStream = ReceiverStream<Result<DumpResponse, Status>>;
+
+    async fn dump(&self, request: Request<DumpRequest>) -> Result<Response<Self::DumpStream>, Status> {
+        self.metrics.observe("Dump", self.service.dump(request)).await
+    }
+
+    async fn load(&self, request: Request<Streaming<LoadRequest>>) -> Result<Response<LoadResponse>, Status> {
+        self.metrics.observe("Load", self.service.load(request)).await
+    }
+
+    async fn stats(&self, request: Request<StatsRequest>) -> Result<Response<StatsResponse>, Status> {
+        self.metrics.observe("Stats", self.service.stats(request)).await
+    }
+}
@@ -0,0 +1,139 @@
+use clap::{Parser, Subcommand};
+use kvdb::{FileStorage, KvError, LogFile, LogRecord, RecordKind};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+#[derive(Parser)]
+#[clap(author, version, about = "Offline tool for the data file of a KVDB database")]
+struct Cli {
+    #[clap(subcommand)]
+    command: Commands,
+}
+
+// Every command works on a database directory that no server has open
+#[derive(Subcommand)]
+enum Commands {
+    /// Print every record with its offset, type, key, flags and size
+    Inspect {
+        /// The database directory
+        path: PathBuf,
+        /// Only print records at or after this offset
+        #[clap(long, default_value_t = 0)]
+        from: u64,
+        /// Stop after this many records
+        #[clap(long)]
+        limit: Option<usize>,
+    },
+    /// Check the structure of every record, reporting the first bad offset
+    Verify {
+        /// The database directory
+        path: PathBuf,
+    },
+    /// Count the records and keys, and the live and dead bytes
+    Stats {
+        /// The database directory
+        path: PathBuf,
+    },
+    /// Copy every readable record into a new data file, keeping the old one as data.db.corrupt
+    Repair {
+        /// The database directory
+        path: PathBuf,
+    },
+}
+
+// Describe the flags in a record header
+fn describe_flags(record: &LogRecord) -> String {
+    let names: &[(u8, &str)] = match record.kind {
+        RecordKind::Version => &[(0x10, "truncated")],
+        _ => &[(0x10, "compressed"), (0x20, "encrypted"), (0x40, "chunked")],
+    };
+    let set: Vec<&str> = names.iter().filter(|(flag, _)| record.flags & flag != 0).map(|(_, name)| *name).collect();
+    set.join(",")
+}
+
+fn print_record(record: &LogRecord) {
+    let mut line = format!("{:>12}  {:<7}", record.offset, record.kind);
+    match (record.key, record.version) {
+        (Some(key), _) => line += &format!("  key={}", key),
+        (None, Some((version, timestamp))) => line += &format!("  version={} timestamp={}", version, timestamp),
+        (None, None) => {}
+    }
+    line += &format!("  len={}", record.len);
+    if record.kind != RecordKind::Remove && record.kind != RecordKind::Version {
+        line += &format!("  value_size={}", record.value_size);
+    }
+    if let Some(previous) = record.previous() {
+        line += &format!("  previous={}", previous);
+    }
+    let flags = describe_flags(record);
+    if !flags.is_empty() {
+        line += &format!("  flags={}", flags);
+    }
+    println!("{}", line);
+}
+
+fn run(command: Commands) -> kvdb::Result<bool> {
+    let path = match &command {
+        Commands::Inspect { path, .. } | Commands::Verify { path } | Commands::Stats { path } | Commands::Repair { path } => path,
+    };
+    if !path.is_dir() {
+        eprintln!("No database directory at {:?}", path);
+        return Ok(false);
+    }
+    let storage = FileStorage::new(path)?;
+    let log = LogFile::open(&storage)?;
+
+    match command {
+        Commands::Inspect { from, limit, .. } => {
+            // Records are found by reading from the start, so skip the ones before `from`
+            let records = log.records().filter(|record| record.as_ref().map_or(true, |record| record.offset >= from));
+            for record in records.take(limit.unwrap_or(usize::MAX)) {
+                match record {
+                    Ok(record) => print_record(&record),
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        Commands::Verify { .. } => match log.verify() {
+            Ok(records) => println!("OK: {} records in {} bytes", records, log.size()),
+            Err(err @ KvError::Corrupted { .. }) => {
+                eprintln!("{}", err);
+                return Ok(false);
+            }
+            Err(err) => return Err(err),
+        },
+        Commands::Stats { .. } => {
+            let stats = log.stats()?;
+            let percent = |bytes: u64| if stats.file_size == 0 { 0.0 } else { bytes as f64 * 100.0 / stats.file_size as f64 };
+            println!("File size:  {} bytes", stats.file_size);
+            println!("Records:    {}", stats.records);
+            println!("Keys:       {}", stats.keys);
+            println!("Live bytes: {} ({:.1}%)", stats.live_bytes, percent(stats.live_bytes));
+            println!("Dead bytes: {} ({:.1}%)", stats.dead_bytes, percent(stats.dead_bytes));
+        }
+        Commands::Repair { .. } => {
+            let report = log.repair(&storage)?;
+            println!("Salvaged {} records", report.records);
+            println!("Skipped {} unreadable bytes", report.skipped_bytes);
+            println!("Lost {} values, which are now removed", report.lost_values);
+        }
+    }
+    Ok(true)
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+    let cli = Cli::parse();
+
+    match run(cli.command) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{read_payload, KvDb, KvError, LogStore, Result, StorageFile, ValuePos};
+
+// Describes a value stored as a sequence of chunk records.
+// It is stored as the payload of the Set record for the value.
+#[derive(Debug, Default)]
+pub(crate) struct Manifest {
+    // The total length of the value in bytes
+    pub(crate) len: u64,
+    // The positions of the chunk payloads, in order
+    pub(crate) chunks: Vec<ValuePos>,
+}
+
+impl Manifest {
+    // Serialize the manifest: total length, chunk count, then each chunk position
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(8 + 4 + self.chunks.len() * (8 + 8 + 1));
+        payload.write_u64::<LittleEndian>(self.len).unwrap();
+        payload.write_u32::<LittleEndian>(self.chunks.len() as u32).unwrap();
+        for chunk in &self.chunks {
+            payload.write_u64::<LittleEndian>(chunk.offset).unwrap();
+            payload.write_u64::<LittleEndian>(chunk.size).unwrap();
+            payload.write_u8(chunk.flags).unwrap();
+        }
+        payload
+    }
+
+    pub(crate) fn decode(mut payload: &[u8]) -> Result<Self> {
+        let len = payload.read_u64::<LittleEndian>()?;
+        let count = payload.read_u32::<LittleEndian>()?;
+
+        let mut chunks = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            chunks.push(ValuePos {
+                offset: payload.read_u64::<LittleEndian>()?,
+                size: payload.read_u64::<LittleEndian>()?,
+                flags: payload.read_u8()?,
+            });
+        }
+
+        if !payload.is_empty() {
+            return Err(KvError::InvalidFormat);
+        }
+        Ok(Self { len, chunks })
+    }
+}
+
+// The chunks a blob writer has appended to the log so far, which aren't
+// reachable from the index until its value is committed
+pub(crate) struct PendingBlob {
+    pub(crate) key: i64,
+    pub(crate) chunks: Vec<ValuePos>,
+}
+
+// Streams a value out of the database one chunk at a time.
+// Chunked values are read from the data file the reader was created on, so it
+// keeps working even if garbage collection replaces the data file in the meantime.
+pub struct ValueReader<'a> {
+    db: &'a KvDb,
+    key: i64,
+    file: Option<Arc<dyn StorageFile>>,
+    chunks: VecDeque<ValuePos>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl<'a> ValueReader<'a> {
+    // A reader over a value that is already in memory
+    pub(crate) fn from_value(db: &'a KvDb, key: i64, value: Vec<u8>) -> Self {
+        Self {
+            db,
+            key,
+            file: None,
+            chunks: VecDeque::new(),
+            buffer: value,
+            position: 0,
+        }
+    }
+
+    // A reader over a chunked value, loading chunks from the file as needed
+    pub(crate) fn from_chunks(db: &'a KvDb, key: i64, file: Arc<dyn StorageFile>, manifest: Manifest) -> Self {
+        Self {
+            db,
+            key,
+            file: Some(file),
+            chunks: manifest.chunks.into(),
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+
+    // Load the next chunk into the buffer, returning false when there are none left
+    fn next_chunk(&mut self) -> Result<bool> {
+        let (Some(file), Some(chunk)) = (self.file.as_ref(), self.chunks.pop_front()) else {
+            return Ok(false);
+        };
+
+        let payload = read_payload(&**file, &chunk)?;
+        self.buffer = self.db.decode_value(self.key, chunk.flags, payload)?;
+        self.position = 0;
+        Ok(true)
+    }
+}
+
+impl Read for ValueReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            if !self.next_chunk().map_err(into_io_error)? {
+                return Ok(0);
+            }
+        }
+
+        let len = buf.len().min(self.buffer.len() - self.position);
+        buf[..len].copy_from_slice(&self.buffer[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+// Writes a large value into the database as it arrives.
+// Data is appended to the log in chunks of `Config::chunk_size` bytes and the
+// value only becomes visible once `finish` is called. Dropping the writer
+// without finishing it discards the value.
+pub struct BlobWriter<'a> {
+    db: &'a KvDb,
+    log: &'a LogStore,
+    key: i64,
+    // Identifies the chunks of this writer in `LogStore::pending_blobs`
+    id: u64,
+    buffer: Vec<u8>,
+    // The bytes appended to the log as chunks so far
+    flushed: u64,
+}
+
+impl<'a> BlobWriter<'a> {
+    pub(crate) fn new(db: &'a KvDb, log: &'a LogStore, key: i64) -> Self {
+        // Garbage collection copies the chunks written so far and updates
+        // their positions, as it does for values in the index
+        let id = log.next_blob_writer.fetch_add(1, Ordering::SeqCst);
+        log.pending_blobs.lock().unwrap().insert(id, PendingBlob { key, chunks: Vec::new() });
+
+        Self {
+            db,
+            log,
+            key,
+            id,
+            buffer: Vec::new(),
+            flushed: 0,
+        }
+    }
+
+    // Make the value visible, replacing any previous value for the key
+    pub fn finish(mut self) -> Result<()> {
+        let mut indexes = self.db.lock_secondary_indexes();
+        let key = self.key;
+        let value = if !indexes.is_active() {
+            None
+        } else if self.flushed == 0 {
+            Some(self.buffer.clone())
+        } else {
+            // Read a value stored in chunks back from the log to index it
+            if !self.buffer.is_empty() {
+                self.flush_chunk()?;
+            }
+            Some(self.db.read_pending_blob(self.log, self.id, self.flushed)?)
+        };
+        self.commit()?;
+        indexes.update(key, value.as_deref());
+        Ok(())
+    }
+
+    // Make the value visible, leaving the secondary indexes to the caller
+    pub(crate) fn commit(mut self) -> Result<()> {
+        if self.flushed == 0 {
+            // The value fits in a single record
+            let (flags, payload) = self.db.encode_value(self.key, &self.buffer)?;
+            return self.db.commit_value(self.key, flags, &payload, None);
+        }
+
+        if !self.buffer.is_empty() {
+            self.flush_chunk()?;
+        }
+        self.db.commit_blob(self.log, self.id, self.flushed)
+    }
+
+    // Append the buffered data to the log as a chunk
+    fn flush_chunk(&mut self) -> Result<()> {
+        let data = std::mem::take(&mut self.buffer);
+        let file = self.log.file.lock().unwrap();
+        let pos = self.db.append_chunk(&**file, self.key, &data)?;
+
+        self.flushed += data.len() as u64;
+        self.log.pending_blobs.lock().unwrap().get_mut(&self.id).unwrap().chunks.push(pos);
+        Ok(())
+    }
+}
+
+impl Write for BlobWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let chunk_size = self.db.config.chunk_size;
+        let len = buf.len().min(chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+
+        if self.buffer.len() == chunk_size {
+            self.flush_chunk().map_err(into_io_error)?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for BlobWriter<'_> {
+    fn drop(&mut self) {
+        self.log.pending_blobs.lock().unwrap().remove(&self.id);
+    }
+}
+
+fn into_io_error(err: KvError) -> io::Error {
+    match err {
+        KvError::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
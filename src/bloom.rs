@@ -0,0 +1,181 @@
+use std::io::{ErrorKind, Read};
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::storage::{FileReader, Storage};
+use crate::{KvError, Result};
+
+// Bits per key, for a false positive rate of about 1%
+const BITS_PER_KEY: usize = 10;
+
+// The number of bits set per key, close to the optimum of BITS_PER_KEY * ln 2
+const HASHES: u32 = 7;
+
+// The filter of the log-structured engine is persisted next to the index
+const FILTER_FILE: &str = "filter.db";
+const FILTER_TEMP_FILE: &str = "filter.tmp";
+const FILTER_MAGIC: &[u8; 8] = b"KVDBBLM1";
+
+// Hash a key with a seed (the splitmix64 finalizer)
+pub(crate) fn hash_key(key: i64, seed: u64) -> u64 {
+    let mut hash = (key as u64).wrapping_add(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    hash ^ (hash >> 31)
+}
+
+// A compact set of keys that can tell for certain that a key was never added.
+// It may claim to hold a key it doesn't, but never the other way around.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+    // The number of keys the filter was sized for, and the number added
+    capacity: usize,
+    len: usize,
+}
+
+impl BloomFilter {
+    pub(crate) fn new(capacity: usize) -> Self {
+        let words = (capacity.max(1) * BITS_PER_KEY).div_ceil(64);
+        Self {
+            bits: vec![0; words],
+            capacity,
+            len: 0,
+        }
+    }
+
+    // The bits for a key, by double hashing
+    fn bit_indexes(&self, key: i64) -> impl Iterator<Item = usize> {
+        let bits = self.bits.len() as u64 * 64;
+        let (first, second) = (hash_key(key, 1), hash_key(key, 2) | 1);
+        (0..HASHES as u64).map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % bits) as usize)
+    }
+
+    pub(crate) fn insert(&mut self, key: i64) {
+        for index in self.bit_indexes(key) {
+            self.bits[index / 64] |= 1 << (index % 64);
+        }
+        self.len += 1;
+    }
+
+    pub(crate) fn may_contain(&self, key: i64) -> bool {
+        self.bit_indexes(key).all(|index| self.bits[index / 64] & (1 << (index % 64)) != 0)
+    }
+
+    // Whether more keys were added than the filter was sized for,
+    // so that false positives become more likely than intended
+    pub(crate) fn is_full(&self) -> bool {
+        self.len > self.capacity
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(8 + 8 + 8 + self.bits.len() * 8);
+        data.write_u64::<LittleEndian>(self.capacity as u64).unwrap();
+        data.write_u64::<LittleEndian>(self.len as u64).unwrap();
+        data.write_u64::<LittleEndian>(self.bits.len() as u64).unwrap();
+        for &word in &self.bits {
+            data.write_u64::<LittleEndian>(word).unwrap();
+        }
+        data
+    }
+
+    pub(crate) fn decode(mut data: &[u8]) -> Result<Self> {
+        let capacity = data.read_u64::<LittleEndian>()? as usize;
+        let len = data.read_u64::<LittleEndian>()? as usize;
+        let words = data.read_u64::<LittleEndian>()? as usize;
+        if words == 0 || data.len() != words * 8 {
+            return Err(KvError::InvalidFormat);
+        }
+        let bits = data
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        Ok(Self { bits, capacity, len })
+    }
+}
+
+// Load the persisted filter of the log-structured engine, if it covers the
+// log up to `log_offset`
+pub(crate) fn load(storage: &dyn Storage, log_offset: u64) -> Result<Option<BloomFilter>> {
+    let file = match storage.open(FILTER_FILE) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut data = Vec::new();
+    FileReader::new(file, 0).read_to_end(&mut data)?;
+
+    let mut reader = &data[..];
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    let filter_log_offset = reader.read_u64::<LittleEndian>()?;
+    if &magic != FILTER_MAGIC {
+        return Err(KvError::InvalidFormat);
+    }
+    if filter_log_offset != log_offset {
+        return Ok(None);
+    }
+    Ok(Some(BloomFilter::decode(reader)?))
+}
+
+// Persist the filter of the log-structured engine, recording the log offset it covers
+pub(crate) fn save(storage: &Arc<dyn Storage>, filter: &BloomFilter, log_offset: u64) -> Result<()> {
+    let mut data = FILTER_MAGIC.to_vec();
+    data.write_u64::<LittleEndian>(log_offset)?;
+    data.extend_from_slice(&filter.encode());
+
+    let file = storage.create(FILTER_TEMP_FILE)?;
+    file.append(&data)?;
+    file.sync()?;
+    storage.rename(FILTER_TEMP_FILE, FILTER_FILE)?;
+    Ok(())
+}
+
+// Remove the persisted filter ahead of the log being replaced
+pub(crate) fn invalidate(storage: &dyn Storage) -> Result<()> {
+    match storage.remove(FILTER_FILE) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut filter = BloomFilter::new(1000);
+        for key in (0..1000).map(|n| n * 3) {
+            filter.insert(key);
+        }
+        assert!(!filter.is_full());
+        assert!((0..1000).all(|n| filter.may_contain(n * 3)));
+
+        // About 1% false positives
+        let false_positives = (0..10_000).filter(|&n| filter.may_contain(n * 3 + 1)).count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+
+        assert_eq!(BloomFilter::decode(&filter.encode()).unwrap(), filter);
+        filter.insert(-1);
+        assert!(filter.is_full());
+    }
+
+    #[test]
+    fn test_persistence() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let mut filter = BloomFilter::new(10);
+        filter.insert(42);
+
+        assert_eq!(load(&*storage, 100).unwrap(), None);
+        save(&storage, &filter, 100).unwrap();
+        assert_eq!(load(&*storage, 100).unwrap(), Some(filter));
+
+        // A filter for a different part of the log isn't used
+        assert_eq!(load(&*storage, 200).unwrap(), None);
+        invalidate(&*storage).unwrap();
+        assert_eq!(load(&*storage, 100).unwrap(), None);
+    }
+}
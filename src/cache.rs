@@ -0,0 +1,690 @@
+use std::io::{ErrorKind, Read};
+use std::mem;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use lru::LruCache;
+
+use crate::bloom::hash_key;
+use crate::storage::{FileReader, Storage};
+use crate::{KvError, Result};
+
+// The keys that were cached when the database last saved them, so the cache
+// can be warmed up after a restart
+const HOT_KEYS_FILE: &str = "hot_keys.db";
+const HOT_KEYS_TEMP_FILE: &str = "hot_keys.tmp";
+const HOT_KEYS_MAGIC: &[u8; 8] = b"KVDBHOT1";
+
+// How the value cache chooses which entries to evict
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CachePolicy {
+    // Evict the least recently used entry
+    #[default]
+    Lru,
+
+    // W-TinyLFU: new entries go into a small LRU window, and only move on to
+    // the main cache if they are used more often than the entry they would
+    // replace. One-off reads, like a full scan, can't flush out the hot set.
+    TinyLfu,
+}
+
+// Counters describing how well the value cache is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    // Lookups answered by a key remembered to be absent, which aren't hits
+    pub negative_hits: u64,
+    // Entries dropped to make room for others
+    pub evictions: u64,
+    // The number of cached values and the bytes they take up
+    pub entries: usize,
+    pub size_bytes: usize,
+    pub capacity_bytes: usize,
+    // The number of keys remembered to be absent
+    pub absent_keys: usize,
+}
+
+impl CacheStats {
+    // The fraction of lookups that found the key cached, or 0 before any lookup.
+    // Lookups of keys remembered to be absent are left out.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+// The bytes an entry counts for: its key and the value's contents
+fn entry_size(value: &str) -> usize {
+    mem::size_of::<i64>() + value.len()
+}
+
+// Decides which values the cache keeps, within a budget of bytes
+trait Policy: Send {
+    // Look up a value, recording the access
+    fn get(&mut self, key: i64) -> Option<String>;
+
+    // Add the value for a key that isn't cached, returning the number of
+    // entries evicted to make room. The new entry itself may be evicted.
+    fn insert(&mut self, key: i64, value: String) -> u64;
+
+    fn remove(&mut self, key: i64);
+
+    fn len(&self) -> usize;
+
+    // The bytes taken up by the cached entries
+    fn size(&self) -> usize;
+
+    // The cached keys, those most worth keeping first
+    fn keys(&self) -> Vec<i64>;
+}
+
+// A cache of values bounded by the bytes they take up, with counters.
+// Sizes are kept up to date as entries come and go, so every operation
+// takes constant time.
+pub(crate) struct ValueCache {
+    policy: Box<dyn Policy>,
+    capacity: usize,
+    // Keys known to be absent from the database, least recently used first
+    absent: LruCache<i64, ()>,
+    absent_capacity: usize,
+    hits: u64,
+    misses: u64,
+    negative_hits: u64,
+    evictions: u64,
+}
+
+impl ValueCache {
+    // A cache holding up to `capacity` bytes of values and remembering up to
+    // `absent_capacity` absent keys; zero disables either
+    pub(crate) fn new(policy: CachePolicy, capacity: usize, absent_capacity: usize) -> Self {
+        let policy: Box<dyn Policy> = match policy {
+            CachePolicy::Lru => Box::new(Segment::new(capacity)),
+            CachePolicy::TinyLfu => Box::new(TinyLfu::new(capacity)),
+        };
+        Self {
+            policy,
+            capacity,
+            absent: LruCache::unbounded(),
+            absent_capacity,
+            hits: 0,
+            misses: 0,
+            negative_hits: 0,
+            evictions: 0,
+        }
+    }
+
+    // Look up a key. Some(None) means the key is known to be absent.
+    pub(crate) fn get(&mut self, key: i64) -> Option<Option<String>> {
+        if self.absent.get(&key).is_some() {
+            self.negative_hits += 1;
+            return Some(None);
+        }
+
+        let value = self.policy.get(key);
+        match value {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        value.map(Some)
+    }
+
+    // Cache a value, replacing any cached value for the key.
+    // Values that don't fit in the cache at all are not cached.
+    pub(crate) fn put(&mut self, key: i64, value: &str) {
+        self.absent.pop(&key);
+        self.policy.remove(key);
+        if entry_size(value) <= self.capacity {
+            self.evictions += self.policy.insert(key, value.to_string());
+        }
+    }
+
+    // Cache a value read as bytes. Only values that are valid UTF-8 can be cached,
+    // so for any other the key is forgotten instead.
+    pub(crate) fn put_bytes(&mut self, key: i64, value: &[u8]) {
+        match std::str::from_utf8(value) {
+            Ok(value) => self.put(key, value),
+            Err(_) => self.remove(key),
+        }
+    }
+
+    // Forget anything cached about a key
+    pub(crate) fn remove(&mut self, key: i64) {
+        self.absent.pop(&key);
+        self.policy.remove(key);
+    }
+
+    // Replace a cached value with one computed from it, or forget the value if that
+    // fails. Nothing is cached for a key that had no value cached.
+    pub(crate) fn update(&mut self, key: i64, f: impl FnOnce(&str) -> Option<String>) {
+        self.absent.pop(&key);
+        let Some(value) = self.policy.get(key) else {
+            return;
+        };
+        match f(&value) {
+            Some(value) => self.put(key, &value),
+            None => self.policy.remove(key),
+        }
+    }
+
+    // Remember that a key is absent from the database
+    pub(crate) fn put_absent(&mut self, key: i64) {
+        self.policy.remove(key);
+        if self.absent_capacity == 0 {
+            return;
+        }
+        self.absent.put(key, ());
+        if self.absent.len() > self.absent_capacity {
+            self.absent.pop_lru();
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            negative_hits: self.negative_hits,
+            evictions: self.evictions,
+            entries: self.policy.len(),
+            size_bytes: self.policy.size(),
+            capacity_bytes: self.capacity,
+            absent_keys: self.absent.len(),
+        }
+    }
+
+    // The keys of the cached values, those most worth keeping first
+    pub(crate) fn keys(&self) -> Vec<i64> {
+        self.policy.keys()
+    }
+}
+
+// Save the keys of the cached values, so a restarted database can warm up its cache
+pub(crate) fn save_hot_keys(storage: &Arc<dyn Storage>, cache: &Mutex<ValueCache>) -> Result<()> {
+    let keys = cache.lock().unwrap().keys();
+    let mut data = HOT_KEYS_MAGIC.to_vec();
+    data.write_u64::<LittleEndian>(keys.len() as u64)?;
+    for key in keys {
+        data.write_i64::<LittleEndian>(key)?;
+    }
+
+    let file = storage.create(HOT_KEYS_TEMP_FILE)?;
+    file.append(&data)?;
+    file.sync()?;
+    storage.rename(HOT_KEYS_TEMP_FILE, HOT_KEYS_FILE)?;
+    Ok(())
+}
+
+// Load the keys saved by `save_hot_keys`, hottest first. There are none if they were never saved.
+pub(crate) fn load_hot_keys(storage: &dyn Storage) -> Result<Vec<i64>> {
+    let file = match storage.open(HOT_KEYS_FILE) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut data = Vec::new();
+    FileReader::new(file, 0).read_to_end(&mut data)?;
+
+    let mut reader = &data[..];
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    let count = reader.read_u64::<LittleEndian>()? as usize;
+    if &magic != HOT_KEYS_MAGIC || reader.len() != count * 8 {
+        return Err(KvError::InvalidFormat);
+    }
+    (0..count).map(|_| Ok(reader.read_i64::<LittleEndian>()?)).collect()
+}
+
+// A thread saving the hot keys at regular intervals until it is stopped
+pub(crate) struct HotKeySaver {
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl HotKeySaver {
+    pub(crate) fn start(storage: Arc<dyn Storage>, cache: Arc<Mutex<ValueCache>>, interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            // Stopping disconnects the channel
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if let Err(err) = save_hot_keys(&storage, &cache) {
+                    log::warn!("Failed to save the hot keys: {}", err);
+                }
+            }
+        });
+        Self { stop, thread }
+    }
+
+    pub(crate) fn stop(self) {
+        drop(self.stop);
+        let _ = self.thread.join();
+    }
+}
+
+// A list of entries in order of use that keeps track of their total size.
+// On its own, it's the LRU policy.
+struct Segment {
+    entries: LruCache<i64, String>,
+    size: usize,
+    capacity: usize,
+}
+
+impl Segment {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: LruCache::unbounded(),
+            size: 0,
+            capacity,
+        }
+    }
+
+    // Add an entry as the most recently used
+    fn push(&mut self, key: i64, value: String) {
+        self.size += entry_size(&value);
+        self.entries.put(key, value);
+    }
+
+    fn pop_lru(&mut self) -> Option<(i64, String)> {
+        let (key, value) = self.entries.pop_lru()?;
+        self.size -= entry_size(&value);
+        Some((key, value))
+    }
+
+    fn take(&mut self, key: i64) -> Option<String> {
+        let value = self.entries.pop(&key)?;
+        self.size -= entry_size(&value);
+        Some(value)
+    }
+}
+
+impl Policy for Segment {
+    fn get(&mut self, key: i64) -> Option<String> {
+        self.entries.get(&key).cloned()
+    }
+
+    fn insert(&mut self, key: i64, value: String) -> u64 {
+        self.push(key, value);
+        let mut evictions = 0;
+        while self.size > self.capacity {
+            self.pop_lru();
+            evictions += 1;
+        }
+        evictions
+    }
+
+    fn remove(&mut self, key: i64) {
+        self.take(key);
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn keys(&self) -> Vec<i64> {
+        self.entries.iter().map(|(&key, _)| key).collect()
+    }
+}
+
+// The share of the capacity given to the window, in percent
+const WINDOW_PERCENT: usize = 1;
+
+// The share of the main cache given to the protected segment, in percent
+const PROTECTED_PERCENT: usize = 80;
+
+// The W-TinyLFU policy. The main cache is a segmented LRU: entries start out
+// on probation and become protected when they are used again. An entry
+// leaving the window is admitted to the main cache only if the frequency
+// sketch has seen it more often than the entry it would evict.
+struct TinyLfu {
+    sketch: FrequencySketch,
+    window: Segment,
+    probation: Segment,
+    protected: Segment,
+    // The capacity of probation and protected together
+    main_capacity: usize,
+}
+
+impl TinyLfu {
+    fn new(capacity: usize) -> Self {
+        let window_capacity = capacity * WINDOW_PERCENT / 100;
+        let main_capacity = capacity - window_capacity;
+        Self {
+            // Sized for entries of around 64 bytes
+            sketch: FrequencySketch::new(capacity / 64),
+            window: Segment::new(window_capacity),
+            probation: Segment::new(main_capacity),
+            protected: Segment::new(main_capacity * PROTECTED_PERCENT / 100),
+            main_capacity,
+        }
+    }
+
+    // Move an entry evicted from the window into the main cache if it is used
+    // more often than the entries it would replace, returning the number of
+    // entries evicted
+    fn admit(&mut self, key: i64, value: String) -> u64 {
+        let size = entry_size(&value);
+        if size > self.main_capacity {
+            return 1;
+        }
+
+        // Pick the victims that would make room, from probation first, and
+        // keep them all unless the candidate is used more often than each
+        let frequency = self.sketch.estimate(key);
+        let excess = (self.probation.size + self.protected.size + size).saturating_sub(self.main_capacity);
+        let mut freed = 0;
+        let mut victims = (0, 0);
+        let candidates = self.probation.entries.iter().rev().map(|entry| (entry, true));
+        let candidates = candidates.chain(self.protected.entries.iter().rev().map(|entry| (entry, false)));
+        for ((&victim, victim_value), on_probation) in candidates {
+            if freed >= excess {
+                break;
+            }
+            if frequency <= self.sketch.estimate(victim) {
+                return 1;
+            }
+            freed += entry_size(victim_value);
+            if on_probation {
+                victims.0 += 1;
+            } else {
+                victims.1 += 1;
+            }
+        }
+
+        for _ in 0..victims.0 {
+            self.probation.pop_lru();
+        }
+        for _ in 0..victims.1 {
+            self.protected.pop_lru();
+        }
+        let evictions = victims.0 + victims.1;
+        self.probation.push(key, value);
+        evictions
+    }
+}
+
+impl Policy for TinyLfu {
+    fn get(&mut self, key: i64) -> Option<String> {
+        self.sketch.increment(key);
+
+        if let Some(value) = self.window.entries.get(&key) {
+            return Some(value.clone());
+        }
+        if let Some(value) = self.protected.entries.get(&key) {
+            return Some(value.clone());
+        }
+
+        // A second use protects an entry, which may push older ones back on probation
+        let value = self.probation.take(key)?;
+        self.protected.push(key, value.clone());
+        while self.protected.size > self.protected.capacity {
+            let (key, value) = self.protected.pop_lru().unwrap();
+            self.probation.push(key, value);
+        }
+        Some(value)
+    }
+
+    fn insert(&mut self, key: i64, value: String) -> u64 {
+        self.window.push(key, value);
+        let mut evictions = 0;
+        while self.window.size > self.window.capacity {
+            let (key, value) = self.window.pop_lru().unwrap();
+            evictions += self.admit(key, value);
+        }
+        evictions
+    }
+
+    fn remove(&mut self, key: i64) {
+        self.window.take(key);
+        self.probation.take(key);
+        self.protected.take(key);
+    }
+
+    fn len(&self) -> usize {
+        self.window.entries.len() + self.probation.entries.len() + self.protected.entries.len()
+    }
+
+    fn size(&self) -> usize {
+        self.window.size + self.probation.size + self.protected.size
+    }
+
+    fn keys(&self) -> Vec<i64> {
+        [&self.protected, &self.window, &self.probation]
+            .into_iter()
+            .flat_map(|segment| segment.entries.iter().map(|(&key, _)| key))
+            .collect()
+    }
+}
+
+// The number of rows of counters in the frequency sketch
+const SKETCH_ROWS: usize = 4;
+
+// Counters stop counting here
+const MAX_FREQUENCY: u8 = 15;
+
+// A count-min sketch estimating how often keys were used recently.
+// All counters are halved every so often, so old popularity fades.
+struct FrequencySketch {
+    counters: Vec<u8>,
+    // Counters per row, minus one
+    mask: usize,
+    additions: usize,
+    // The number of additions after which counters are halved
+    sample_size: usize,
+}
+
+impl FrequencySketch {
+    fn new(entries: usize) -> Self {
+        let width = entries.clamp(16, 1 << 24).next_power_of_two();
+        Self {
+            counters: vec![0; width * SKETCH_ROWS],
+            mask: width - 1,
+            additions: 0,
+            sample_size: width * 10,
+        }
+    }
+
+    // The counter for a key in each row
+    fn slots(&self, key: i64) -> [usize; SKETCH_ROWS] {
+        let mut slots = [0; SKETCH_ROWS];
+        for (row, slot) in slots.iter_mut().enumerate() {
+            *slot = row * (self.mask + 1) + (hash_key(key, row as u64 + 1) as usize & self.mask);
+        }
+        slots
+    }
+
+    fn estimate(&self, key: i64) -> u8 {
+        self.slots(key).iter().map(|&slot| self.counters[slot]).min().unwrap()
+    }
+
+    fn increment(&mut self, key: i64) {
+        // Only the smallest counters are raised, which keeps overestimates down
+        let slots = self.slots(key);
+        let frequency = slots.iter().map(|&slot| self.counters[slot]).min().unwrap();
+        if frequency < MAX_FREQUENCY {
+            for slot in slots {
+                if self.counters[slot] == frequency {
+                    self.counters[slot] += 1;
+                }
+            }
+        }
+
+        self.additions += 1;
+        if self.additions >= self.sample_size {
+            for counter in &mut self.counters {
+                *counter /= 2;
+            }
+            self.additions /= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_accounting() {
+        // Room for three entries of 8 + 8 bytes
+        let mut cache = ValueCache::new(CachePolicy::Lru, 48, 0);
+        for key in 0..3 {
+            cache.put(key, "12345678");
+        }
+        assert_eq!(cache.get(0), Some(Some("12345678".to_string())));
+        assert_eq!(cache.get(5), None);
+
+        // Key 1 is now the least recently used
+        cache.put(3, "12345678");
+        assert_eq!(cache.get(1), None);
+
+        // Replacing a value accounts for the difference in size
+        cache.put(3, "1234");
+        assert_eq!(cache.stats().size_bytes, 16 + 16 + 12);
+        cache.remove(3);
+
+        // Too large to cache at all
+        cache.put(4, &"x".repeat(100));
+        assert_eq!(cache.get(4), None);
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 3,
+                negative_hits: 0,
+                evictions: 1,
+                entries: 2,
+                size_bytes: 32,
+                capacity_bytes: 48,
+                absent_keys: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_disabled() {
+        for policy in [CachePolicy::Lru, CachePolicy::TinyLfu] {
+            let mut cache = ValueCache::new(policy, 0, 0);
+            cache.put(1, "value");
+            cache.put_absent(2);
+            assert_eq!(cache.get(1), None);
+            assert_eq!(cache.get(2), None);
+            assert_eq!(cache.stats().entries, 0);
+        }
+
+        // Without remembering absent keys, values are still dropped
+        let mut cache = ValueCache::new(CachePolicy::Lru, 1024, 0);
+        cache.put(1, "value");
+        cache.put_absent(1);
+        assert_eq!(cache.get(1), None);
+    }
+
+    // The number of hot keys still cached after a scan
+    fn hot_keys_after_scan(policy: CachePolicy) -> usize {
+        // Room for 100 entries of 8 + 32 bytes
+        let value = "x".repeat(32);
+        let mut cache = ValueCache::new(policy, 100 * 40, 0);
+        let read = |cache: &mut ValueCache, key| {
+            if cache.get(key).is_none() {
+                cache.put(key, &value);
+            }
+        };
+
+        // Make keys 0..50 hot, then read many other keys once
+        for _ in 0..5 {
+            for key in 0..50 {
+                read(&mut cache, key);
+            }
+        }
+        for key in 1000..10_000 {
+            read(&mut cache, key);
+        }
+
+        let stats = cache.stats();
+        assert!(stats.size_bytes <= stats.capacity_bytes);
+        assert_eq!(stats.size_bytes, stats.entries * 40);
+        (0..50).filter(|&key| cache.get(key).is_some()).count()
+    }
+
+    #[test]
+    fn test_scan_resistance() {
+        assert_eq!(hot_keys_after_scan(CachePolicy::Lru), 0);
+
+        // The sketch may overestimate a few scanned keys
+        assert!(hot_keys_after_scan(CachePolicy::TinyLfu) >= 45);
+    }
+
+    #[test]
+    fn test_admission() {
+        // The main cache holds two entries of 8 + 400 bytes, key 1 the least recently used
+        let mut policy = TinyLfu::new(1000);
+        policy.admit(1, "x".repeat(400));
+        policy.admit(2, "x".repeat(400));
+        for _ in 0..3 {
+            policy.sketch.increment(2);
+        }
+
+        // A candidate needing both their room loses to the more frequent one,
+        // and neither is evicted
+        for _ in 0..2 {
+            policy.sketch.increment(3);
+        }
+        assert_eq!(policy.admit(3, "x".repeat(700)), 1);
+        assert_eq!(policy.probation.entries.len(), 2);
+
+        // Used more often than both, it replaces them
+        for _ in 0..2 {
+            policy.sketch.increment(3);
+        }
+        assert_eq!(policy.admit(3, "x".repeat(700)), 2);
+        let keys: Vec<i64> = policy.probation.entries.iter().map(|(&key, _)| key).collect();
+        assert_eq!(keys, vec![3]);
+    }
+
+    #[test]
+    fn test_absent_keys() {
+        let mut cache = ValueCache::new(CachePolicy::Lru, 1024, 2);
+        cache.put(1, "value");
+        cache.put_absent(1);
+        cache.put_absent(2);
+        assert_eq!(cache.get(1), Some(None));
+
+        // Key 2 is the least recently used
+        cache.put_absent(3);
+        assert_eq!(cache.get(2), None);
+
+        // Writing a value replaces the absence
+        cache.put(3, "value");
+        assert_eq!(cache.get(3), Some(Some("value".to_string())));
+        cache.remove(1);
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.stats().absent_keys, 0);
+
+        // Answers from absent keys are counted apart from hits
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.negative_hits), (1, 2, 1));
+    }
+
+    #[test]
+    fn test_hot_keys() {
+        let mut cache = ValueCache::new(CachePolicy::Lru, 1024, 0);
+        for key in 0..3 {
+            cache.put(key, "value");
+        }
+        cache.get(0);
+        assert_eq!(cache.keys(), vec![0, 2, 1]);
+
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        assert_eq!(load_hot_keys(&*storage).unwrap(), Vec::<i64>::new());
+        save_hot_keys(&storage, &Mutex::new(cache)).unwrap();
+        assert_eq!(load_hot_keys(&*storage).unwrap(), vec![0, 2, 1]);
+    }
+}
@@ -0,0 +1,152 @@
+use crate::{KvError, Result};
+
+// The compression codec applied to values before they are written to the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    // Store values as-is
+    #[default]
+    None,
+
+    // LZ4 block compression, fast with a moderate ratio
+    Lz4,
+
+    // Zstandard at the given level, slower but with a better ratio
+    Zstd(i32),
+}
+
+// Codec identifiers stored as the first byte of a compressed payload
+const CODEC_LZ4: u8 = 1;
+const CODEC_ZSTD: u8 = 2;
+
+// The largest value that is compressed. Larger values are stored as-is, so a
+// damaged length in a compressed payload can't make decompression allocate more.
+const MAX_UNCOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+// Compress a value with the given codec.
+// Returns None if compression is disabled, the value is too large, or compression
+// would not make it smaller, in which case the value should be stored uncompressed.
+pub(crate) fn compress(compression: Compression, data: &[u8]) -> Result<Option<Vec<u8>>> {
+    if data.len() > MAX_UNCOMPRESSED_SIZE {
+        return Ok(None);
+    }
+
+    let payload = match compression {
+        Compression::None => return Ok(None),
+        Compression::Lz4 => {
+            let mut payload = vec![CODEC_LZ4];
+            payload.extend_from_slice(&lz4_flex::compress_prepend_size(data));
+            payload
+        }
+        Compression::Zstd(level) => {
+            let mut payload = vec![CODEC_ZSTD];
+            payload.extend_from_slice(&zstd::bulk::compress(data, level)?);
+            payload
+        }
+    };
+
+    if payload.len() < data.len() {
+        Ok(Some(payload))
+    } else {
+        Ok(None)
+    }
+}
+
+// Decompress a payload produced by `compress`. The uncompressed length stored in
+// the payload is checked before anything is allocated, and the value has to
+// decompress to exactly that length.
+pub(crate) fn decompress(payload: &[u8]) -> Result<Vec<u8>> {
+    let (&codec, data) = payload.split_first().ok_or(KvError::InvalidFormat)?;
+
+    let (size, value) = match codec {
+        CODEC_LZ4 => {
+            let (size, block) = lz4_flex::block::uncompressed_size(data)
+                .map_err(|err| KvError::Compression(err.to_string()))?;
+            check_size(size as u64)?;
+            let value = lz4_flex::block::decompress(block, size)
+                .map_err(|err| KvError::Corruption(format!("LZ4 block doesn't fit its stored length: {}", err)))?;
+            (size, value)
+        }
+        CODEC_ZSTD => {
+            let size = zstd::zstd_safe::get_frame_content_size(data)
+                .map_err(|_| KvError::Corruption("invalid Zstandard frame header".to_string()))?
+                .ok_or_else(|| KvError::Corruption("Zstandard frame has no stored length".to_string()))?;
+            check_size(size)?;
+            let value = zstd::bulk::decompress(data, size as usize)
+                .map_err(|err| KvError::Corruption(format!("Zstandard frame doesn't fit its stored length: {}", err)))?;
+            (size as usize, value)
+        }
+        _ => return Err(KvError::InvalidFormat),
+    };
+
+    if value.len() != size {
+        return Err(KvError::Corruption(format!(
+            "value decompressed to {} bytes instead of its stored length of {}",
+            value.len(),
+            size
+        )));
+    }
+    Ok(value)
+}
+
+// Check the uncompressed length stored in a payload against the largest value compressed
+fn check_size(size: u64) -> Result<()> {
+    if size > MAX_UNCOMPRESSED_SIZE as u64 {
+        return Err(KvError::Corruption(format!(
+            "stored length of {} bytes is larger than any compressed value",
+            size
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let data = "{\"name\": \"value\"}".repeat(100);
+
+        for compression in [Compression::Lz4, Compression::Zstd(3)] {
+            let payload = compress(compression, data.as_bytes()).unwrap().unwrap();
+            assert!(payload.len() < data.len());
+            assert_eq!(decompress(&payload).unwrap(), data.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_incompressible_values_are_left_alone() {
+        assert!(compress(Compression::None, b"hello").unwrap().is_none());
+        assert!(compress(Compression::Lz4, b"a").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_stored_length_is_enforced() {
+        let data = "{\"name\": \"value\"}".repeat(100);
+
+        // A stored length beyond the largest compressed value is rejected up front
+        let mut payload = compress(Compression::Lz4, data.as_bytes()).unwrap().unwrap();
+        payload[1..5].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(decompress(&payload), Err(KvError::Corruption(_))));
+
+        // So is a block that decompresses to more or less than its stored length
+        for size in [data.len() as u32 - 1, data.len() as u32 + 1] {
+            let mut payload = compress(Compression::Lz4, data.as_bytes()).unwrap().unwrap();
+            payload[1..5].copy_from_slice(&size.to_le_bytes());
+            assert!(matches!(decompress(&payload), Err(KvError::Corruption(_))));
+        }
+
+        // Zstandard frames are checked against the length in their header
+        let frame = zstd::bulk::Compressor::new(3)
+            .and_then(|mut compressor| {
+                compressor.set_parameter(zstd::zstd_safe::CParameter::ContentSizeFlag(false))?;
+                compressor.compress(data.as_bytes())
+            })
+            .unwrap();
+        let mut payload = vec![CODEC_ZSTD];
+        payload.extend_from_slice(&frame);
+        assert!(matches!(decompress(&payload), Err(KvError::Corruption(_))));
+
+        assert!(compress(Compression::Lz4, &vec![0; MAX_UNCOMPRESSED_SIZE + 1]).unwrap().is_none());
+    }
+}
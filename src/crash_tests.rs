@@ -0,0 +1,417 @@
+// Crash-consistency tests. Workloads run on a simulated filesystem that loses
+// unsynced data and directory changes, tears writes and fails renames at chosen
+// points; the database is then reopened and checked against the writes that
+// were acknowledged.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+use crate::{Config, EngineKind, IndexKind, KvDb, LsmOptions, Storage, StorageFile};
+
+// A fault to inject into one operation
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Fault {
+    // Lose power during the operation
+    Crash,
+    // Fail a rename, leaving both files as they were
+    FailRename,
+}
+
+// A small deterministic random number generator (xorshift64*)
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) % n as u64) as usize
+    }
+}
+
+struct SimState {
+    files: HashMap<String, Arc<SimFile>>,
+    // The files as of the last time the directory was synced, the ones a crash keeps
+    durable: HashMap<String, Arc<SimFile>>,
+    // The number of operations that modified a file so far
+    ops: usize,
+    // The operation numbers of the renames so far
+    renames: Vec<usize>,
+    fault: Option<(usize, Fault)>,
+    // The files as they were left by the crash, once there was one
+    image: Option<HashMap<String, Vec<u8>>>,
+    // Decides how much unsynced data survives the crash
+    rng: Rng,
+}
+
+impl SimState {
+    // Count an operation that modifies a file, failing it if a fault is due
+    fn begin(&mut self, rename: bool) -> io::Result<()> {
+        if self.image.is_some() {
+            return Err(io::Error::other("simulated crash"));
+        }
+        self.ops += 1;
+        if rename {
+            self.renames.push(self.ops);
+        }
+
+        match self.fault {
+            Some((at, Fault::Crash)) if at == self.ops => Err(self.crash()),
+            Some((at, Fault::FailRename)) if at == self.ops && rename => Err(io::Error::other("simulated rename failure")),
+            _ => Ok(()),
+        }
+    }
+
+    // Sync the directory like `FileStorage` does, so the files survive a crash
+    // under the names they have now
+    fn sync_dir(&mut self) -> io::Result<()> {
+        self.begin(false)?;
+        self.durable = self.files.clone();
+        Ok(())
+    }
+
+    // Capture what the files look like after a crash right now. Creates,
+    // renames and removes since the directory was last synced are undone, and
+    // of the data appended since a file was last synced, anything from none to
+    // all of it may survive.
+    fn crash(&mut self) -> io::Error {
+        if self.image.is_none() {
+            let mut image = HashMap::new();
+            for (name, file) in &self.durable {
+                let contents = file.contents.lock().unwrap();
+                let unsynced = contents.data.len() - contents.synced;
+                let kept = match self.rng.below(3) {
+                    0 => 0,
+                    1 => unsynced,
+                    _ => self.rng.below(unsynced + 1),
+                };
+                image.insert(name.clone(), contents.data[..contents.synced + kept].to_vec());
+            }
+            self.image = Some(image);
+        }
+        io::Error::other("simulated crash")
+    }
+}
+
+// A filesystem in memory that injects a fault into one chosen operation
+#[derive(Clone)]
+struct SimStorage(Arc<Mutex<SimState>>);
+
+impl SimStorage {
+    fn new(seed: u64, fault: Option<(usize, Fault)>) -> Self {
+        Self::with_files(HashMap::new(), seed, fault)
+    }
+
+    fn with_files(image: HashMap<String, Vec<u8>>, seed: u64, fault: Option<(usize, Fault)>) -> Self {
+        let storage = Self(Arc::new(Mutex::new(SimState {
+            files: HashMap::new(),
+            durable: HashMap::new(),
+            ops: 0,
+            renames: Vec::new(),
+            fault,
+            image: None,
+            rng: Rng::new(seed),
+        })));
+
+        let mut state = storage.0.lock().unwrap();
+        for (name, data) in image {
+            let file = storage.new_file(false);
+            *file.contents.lock().unwrap() = SimContents { synced: data.len(), data };
+            state.files.insert(name, file);
+        }
+        state.durable = state.files.clone();
+        drop(state);
+        storage
+    }
+
+    fn new_file(&self, created: bool) -> Arc<SimFile> {
+        Arc::new(SimFile {
+            state: Arc::downgrade(&self.0),
+            contents: Mutex::new(SimContents::default()),
+            created: Mutex::new(created),
+        })
+    }
+
+    fn begin(&self, rename: bool) -> io::Result<MutexGuard<'_, SimState>> {
+        let mut state = self.0.lock().unwrap();
+        state.begin(rename)?;
+        Ok(state)
+    }
+
+    fn crashed(&self) -> bool {
+        self.0.lock().unwrap().image.is_some()
+    }
+
+    // Crash now, unless the storage has crashed already
+    fn crash(&self) {
+        self.0.lock().unwrap().crash();
+    }
+
+    // A storage holding the files as they were left by the crash
+    fn recover(&self) -> SimStorage {
+        let state = self.0.lock().unwrap();
+        SimStorage::with_files(state.image.clone().expect("storage has not crashed"), 0, None)
+    }
+}
+
+impl fmt::Debug for SimStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SimStorage")
+    }
+}
+
+impl Storage for SimStorage {
+    fn open(&self, name: &str) -> io::Result<Arc<dyn StorageFile>> {
+        match self.0.lock().unwrap().files.get(name) {
+            Some(file) => Ok(file.clone()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn create(&self, name: &str) -> io::Result<Arc<dyn StorageFile>> {
+        let mut state = self.begin(false)?;
+        let file = self.new_file(true);
+        state.files.insert(name.to_string(), file.clone());
+        Ok(file)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut state = self.begin(true)?;
+        let file = state.files.remove(from).ok_or(io::ErrorKind::NotFound)?;
+        state.files.insert(to.to_string(), file);
+        state.sync_dir()
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        let mut state = self.begin(false)?;
+        match state.files.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        Ok(self.0.lock().unwrap().files.keys().cloned().collect())
+    }
+}
+
+#[derive(Default)]
+struct SimContents {
+    data: Vec<u8>,
+    // The length of the prefix that survives a crash
+    synced: usize,
+}
+
+struct SimFile {
+    state: Weak<Mutex<SimState>>,
+    contents: Mutex<SimContents>,
+    // Whether the file was created since it was last synced, which syncs the directory too
+    created: Mutex<bool>,
+}
+
+impl SimFile {
+    // Run an operation that modifies the file, in the order the storage counts them.
+    // With `torn` the modification is made first, so a crash may keep part of it.
+    fn modify(&self, torn: bool, op: impl FnOnce(&mut SimContents)) -> io::Result<()> {
+        let state = self.state.upgrade().ok_or_else(|| io::Error::other("storage is gone"))?;
+        let mut state = state.lock().unwrap();
+        if torn && state.image.is_none() {
+            op(&mut self.contents.lock().unwrap());
+            return state.begin(false);
+        }
+        state.begin(false)?;
+        op(&mut self.contents.lock().unwrap());
+        Ok(())
+    }
+}
+
+impl StorageFile for SimFile {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.contents.lock().unwrap().data.len() as u64)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let contents = self.contents.lock().unwrap();
+        let start = offset.min(contents.data.len() as u64) as usize;
+        match contents.data[start..].get(..buf.len()) {
+            Some(slice) => {
+                buf.copy_from_slice(slice);
+                Ok(())
+            }
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    fn append(&self, data: &[u8]) -> io::Result<()> {
+        self.modify(true, |contents| contents.data.extend_from_slice(data))
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.modify(false, |contents| contents.synced = contents.data.len())?;
+
+        let mut created = self.created.lock().unwrap();
+        if *created {
+            let state = self.state.upgrade().ok_or_else(|| io::Error::other("storage is gone"))?;
+            state.lock().unwrap().sync_dir()?;
+            *created = false;
+        }
+        Ok(())
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        self.modify(false, |contents| {
+            contents.data.truncate(len as usize);
+            contents.synced = contents.synced.min(contents.data.len());
+        })
+    }
+}
+
+// What a key may hold after a crash: the last acknowledged value, or the
+// value of a write that failed since
+#[derive(Default)]
+struct Expected {
+    acknowledged: Option<String>,
+    unacknowledged: Vec<Option<String>>,
+}
+
+const KEYS: usize = 16;
+const STEPS: usize = 150;
+
+// Run the workload until it finishes or the storage crashes, crash, then reopen
+// the database and check its contents. Returns the storage the workload ran on.
+fn run_workload(config: &Config, fault: Option<(usize, Fault)>) -> SimStorage {
+    let seed = fault.map_or(0, |(at, _)| at as u64);
+    let storage = SimStorage::new(seed, fault);
+    let mut config = Config {
+        storage: Some(Arc::new(storage.clone())),
+        ..config.clone()
+    };
+
+    // The same workload every time, so every fault point is reached
+    let mut rng = Rng::new(42);
+    let mut expected: HashMap<i64, Expected> = HashMap::new();
+    if let Ok(db) = KvDb::open(config.clone()) {
+        for step in 0..STEPS {
+            let key = rng.below(KEYS) as i64;
+            let (value, result) = match rng.below(10) {
+                0..=5 => {
+                    // Some values are large enough to be stored in chunks
+                    let value = format!("{}-{}-", key, step).repeat(1 + rng.below(20));
+                    let result = db.set(key, &value).map(drop);
+                    (Some(value), result)
+                }
+                6..=8 => (None, db.remove(key).map(drop)),
+                _ if config.engine == EngineKind::Log => {
+                    let _ = db.garbage_collect();
+                    if storage.crashed() {
+                        break;
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+
+            let expected = expected.entry(key).or_default();
+            match result {
+                Ok(()) => {
+                    expected.acknowledged = value;
+                    expected.unacknowledged.clear();
+                }
+                Err(_) => expected.unacknowledged.push(value),
+            }
+            if storage.crashed() {
+                break;
+            }
+        }
+
+        // Anything that isn't synced by now may be lost
+        storage.crash();
+    }
+
+    config.storage = Some(Arc::new(storage.recover()));
+    let engine = config.engine;
+    let db = KvDb::open(config).unwrap_or_else(|err| panic!("reopening after {:?} failed: {}", fault, err));
+    let mut live = 0;
+    for key in 0..KEYS as i64 {
+        let value = db.get(key).unwrap();
+        let expected = expected.remove(&key).unwrap_or_default();
+        assert!(
+            value == expected.acknowledged || expected.unacknowledged.contains(&value),
+            "key {} holds {:?} after {:?}, expected {:?} or one of {:?}",
+            key,
+            value,
+            fault,
+            expected.acknowledged,
+            expected.unacknowledged,
+        );
+        live += value.is_some() as usize;
+    }
+    // The LSM-tree engine only estimates its number of keys, counting overwrites
+    // that haven't been flushed yet
+    if engine == EngineKind::Log {
+        assert_eq!(db.len(), live, "wrong number of keys after {:?}", fault);
+    } else {
+        assert!(db.len() >= live, "too few keys counted after {:?}", fault);
+    }
+    storage
+}
+
+// Crash at every operation of the workload in turn, then fail every rename
+fn check_crash_consistency(config: Config) {
+    let config = Config {
+        sync_writes: true,
+        chunk_size: 64,
+        ..config
+    };
+
+    let storage = run_workload(&config, None);
+    let (ops, renames) = {
+        let state = storage.0.lock().unwrap();
+        (state.ops, state.renames.clone())
+    };
+    assert!(!renames.is_empty());
+
+    for at in 1..=ops {
+        run_workload(&config, Some((at, Fault::Crash)));
+    }
+    for at in renames {
+        run_workload(&config, Some((at, Fault::FailRename)));
+    }
+}
+
+#[test]
+fn test_log_crash_consistency() {
+    check_crash_consistency(Config {
+        gc_threshold: 2048,
+        ..Config::default()
+    });
+}
+
+#[test]
+fn test_disk_index_crash_consistency() {
+    check_crash_consistency(Config {
+        gc_threshold: 2048,
+        index: IndexKind::Disk { max_buffered_keys: 4 },
+        ..Config::default()
+    });
+}
+
+#[test]
+fn test_lsm_crash_consistency() {
+    check_crash_consistency(Config {
+        engine: EngineKind::Lsm(LsmOptions {
+            memtable_size: 512,
+            level0_tables: 2,
+            level1_size: 1024,
+            level_multiplier: 2,
+            table_size: 512,
+        }),
+        ..Config::default()
+    });
+}
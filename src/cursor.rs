@@ -0,0 +1,94 @@
+use std::ops::{Bound, RangeBounds};
+
+use crate::{into_string, Engine, KvDb, KvError, Operation, Result};
+
+// The bytes an entry counts for against the limit of a page
+fn entry_size(value: &[u8]) -> usize {
+    std::mem::size_of::<i64>() + value.len()
+}
+
+// Reads the live entries of a range in key order a page at a time, as returned
+// by `KvDb::scan_cursor`. No lock is held between pages, so a page reflects the
+// writes made before it was read. With the log-structured engine the keys in the
+// range are collected when the cursor is made, and keys added later are left out.
+pub struct ScanCursor<'a> {
+    db: &'a KvDb,
+    // The part of the range not read yet
+    range: (Bound<i64>, Bound<i64>),
+    // With the log-structured engine, the keys in the range in order, and how
+    // many of them have been read
+    keys: Vec<i64>,
+    next_key: usize,
+}
+
+impl<'a> ScanCursor<'a> {
+    pub(crate) fn new(db: &'a KvDb, range: (Bound<i64>, Bound<i64>)) -> Result<Self> {
+        // The index isn't ordered, so the keys are sorted up front
+        let mut keys = Vec::new();
+        if let Engine::Log(log) = &db.engine {
+            log.index.read().unwrap().for_each(&mut |key, _| {
+                if range.contains(&key) {
+                    keys.push(key);
+                }
+                Ok(())
+            })?;
+            keys.sort_unstable();
+        }
+
+        Ok(Self { db, range, keys, next_key: 0 })
+    }
+
+    // Get the next entries of the range, at most `max_entries` of them with keys and
+    // values adding up to at most `max_bytes`, though a page holds at least one entry
+    // however large. Returns an empty page once the whole range has been read.
+    pub fn next_page(&mut self, max_entries: usize, max_bytes: usize) -> Result<Vec<(i64, String)>> {
+        let db = self.db;
+        db.metrics.track(Operation::Scan, || self.read_page(max_entries.max(1), max_bytes))
+    }
+
+    // Does the work of `next_page`, which tracks it in the stats
+    fn read_page(&mut self, max_entries: usize, max_bytes: usize) -> Result<Vec<(i64, String)>> {
+        // Check if the database is closed
+        if *self.db.closed.read().unwrap() {
+            return Err(KvError::DbClosed);
+        }
+
+        let mut page = Vec::new();
+        let mut size = 0;
+        match &self.db.engine {
+            Engine::Log(log) => {
+                let index = log.index.read().unwrap();
+                let file = log.file.lock().unwrap();
+                while page.len() < max_entries {
+                    let Some(&key) = self.keys.get(self.next_key) else {
+                        break;
+                    };
+
+                    // Keys removed since the cursor was made are skipped
+                    if let Some(pos) = index.get(key)? {
+                        let value = self.db.read_value(&**file, key, &pos)?;
+                        if !page.is_empty() && size + entry_size(&value) > max_bytes {
+                            break;
+                        }
+                        size += entry_size(&value);
+                        page.push((key, into_string(value)));
+                    }
+                    self.next_key += 1;
+                }
+            }
+            Engine::Lsm(tree) => {
+                let entries = tree.read().unwrap().scan(self.range, max_entries)?;
+                for (key, (flags, payload)) in entries {
+                    let value = self.db.decode_value(key, flags, payload)?;
+                    if !page.is_empty() && size + entry_size(&value) > max_bytes {
+                        break;
+                    }
+                    size += entry_size(&value);
+                    page.push((key, into_string(value)));
+                    self.range.0 = Bound::Excluded(key);
+                }
+            }
+        }
+        Ok(page)
+    }
+}
@@ -0,0 +1,174 @@
+use std::fmt;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use crate::{KvError, Result};
+
+// Length of the key identifier stored in front of every encrypted payload
+pub(crate) const KEY_ID_LEN: usize = 4;
+
+// Length of the per-record nonce
+const NONCE_LEN: usize = 12;
+
+// A 256-bit key used to encrypt values with ChaCha20-Poly1305
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<[u8; 32]> for EncryptionKey {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self::new(bytes)
+    }
+}
+
+// Never print key material
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(<redacted>)")
+    }
+}
+
+// An initialized cipher together with the identifier of its key
+struct Cipher {
+    id: [u8; KEY_ID_LEN],
+    aead: ChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn new(key: &EncryptionKey) -> Self {
+        let aead = ChaCha20Poly1305::new(Key::from_slice(&key.0));
+
+        // Identify the key by its tag over an empty message with a zero nonce,
+        // which reveals nothing about the key but lets us tell keys apart
+        let check = aead
+            .encrypt(Nonce::from_slice(&[0; NONCE_LEN]), &[][..])
+            .expect("encrypting an empty message cannot fail");
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&check[..KEY_ID_LEN]);
+
+        Self { id, aead }
+    }
+}
+
+// The set of keys the database can encrypt and decrypt with.
+// New values are encrypted with the current key; the previous keys are only
+// used to read records that have not been rotated yet.
+pub(crate) struct Keyring {
+    current: Option<Cipher>,
+    previous: Vec<Cipher>,
+}
+
+impl Keyring {
+    pub(crate) fn new(current: Option<&EncryptionKey>, previous: &[EncryptionKey]) -> Self {
+        Self {
+            current: current.map(Cipher::new),
+            previous: previous.iter().map(Cipher::new).collect(),
+        }
+    }
+
+    // Whether new values get encrypted
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.current.is_some()
+    }
+
+    // Encrypt a value for the given key with the current encryption key.
+    // The payload layout is: key id, nonce, ciphertext (with tag).
+    pub(crate) fn encrypt(&self, key: i64, data: &[u8]) -> Result<Vec<u8>> {
+        let cipher = self.current.as_ref().ok_or(KvError::WrongEncryptionKey)?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        // Bind the ciphertext to its key so records can't be swapped around
+        let aad = key.to_le_bytes();
+        let ciphertext = cipher
+            .aead
+            .encrypt(&nonce, Payload { msg: data, aad: &aad })
+            .map_err(|_| KvError::Decryption)?;
+
+        let mut payload = Vec::with_capacity(KEY_ID_LEN + NONCE_LEN + ciphertext.len());
+        payload.extend_from_slice(&cipher.id);
+        payload.extend_from_slice(&nonce);
+        payload.extend_from_slice(&ciphertext);
+        Ok(payload)
+    }
+
+    // Decrypt a payload produced by `encrypt`, verifying its integrity
+    pub(crate) fn decrypt(&self, key: i64, payload: &[u8]) -> Result<Vec<u8>> {
+        if payload.len() < KEY_ID_LEN + NONCE_LEN {
+            return Err(KvError::InvalidFormat);
+        }
+        let (id, rest) = payload.split_at(KEY_ID_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        let cipher = self.find(id)?;
+        let aad = key.to_le_bytes();
+        cipher
+            .aead
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+            .map_err(|_| KvError::Decryption)
+    }
+
+    // Check that a record encrypted under the given key id can be read
+    pub(crate) fn check_key_id(&self, id: &[u8]) -> Result<()> {
+        self.find(id).map(|_| ())
+    }
+
+    // Whether a stored payload should be re-encrypted with the current key.
+    // This is the case for records written under a previous key and for
+    // records whose encryption state doesn't match the current configuration.
+    pub(crate) fn needs_rotation(&self, encrypted: bool, payload: &[u8]) -> bool {
+        match (&self.current, encrypted) {
+            (Some(cipher), true) => !payload.starts_with(&cipher.id),
+            (None, false) => false,
+            _ => true,
+        }
+    }
+
+    fn find(&self, id: &[u8]) -> Result<&Cipher> {
+        self.current
+            .iter()
+            .chain(self.previous.iter())
+            .find(|cipher| cipher.id == id)
+            .ok_or(KvError::WrongEncryptionKey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let keyring = Keyring::new(Some(&EncryptionKey::new([7; 32])), &[]);
+
+        let payload = keyring.encrypt(1, b"secret").unwrap();
+        assert!(!payload.windows(6).any(|window| window == b"secret"));
+        assert_eq!(keyring.decrypt(1, &payload).unwrap(), b"secret");
+
+        // Payloads are bound to their key and protected against tampering
+        assert!(matches!(keyring.decrypt(2, &payload), Err(KvError::Decryption)));
+        let mut tampered = payload.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(keyring.decrypt(1, &tampered), Err(KvError::Decryption)));
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        let old_key = EncryptionKey::new([1; 32]);
+        let new_key = EncryptionKey::new([2; 32]);
+
+        let payload = Keyring::new(Some(&old_key), &[]).encrypt(1, b"secret").unwrap();
+
+        let wrong = Keyring::new(Some(&new_key), &[]);
+        assert!(matches!(wrong.decrypt(1, &payload), Err(KvError::WrongEncryptionKey)));
+
+        let rotated = Keyring::new(Some(&new_key), &[old_key]);
+        assert_eq!(rotated.decrypt(1, &payload).unwrap(), b"secret");
+        assert!(rotated.needs_rotation(true, &payload));
+    }
+}
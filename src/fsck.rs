@@ -0,0 +1,570 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::blob::Manifest;
+use crate::storage::{FileWriter, Storage, StorageFile};
+use crate::{
+    bloom, compression, encryption, index, parse_header, read_payload, write_merge_record, write_record, KvError, OpType, Result,
+    ValuePos, FLAG_CHUNKED, FLAG_COMPRESSED, FLAG_ENCRYPTED, FLAG_MERGE, FLAG_TRUNCATED, MERGE_LINK_SIZE,
+    VERSION_RECORD_SIZE,
+};
+
+// The data file of the log-structured engine
+const DATA_FILE: &str = "data.db";
+
+// Where `LogFile::repair` keeps the file it replaced, and writes the new one
+const CORRUPT_FILE: &str = "data.db.corrupt";
+const REPAIR_TEMP_FILE: &str = "repair.tmp";
+
+// The number of records in a row that must be readable for repair to carry on
+// after unreadable data
+const RESYNC_RECORDS: usize = 16;
+
+// The size of the pieces files are copied in
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+// The kind of a record in the data file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    Set,
+    Remove,
+    Chunk,
+    Merge,
+    Version,
+}
+
+impl fmt::Display for RecordKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+// A record read from the data file, without its value
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    pub offset: u64,
+    pub kind: RecordKind,
+    // The flags in the record header
+    pub flags: u8,
+    // None for a Version record
+    pub key: Option<i64>,
+    // The length of the whole record, and of the value at its end
+    pub len: u64,
+    pub value_size: u64,
+    // For a Version record, the version number and the time of the writes that
+    // follow, in milliseconds since the Unix epoch
+    pub version: Option<(u64, u64)>,
+    // For a Merge record, the position of the value of the previous record for its key
+    link: Option<ValuePos>,
+}
+
+impl LogRecord {
+    // The offset of the value, right after the header
+    pub fn value_offset(&self) -> u64 {
+        self.offset + self.len - self.value_size
+    }
+
+    // For a Merge record, the offset of the value of the previous record for its
+    // key, if there is one
+    pub fn previous(&self) -> Option<u64> {
+        self.link.map(|link| link.offset)
+    }
+
+    // The position of the value, as the index would hold it
+    fn value_pos(&self) -> ValuePos {
+        let flags = if self.kind == RecordKind::Merge { self.flags | FLAG_MERGE } else { self.flags };
+        ValuePos { offset: self.value_offset(), size: self.value_size, flags }
+    }
+
+    fn corrupted(&self, reason: impl Into<String>) -> KvError {
+        KvError::Corrupted { offset: self.offset, reason: reason.into() }
+    }
+}
+
+// How much of a data file is taken up by current values
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogStats {
+    pub file_size: u64,
+    pub records: usize,
+    // The number of keys with a value
+    pub keys: usize,
+    // The bytes of the records current values are made of: their Set or Merge
+    // records, the records earlier in their merge chains and their chunks. The
+    // rest is dead, and garbage collection reclaims it unless history is kept.
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+}
+
+// What `LogFile::repair` salvaged
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    // The records copied to the new file
+    pub records: usize,
+    // The bytes skipped over because no record could be read from them
+    pub skipped_bytes: u64,
+    // The values that couldn't be salvaged, and were replaced with Remove records
+    pub lost_values: usize,
+}
+
+// Offline access to the data file of a log-structured database, for looking
+// into it and repairing it. The database must not be open while it is used.
+pub struct LogFile {
+    file: Arc<dyn StorageFile>,
+    size: u64,
+}
+
+impl LogFile {
+    pub fn open(storage: &dyn Storage) -> Result<Self> {
+        let file = storage.open(DATA_FILE)?;
+        let size = file.size()?;
+        Ok(Self { file, size })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // The records in the file, in order. Iteration stops after the first record
+    // that can't be read, which is returned as `KvError::Corrupted`.
+    pub fn records(&self) -> Records<'_> {
+        Records { log: self, offset: 0, failed: false }
+    }
+
+    // Read the header of the record at an offset
+    pub fn read_record(&self, offset: u64) -> Result<LogRecord> {
+        let corrupted = |reason: String| KvError::Corrupted { offset, reason };
+        let mut header = [0; 1 + 8 + MERGE_LINK_SIZE as usize + 8];
+        let available = (self.size.saturating_sub(offset)).min(header.len() as u64) as usize;
+        if available == 0 {
+            return Err(corrupted("offset is at or past the end of the file".to_string()));
+        }
+        self.file.read_at(offset, &mut header[..available])?;
+
+        let (op_type, flags) = parse_header(header[0]).map_err(|_| corrupted(format!("invalid header byte {:#04x}", header[0])))?;
+        let header_size = match op_type {
+            OpType::Remove => 1 + 8,
+            OpType::Merge => 1 + 8 + MERGE_LINK_SIZE + 8,
+            OpType::Set | OpType::Chunk => 1 + 8 + 8,
+            OpType::Version => VERSION_RECORD_SIZE,
+        };
+        if (available as u64) < header_size {
+            return Err(corrupted("record is cut short by the end of the file".to_string()));
+        }
+        let mut fields = &header[1..header_size as usize];
+
+        let mut record = LogRecord {
+            offset,
+            kind: RecordKind::Version,
+            flags,
+            key: None,
+            len: header_size,
+            value_size: 0,
+            version: None,
+            link: None,
+        };
+        match op_type {
+            OpType::Version => {
+                if flags & !FLAG_TRUNCATED != 0 {
+                    return Err(corrupted(format!("invalid flags {:#04x} on a Version record", flags)));
+                }
+                record.version = Some((fields.read_u64::<LittleEndian>()?, fields.read_u64::<LittleEndian>()?));
+                return Ok(record);
+            }
+            OpType::Remove if flags != 0 => {
+                return Err(corrupted(format!("invalid flags {:#04x} on a Remove record", flags)));
+            }
+            _ => {}
+        }
+
+        record.key = Some(fields.read_i64::<LittleEndian>()?);
+        record.kind = match op_type {
+            OpType::Set => RecordKind::Set,
+            OpType::Remove => RecordKind::Remove,
+            OpType::Chunk => RecordKind::Chunk,
+            OpType::Merge => RecordKind::Merge,
+            OpType::Version => unreachable!("Version records are read above"),
+        };
+        if op_type == OpType::Merge {
+            let link = ValuePos {
+                offset: fields.read_u64::<LittleEndian>()?,
+                size: fields.read_u64::<LittleEndian>()?,
+                flags: fields.read_u8()?,
+            };
+            record.link = (link.offset != 0).then_some(link);
+        }
+        if op_type != OpType::Remove {
+            record.value_size = fields.read_u64::<LittleEndian>()?;
+            if record.value_size > self.size - offset - header_size {
+                return Err(corrupted(format!("value of {} bytes runs past the end of the file", record.value_size)));
+            }
+            record.len += record.value_size;
+        }
+        Ok(record)
+    }
+
+    fn read_value(&self, record: &LogRecord) -> Result<Vec<u8>> {
+        read_payload(&*self.file, &record.value_pos())
+    }
+
+    // Check the value of a record on its own: its flags, and that it decompresses
+    // if it is compressed. Encrypted values can't be checked without their keys.
+    fn check_value(&self, record: &LogRecord) -> Result<()> {
+        if record.flags & FLAG_CHUNKED != 0 {
+            if record.kind != RecordKind::Set || record.flags != FLAG_CHUNKED {
+                return Err(record.corrupted("invalid flags on a chunked value"));
+            }
+            Manifest::decode(&self.read_value(record)?).map_err(|_| record.corrupted("unreadable chunk manifest"))?;
+        } else if record.flags & FLAG_ENCRYPTED != 0 {
+            if record.value_size < encryption::KEY_ID_LEN as u64 {
+                return Err(record.corrupted("encrypted value is too short"));
+            }
+        } else if record.flags & FLAG_COMPRESSED != 0 {
+            compression::decompress(&self.read_value(record)?)
+                .map_err(|err| record.corrupted(format!("compressed value doesn't decompress: {}", err)))?;
+        }
+        Ok(())
+    }
+
+    // Check that every record is well formed: that its header and sizes are valid,
+    // that Merge links and chunk manifests point at earlier records of the right
+    // kind and key, and that compressed values decompress. The file has no
+    // checksums, so damage inside a value that isn't compressed goes unnoticed.
+    // Returns the number of records, or `KvError::Corrupted` for the first bad one.
+    pub fn verify(&self) -> Result<usize> {
+        // The values read so far, by their offset
+        let mut values: HashMap<u64, (RecordKind, Option<i64>, ValuePos)> = HashMap::new();
+        let mut count = 0;
+        for record in self.records() {
+            let record = record?;
+            self.check_value(&record)?;
+
+            if let Some(link) = record.link {
+                match values.get(&link.offset) {
+                    Some((RecordKind::Set | RecordKind::Merge, key, pos))
+                        if *key == record.key && pos.size == link.size && pos.flags == link.flags => {}
+                    _ => return Err(record.corrupted("Merge record links to something other than an earlier value of its key")),
+                }
+            }
+            if record.flags & FLAG_CHUNKED != 0 {
+                for chunk in Manifest::decode(&self.read_value(&record)?)?.chunks {
+                    match values.get(&chunk.offset) {
+                        Some((RecordKind::Chunk, key, pos)) if *key == record.key && pos.size == chunk.size && pos.flags == chunk.flags => {}
+                        _ => return Err(record.corrupted("chunk manifest lists something other than an earlier chunk of its key")),
+                    }
+                }
+            }
+
+            if !matches!(record.kind, RecordKind::Remove | RecordKind::Version) {
+                values.insert(record.value_offset(), (record.kind, record.key, record.value_pos()));
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // Count the live and dead bytes in the file. Fails on the first record that
+    // can't be read.
+    pub fn stats(&self) -> Result<LogStats> {
+        // The current value of each key, and the length and link of every value
+        let mut current: HashMap<i64, u64> = HashMap::new();
+        let mut values: HashMap<u64, (u64, Option<u64>)> = HashMap::new();
+        let mut chunked = Vec::new();
+        let mut stats = LogStats { file_size: self.size, ..LogStats::default() };
+        for record in self.records() {
+            let record = record?;
+            stats.records += 1;
+            match (record.kind, record.key) {
+                (RecordKind::Remove, Some(key)) => {
+                    current.remove(&key);
+                }
+                (RecordKind::Set | RecordKind::Merge, Some(key)) => {
+                    current.insert(key, record.value_offset());
+                }
+                _ => {}
+            }
+            if record.flags & FLAG_CHUNKED != 0 {
+                chunked.push(record.clone());
+            }
+            if !matches!(record.kind, RecordKind::Remove | RecordKind::Version) {
+                values.insert(record.value_offset(), (record.len, record.previous()));
+            }
+        }
+
+        let manifests: HashMap<u64, &LogRecord> = chunked.iter().map(|record| (record.value_offset(), record)).collect();
+        stats.keys = current.len();
+        for mut offset in current.into_values() {
+            // Follow the merge chain back to its start, taking in the chunks on the way
+            while let Some(&(len, previous)) = values.get(&offset) {
+                stats.live_bytes += len;
+                if let Some(record) = manifests.get(&offset) {
+                    for chunk in Manifest::decode(&self.read_value(record)?)?.chunks {
+                        stats.live_bytes += values.get(&chunk.offset).map_or(0, |&(len, _)| len);
+                    }
+                }
+                match previous {
+                    Some(previous) => offset = previous,
+                    None => break,
+                }
+            }
+        }
+        stats.dead_bytes = self.size - stats.live_bytes;
+        Ok(stats)
+    }
+
+    // The offset of the first readable record after a bad one. Bytes inside a record
+    // often look like a record too, so a record only counts if the ones after it can
+    // be read up to the end of the file, or for `RESYNC_RECORDS` records. Nor does
+    // a Remove record for a key that hasn't been seen, which a few stray bytes can
+    // pass for.
+    fn resync(&self, bad_offset: u64, keys: &HashSet<i64>) -> Option<u64> {
+        (bad_offset + 1..self.size).find(|&start| {
+            match self.read_record(start) {
+                Ok(record) if record.kind == RecordKind::Remove && !keys.contains(&record.key.unwrap_or_default()) => return false,
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+            let mut offset = start;
+            for _ in 0..RESYNC_RECORDS {
+                match self.read_record(offset) {
+                    Ok(record) if offset + record.len == self.size => return true,
+                    Ok(record) => offset += record.len,
+                    Err(_) => return false,
+                }
+            }
+            true
+        })
+    }
+
+    // Copy every record that can be read to a new data file, which replaces this
+    // one. The old file is kept as "data.db.corrupt". Where a record can't be read,
+    // the bytes up to the next one that can are skipped. Merge records whose
+    // earlier records were lost start their chains afresh. Values that are damaged,
+    // or whose chunks were lost, are replaced with Remove records so they don't
+    // bring back older values. The persisted index and filter are removed, to be
+    // rebuilt when the database is next opened.
+    pub fn repair(self, storage: &dyn Storage) -> Result<RepairReport> {
+        let temp_file = storage.create(REPAIR_TEMP_FILE)?;
+        let mut writer = BufWriter::new(FileWriter(temp_file.clone()));
+        let mut new_offset = 0;
+        let mut report = RepairReport::default();
+
+        // Where the values copied so far ended up, by their old offset
+        let mut moved: HashMap<u64, ValuePos> = HashMap::new();
+        let mut keys = HashSet::new();
+
+        let mut offset = 0;
+        while offset < self.size {
+            let record = match self.read_record(offset) {
+                Ok(record) => record,
+                Err(err) => {
+                    log::warn!("Skipping unreadable data: {}", err);
+                    let next = self.resync(offset, &keys).unwrap_or(self.size);
+                    report.skipped_bytes += next - offset;
+                    offset = next;
+                    continue;
+                }
+            };
+            offset += record.len;
+            report.records += 1;
+
+            let key = record.key.unwrap_or_default();
+            keys.extend(record.key);
+            let mut value = match record.kind {
+                RecordKind::Remove | RecordKind::Version => None,
+                _ => match self.check_value(&record) {
+                    Ok(()) => Some(self.read_value(&record)?),
+                    Err(err) => {
+                        log::warn!("Dropping a damaged value: {}", err);
+                        None
+                    }
+                },
+            };
+
+            // Point chunk manifests at the new positions of their chunks
+            if let (Some(payload), true) = (&value, record.flags & FLAG_CHUNKED != 0) {
+                let manifest = Manifest::decode(payload)?;
+                let chunks: Option<Vec<ValuePos>> = manifest.chunks.iter().map(|chunk| moved.get(&chunk.offset).copied()).collect();
+                value = chunks.map(|chunks| Manifest { len: manifest.len, chunks }.encode());
+            }
+
+            let mut record_bytes = Vec::with_capacity(record.len as usize);
+            match (record.kind, &value) {
+                (RecordKind::Remove | RecordKind::Version, _) => {
+                    record_bytes = read_payload(&*self.file, &ValuePos { offset: record.offset, size: record.len, flags: 0 })?;
+                }
+                (RecordKind::Set, Some(payload)) => write_record(&mut record_bytes, OpType::Set as u8 | record.flags, key, payload)?,
+                (RecordKind::Chunk, Some(payload)) => write_record(&mut record_bytes, OpType::Chunk as u8 | record.flags, key, payload)?,
+                (RecordKind::Merge, Some(payload)) => {
+                    let link = record.link.and_then(|link| moved.get(&link.offset).copied());
+                    write_merge_record(&mut record_bytes, record.flags, key, link, payload)?;
+                }
+                // Lost chunks only matter to their manifests
+                (RecordKind::Chunk, None) => {
+                    report.records -= 1;
+                    continue;
+                }
+                (RecordKind::Set | RecordKind::Merge, None) => {
+                    report.lost_values += 1;
+                    record_bytes.write_u8(OpType::Remove as u8)?;
+                    record_bytes.write_i64::<LittleEndian>(key)?;
+                }
+            }
+
+            // Values are at the end of their records
+            if let Some(payload) = &value {
+                let size = payload.len() as u64;
+                let offset = new_offset + record_bytes.len() as u64 - size;
+                moved.insert(record.value_offset(), ValuePos { offset, size, flags: record.value_pos().flags });
+            }
+            writer.write_all(&record_bytes)?;
+            new_offset += record_bytes.len() as u64;
+        }
+        writer.flush()?;
+        temp_file.sync()?;
+
+        // Keep a copy of the old file, then swap in the new one
+        let backup = storage.create(CORRUPT_FILE)?;
+        let mut buffer = vec![0; COPY_BUFFER_SIZE];
+        let mut copied = 0;
+        while copied < self.size {
+            let len = (self.size - copied).min(buffer.len() as u64) as usize;
+            self.file.read_at(copied, &mut buffer[..len])?;
+            backup.append(&buffer[..len])?;
+            copied += len as u64;
+        }
+        backup.sync()?;
+        index::invalidate(storage)?;
+        bloom::invalidate(storage)?;
+        storage.rename(REPAIR_TEMP_FILE, DATA_FILE)?;
+        Ok(report)
+    }
+}
+
+// The records of a data file, in order
+pub struct Records<'a> {
+    log: &'a LogFile,
+    offset: u64,
+    failed: bool,
+}
+
+impl Iterator for Records<'_> {
+    type Item = Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.offset >= self.log.size {
+            return None;
+        }
+        match self.log.read_record(self.offset) {
+            Ok(record) => {
+                self.offset += record.len;
+                Some(Ok(record))
+            }
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AppendOperator, Compression, Config, KvDb, MemoryStorage};
+
+    fn config(storage: &Arc<MemoryStorage>) -> Config {
+        Config {
+            storage: Some(storage.clone()),
+            compression: Compression::Lz4,
+            chunk_size: 64,
+            max_versions: 2,
+            merge_operator: Some(Arc::new(AppendOperator { delimiter: ",".to_string() })),
+            ..Config::default()
+        }
+    }
+
+    // Write a database with every kind of record, returning the offsets of the
+    // records for key 3 and key 4
+    fn write_db(storage: &Arc<MemoryStorage>) -> (u64, u64) {
+        let db = KvDb::open(config(storage)).unwrap();
+        db.set(1, "a").unwrap();
+        db.set(2, &"large value ".repeat(20)).unwrap();
+        db.merge(1, "b").unwrap();
+        db.set(3, "c").unwrap();
+        db.remove(3).unwrap();
+        db.set(4, &"x".repeat(60)).unwrap();
+        db.close().unwrap();
+
+        let log = LogFile::open(&**storage).unwrap();
+        let offset = |key, kind| log.records().map(Result::unwrap).find(|record| record.key == Some(key) && record.kind == kind).unwrap().offset;
+        (offset(3, RecordKind::Remove), offset(4, RecordKind::Set))
+    }
+
+    // Change a byte of the data file
+    fn damage(storage: &MemoryStorage, offset: u64, byte: u8) {
+        let file = storage.open(DATA_FILE).unwrap();
+        let mut data = vec![0; file.size().unwrap() as usize];
+        file.read_at(0, &mut data).unwrap();
+        data[offset as usize] = byte;
+        storage.create(DATA_FILE).unwrap().append(&data).unwrap();
+    }
+
+    #[test]
+    fn test_inspect() {
+        let storage = Arc::new(MemoryStorage::new());
+        write_db(&storage);
+        let log = LogFile::open(&*storage).unwrap();
+        let records: Vec<LogRecord> = log.records().collect::<Result<_>>().unwrap();
+        for kind in [RecordKind::Set, RecordKind::Remove, RecordKind::Chunk, RecordKind::Merge, RecordKind::Version] {
+            assert!(records.iter().any(|record| record.kind == kind), "no {} record", kind);
+        }
+        assert_eq!(records.iter().map(|record| record.len).sum::<u64>(), log.size());
+        assert_eq!(log.verify().unwrap(), records.len());
+
+        let stats = log.stats().unwrap();
+        assert_eq!(stats.keys, 3);
+        assert_eq!(stats.records, records.len());
+        assert!(stats.live_bytes > 20 * 12 && stats.dead_bytes > 0);
+        assert_eq!(stats.live_bytes + stats.dead_bytes, log.size());
+    }
+
+    #[test]
+    fn test_repair() {
+        // An unreadable record is skipped, so the key it removed comes back
+        let storage = Arc::new(MemoryStorage::new());
+        let (remove_offset, _) = write_db(&storage);
+        damage(&storage, remove_offset, 0x0f);
+        let log = LogFile::open(&*storage).unwrap();
+        assert!(matches!(log.verify(), Err(KvError::Corrupted { offset, .. }) if offset == remove_offset));
+        assert!(KvDb::open(config(&storage)).is_err());
+
+        let report = log.repair(&*storage).unwrap();
+        assert_eq!(report.skipped_bytes, 1 + 8);
+        assert_eq!(report.lost_values, 0);
+        assert!(storage.list().unwrap().contains(&CORRUPT_FILE.to_string()));
+        LogFile::open(&*storage).unwrap().verify().unwrap();
+        let db = KvDb::open(config(&storage)).unwrap();
+        assert_eq!(db.get(1).unwrap(), Some("a,b".to_string()));
+        assert_eq!(db.get(2).unwrap(), Some("large value ".repeat(20)));
+        assert_eq!(db.get(3).unwrap(), Some("c".to_string()));
+        assert_eq!(db.get(4).unwrap(), Some("x".repeat(60)));
+        db.close().unwrap();
+
+        // A damaged value is replaced with a Remove record
+        let storage = Arc::new(MemoryStorage::new());
+        let (_, set_offset) = write_db(&storage);
+        damage(&storage, set_offset + 1 + 8 + 8, 0xff);
+        let log = LogFile::open(&*storage).unwrap();
+        assert!(matches!(log.verify(), Err(KvError::Corrupted { offset, .. }) if offset == set_offset));
+
+        let report = log.repair(&*storage).unwrap();
+        assert_eq!((report.skipped_bytes, report.lost_values), (0, 1));
+        let db = KvDb::open(config(&storage)).unwrap();
+        assert_eq!(db.get(4).unwrap(), None);
+        assert_eq!(db.get(1).unwrap(), Some("a,b".to_string()));
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{KvError, Result, ValuePos};
+
+// A point in the history of the database to read at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsOf {
+    // Right after the write with this version number
+    Version(u64),
+    // At this time, to the millisecond
+    Time(SystemTime),
+}
+
+// A past or current version of a key, as returned by `KvDb::history`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub version: u64,
+    pub timestamp: SystemTime,
+    // The value the write left, or None if it removed the key
+    pub value: Option<String>,
+}
+
+// The retained versions of a key, as returned by `KvDb::history`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VersionHistory {
+    // Oldest first, including removals
+    pub versions: Vec<Version>,
+    // Whether older versions were discarded, so reads from before the oldest
+    // one fail with `KvError::VersionNotRetained`
+    pub truncated: bool,
+}
+
+// Turn a stored timestamp, in milliseconds since the Unix epoch, into a time
+pub(crate) fn to_system_time(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+// A retained version of a key: its version number, the time it was written in
+// milliseconds since the Unix epoch, and the position of its value, or None if
+// the key was removed
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct VersionEntry {
+    pub(crate) version: u64,
+    pub(crate) timestamp: u64,
+    pub(crate) pos: Option<ValuePos>,
+}
+
+// The retained versions of a key, oldest first
+#[derive(Default)]
+pub(crate) struct KeyHistory {
+    pub(crate) entries: VecDeque<VersionEntry>,
+    // Whether older versions were discarded, so reads from before the oldest
+    // retained one can't be answered
+    pub(crate) truncated: bool,
+}
+
+impl KeyHistory {
+    // The number of oldest versions that were replaced at or before `cutoff`, a
+    // timestamp, and so have aged out of the retention window. All of them once
+    // the newest version is a removal that old, as the key is gone for good.
+    pub(crate) fn expired(&self, cutoff: u64) -> usize {
+        match self.entries.back() {
+            Some(newest) if newest.pos.is_none() && newest.timestamp <= cutoff => self.entries.len(),
+            _ => self.entries.iter().skip(1).take_while(|entry| entry.timestamp <= cutoff).count(),
+        }
+    }
+}
+
+// The versions of every key written since history was enabled, up to
+// `Config::max_versions` per key, including removed keys. Kept in memory and
+// rebuilt from the Version records in the log when the database is opened.
+// Versions older than `Config::version_retention` are dropped by garbage collection.
+pub(crate) struct History {
+    max_versions: usize,
+    retention: Option<Duration>,
+    keys: HashMap<i64, KeyHistory>,
+    // The last version number and timestamp handed out
+    last_version: u64,
+    last_timestamp: u64,
+}
+
+impl History {
+    pub(crate) fn new(max_versions: usize, retention: Option<Duration>) -> Self {
+        Self { max_versions, retention, keys: HashMap::new(), last_version: 0, last_timestamp: 0 }
+    }
+
+    // Start a new version for a write, returning its number and timestamp.
+    // Timestamps never go back, even if the clock does.
+    pub(crate) fn next_version(&mut self) -> (u64, u64) {
+        self.last_version += 1;
+        self.last_timestamp = self.last_timestamp.max(to_millis(SystemTime::now()));
+        (self.last_version, self.last_timestamp)
+    }
+
+    pub(crate) fn last_version(&self) -> u64 {
+        self.last_version
+    }
+
+    // The timestamp at or before which replaced versions are no longer
+    // retained, if they are only kept for a while
+    pub(crate) fn cutoff(&self) -> Option<u64> {
+        let retention = self.retention?;
+        Some(to_millis(SystemTime::now()).saturating_sub(retention.as_millis() as u64))
+    }
+
+    // Add the next version of a key, discarding the oldest one beyond the limit.
+    // A key written more than once in a version keeps the last value.
+    pub(crate) fn record(&mut self, key: i64, entry: VersionEntry) {
+        self.last_version = self.last_version.max(entry.version);
+        self.last_timestamp = self.last_timestamp.max(entry.timestamp);
+
+        let history = self.keys.entry(key).or_default();
+        if history.entries.back().is_some_and(|last| last.version == entry.version) {
+            history.entries.pop_back();
+        }
+        history.entries.push_back(entry);
+        while history.entries.len() > self.max_versions {
+            history.entries.pop_front();
+            history.truncated = true;
+        }
+    }
+
+    // Mark that versions of a key before the ones retained were discarded
+    pub(crate) fn truncate(&mut self, key: i64) {
+        self.keys.entry(key).or_default().truncated = true;
+    }
+
+    // The position of the value of a key as of a point in its history,
+    // or None if it didn't exist then
+    pub(crate) fn find(&self, key: i64, as_of: AsOf) -> Result<Option<ValuePos>> {
+        let Some(history) = self.keys.get(&key) else {
+            return Ok(None);
+        };
+        let found = history.entries.iter().rev().find(|entry| match as_of {
+            AsOf::Version(version) => entry.version <= version,
+            AsOf::Time(time) => entry.timestamp <= to_millis(time),
+        });
+        match found {
+            Some(entry) => Ok(entry.pos),
+            None if history.truncated => Err(KvError::VersionNotRetained),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub(crate) fn get(&self, key: i64) -> Option<&KeyHistory> {
+        self.keys.get(&key)
+    }
+
+    // The keys with a history, in order
+    pub(crate) fn keys(&self) -> Vec<i64> {
+        let mut keys: Vec<i64> = self.keys.keys().copied().collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    // Replace the history with one for a compacted file
+    pub(crate) fn replace_keys(&mut self, keys: HashMap<i64, KeyHistory>) {
+        self.keys = keys;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(version: u64, offset: Option<u64>) -> VersionEntry {
+        let pos = offset.map(|offset| ValuePos { offset, size: 1, flags: 0 });
+        VersionEntry { version, timestamp: version * 1000, pos }
+    }
+
+    fn offset(pos: Option<ValuePos>) -> Option<u64> {
+        pos.map(|pos| pos.offset)
+    }
+
+    #[test]
+    fn test_history() {
+        let mut history = History::new(3, None);
+        history.record(1, entry(2, Some(10)));
+        history.record(1, entry(4, None));
+        history.record(1, entry(6, Some(30)));
+        assert_eq!(history.last_version(), 6);
+
+        assert_eq!(offset(history.find(1, AsOf::Version(1)).unwrap()), None);
+        assert_eq!(offset(history.find(1, AsOf::Version(3)).unwrap()), Some(10));
+        assert_eq!(offset(history.find(1, AsOf::Version(5)).unwrap()), None);
+        assert_eq!(offset(history.find(1, AsOf::Version(100)).unwrap()), Some(30));
+        assert_eq!(offset(history.find(1, AsOf::Time(to_system_time(2500))).unwrap()), Some(10));
+        assert_eq!(offset(history.find(2, AsOf::Version(5)).unwrap()), None);
+
+        // A second write in the same version replaces the first
+        history.record(1, entry(6, Some(35)));
+        assert_eq!(offset(history.find(1, AsOf::Version(6)).unwrap()), Some(35));
+
+        // Versions beyond the limit are discarded, after which older reads fail
+        history.record(1, entry(8, Some(40)));
+        assert_eq!(history.get(1).unwrap().entries.len(), 3);
+        assert_eq!(offset(history.find(1, AsOf::Version(5)).unwrap()), None);
+        assert!(matches!(history.find(1, AsOf::Version(3)), Err(KvError::VersionNotRetained)));
+
+        // Version numbers and timestamps keep increasing
+        let (version, timestamp) = history.next_version();
+        assert_eq!(version, 9);
+        assert!(timestamp >= 8000);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut history = History::new(5, Some(Duration::from_secs(1)));
+        history.record(1, entry(2, Some(10)));
+        history.record(1, entry(4, Some(20)));
+        history.record(1, entry(6, Some(30)));
+        let key_history = history.get(1).unwrap();
+
+        // A version is kept until the time it was replaced falls out of the window
+        assert_eq!(key_history.expired(3999), 0);
+        assert_eq!(key_history.expired(4000), 1);
+        assert_eq!(key_history.expired(100_000), 2);
+
+        // The whole history of a removed key ages out with the removal
+        history.record(1, entry(8, None));
+        let key_history = history.get(1).unwrap();
+        assert_eq!(key_history.expired(7999), 2);
+        assert_eq!(key_history.expired(8000), 4);
+
+        assert!(history.cutoff().unwrap() <= to_millis(SystemTime::now()) - 1000);
+        assert_eq!(History::new(5, None).cutoff(), None);
+    }
+}
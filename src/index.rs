@@ -0,0 +1,589 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::storage::{FileReader, FileWriter, Storage, StorageFile};
+use crate::{KvError, Result, ValuePos};
+
+// Which index implementation the database keeps its keys in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexKind {
+    // Every key in a hash map in memory, rebuilt from the log on open
+    #[default]
+    Memory,
+
+    // Keys in a sorted file on disk with a small sparse index in memory.
+    // Up to `max_buffered_keys` recent changes are held in memory before
+    // they are merged into the file.
+    Disk { max_buffered_keys: usize },
+}
+
+// Maps live keys to the position of their values in the data file
+pub(crate) trait Index: Send + Sync {
+    fn get(&self, key: i64) -> Result<Option<ValuePos>>;
+
+    // Point a key at a new position. `old` is the position the caller looked the
+    // key up at, which saves a disk index from reading it again.
+    fn insert(&mut self, key: i64, pos: ValuePos, old: Option<ValuePos>) -> Result<()>;
+
+    // Drop a key, given the position the caller looked it up at
+    fn remove(&mut self, key: i64, old: Option<ValuePos>) -> Result<()>;
+
+    // The number of live keys
+    fn len(&self) -> usize;
+
+    // Visit every live key. Disk indexes visit keys in ascending order.
+    fn for_each(&self, f: &mut dyn FnMut(i64, ValuePos) -> Result<()>) -> Result<()>;
+
+    // Note that every log record before `offset` is reflected in the index
+    fn set_log_offset(&mut self, offset: u64);
+
+    // The log offset the index was persisted at when it was opened.
+    // Records from this offset on have to be replayed into the index.
+    fn persisted_log_offset(&self) -> u64;
+
+    // Persist the index, if it is persistent
+    fn flush(&mut self) -> Result<()>;
+
+    // Start building a replacement index of the same kind, for a compacted data file
+    fn rebuild(&self) -> Result<Box<dyn IndexBuilder>>;
+
+    // Discard any persisted state ahead of the data file being replaced
+    fn invalidate(&mut self) -> Result<()>;
+
+    // Make a newly finished index the persisted one. This can't fail: an index
+    // that isn't moved into place is rebuilt from the log on the next open.
+    fn install(&self);
+}
+
+// Builds a new index from entries pushed in the order `Index::for_each` visits them
+pub(crate) trait IndexBuilder {
+    fn push(&mut self, key: i64, pos: ValuePos) -> Result<()>;
+
+    // Complete the index, recording the log offset it covers.
+    // It is only persisted once `Index::install` is called.
+    fn finish(self: Box<Self>, log_offset: u64) -> Result<Box<dyn Index>>;
+}
+
+// Open the configured kind of index for the database in `storage`
+pub(crate) fn open_index(kind: IndexKind, storage: Arc<dyn Storage>) -> Result<Box<dyn Index>> {
+    match kind {
+        IndexKind::Memory => Ok(Box::<MemIndex>::default()),
+        IndexKind::Disk { max_buffered_keys } => Ok(Box::new(DiskIndex::open(storage, max_buffered_keys)?)),
+    }
+}
+
+// The in-memory index
+#[derive(Default)]
+pub(crate) struct MemIndex {
+    map: HashMap<i64, ValuePos>,
+}
+
+impl Index for MemIndex {
+    fn get(&self, key: i64) -> Result<Option<ValuePos>> {
+        Ok(self.map.get(&key).copied())
+    }
+
+    fn insert(&mut self, key: i64, pos: ValuePos, _old: Option<ValuePos>) -> Result<()> {
+        self.map.insert(key, pos);
+        Ok(())
+    }
+
+    fn remove(&mut self, key: i64, _old: Option<ValuePos>) -> Result<()> {
+        self.map.remove(&key);
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(i64, ValuePos) -> Result<()>) -> Result<()> {
+        for (&key, &pos) in &self.map {
+            f(key, pos)?;
+        }
+        Ok(())
+    }
+
+    fn set_log_offset(&mut self, _offset: u64) {}
+
+    // Nothing is persisted, so the whole log is replayed on open
+    fn persisted_log_offset(&self) -> u64 {
+        0
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn rebuild(&self) -> Result<Box<dyn IndexBuilder>> {
+        Ok(Box::<MemIndex>::default())
+    }
+
+    fn invalidate(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn install(&self) {}
+}
+
+impl IndexBuilder for MemIndex {
+    fn push(&mut self, key: i64, pos: ValuePos) -> Result<()> {
+        self.map.insert(key, pos);
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, _log_offset: u64) -> Result<Box<dyn Index>> {
+        Ok(self)
+    }
+}
+
+// The disk index file layout is:
+// 1. Entries sorted by key: key, value offset, value size, value flags
+// 2. Sparse index: the first key of every block of entries
+// 3. Footer: the log offset the index covers, the number of entries, and magic.
+//    Recording a new log offset appends another footer; the last one is current.
+const INDEX_FILE: &str = "index.db";
+const INDEX_TEMP_FILE: &str = "index.tmp";
+const MAGIC: &[u8; 8] = b"KVDBIDX2";
+const FOOTER_SIZE: u64 = 8 + 8 + 8; // log_offset + count + magic
+const ENTRY_SIZE: u64 = 8 + 8 + 8 + 1; // key + offset + size + flags
+
+// The number of entries covered by each sparse index key
+const BLOCK_ENTRIES: u64 = 128;
+
+// Remove the persisted index ahead of the log being replaced
+pub(crate) fn invalidate(storage: &dyn Storage) -> Result<()> {
+    match storage.remove(INDEX_FILE) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+// An index kept in a sorted file, with recent changes buffered in memory
+pub(crate) struct DiskIndex {
+    storage: Arc<dyn Storage>,
+    max_buffered_keys: usize,
+    file: Arc<dyn StorageFile>,
+    // The number of entries in the file
+    file_entries: u64,
+    // The first key of each block of entries in the file
+    sparse: Vec<i64>,
+    // Changes not merged into the file yet; None marks a key removed since
+    delta: BTreeMap<i64, Option<ValuePos>>,
+    len: usize,
+    log_offset: u64,
+    persisted_log_offset: u64,
+}
+
+impl DiskIndex {
+    fn open(storage: Arc<dyn Storage>, max_buffered_keys: usize) -> Result<Self> {
+        // Start over with an empty index if there is none or it can't be used;
+        // the whole log then gets replayed into it
+        let index = match Self::load(&storage, max_buffered_keys) {
+            Ok(Some(index)) => return Ok(index),
+            Ok(None) => DiskIndexBuilder::create(storage, max_buffered_keys)?.finish_disk(0)?,
+            Err(err) => {
+                log::warn!("Rebuilding unreadable index: {}", err);
+                DiskIndexBuilder::create(storage, max_buffered_keys)?.finish_disk(0)?
+            }
+        };
+        index.install();
+        Ok(index)
+    }
+
+    // Load the index file, if there is one
+    fn load(storage: &Arc<dyn Storage>, max_buffered_keys: usize) -> Result<Option<Self>> {
+        match storage.open(INDEX_FILE) {
+            Ok(file) => Ok(Some(Self::from_file(storage.clone(), file, max_buffered_keys)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn from_file(storage: Arc<dyn Storage>, file: Arc<dyn StorageFile>, max_buffered_keys: usize) -> Result<Self> {
+        // Read the last footer
+        let size = file.size()?;
+        if size < FOOTER_SIZE {
+            return Err(KvError::InvalidFormat);
+        }
+        let mut footer = [0; FOOTER_SIZE as usize];
+        file.read_at(size - FOOTER_SIZE, &mut footer)?;
+        let mut reader = &footer[..];
+        let persisted_log_offset = reader.read_u64::<LittleEndian>()?;
+        let file_entries = reader.read_u64::<LittleEndian>()?;
+        if reader != MAGIC {
+            return Err(KvError::InvalidFormat);
+        }
+
+        // Load the sparse index from after the entries
+        let blocks = file_entries.div_ceil(BLOCK_ENTRIES);
+        let data_size = file_entries * ENTRY_SIZE + blocks * 8;
+        if size < data_size + FOOTER_SIZE || !(size - data_size).is_multiple_of(FOOTER_SIZE) {
+            return Err(KvError::InvalidFormat);
+        }
+        let mut buffer = vec![0; (blocks * 8) as usize];
+        file.read_at(file_entries * ENTRY_SIZE, &mut buffer)?;
+        let sparse = buffer
+            .chunks_exact(8)
+            .map(|key| i64::from_le_bytes(key.try_into().unwrap()))
+            .collect();
+
+        Ok(Self {
+            storage,
+            max_buffered_keys,
+            file,
+            file_entries,
+            sparse,
+            delta: BTreeMap::new(),
+            len: file_entries as usize,
+            log_offset: persisted_log_offset,
+            persisted_log_offset,
+        })
+    }
+
+    // Look a key up in the file, reading only the block that may contain it
+    fn get_from_file(&self, key: i64) -> Result<Option<ValuePos>> {
+        let block = match self.sparse.partition_point(|&first| first <= key) {
+            0 => return Ok(None),
+            block => block as u64 - 1,
+        };
+
+        let first_entry = block * BLOCK_ENTRIES;
+        let entries = BLOCK_ENTRIES.min(self.file_entries - first_entry);
+        let mut buffer = vec![0; (entries * ENTRY_SIZE) as usize];
+        self.file.read_at(first_entry * ENTRY_SIZE, &mut buffer)?;
+
+        let entry = |i: usize| &buffer[i * ENTRY_SIZE as usize..(i + 1) * ENTRY_SIZE as usize];
+        let entry_key = |i: usize| i64::from_le_bytes(entry(i)[..8].try_into().unwrap());
+
+        // Binary search the block for the key
+        let (mut low, mut high) = (0, entries as usize);
+        while low < high {
+            let mid = (low + high) / 2;
+            if entry_key(mid) < key {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        if low < entries as usize && entry_key(low) == key {
+            Ok(Some(decode_entry(entry(low))?.1))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Merge the buffered changes into a new index file
+    fn merge(&mut self) -> Result<()> {
+        let mut builder = DiskIndexBuilder::create(self.storage.clone(), self.max_buffered_keys)?;
+        self.for_each(&mut |key, pos| builder.push(key, pos))?;
+        let index = builder.finish_disk(self.log_offset)?;
+        index.install();
+        *self = index;
+        Ok(())
+    }
+}
+
+impl Index for DiskIndex {
+    fn get(&self, key: i64) -> Result<Option<ValuePos>> {
+        match self.delta.get(&key) {
+            Some(change) => Ok(*change),
+            None => self.get_from_file(key),
+        }
+    }
+
+    fn insert(&mut self, key: i64, pos: ValuePos, old: Option<ValuePos>) -> Result<()> {
+        if old.is_none() {
+            self.len += 1;
+        }
+        self.delta.insert(key, Some(pos));
+
+        if self.delta.len() >= self.max_buffered_keys {
+            self.merge()?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, key: i64, old: Option<ValuePos>) -> Result<()> {
+        if old.is_none() {
+            return Ok(());
+        }
+        self.len -= 1;
+
+        // The file may hold the key, so the removal is remembered until the
+        // next merge whether it does or not
+        self.delta.insert(key, None);
+
+        if self.delta.len() >= self.max_buffered_keys {
+            self.merge()?;
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    // Visit the entries of the file merged with the buffered changes, in key order
+    fn for_each(&self, f: &mut dyn FnMut(i64, ValuePos) -> Result<()>) -> Result<()> {
+        let mut reader = BufReader::new(FileReader::new(self.file.clone(), 0));
+
+        let mut remaining = self.file_entries;
+        let mut next_from_file = || -> Result<Option<(i64, ValuePos)>> {
+            if remaining == 0 {
+                return Ok(None);
+            }
+            remaining -= 1;
+            let mut entry = [0; ENTRY_SIZE as usize];
+            reader.read_exact(&mut entry)?;
+            Ok(Some(decode_entry(&entry)?))
+        };
+
+        let mut delta = self.delta.iter().peekable();
+        let mut file_entry = next_from_file()?;
+        loop {
+            match (file_entry, delta.peek()) {
+                (None, None) => break,
+                (Some((key, pos)), Some((&delta_key, _))) if key < delta_key => {
+                    f(key, pos)?;
+                    file_entry = next_from_file()?;
+                }
+                (Some((key, pos)), None) => {
+                    f(key, pos)?;
+                    file_entry = next_from_file()?;
+                }
+                (_, Some(_)) => {
+                    let (&key, change) = delta.next().unwrap();
+
+                    // A buffered change replaces the file entry for the same key
+                    if matches!(file_entry, Some((file_key, _)) if file_key == key) {
+                        file_entry = next_from_file()?;
+                    }
+                    if let Some(pos) = change {
+                        f(key, *pos)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_log_offset(&mut self, offset: u64) {
+        self.log_offset = offset;
+    }
+
+    fn persisted_log_offset(&self) -> u64 {
+        self.persisted_log_offset
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.delta.is_empty() {
+            return self.merge();
+        }
+
+        // Nothing to merge, just record how far the log is covered
+        if self.log_offset != self.persisted_log_offset {
+            self.file.append(&encode_footer(self.log_offset, self.file_entries))?;
+            self.file.sync()?;
+            self.persisted_log_offset = self.log_offset;
+        }
+        Ok(())
+    }
+
+    fn rebuild(&self) -> Result<Box<dyn IndexBuilder>> {
+        Ok(Box::new(DiskIndexBuilder::create(self.storage.clone(), self.max_buffered_keys)?))
+    }
+
+    fn invalidate(&mut self) -> Result<()> {
+        invalidate(&*self.storage)
+    }
+
+    // The file stays in use even if it can't be renamed. The next open then
+    // finds either no index or the previous one, which still matches the part
+    // of the log it covers.
+    fn install(&self) {
+        if let Err(err) = self.storage.rename(INDEX_TEMP_FILE, INDEX_FILE) {
+            log::warn!("Failed to replace the index file: {}", err);
+        }
+    }
+}
+
+// Writes a disk index file from entries in ascending key order
+pub(crate) struct DiskIndexBuilder {
+    storage: Arc<dyn Storage>,
+    max_buffered_keys: usize,
+    file: Arc<dyn StorageFile>,
+    writer: BufWriter<FileWriter>,
+    count: u64,
+    sparse: Vec<i64>,
+    last_key: Option<i64>,
+}
+
+impl DiskIndexBuilder {
+    fn create(storage: Arc<dyn Storage>, max_buffered_keys: usize) -> Result<Self> {
+        let file = storage.create(INDEX_TEMP_FILE)?;
+        let writer = BufWriter::new(FileWriter(file.clone()));
+
+        Ok(Self {
+            storage,
+            max_buffered_keys,
+            file,
+            writer,
+            count: 0,
+            sparse: Vec::new(),
+            last_key: None,
+        })
+    }
+
+    // Write out the sparse index and footer, then open the file as an index
+    fn finish_disk(mut self, log_offset: u64) -> Result<DiskIndex> {
+        for &key in &self.sparse {
+            self.writer.write_i64::<LittleEndian>(key)?;
+        }
+        self.writer.write_all(&encode_footer(log_offset, self.count))?;
+        self.writer.flush()?;
+        self.file.sync()?;
+        DiskIndex::from_file(self.storage, self.file, self.max_buffered_keys)
+    }
+}
+
+impl IndexBuilder for DiskIndexBuilder {
+    fn push(&mut self, key: i64, pos: ValuePos) -> Result<()> {
+        // Lookups rely on the entries being sorted and unique
+        if self.last_key.is_some_and(|last| last >= key) {
+            return Err(KvError::InvalidFormat);
+        }
+        self.last_key = Some(key);
+
+        if self.count.is_multiple_of(BLOCK_ENTRIES) {
+            self.sparse.push(key);
+        }
+        self.writer.write_i64::<LittleEndian>(key)?;
+        self.writer.write_u64::<LittleEndian>(pos.offset)?;
+        self.writer.write_u64::<LittleEndian>(pos.size)?;
+        self.writer.write_u8(pos.flags)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, log_offset: u64) -> Result<Box<dyn Index>> {
+        Ok(Box::new(self.finish_disk(log_offset)?))
+    }
+}
+
+fn encode_footer(log_offset: u64, count: u64) -> Vec<u8> {
+    let mut footer = Vec::with_capacity(FOOTER_SIZE as usize);
+    footer.write_u64::<LittleEndian>(log_offset).unwrap();
+    footer.write_u64::<LittleEndian>(count).unwrap();
+    footer.extend_from_slice(MAGIC);
+    footer
+}
+
+fn decode_entry(mut entry: &[u8]) -> Result<(i64, ValuePos)> {
+    let key = entry.read_i64::<LittleEndian>()?;
+    let pos = ValuePos {
+        offset: entry.read_u64::<LittleEndian>()?,
+        size: entry.read_u64::<LittleEndian>()?,
+        flags: entry.read_u8()?,
+    };
+    Ok((key, pos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn pos(n: u64) -> ValuePos {
+        ValuePos { offset: n * 100, size: n, flags: 0 }
+    }
+
+    fn contents(index: &dyn Index) -> Vec<(i64, u64)> {
+        let mut entries = Vec::new();
+        index.for_each(&mut |key, pos| {
+            entries.push((key, pos.size));
+            Ok(())
+        }).unwrap();
+        entries.sort();
+        entries
+    }
+
+    // The behavior every index implementation has to provide
+    fn check_index(kind: IndexKind) {
+        let mut index = open_index(kind, Arc::new(MemoryStorage::new())).unwrap();
+        assert_eq!(index.get(1).unwrap().map(|p| p.size), None);
+
+        // Enough keys to span several blocks and merges
+        for key in -500..500 {
+            index.insert(key, pos(key.unsigned_abs()), None).unwrap();
+        }
+        for key in (-500..500).step_by(3) {
+            let old = index.get(key).unwrap();
+            index.remove(key, old).unwrap();
+        }
+        let old = index.get(7).unwrap();
+        index.insert(7, pos(70), old).unwrap();
+        index.remove(12345, None).unwrap();
+
+        let expected: Vec<_> = (-500..500i64)
+            .filter(|&key| (key + 500) % 3 != 0 || key == 7)
+            .map(|key| (key, if key == 7 { 70 } else { key.unsigned_abs() }))
+            .collect();
+        assert_eq!(index.len(), expected.len());
+        assert_eq!(contents(&*index), expected);
+        for &(key, size) in &expected {
+            assert_eq!(index.get(key).unwrap().map(|p| p.size), Some(size));
+        }
+        assert_eq!(index.get(-500).unwrap().map(|p| p.size), None);
+
+        // Rebuilding from the visited entries gives the same index
+        let mut builder = index.rebuild().unwrap();
+        index.for_each(&mut |key, pos| builder.push(key, pos)).unwrap();
+        let index = builder.finish(0).unwrap();
+        assert_eq!(index.len(), expected.len());
+        assert_eq!(contents(&*index), expected);
+    }
+
+    #[test]
+    fn test_mem_index() {
+        check_index(IndexKind::Memory);
+    }
+
+    #[test]
+    fn test_disk_index() {
+        check_index(IndexKind::Disk { max_buffered_keys: 64 });
+    }
+
+    #[test]
+    fn test_disk_index_persistence() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let kind = IndexKind::Disk { max_buffered_keys: 16 };
+
+        {
+            let mut index = open_index(kind, storage.clone()).unwrap();
+            for key in 0..100 {
+                index.insert(key, pos(key as u64), None).unwrap();
+            }
+            index.set_log_offset(1234);
+            index.flush().unwrap();
+        }
+
+        // Reopening picks up where the index left off
+        let mut index = open_index(kind, storage.clone()).unwrap();
+        assert_eq!(index.persisted_log_offset(), 1234);
+        assert_eq!(index.len(), 100);
+        assert_eq!(index.get(42).unwrap().map(|p| p.size), Some(42));
+
+        // Moving the log offset on without other changes is recorded as well
+        index.set_log_offset(2000);
+        index.flush().unwrap();
+        let index = open_index(kind, storage).unwrap();
+        assert_eq!(index.persisted_log_offset(), 2000);
+        assert_eq!(index.len(), 100);
+    }
+}
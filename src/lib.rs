@@ -1,13 +1,62 @@
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use lru::LruCache;
 use thiserror::Error;
 
+mod async_db;
+mod blob;
+mod bloom;
+mod cache;
+mod compression;
+#[cfg(test)]
+mod crash_tests;
+mod cursor;
+mod encryption;
+mod fsck;
+mod history;
+mod index;
+mod lsm;
+mod merge;
+mod secondary;
+mod stats;
+mod storage;
+mod transfer;
+mod typed;
+
+pub use async_db::{AsyncBlobWriter, AsyncKvDb, AsyncScanner, AsyncValueReader};
+pub use blob::{BlobWriter, ValueReader};
+pub use cache::{CachePolicy, CacheStats};
+pub use compression::Compression;
+pub use cursor::ScanCursor;
+pub use encryption::EncryptionKey;
+pub use fsck::{LogFile, LogRecord, LogStats, RecordKind, Records, RepairReport};
+pub use history::{AsOf, Version, VersionHistory};
+pub use index::IndexKind;
+pub use lsm::LsmOptions;
+pub use merge::{AppendOperator, JsonMergePatchOperator, MergeOperator};
+pub use secondary::SecondaryIndex;
+pub use stats::{DbStats, Operation, OperationStats, LATENCY_BUCKETS};
+pub use storage::{FileStorage, MemoryStorage, Storage, StorageFile};
+pub use transfer::{DataFormat, EntryReader, EntryWriter};
+pub use typed::{BinaryCodec, Codec, JsonCodec, TypedKey, TypedTree};
+
+use blob::{Manifest, PendingBlob};
+use bloom::BloomFilter;
+use cache::{HotKeySaver, ValueCache};
+use encryption::Keyring;
+use history::{History, KeyHistory, VersionEntry};
+use index::{open_index, Index};
+use lsm::LsmTree;
+use secondary::{IndexWriter, SecondaryIndexes};
+use stats::Metrics;
+use storage::{FileReader, FileWriter, PrefixStorage};
+
 // Define the error types for our database operations
 #[derive(Error, Debug)]
 pub enum KvError {
@@ -20,8 +69,62 @@ pub enum KvError {
     #[error("Invalid data format")]
     InvalidFormat,
 
+    #[error("Compression error: {0}")]
+    Compression(String),
+
+    #[error("Corrupted value: {0}")]
+    Corruption(String),
+
+    #[error("Wrong encryption key: data was encrypted with a key that was not supplied")]
+    WrongEncryptionKey,
+
+    #[error("Failed to decrypt value: data is corrupted or was tampered with")]
+    Decryption,
+
     #[error("Database is closed")]
     DbClosed,
+
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(&'static str),
+
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(&'static str),
+
+    #[error("{0} is not supported by this storage engine")]
+    Unsupported(&'static str),
+
+    #[error("Value is not an integer")]
+    NotAnInteger,
+
+    #[error("Integer overflow")]
+    Overflow,
+
+    #[error("Merge failed: {0}")]
+    Merge(String),
+
+    #[error("Invalid column family name: {0:?}")]
+    InvalidColumnFamily(String),
+
+    #[error("Serialization error: {0}")]
+    Serialization(String),
+
+    #[error("Invalid secondary index: {0}")]
+    InvalidIndex(String),
+
+    #[error("History is not kept: Config::max_versions is 1")]
+    HistoryDisabled,
+
+    #[error("The version asked for is no longer retained")]
+    VersionNotRetained,
+
+    #[error("Invalid import data: {0}")]
+    Import(String),
+
+    #[error("Bulk loading needs an empty database")]
+    NotEmpty,
+
+    #[error("Corrupted data file at offset {offset}: {reason}")]
+    Corrupted { offset: u64, reason: String },
 }
 
 pub type Result<T> = std::result::Result<T, KvError>;
@@ -31,6 +134,12 @@ pub type Result<T> = std::result::Result<T, KvError>;
 enum OpType {
     Set = 0,
     Remove = 1,
+    // A piece of a large value, referenced by the manifest in a later Set record
+    Chunk = 2,
+    // An operand for the merge operator, linked to the previous record for its key
+    Merge = 3,
+    // The version number and time of the records that follow, up to the next one
+    Version = 4,
 }
 
 impl OpType {
@@ -38,399 +147,2264 @@ impl OpType {
         match value {
             0 => Ok(OpType::Set),
             1 => Ok(OpType::Remove),
+            2 => Ok(OpType::Chunk),
+            3 => Ok(OpType::Merge),
+            4 => Ok(OpType::Version),
             _ => Err(KvError::InvalidFormat),
         }
     }
 }
 
+// The first byte of every record holds the operation type in its low bits
+// and a set of flags describing how the value is stored in its high bits
+const OP_TYPE_MASK: u8 = 0x0F;
+
+// The value payload is compressed (see the compression module)
+const FLAG_COMPRESSED: u8 = 0x10;
+
+// The value payload is encrypted (see the encryption module).
+// Encryption is applied after compression.
+const FLAG_ENCRYPTED: u8 = 0x20;
+
+// The value payload is a manifest listing the chunks of a large value
+// (see the blob module). The chunks carry their own flags.
+const FLAG_CHUNKED: u8 = 0x40;
+
+// All flags understood by this version of the database
+const KNOWN_FLAGS: u8 = FLAG_COMPRESSED | FLAG_ENCRYPTED | FLAG_CHUNKED;
+
+// Marks the position of a merge operand in the index. It isn't stored in
+// record headers, which have the Merge operation type instead.
+const FLAG_MERGE: u8 = 0x80;
+
+// On a Version record, which has no payload: the versions of the key of the next
+// record from before this one were discarded
+const FLAG_TRUNCATED: u8 = 0x10;
+
+// A Version record holds the version number and the time it was written, in
+// milliseconds since the Unix epoch
+const VERSION_RECORD_SIZE: u64 = 1 + 8 + 8;
+
+// The link from a Merge record to the previous record for its key:
+// the offset, size and flags of its value, or zeros if there is none
+const MERGE_LINK_SIZE: u64 = 8 + 8 + 1;
+
+// The number of entries `KvDb::import` sets at a time
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+// The most entries, and bytes of them, `KvDb::export` reads at a time
+const EXPORT_BATCH_SIZE: usize = 1000;
+const EXPORT_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+// Split a record header byte into its operation type and flags
+fn parse_header(header: u8) -> Result<(OpType, u8)> {
+    let flags = header & !OP_TYPE_MASK;
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(KvError::InvalidFormat);
+    }
+    Ok((OpType::from_u8(header & OP_TYPE_MASK)?, flags))
+}
+
 // Represents the position of a value in the data file
 #[derive(Debug, Clone, Copy)]
 struct ValuePos {
     offset: u64,
     size: u64,
+    // The record flags, needed to decode the stored payload
+    flags: u8,
+}
+
+// Read the payload at the given position
+fn read_payload(file: &dyn StorageFile, pos: &ValuePos) -> Result<Vec<u8>> {
+    let mut payload = vec![0; pos.size as usize];
+    file.read_at(pos.offset, &mut payload)?;
+    Ok(payload)
+}
+
+// Write a record with a payload (a Set or a Chunk)
+fn write_record(writer: &mut impl Write, header: u8, key: i64, payload: &[u8]) -> io::Result<()> {
+    // Write the operation type and flags
+    writer.write_u8(header)?;
+    
+    // Write the key
+    writer.write_i64::<LittleEndian>(key)?;
+    
+    // Write the payload size
+    writer.write_u64::<LittleEndian>(payload.len() as u64)?;
+    
+    // Write the payload
+    writer.write_all(payload)
+}
+
+// Write a Version record for the records that follow it
+fn write_version_record(writer: &mut impl Write, version: u64, timestamp: u64, truncated: bool) -> io::Result<()> {
+    writer.write_u8(OpType::Version as u8 | if truncated { FLAG_TRUNCATED } else { 0 })?;
+    writer.write_u64::<LittleEndian>(version)?;
+    writer.write_u64::<LittleEndian>(timestamp)
+}
+
+// Write a Merge record, linking the operand to the previous value position of its key
+fn write_merge_record(writer: &mut impl Write, flags: u8, key: i64, previous: Option<ValuePos>, payload: &[u8]) -> io::Result<()> {
+    writer.write_u8(OpType::Merge as u8 | flags)?;
+    writer.write_i64::<LittleEndian>(key)?;
+    
+    // Write the link
+    let previous = previous.unwrap_or(ValuePos { offset: 0, size: 0, flags: 0 });
+    writer.write_u64::<LittleEndian>(previous.offset)?;
+    writer.write_u64::<LittleEndian>(previous.size)?;
+    writer.write_u8(previous.flags)?;
+    
+    writer.write_u64::<LittleEndian>(payload.len() as u64)?;
+    writer.write_all(payload)
+}
+
+// Read the link of the Merge record whose operand is at the given position
+fn read_merge_link(file: &dyn StorageFile, pos: &ValuePos) -> Result<Option<ValuePos>> {
+    let mut link = [0; MERGE_LINK_SIZE as usize];
+    file.read_at(pos.offset - 8 - MERGE_LINK_SIZE, &mut link)?;
+    let mut link = &link[..];
+    let previous = ValuePos {
+        offset: link.read_u64::<LittleEndian>()?,
+        size: link.read_u64::<LittleEndian>()?,
+        flags: link.read_u8()?,
+    };
+    // No value starts at the beginning of the file
+    Ok((previous.offset != 0).then_some(previous))
 }
 
-// Our in-memory index maps keys to their value positions
-type MemIndex = HashMap<i64, Option<ValuePos>>;
+// The storage engine behind the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EngineKind {
+    // A single append-only log with an index of every key (see the index module).
+    // Supports streaming large values in chunks.
+    #[default]
+    Log,
 
-// The maximum size of our cache in bytes (16MB)
-const MAX_CACHE_SIZE: usize = 16 * 1024 * 1024;
+    // A log-structured merge tree (see the lsm module). Keys don't have to fit
+    // in memory and are stored in order. Large values are stored inline.
+    Lsm(LsmOptions),
+}
 
 // Configuration for the database
 #[derive(Debug, Clone)]
 pub struct Config {
     // The path to the database files
     pub path: PathBuf,
+
+    // Where the database files are kept. Defaults to a `FileStorage` in `path`;
+    // a `MemoryStorage` keeps the whole database in memory.
+    pub storage: Option<Arc<dyn Storage>>,
     
     // The threshold size in bytes to trigger garbage collection
     pub gc_threshold: u64,
+
+    // The compression applied to newly written values.
+    // Records written with a different setting remain readable.
+    pub compression: Compression,
+
+    // The key used to encrypt newly written values, if any.
+    // Keys themselves are stored in plaintext.
+    pub encryption_key: Option<EncryptionKey>,
+
+    // Keys that older records may still be encrypted with.
+    // Garbage collection re-encrypts such records with `encryption_key`.
+    pub previous_encryption_keys: Vec<EncryptionKey>,
+
+    // Where the index of keys is kept (see the index module)
+    pub index: IndexKind,
+
+    // Values larger than this many bytes are stored as a sequence of chunks
+    // of this size, so they can be written and read without holding them in memory
+    pub chunk_size: usize,
+
+    // The storage engine; a database has to be reopened with the engine it was created with
+    pub engine: EngineKind,
+
+    // The most memory the cache of recently used values may take up, counting
+    // the keys and the values' contents. Zero disables the cache.
+    pub cache_capacity_bytes: usize,
+
+    // How the cache chooses which values to evict
+    pub cache_policy: CachePolicy,
+
+    // The number of keys found to be absent that are remembered, so that
+    // repeated lookups of missing keys are answered from memory. Zero disables this.
+    pub negative_cache_keys: usize,
+
+    // How often the keys of the cached values are saved, so that the cache can be
+    // warmed up after a restart. They are also saved on close. None disables this.
+    pub hot_keys_interval: Option<Duration>,
+
+    // Prefetch the values of the saved hot keys before `open` returns. To serve
+    // requests while the cache warms up, call `KvDb::warm_cache` from another thread instead.
+    pub warm_cache_on_open: bool,
+
+    // Sync every write to disk before acknowledging it, so that acknowledged
+    // writes survive a crash. Writes are much slower with this turned on.
+    pub sync_writes: bool,
+
+    // The number of versions of each key to keep, including the current one, for
+    // `KvDb::get_at` and `KvDb::history`. Older versions are discarded by garbage
+    // collection. With the default of 1 no history is kept. Only the log-structured
+    // engine with the in-memory index keeps history.
+    pub max_versions: usize,
+    
+    // How long a version is kept once it has been replaced. Garbage collection
+    // drops older versions, and the whole history of a key removed longer ago,
+    // even if `max_versions` would keep them. With None, removed keys keep their
+    // history for good.
+    pub version_retention: Option<Duration>,
+
+    // Combines the operands written by `KvDb::merge` with the values of their keys.
+    // A database with merge records has to be opened with the same operator to read them.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+
+    // Settings for column families opened with `KvDb::cf`, by name. Column families
+    // that aren't listed use the settings above.
+    pub column_families: HashMap<String, ColumnFamilyOptions>,
+
+    // Secondary indexes on fields of JSON values, kept up to date by writes and
+    // queried with `KvDb::query_by_index`. They are held in memory and built from
+    // the values when the database is opened.
+    pub secondary_indexes: Vec<SecondaryIndex>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             path: PathBuf::from("db"),
+            storage: None,
             gc_threshold: 1024 * 1024 * 100, // 100MB
+            compression: Compression::None,
+            encryption_key: None,
+            previous_encryption_keys: Vec::new(),
+            index: IndexKind::Memory,
+            chunk_size: 1024 * 1024, // 1MB
+            engine: EngineKind::Log,
+            cache_capacity_bytes: 16 * 1024 * 1024, // 16MB
+            cache_policy: CachePolicy::Lru,
+            negative_cache_keys: 0,
+            hot_keys_interval: None,
+            warm_cache_on_open: false,
+            sync_writes: false,
+            max_versions: 1,
+            version_retention: Some(Duration::from_secs(7 * 24 * 60 * 60)), // 1 week
+            merge_operator: None,
+            column_families: HashMap::new(),
+            secondary_indexes: Vec::new(),
+        }
+    }
+}
+
+// The settings a column family can have of its own. The others, like compression
+// and encryption, are shared with the database it belongs to.
+#[derive(Debug, Clone)]
+pub struct ColumnFamilyOptions {
+    pub index: IndexKind,
+    pub engine: EngineKind,
+    pub gc_threshold: u64,
+    pub cache_capacity_bytes: usize,
+    pub cache_policy: CachePolicy,
+}
+
+impl Default for ColumnFamilyOptions {
+    fn default() -> Self {
+        let config = Config::default();
+        Self {
+            index: config.index,
+            engine: config.engine,
+            gc_threshold: config.gc_threshold,
+            cache_capacity_bytes: config.cache_capacity_bytes,
+            cache_policy: config.cache_policy,
         }
     }
 }
 
+// The files of a column family are kept next to those of the default one,
+// with this prefix and the name of the column family in front of their names
+const COLUMN_FAMILY_PREFIX: &str = "cf.";
+
+// Whether a name can be used for a column family, and so in file names
+fn is_valid_column_family(name: &str) -> bool {
+    (1..=64).contains(&name.len()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// The state of the log-structured engine
+struct LogStore {
+    file: Arc<Mutex<Arc<dyn StorageFile>>>,
+    // The index maps live keys to their value positions.
+    // Removed keys are dropped from the index right away: the Remove record in the
+    // log keeps them deleted across restarts until garbage collection rewrites the
+    // file without the older values, at which point the tombstone isn't needed either.
+    index: Arc<RwLock<Box<dyn Index>>>,
+    // Holds every key in the index, and possibly some removed ones.
+    // Lookups of keys it rules out don't have to touch the index.
+    filter: Arc<RwLock<BloomFilter>>,
+    file_size: Arc<Mutex<u64>>,
+    // The chunks of each blob writer whose value isn't committed yet, by writer.
+    // Locked after the index and the file.
+    pending_blobs: Mutex<HashMap<u64, PendingBlob>>,
+    next_blob_writer: AtomicU64,
+    // The retained versions of every key, if history is kept. Locked after the
+    // index and the file.
+    history: Option<Mutex<History>>,
+    // The bytes of the records the indexed values are made of, for `KvDb::stats`.
+    // Counted when the database is opened, and kept up to date while holding the
+    // index lock by every write to it.
+    live_bytes: AtomicU64,
+}
+
+enum Engine {
+    Log(LogStore),
+    Lsm(RwLock<LsmTree>),
+}
+
 // The main database structure
 pub struct KvDb {
     config: Config,
-    file: Arc<Mutex<File>>,
-    index: Arc<RwLock<MemIndex>>,
-    // LRU cache using our keys as i64 and values as strings
-    // LRU eviction policy is used to keep the most frequently accessed items
-    cache: Arc<Mutex<LruCache<i64, String>>>,
-    file_size: Arc<Mutex<u64>>,
+    keyring: Keyring,
+    storage: Arc<dyn Storage>,
+    engine: Engine,
+    // Cache of recently used values, with the configured eviction policy
+    cache: Arc<Mutex<ValueCache>>,
+    // Saves the hot keys in the background, if configured
+    hot_key_saver: Mutex<Option<HotKeySaver>>,
+    // The column families opened so far, by name
+    column_families: RwLock<HashMap<String, Arc<KvDb>>>,
+    // The secondary indexes on fields of JSON values, as configured
+    secondary_indexes: RwLock<SecondaryIndexes>,
+    // Operation counts and latencies, and other counters for `stats`
+    metrics: Metrics,
     closed: Arc<RwLock<bool>>,
 }
 
 impl KvDb {
     pub fn open(config: Config) -> Result<Self> {
-        // Create the database directory if it doesn't exist
-        std::fs::create_dir_all(&config.path)?;
+        // Every chunk of a large value has to hold some of it
+        if config.chunk_size == 0 {
+            return Err(KvError::InvalidConfig("Config::chunk_size must be at least 1"));
+        }
         
-        let data_path = config.path.join("data.db");
+        // A disk index merges its buffered changes into its file once it holds this many
+        if matches!(config.index, IndexKind::Disk { max_buffered_keys: 0 }) {
+            return Err(KvError::InvalidConfig("IndexKind::Disk::max_buffered_keys must be at least 1"));
+        }
         
-        // Open the data file, create it if it doesn't exist
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&data_path)?;
+        // Use files in the database directory unless told otherwise
+        let storage: Arc<dyn Storage> = match &config.storage {
+            Some(storage) => storage.clone(),
+            None => Arc::new(FileStorage::new(&config.path)?),
+        };
         
-        // Get the current size of the file
-        let file_size = file.metadata()?.len();
+        let secondary_indexes = SecondaryIndexes::new(&config.secondary_indexes)?;
+        
+        // Set up the encryption keys
+        let keyring = Keyring::new(config.encryption_key.as_ref(), &config.previous_encryption_keys);
+        
+        let engine = match config.engine {
+            // The history is rebuilt from the whole log on open, which would undo
+            // what the disk index saves in memory and time
+            EngineKind::Log if config.max_versions > 1 && matches!(config.index, IndexKind::Disk { .. }) => {
+                return Err(KvError::Unsupported("Multi-version storage with the disk index"))
+            }
+            EngineKind::Log => Engine::Log(LogStore::open(&storage, config.index, config.max_versions, config.version_retention)?),
+            EngineKind::Lsm(_) if config.max_versions > 1 => return Err(KvError::Unsupported("Multi-version storage")),
+            EngineKind::Lsm(options) => Engine::Lsm(RwLock::new(LsmTree::open(storage.clone(), options, config.sync_writes)?)),
+        };
         
-        // Create an empty index
-        let index = MemIndex::new();
+        let cache = Arc::new(Mutex::new(ValueCache::new(
+            config.cache_policy,
+            config.cache_capacity_bytes,
+            config.negative_cache_keys,
+        )));
+        let hot_key_saver = config
+            .hot_keys_interval
+            .map(|interval| HotKeySaver::start(storage.clone(), cache.clone(), interval));
         
         // Create a new database instance
-        let mut db = Self {
+        let db = Self {
             config,
-            file: Arc::new(Mutex::new(file)),
-            index: Arc::new(RwLock::new(index)),
-            // Initialize the cache with a maximum size based on bytes
-            cache: Arc::new(Mutex::new(LruCache::unbounded())),
-            file_size: Arc::new(Mutex::new(file_size)),
+            keyring,
+            storage,
+            engine,
+            cache,
+            hot_key_saver: Mutex::new(hot_key_saver),
+            column_families: RwLock::new(HashMap::new()),
+            secondary_indexes: RwLock::new(secondary_indexes),
+            metrics: Metrics::default(),
             closed: Arc::new(RwLock::new(false)),
         };
         
         // Load the index from the data file
-        db.load_index()?;
+        if let Engine::Log(log) = &db.engine {
+            db.load_index(log)?;
+            log.live_bytes.store(db.count_live_bytes(log)?, Ordering::Relaxed);
+        }
+        
+        // The secondary indexes aren't persisted, so build them from the values
+        if !db.config.secondary_indexes.is_empty() {
+            db.build_secondary_indexes()?;
+        }
+        
+        if db.config.warm_cache_on_open {
+            db.warm_cache()?;
+        }
         
         Ok(db)
     }
     
-    // Load the index by reading through the entire data file
-    fn load_index(&mut self) -> Result<()> {
-        let mut file = self.file.lock().unwrap();
-        let file_size = file.metadata()?.len();
+    // The log-structured engine, for operations only it supports
+    fn log_store(&self, operation: &'static str) -> Result<&LogStore> {
+        match &self.engine {
+            Engine::Log(log) => Ok(log),
+            Engine::Lsm(_) => Err(KvError::Unsupported(operation)),
+        }
+    }
+    
+    // Load the index by reading through the data file.
+    // Records already covered by a persisted index are skipped.
+    fn load_index(&self, log: &LogStore) -> Result<()> {
+        let file = log.file.lock().unwrap();
+        let file_size = file.size()?;
         
-        if file_size == 0 {
-            return Ok(());
+        // A persisted index that is ahead of the file doesn't belong to it
+        let mut offset = log.index.read().unwrap().persisted_log_offset();
+        if offset > file_size {
+            let mut index = log.index.write().unwrap();
+            *index = index.rebuild()?.finish(0)?;
+            index.install();
+            *log.filter.write().unwrap() = filter_from_index(&**index)?;
+            offset = 0;
+        }
+        
+        // The history of every key is only in the log, so it is read in full
+        if log.history.is_some() {
+            offset = 0;
         }
         
-        file.seek(SeekFrom::Start(0))?;
-        let mut reader = BufReader::new(&mut *file);
-        let mut offset = 0;
+        let mut reader = BufReader::new(FileReader::new(file.clone(), offset));
         
-        // Read through the file and build the index
+        // The version of the records being read, and whether older versions of
+        // the key of the next one were discarded. Records written before history
+        // was kept count as version 0.
+        let mut version = (0, 0);
+        let mut truncated = false;
+        
+        // Read through the file and build the index.
+        // A record cut short by a crash ends the loop early.
         while offset < file_size {
-            let op_type = OpType::from_u8(reader.read_u8()?)?;
+            let (op_type, flags) = parse_header(reader.read_u8()?)?;
+            let header_size = match op_type {
+                OpType::Remove => 1 + 8,
+                OpType::Merge => 1 + 8 + MERGE_LINK_SIZE + 8,
+                OpType::Set | OpType::Chunk => 1 + 8 + 8,
+                OpType::Version => VERSION_RECORD_SIZE,
+            };
+            if file_size - offset < header_size {
+                break;
+            }
+            if op_type == OpType::Version {
+                version = (reader.read_u64::<LittleEndian>()?, reader.read_u64::<LittleEndian>()?);
+                truncated = flags & FLAG_TRUNCATED != 0;
+                offset += VERSION_RECORD_SIZE;
+                continue;
+            }
             let key = reader.read_i64::<LittleEndian>()?;
             
             match op_type {
-                OpType::Set => {
+                OpType::Set | OpType::Chunk | OpType::Merge => {
+                    // The link of a Merge record was the position in the index
+                    // when it was written, which replaying gets back to as well
+                    if op_type == OpType::Merge {
+                        reader.seek(SeekFrom::Current(MERGE_LINK_SIZE as i64))?;
+                    }
                     let value_size = reader.read_u64::<LittleEndian>()?;
+                    if file_size - offset - header_size < value_size {
+                        break;
+                    }
                     let value_pos = ValuePos {
-                        offset: offset + 1 + 8 + 8, // op_type + key + value_size
+                        offset: offset + header_size,
                         size: value_size,
+                        flags: if op_type == OpType::Merge { flags | FLAG_MERGE } else { flags },
                     };
                     
-                    // Skip over the value content
-                    reader.seek(SeekFrom::Current(value_size as i64))?;
+                    if flags & FLAG_ENCRYPTED != 0 {
+                        // Make sure we hold the key this value was encrypted with,
+                        // so a wrong key is reported at open rather than on read
+                        let mut key_id = [0; encryption::KEY_ID_LEN];
+                        if value_size < key_id.len() as u64 {
+                            return Err(KvError::InvalidFormat);
+                        }
+                        reader.read_exact(&mut key_id)?;
+                        self.keyring.check_key_id(&key_id)?;
+                        
+                        // Skip over the rest of the value content
+                        reader.seek(SeekFrom::Current((value_size - key_id.len() as u64) as i64))?;
+                    } else {
+                        // Skip over the value content
+                        reader.seek(SeekFrom::Current(value_size as i64))?;
+                    }
                     
-                    // Update the index
-                    let mut index = self.index.write().unwrap();
-                    index.insert(key, Some(value_pos));
+                    // Update the index; chunks are only reachable through their manifest
+                    if op_type != OpType::Chunk {
+                        let mut index = log.index.write().unwrap();
+                        let old_pos = index.get(key)?;
+                        index.insert(key, value_pos, old_pos)?;
+                        log.add_to_filter(&**index, key)?;
+                        log.record_version(key, version, std::mem::take(&mut truncated), Some(value_pos));
+                    }
                     
-                    offset += 1 + 8 + 8 + value_size; // op_type + key + value_size + value
+                    offset += header_size + value_size;
                 },
                 OpType::Remove => {
-                    // Mark the key as removed in the index
-                    let mut index = self.index.write().unwrap();
-                    index.insert(key, None);
+                    // Drop the key from the index
+                    let mut index = log.index.write().unwrap();
+                    let old_pos = index.get(key)?;
+                    index.remove(key, old_pos)?;
+                    log.record_version(key, version, std::mem::take(&mut truncated), None);
                     
                     offset += 1 + 8; // op_type + key
                 }
+                OpType::Version => unreachable!("Version records are read above"),
             }
         }
         
+        // Discard the torn record, so new records don't end up behind it
+        if offset < file_size {
+            log::warn!("Discarding a torn record at the end of the data file");
+            file.truncate(offset)?;
+            *log.file_size.lock().unwrap() = offset;
+        }
+        
+        log.index.write().unwrap().set_log_offset(offset);
+        
         Ok(())
     }
     
     // Set a key-value pair in the database
     pub fn set(&self, key: i64, value: &str) -> Result<Option<String>> {
-        // Check if the database is closed
-        if *self.closed.read().unwrap() {
-            return Err(KvError::DbClosed);
+        self.metrics.track(Operation::Set, || {
+            // Check if the database is closed
+            if *self.closed.read().unwrap() {
+                return Err(KvError::DbClosed);
+            }
+            
+            // Get the old value for the key, if it exists
+            let old_value = self.old_value(key)?;
+            self.write_value(key, value.as_bytes())?;
+            Ok(old_value)
+        })
+    }
+    
+    // Set a value that doesn't have to be UTF-8
+    pub fn set_bytes(&self, key: i64, value: &[u8]) -> Result<()> {
+        self.metrics.track(Operation::Set, || {
+            // Check if the database is closed
+            if *self.closed.read().unwrap() {
+                return Err(KvError::DbClosed);
+            }
+            
+            self.write_value(key, value)
+        })
+    }
+    
+    // Write a value for a key, caching it if it is valid UTF-8
+    fn write_value(&self, key: i64, value: &[u8]) -> Result<()> {
+        let mut indexes = self.lock_secondary_indexes();
+        if let Engine::Lsm(tree) = &self.engine {
+            let (flags, value_bytes) = self.encode_value(key, value)?;
+            
+            // Update the cache while holding the tree lock, in the same order as readers
+            let mut tree = tree.write().unwrap();
+            tree.write(key, Some((flags, value_bytes)))?;
+            self.cache.lock().unwrap().put_bytes(key, value);
+            indexes.update(key, Some(value));
+            return Ok(());
         }
         
-        // Get the old value for the key, if it exists
-        let old_value = self.get(key)?;
-        
-        // Write the new key-value pair to the file
-        let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::End(0))?;
-        
-        // Write the operation type (Set)
-        file.write_u8(OpType::Set as u8)?;
-        
-        // Write the key
-        file.write_i64::<LittleEndian>(key)?;
-        
-        // Write the value size
-        let value_bytes = value.as_bytes();
-        file.write_u64::<LittleEndian>(value_bytes.len() as u64)?;
-        
-        // Write the value
-        file.write_all(value_bytes)?;
-        file.flush()?;
-        
-        // Update the file size
-        let offset = *self.file_size.lock().unwrap();
-        let value_pos = ValuePos {
-            offset: offset + 1 + 8 + 8, // op_type + key + value_size
-            size: value_bytes.len() as u64,
-        };
-        
-        // Update the file size
-        let new_size = offset + 1 + 8 + 8 + value_bytes.len() as u64;
-        *self.file_size.lock().unwrap() = new_size;
-        
-        // Update the index
-        let mut index = self.index.write().unwrap();
-        index.insert(key, Some(value_pos));
+        // Store large values as a sequence of chunks
+        if value.len() > self.config.chunk_size {
+            let mut writer = self.blob_writer(key)?;
+            writer.write_all(value)?;
+            writer.commit()?;
+            indexes.update(key, Some(value));
+            return Ok(());
+        }
         
-        // Update the cache
-        let mut cache = self.cache.lock().unwrap();
-        self.manage_cache_size(&mut cache, key, value);
-        cache.put(key, value.to_string());
+        // Compress and encrypt the value as configured
+        let (flags, value_bytes) = self.encode_value(key, value)?;
         
-        // Check if we need to do garbage collection
-        if new_size > self.config.gc_threshold {
-            drop(file);
-            drop(index);
-            drop(cache);
-            self.garbage_collect()?;
+        // Write the value and cache it
+        self.commit_value(key, flags, &value_bytes, std::str::from_utf8(value).ok())?;
+        indexes.update(key, Some(value));
+        Ok(())
+    }
+    
+    // Take the secondary indexes for a write, if there are any
+    pub(crate) fn lock_secondary_indexes(&self) -> IndexWriter<'_> {
+        if self.config.secondary_indexes.is_empty() {
+            IndexWriter(None)
+        } else {
+            IndexWriter(Some(self.secondary_indexes.write().unwrap()))
         }
-        
-        Ok(old_value)
     }
     
-    // Get a value from the database
-    pub fn get(&self, key: i64) -> Result<Option<String>> {
+    // The value a write replaces. A chain of merges that can't be resolved is
+    // replaced all the same, as if there was no value.
+    fn old_value(&self, key: i64) -> Result<Option<String>> {
+        match self.get(key) {
+            Err(KvError::Merge(_)) => Ok(None),
+            result => result,
+        }
+    }
+    
+    // Set many key-value pairs in order, taking each lock once, and return the values
+    // they replaced as of before the call. The writes are not atomic: a failure or a
+    // crash may leave some of them done.
+    pub fn set_many(&self, entries: &[(i64, &str)]) -> Result<Vec<Option<String>>> {
+        self.metrics.track(Operation::SetMany, || self.write_many(entries))
+    }
+    
+    // Does the work of `set_many`, which tracks it in the stats
+    fn write_many(&self, entries: &[(i64, &str)]) -> Result<Vec<Option<String>>> {
         // Check if the database is closed
         if *self.closed.read().unwrap() {
             return Err(KvError::DbClosed);
         }
         
-        // First check the cache
-        {
+        let keys: Vec<i64> = entries.iter().map(|&(key, _)| key).collect();
+        let old_values = match self.get_many(&keys) {
+            // Find out which keys the failed merges are for
+            Err(KvError::Merge(_)) => keys.iter().map(|&key| self.old_value(key)).collect::<Result<_>>()?,
+            result => result?,
+        };
+        
+        let mut indexes = self.lock_secondary_indexes();
+        if let Engine::Lsm(tree) = &self.engine {
+            let mut batch = Vec::with_capacity(entries.len());
+            for &(key, value) in entries {
+                batch.push((key, Some(self.encode_value(key, value.as_bytes())?)));
+            }
+            
+            // Update the cache while holding the tree lock, in the same order as readers
+            let mut tree = tree.write().unwrap();
+            tree.write_batch(batch)?;
             let mut cache = self.cache.lock().unwrap();
-            if let Some(value) = cache.get(&key) {
-                return Ok(Some(value.clone()));
+            for &(key, value) in entries {
+                cache.put(key, value);
+                indexes.update(key, Some(value.as_bytes()));
             }
+            return Ok(old_values);
         }
         
-        // If not in cache, check the index
-        let index = self.index.read().unwrap();
+        // Write runs of values in one go, and large values on their own in between
+        let mut pending = Vec::new();
+        for &(key, value) in entries {
+            if value.len() <= self.config.chunk_size {
+                let (flags, value_bytes) = self.encode_value(key, value.as_bytes())?;
+                pending.push((key, flags, value_bytes, value));
+                continue;
+            }
+            self.commit_pending(&mut pending, &mut indexes)?;
+            let mut writer = self.blob_writer(key)?;
+            writer.write_all(value.as_bytes())?;
+            writer.commit()?;
+            indexes.update(key, Some(value.as_bytes()));
+        }
+        self.commit_pending(&mut pending, &mut indexes)?;
         
-        match index.get(&key) {
-            Some(Some(pos)) => {
-                // Read the value from the file
-                let mut file = self.file.lock().unwrap();
-                file.seek(SeekFrom::Start(pos.offset))?;
-                
-                let mut value_bytes = vec![0; pos.size as usize];
-                file.read_exact(&mut value_bytes)?;
-                
-                let value = String::from_utf8_lossy(&value_bytes).to_string();
-                
-                // Update the cache
-                let mut cache = self.cache.lock().unwrap();
-                self.manage_cache_size(&mut cache, key, &value);
-                cache.put(key, value.clone());
-                
-                Ok(Some(value))
-            },
-            Some(None) => Ok(None), // Key was removed
-            None => Ok(None), // Key doesn't exist
+        Ok(old_values)
+    }
+    
+    // Write, cache and index the values collected by `set_many`
+    fn commit_pending(&self, pending: &mut Vec<(i64, u8, Vec<u8>, &str)>, indexes: &mut IndexWriter) -> Result<()> {
+        if pending.is_empty() {
+            return Ok(());
         }
+        let values: Vec<_> = pending
+            .iter()
+            .map(|(key, flags, value_bytes, value)| (*key, *flags, &value_bytes[..], Some(*value)))
+            .collect();
+        self.commit_values(&values)?;
+        for (key, _, _, value) in pending.drain(..) {
+            indexes.update(key, Some(value.as_bytes()));
+        }
+        Ok(())
     }
     
-    // Remove a key from the database
-    pub fn remove(&self, key: i64) -> Result<Option<String>> {
+    // Atomically add `delta` to the integer stored for a key, returning the new value.
+    // A missing key counts as zero. The value is left unchanged, and an error returned,
+    // if it isn't a decimal integer or the result would overflow an i64.
+    pub fn incr(&self, key: i64, delta: i64) -> Result<i64> {
+        self.metrics.track(Operation::Incr, || self.increment(key, delta))
+    }
+    
+    // Does the work of `incr`, which tracks it in the stats
+    fn increment(&self, key: i64, delta: i64) -> Result<i64> {
         // Check if the database is closed
         if *self.closed.read().unwrap() {
             return Err(KvError::DbClosed);
         }
         
-        // Get the old value for the key, if it exists
-        let old_value = match self.get(key)? {
-            Some(val) => {
-                // Store a copy of the old value to return later
-                let old_val = Some(val);
-                
-                // Write the removal operation to the file
-                let mut file = self.file.lock().unwrap();
-                file.seek(SeekFrom::End(0))?;
-                
-                // Write the operation type (Remove)
-                file.write_u8(OpType::Remove as u8)?;
-                
-                // Write the key
-                file.write_i64::<LittleEndian>(key)?;
-                file.flush()?;
-                
-                // Update the file size
-                let offset = *self.file_size.lock().unwrap();
-                *self.file_size.lock().unwrap() = offset + 1 + 8; // op_type + key
-                
-                // Update the index
-                let mut index = self.index.write().unwrap();
-                index.insert(key, None);
-                
-                // Remove from the cache
-                let mut cache = self.cache.lock().unwrap();
-                cache.pop(&key);
-                
-                // Check if we need to do garbage collection
-                if *self.file_size.lock().unwrap() > self.config.gc_threshold {
-                    drop(file);
-                    drop(index);
-                    drop(cache);
-                    self.garbage_collect()?;
-                }
+        // The current value is read and the new one written while holding the
+        // lock every writer takes, so concurrent updates can't be lost
+        let mut indexes = self.lock_secondary_indexes();
+        let log = match &self.engine {
+            Engine::Log(log) => log,
+            Engine::Lsm(tree) => {
+                let mut tree = tree.write().unwrap();
+                let current = match tree.get(key)? {
+                    Some((flags, payload)) => Some(self.decode_value(key, flags, payload)?),
+                    None => None,
+                };
+                let counter = add_to_counter(current.as_deref(), delta)?;
+                let value = counter.to_string();
                 
-                old_val
-            },
+                let (flags, value_bytes) = self.encode_value(key, value.as_bytes())?;
+                tree.write(key, Some((flags, value_bytes)))?;
+                self.cache.lock().unwrap().put(key, &value);
+                indexes.update(key, Some(value.as_bytes()));
+                return Ok(counter);
+            }
+        };
+        
+        let mut index = log.index.write().unwrap();
+        let current = match index.get(key)? {
+            Some(pos) => Some(self.read_value(&**log.file.lock().unwrap(), key, &pos)?),
             None => None,
         };
+        let counter = add_to_counter(current.as_deref(), delta)?;
+        let value = counter.to_string();
         
-        Ok(old_value)
+        let (flags, value_bytes) = self.encode_value(key, value.as_bytes())?;
+        let new_size = self.append_values(log, &mut **index, &[(key, flags, &value_bytes, Some(&value))])?;
+        drop(index);
+        indexes.update(key, Some(value.as_bytes()));
+        drop(indexes);
+        
+        // Check if we need to do garbage collection
+        if new_size > self.config.gc_threshold {
+            self.garbage_collect()?;
+        }
+        
+        Ok(counter)
     }
     
-    // Close the database
-    pub fn close(&self) -> Result<()> {
-        let mut closed = self.closed.write().unwrap();
-        *closed = true;
-        Ok(())
+    // Atomically subtract `delta` from the integer stored for a key, as `incr` does
+    pub fn decr(&self, key: i64, delta: i64) -> Result<i64> {
+        self.incr(key, delta.checked_neg().ok_or(KvError::Overflow)?)
     }
     
-    // Manage the cache size to ensure it doesn't exceed MAX_CACHE_SIZE
-    fn manage_cache_size(&self, cache: &mut LruCache<i64, String>, key: i64, value: &str) {
-        // If the cache already has this key, remove it first to recalculate
-        if cache.contains(&key) {
-            cache.pop(&key);
+    // Record an operand for the configured merge operator to combine with the value
+    // of a key, without reading the value. The log-structured engine appends a Merge
+    // record that is resolved on reads and by garbage collection; the LSM engine
+    // applies the operand right away.
+    pub fn merge(&self, key: i64, operand: &str) -> Result<()> {
+        self.metrics.track(Operation::Merge, || self.write_operand(key, operand))
+    }
+    
+    // Does the work of `merge`, which tracks it in the stats
+    fn write_operand(&self, key: i64, operand: &str) -> Result<()> {
+        // Check if the database is closed
+        if *self.closed.read().unwrap() {
+            return Err(KvError::DbClosed);
         }
         
-        // Calculate size of the new entry (key size + value size)
-        let new_entry_size = std::mem::size_of::<i64>() + value.len();
+        let operator = self.merge_operator()?;
+        let mut indexes = self.lock_secondary_indexes();
+        let log = match &self.engine {
+            Engine::Log(log) => log,
+            Engine::Lsm(tree) => {
+                let mut tree = tree.write().unwrap();
+                let existing = match tree.get(key)? {
+                    Some((flags, payload)) => Some(String::from_utf8_lossy(&self.decode_value(key, flags, payload)?).to_string()),
+                    None => None,
+                };
+                let value = operator.merge(key, existing.as_deref(), &[operand]).map_err(KvError::Merge)?;
+                
+                let (flags, value_bytes) = self.encode_value(key, value.as_bytes())?;
+                tree.write(key, Some((flags, value_bytes)))?;
+                self.cache.lock().unwrap().put(key, &value);
+                indexes.update(key, Some(value.as_bytes()));
+                return Ok(());
+            }
+        };
+        
+        // Compress and encrypt the operand as configured
+        let (flags, payload) = self.encode_value(key, operand.as_bytes())?;
         
-        // Keep removing entries until we have enough space
-        let mut current_size: usize = cache.iter().map(|(_, v)| v.len() + std::mem::size_of::<i64>()).sum();
+        // Lock the index before the file, in the same order as readers
+        let mut index = log.index.write().unwrap();
+        let previous = index.get(key)?;
         
-        while current_size + new_entry_size > MAX_CACHE_SIZE && !cache.is_empty() {
-            if let Some((_, removed_value)) = cache.pop_lru() {
-                current_size -= removed_value.len() + std::mem::size_of::<i64>();
-            }
+        // Write the operand, linked to the current position of the key
+        let file = log.file.lock().unwrap();
+        let mut record = Vec::with_capacity((1 + 8 + MERGE_LINK_SIZE + 8) as usize + payload.len());
+        let version = log.begin_version(&mut record)?;
+        write_merge_record(&mut record, flags, key, previous, &payload)?;
+        file.append(&record)?;
+        if self.config.sync_writes {
+            file.sync()?;
         }
-    }
-
-    // Garbage collect the database to reclaim space
-    fn garbage_collect(&self) -> Result<()> {
-        // Create a temporary file for the new data
-        let temp_path = self.config.path.join("temp.db");
-        let mut temp_file = File::create(&temp_path)?;
         
-        // Get a copy of the current index
-        let index = self.index.read().unwrap();
+        // Update the file size
+        let offset = *log.file_size.lock().unwrap();
+        let new_size = offset + record.len() as u64;
+        *log.file_size.lock().unwrap() = new_size;
         
-        // Initialize a new index for the compacted data
-        let mut new_index = MemIndex::new();
+        // Update the index. The chain the operand links to stays live.
+        let value_pos = ValuePos {
+            offset: new_size - payload.len() as u64,
+            size: payload.len() as u64,
+            flags: flags | FLAG_MERGE,
+        };
+        index.insert(key, value_pos, previous)?;
+        log.live_bytes.fetch_add(1 + 8 + MERGE_LINK_SIZE + 8 + value_pos.size, Ordering::Relaxed); // op_type + key + link + value_size + value
+        index.set_log_offset(new_size);
+        log.add_to_filter(&**index, key)?;
+        if let Some((mut history, version, timestamp)) = version {
+            history.record(key, VersionEntry { version, timestamp, pos: Some(value_pos) });
+        }
         
-        // Start at the beginning of the temporary file
-        let mut new_offset = 0u64;
+        // Apply the operand to a cached value, so reads don't have to resolve the chain
+        self.cache.lock().unwrap().update(key, |value| operator.merge(key, Some(value), &[operand]).ok());
+        drop(file);
+        drop(index);
         
-        // For each key with a value in the index, write it to the new file
-        for (&key, &pos_opt) in index.iter() {
-            if let Some(pos) = pos_opt {
-                // Read the value from the original file
-                let mut file = self.file.lock().unwrap();
-                file.seek(SeekFrom::Start(pos.offset))?;
-                
-                let mut value_bytes = vec![0; pos.size as usize];
-                file.read_exact(&mut value_bytes)?;
-                
-                // Write to the new file
-                // Write the operation type (Set)
-                temp_file.write_u8(OpType::Set as u8)?;
-                
-                // Write the key
-                temp_file.write_i64::<LittleEndian>(key)?;
-                
-                // Write the value size
-                temp_file.write_u64::<LittleEndian>(pos.size)?;
-                
-                // Write the value
-                temp_file.write_all(&value_bytes)?;
-                
-                // Update the new index
-                let new_pos = ValuePos {
-                    offset: new_offset + 1 + 8 + 8, // op_type + key + value_size
-                    size: pos.size,
-                };
-                
-                new_index.insert(key, Some(new_pos));
-                
-                // Update the new offset
-                new_offset += 1 + 8 + 8 + pos.size; // op_type + key + value_size + value
-            } else {
-                // This key was removed, just update the index
-                new_index.insert(key, None);
-            }
+        // The merged value is only known once the chain is resolved.
+        // A chain that can't be resolved isn't indexed.
+        if indexes.is_active() {
+            let value = self.get_bytes(key).unwrap_or(None);
+            indexes.update(key, value.as_deref());
         }
+        drop(indexes);
+        
+        // Check if we need to do garbage collection
+        if new_size > self.config.gc_threshold {
+            self.garbage_collect()?;
+        }
+        
+        Ok(())
+    }
+    
+    // Write a Set record with an encoded payload and point the index at it.
+    // The cache is updated with the given value, or invalidated if there is none.
+    fn commit_value(&self, key: i64, flags: u8, payload: &[u8], cached: Option<&str>) -> Result<()> {
+        self.commit_values(&[(key, flags, payload, cached)])
+    }
+    
+    // Write Set records for encoded payloads in one append and point the index at them,
+    // taking each lock once. The cache is updated as in `commit_value`.
+    fn commit_values(&self, values: &[(i64, u8, &[u8], Option<&str>)]) -> Result<()> {
+        let log = self.log_store("Writing to the log")?;
+        
+        // Lock the index before the file, in the same order as readers
+        let mut index = log.index.write().unwrap();
+        let new_size = self.append_values(log, &mut **index, values)?;
+        drop(index);
+        
+        // Check if we need to do garbage collection
+        if new_size > self.config.gc_threshold {
+            self.garbage_collect()?;
+        }
+        
+        Ok(())
+    }
+    
+    // Append the records of `commit_values` while holding the index lock,
+    // returning the new size of the file
+    fn append_values(&self, log: &LogStore, index: &mut dyn Index, values: &[(i64, u8, &[u8], Option<&str>)]) -> Result<u64> {
+        // Write the new key-value pairs to the file
+        let file = log.file.lock().unwrap();
+        let mut records = Vec::with_capacity(values.iter().map(|(_, _, payload, _)| 1 + 8 + 8 + payload.len()).sum());
+        let mut version = log.begin_version(&mut records)?;
+        let version_size = records.len() as u64;
+        for &(key, flags, payload, _) in values {
+            write_record(&mut records, OpType::Set as u8 | flags, key, payload)?;
+        }
+        file.append(&records)?;
+        if self.config.sync_writes {
+            // Also covers the chunks of a large value, written before its manifest
+            file.sync()?;
+        }
+        
+        // Update the file size
+        let offset = *log.file_size.lock().unwrap();
+        let new_size = offset + records.len() as u64;
+        *log.file_size.lock().unwrap() = new_size;
+        
+        // Update the index and the history
+        let mut offset = offset + version_size;
+        for &(key, flags, payload, _) in values {
+            let value_pos = ValuePos {
+                offset: offset + 1 + 8 + 8, // op_type + key + value_size
+                size: payload.len() as u64,
+                flags,
+            };
+            offset = value_pos.offset + value_pos.size;
+            
+            // The records of the value replaced become dead
+            let old_pos = index.get(key)?;
+            if let Some(old_pos) = old_pos {
+                log.live_bytes.fetch_sub(self.value_bytes(&**file, old_pos)?, Ordering::Relaxed);
+            }
+            let mut live_bytes = 1 + 8 + 8 + value_pos.size; // op_type + key + value_size + value
+            if flags & FLAG_CHUNKED != 0 {
+                live_bytes += Manifest::decode(payload)?.chunks.iter().map(|chunk| 1 + 8 + 8 + chunk.size).sum::<u64>();
+            }
+            log.live_bytes.fetch_add(live_bytes, Ordering::Relaxed);
+            
+            index.insert(key, value_pos, old_pos)?;
+            log.add_to_filter(index, key)?;
+            if let Some((history, version, timestamp)) = &mut version {
+                history.record(key, VersionEntry { version: *version, timestamp: *timestamp, pos: Some(value_pos) });
+            }
+        }
+        index.set_log_offset(new_size);
+        
+        // Update the cache
+        let mut cache = self.cache.lock().unwrap();
+        for &(key, _, _, cached) in values {
+            match cached {
+                Some(value) => cache.put(key, value),
+                None => cache.remove(key),
+            }
+        }
+        
+        Ok(new_size)
+    }
+    
+    // Write the manifest of the chunks a blob writer appended and point the index
+    // at it. The chunks are taken while holding the index lock, so garbage
+    // collection can't move them in the meantime.
+    pub(crate) fn commit_blob(&self, log: &LogStore, writer: u64, len: u64) -> Result<()> {
+        let mut index = log.index.write().unwrap();
+        let blob = log.pending_blobs.lock().unwrap().remove(&writer).expect("blob writers stay registered until dropped");
+        let payload = Manifest { len, chunks: blob.chunks }.encode();
+        let new_size = self.append_values(log, &mut **index, &[(blob.key, FLAG_CHUNKED, &payload, None)])?;
+        drop(index);
+        
+        // Check if we need to do garbage collection
+        if new_size > self.config.gc_threshold {
+            self.garbage_collect()?;
+        }
+        
+        Ok(())
+    }
+    
+    // Append a chunk of a large value to the file, returning its position
+    fn append_chunk(&self, file: &dyn StorageFile, key: i64, data: &[u8]) -> Result<ValuePos> {
+        let (flags, payload) = self.encode_value(key, data)?;
+        
+        let mut record = Vec::with_capacity(1 + 8 + 8 + payload.len());
+        write_record(&mut record, OpType::Chunk as u8 | flags, key, &payload)?;
+        file.append(&record)?;
+        
+        // Update the file size
+        let mut file_size = self.log_store("Writing to the log")?.file_size.lock().unwrap();
+        let pos = ValuePos {
+            offset: *file_size + 1 + 8 + 8, // op_type + key + value_size
+            size: payload.len() as u64,
+            flags,
+        };
+        *file_size = pos.offset + pos.size;
+        
+        Ok(pos)
+    }
+    
+    // Read and decode the value stored at the given position
+    fn read_value(&self, file: &dyn StorageFile, key: i64, pos: &ValuePos) -> Result<Vec<u8>> {
+        if pos.flags & FLAG_MERGE != 0 {
+            return self.resolve_merge(file, key, pos);
+        }
+        
+        let payload = read_payload(file, pos)?;
+        if pos.flags & FLAG_CHUNKED == 0 {
+            return self.decode_value(key, pos.flags, payload);
+        }
+        
+        // Reassemble a large value from its chunks
+        let manifest = Manifest::decode(&payload)?;
+        self.read_chunks(file, key, &manifest.chunks, manifest.len)
+    }
+    
+    // Read and decode the chunks of a large value, `len` bytes in all
+    fn read_chunks(&self, file: &dyn StorageFile, key: i64, chunks: &[ValuePos], len: u64) -> Result<Vec<u8>> {
+        let mut value = Vec::with_capacity(len as usize);
+        for chunk in chunks {
+            let payload = read_payload(file, chunk)?;
+            value.extend_from_slice(&self.decode_value(key, chunk.flags, payload)?);
+        }
+        Ok(value)
+    }
+    
+    // Read back the chunks a blob writer has appended, so the value can be
+    // indexed before it is committed. The index lock keeps garbage collection
+    // from moving them in the meantime.
+    pub(crate) fn read_pending_blob(&self, log: &LogStore, writer: u64, len: u64) -> Result<Vec<u8>> {
+        let _index = log.index.read().unwrap();
+        let file = log.file.lock().unwrap();
+        let (key, chunks) = {
+            let pending_blobs = log.pending_blobs.lock().unwrap();
+            let blob = pending_blobs.get(&writer).expect("blob writers stay registered until dropped");
+            (blob.key, blob.chunks.clone())
+        };
+        self.read_chunks(&**file, key, &chunks, len)
+    }
+    
+    // Read the chain of merge operands ending at the given position back to the
+    // value they apply to, then combine them with the merge operator
+    fn resolve_merge(&self, file: &dyn StorageFile, key: i64, pos: &ValuePos) -> Result<Vec<u8>> {
+        let operator = self.merge_operator()?;
+        
+        let mut operands = Vec::new();
+        let mut next = Some(*pos);
+        let existing = loop {
+            match next {
+                Some(pos) if pos.flags & FLAG_MERGE != 0 => {
+                    let operand = self.decode_value(key, pos.flags, read_payload(file, &pos)?)?;
+                    operands.push(String::from_utf8_lossy(&operand).to_string());
+                    next = read_merge_link(file, &pos)?;
+                }
+                Some(pos) => break Some(String::from_utf8_lossy(&self.read_value(file, key, &pos)?).to_string()),
+                None => break None,
+            }
+        };
+        
+        let operands: Vec<&str> = operands.iter().rev().map(String::as_str).collect();
+        let value = operator.merge(key, existing.as_deref(), &operands).map_err(KvError::Merge)?;
+        Ok(value.into_bytes())
+    }
+    
+    fn merge_operator(&self) -> Result<&dyn MergeOperator> {
+        match &self.config.merge_operator {
+            Some(operator) => Ok(&**operator),
+            None => Err(KvError::Merge("no merge operator is configured".to_string())),
+        }
+    }
+    
+    // Get a value from the database
+    pub fn get(&self, key: i64) -> Result<Option<String>> {
+        self.metrics.track(Operation::Get, || Ok(self.get_bytes(key)?.map(into_string)))
+    }
+    
+    // Get a value from the database as it is stored, which doesn't have to be UTF-8
+    pub fn get_bytes(&self, key: i64) -> Result<Option<Vec<u8>>> {
+        self.metrics.track(Operation::Get, || self.read_bytes(key))
+    }
+    
+    // Does the work of `get_bytes`, which tracks it in the stats
+    fn read_bytes(&self, key: i64) -> Result<Option<Vec<u8>>> {
+        // Check if the database is closed
+        if *self.closed.read().unwrap() {
+            return Err(KvError::DbClosed);
+        }
+        
+        // First check the cache, which may also know the key is absent
+        if let Some(value) = self.cache.lock().unwrap().get(key) {
+            return Ok(value.map(String::into_bytes));
+        }
+        self.read_uncached(key)
+    }
+    
+    // Read a value past the cache, then cache it
+    fn read_uncached(&self, key: i64) -> Result<Option<Vec<u8>>> {
+        let log = match &self.engine {
+            Engine::Log(log) => log,
+            Engine::Lsm(tree) => {
+                let tree = tree.read().unwrap();
+                let Some((flags, payload)) = tree.get(key)? else {
+                    self.cache.lock().unwrap().put_absent(key);
+                    return Ok(None);
+                };
+                
+                let value = self.decode_value(key, flags, payload)?;
+                self.cache.lock().unwrap().put_bytes(key, &value);
+                return Ok(Some(value));
+            }
+        };
+        
+        // Keys that were never written are ruled out without touching the index
+        if !log.filter.read().unwrap().may_contain(key) {
+            return Ok(None);
+        }
+        
+        // If not in cache, check the index
+        let index = log.index.read().unwrap();
+        
+        match index.get(key)? {
+            Some(pos) => {
+                // Read the value from the file
+                let file = log.file.lock().unwrap();
+                let value = self.read_value(&**file, key, &pos)?;
+                
+                // Update the cache, unless this is a large value
+                if pos.flags & FLAG_CHUNKED == 0 {
+                    self.cache.lock().unwrap().put_bytes(key, &value);
+                }
+                
+                Ok(Some(value))
+            },
+            None => {
+                // Key doesn't exist or was removed
+                self.cache.lock().unwrap().put_absent(key);
+                Ok(None)
+            }
+        }
+    }
+    
+    // Get the values of many keys, in the order of the keys, taking each lock once.
+    // Values that aren't cached are read from the log in the order they are stored.
+    pub fn get_many(&self, keys: &[i64]) -> Result<Vec<Option<String>>> {
+        self.metrics.track(Operation::GetMany, || self.read_many(keys))
+    }
+    
+    // Does the work of `get_many`, which tracks it in the stats
+    fn read_many(&self, keys: &[i64]) -> Result<Vec<Option<String>>> {
+        // Check if the database is closed
+        if *self.closed.read().unwrap() {
+            return Err(KvError::DbClosed);
+        }
+        
+        // First check the cache, noting which keys still have to be looked up
+        let mut values = vec![None; keys.len()];
+        let mut missing = Vec::new();
+        {
+            let mut cache = self.cache.lock().unwrap();
+            for (i, &key) in keys.iter().enumerate() {
+                match cache.get(key) {
+                    Some(value) => values[i] = value,
+                    None => missing.push(i),
+                }
+            }
+        }
+        if missing.is_empty() {
+            return Ok(values);
+        }
+        
+        let log = match &self.engine {
+            Engine::Log(log) => log,
+            Engine::Lsm(tree) => {
+                let tree = tree.read().unwrap();
+                let mut found = Vec::with_capacity(missing.len());
+                for &i in &missing {
+                    let value = match tree.get(keys[i])? {
+                        Some((flags, payload)) => Some(self.decode_value(keys[i], flags, payload)?),
+                        None => None,
+                    };
+                    found.push(value);
+                }
+                
+                let mut cache = self.cache.lock().unwrap();
+                for (i, value) in missing.into_iter().zip(found) {
+                    match &value {
+                        Some(value) => cache.put_bytes(keys[i], value),
+                        None => cache.put_absent(keys[i]),
+                    }
+                    values[i] = value.map(into_string);
+                }
+                return Ok(values);
+            }
+        };
+        
+        // Keys that were never written are ruled out without touching the index
+        {
+            let filter = log.filter.read().unwrap();
+            missing.retain(|&i| filter.may_contain(keys[i]));
+        }
+        
+        let index = log.index.read().unwrap();
+        let mut positions = Vec::with_capacity(missing.len());
+        let mut absent = Vec::new();
+        for i in missing {
+            match index.get(keys[i])? {
+                Some(pos) => positions.push((i, pos)),
+                None => absent.push(keys[i]),
+            }
+        }
+        
+        // Read the values in the order they are stored in the file
+        positions.sort_unstable_by_key(|(_, pos)| pos.offset);
+        let file = log.file.lock().unwrap();
+        let mut read = Vec::with_capacity(positions.len());
+        for (i, pos) in positions {
+            let value = self.read_value(&**file, keys[i], &pos)?;
+            read.push((i, pos.flags, value));
+        }
+        
+        // Update the cache, leaving out large values
+        let mut cache = self.cache.lock().unwrap();
+        for (i, flags, value) in read {
+            if flags & FLAG_CHUNKED == 0 {
+                cache.put_bytes(keys[i], &value);
+            }
+            values[i] = Some(into_string(value));
+        }
+        for key in absent {
+            cache.put_absent(key);
+        }
+        
+        Ok(values)
+    }
+    
+    // Get a reader that streams a value from the database.
+    // Large values are read one chunk at a time rather than loaded into memory.
+    pub fn get_reader(&self, key: i64) -> Result<Option<ValueReader<'_>>> {
+        // Check if the database is closed
+        if *self.closed.read().unwrap() {
+            return Err(KvError::DbClosed);
+        }
+        
+        // Values are stored inline in the LSM tree
+        let Engine::Log(log) = &self.engine else {
+            let value = self.get(key)?;
+            return Ok(value.map(|value| ValueReader::from_value(self, key, value.into_bytes())));
+        };
+        
+        // First check the cache
+        if let Some(value) = self.cache.lock().unwrap().get(key) {
+            return Ok(value.map(|value| ValueReader::from_value(self, key, value.into_bytes())));
+        }
+        if !log.filter.read().unwrap().may_contain(key) {
+            return Ok(None);
+        }
+        
+        let index = log.index.read().unwrap();
+        let pos = match index.get(key)? {
+            Some(pos) => pos,
+            None => return Ok(None),
+        };
+        
+        let file = log.file.lock().unwrap();
+        if pos.flags & FLAG_CHUNKED == 0 {
+            let value = self.read_value(&**file, key, &pos)?;
+            return Ok(Some(ValueReader::from_value(self, key, value)));
+        }
+        
+        // Hold on to the current data file while holding the index lock, so the
+        // chunk positions stay valid for as long as the reader lives
+        let manifest = Manifest::decode(&read_payload(&**file, &pos)?)?;
+        Ok(Some(ValueReader::from_chunks(self, key, file.clone(), manifest)))
+    }
+    
+    // Start writing a large value for a key piece by piece.
+    // The value replaces the current one when `BlobWriter::finish` is called.
+    pub fn blob_writer(&self, key: i64) -> Result<BlobWriter<'_>> {
+        // Check if the database is closed
+        if *self.closed.read().unwrap() {
+            return Err(KvError::DbClosed);
+        }
+        
+        let log = self.log_store("Streaming writes")?;
+        Ok(BlobWriter::new(self, log, key))
+    }
+    
+    // Remove a key from the database
+    pub fn remove(&self, key: i64) -> Result<Option<String>> {
+        self.metrics.track(Operation::Remove, || self.remove_key(key))
+    }
+    
+    // Does the work of `remove`, which tracks it in the stats
+    fn remove_key(&self, key: i64) -> Result<Option<String>> {
+        // Check if the database is closed
+        if *self.closed.read().unwrap() {
+            return Err(KvError::DbClosed);
+        }
+        
+        // Get the old value for the key, if it exists
+        let old_value = match self.get(key) {
+            Ok(Some(val)) => Some(val),
+            Ok(None) => return Ok(None),
+            // A chain of merges that can't be resolved is removed all the same
+            Err(KvError::Merge(_)) => None,
+            Err(err) => return Err(err),
+        };
+        
+        let mut indexes = self.lock_secondary_indexes();
+        let log = match &self.engine {
+            Engine::Log(log) => log,
+            Engine::Lsm(tree) => {
+                // Write a tombstone, which shadows the value in older tables
+                let mut tree = tree.write().unwrap();
+                tree.write(key, None)?;
+                self.cache.lock().unwrap().put_absent(key);
+                indexes.update(key, None);
+                return Ok(old_value);
+            }
+        };
+        
+        // Lock the index before the file, in the same order as readers
+        let mut index = log.index.write().unwrap();
+        
+        // Write the removal operation to the file
+        let file = log.file.lock().unwrap();
+        let mut record = Vec::with_capacity(1 + 8);
+        let version = log.begin_version(&mut record)?;
+        
+        // Write the operation type (Remove)
+        record.write_u8(OpType::Remove as u8)?;
+        
+        // Write the key
+        record.write_i64::<LittleEndian>(key)?;
+        file.append(&record)?;
+        if self.config.sync_writes {
+            file.sync()?;
+        }
+        
+        // Update the file size
+        let offset = *log.file_size.lock().unwrap();
+        *log.file_size.lock().unwrap() = offset + record.len() as u64;
+        
+        // Update the index, keeping the removal in the history
+        let old_pos = index.get(key)?;
+        if let Some(old_pos) = old_pos {
+            log.live_bytes.fetch_sub(self.value_bytes(&**file, old_pos)?, Ordering::Relaxed);
+        }
+        index.remove(key, old_pos)?;
+        index.set_log_offset(offset + record.len() as u64);
+        if let Some((mut history, version, timestamp)) = version {
+            history.record(key, VersionEntry { version, timestamp, pos: None });
+        }
+        
+        // Remove from the cache, remembering that the key is gone
+        let mut cache = self.cache.lock().unwrap();
+        cache.put_absent(key);
+        indexes.update(key, None);
+        
+        // Check if we need to do garbage collection
+        if *log.file_size.lock().unwrap() > self.config.gc_threshold {
+            drop(file);
+            drop(index);
+            drop(cache);
+            drop(indexes);
+            self.garbage_collect()?;
+        }
+        
+        Ok(old_value)
+    }
+    
+    // Get the live key-value pairs with keys in the given range, in key order
+    pub fn scan(&self, range: impl RangeBounds<i64>) -> Result<Vec<(i64, String)>> {
+        self.metrics.track(Operation::Scan, || {
+            let entries = self.scan_bytes(range)?;
+            Ok(entries.into_iter().map(|(key, value)| (key, into_string(value))).collect())
+        })
+    }
+    
+    // Get a cursor that reads the live key-value pairs with keys in the given range
+    // in key order, a page at a time, for ranges too large to read all at once
+    pub fn scan_cursor(&self, range: impl RangeBounds<i64>) -> Result<ScanCursor<'_>> {
+        // Check if the database is closed
+        if *self.closed.read().unwrap() {
+            return Err(KvError::DbClosed);
+        }
+        
+        ScanCursor::new(self, (range.start_bound().cloned(), range.end_bound().cloned()))
+    }
+    
+    // Get the live key-value pairs with keys in the given range as they are stored, in key order
+    pub fn scan_bytes(&self, range: impl RangeBounds<i64>) -> Result<Vec<(i64, Vec<u8>)>> {
+        self.metrics.track(Operation::Scan, || self.read_range(range))
+    }
+    
+    // Does the work of `scan_bytes`, which tracks it in the stats
+    fn read_range(&self, range: impl RangeBounds<i64>) -> Result<Vec<(i64, Vec<u8>)>> {
+        // Check if the database is closed
+        if *self.closed.read().unwrap() {
+            return Err(KvError::DbClosed);
+        }
+        
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let mut entries = Vec::new();
+        match &self.engine {
+            Engine::Log(log) => {
+                // The index isn't ordered, so collect the matching keys first
+                let index = log.index.read().unwrap();
+                let mut positions = Vec::new();
+                index.for_each(&mut |key, pos| {
+                    if range.contains(&key) {
+                        positions.push((key, pos));
+                    }
+                    Ok(())
+                })?;
+                positions.sort_unstable_by_key(|&(key, _)| key);
+                
+                let file = log.file.lock().unwrap();
+                for (key, pos) in positions {
+                    entries.push((key, self.read_value(&**file, key, &pos)?));
+                }
+            }
+            Engine::Lsm(tree) => {
+                let tree = tree.read().unwrap();
+                for (key, (flags, payload)) in tree.scan(range, usize::MAX)? {
+                    entries.push((key, self.decode_value(key, flags, payload)?));
+                }
+            }
+        }
+        
+        Ok(entries)
+    }
+    
+    // Get the value a key had at a point in the history of the database: right
+    // after the write with the given version number, or at the given time.
+    // Fails with `KvError::VersionNotRetained` if the versions of the key from
+    // back then were discarded.
+    pub fn get_at(&self, key: i64, as_of: AsOf) -> Result<Option<String>> {
+        // Check if the database is closed
+        if *self.closed.read().unwrap() {
+            return Err(KvError::DbClosed);
+        }
+        
+        let log = self.log_store("Multi-version reads")?;
+        let history = log.history.as_ref().ok_or(KvError::HistoryDisabled)?;
+        
+        // Writers and garbage collection take the index lock, so holding it
+        // keeps the position valid while the value is read
+        let _index = log.index.read().unwrap();
+        let pos = history.lock().unwrap().find(key, as_of)?;
+        match pos {
+            Some(pos) => Ok(Some(into_string(self.read_value(&**log.file.lock().unwrap(), key, &pos)?))),
+            None => Ok(None),
+        }
+    }
+    
+    // The retained versions of a key, oldest first, including removals, and
+    // whether older ones were discarded. Versions are dropped as soon as there
+    // are more than `Config::max_versions`, but those older than
+    // `Config::version_retention` only when garbage collection runs, so until
+    // then they are still listed.
+    pub fn history(&self, key: i64) -> Result<VersionHistory> {
+        // Check if the database is closed
+        if *self.closed.read().unwrap() {
+            return Err(KvError::DbClosed);
+        }
+        
+        let log = self.log_store("Multi-version reads")?;
+        let history = log.history.as_ref().ok_or(KvError::HistoryDisabled)?;
+        
+        let _index = log.index.read().unwrap();
+        let (entries, truncated): (Vec<VersionEntry>, bool) = match history.lock().unwrap().get(key) {
+            Some(key_history) => (key_history.entries.iter().copied().collect(), key_history.truncated),
+            None => return Ok(VersionHistory::default()),
+        };
+        
+        let file = log.file.lock().unwrap();
+        let mut versions = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let value = match entry.pos {
+                Some(pos) => Some(into_string(self.read_value(&**file, key, &pos)?)),
+                None => None,
+            };
+            versions.push(Version {
+                version: entry.version,
+                timestamp: history::to_system_time(entry.timestamp),
+                value,
+            });
+        }
+        Ok(VersionHistory { versions, truncated })
+    }
+    
+    // The version number of the last write, to read as of with `get_at`
+    pub fn last_version(&self) -> Result<u64> {
+        let log = self.log_store("Multi-version reads")?;
+        let history = log.history.as_ref().ok_or(KvError::HistoryDisabled)?;
+        Ok(history.lock().unwrap().last_version())
+    }
+    
+    // The number of keys in the database. With the LSM-tree engine it is an
+    // estimate, which counts keys overwritten since the memtable was last
+    // flushed twice.
+    pub fn len(&self) -> usize {
+        match &self.engine {
+            Engine::Log(log) => log.index.read().unwrap().len(),
+            Engine::Lsm(tree) => tree.read().unwrap().len(),
+        }
+    }
+    
+    // Whether the database holds no keys
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    
+    // Counters for the cache of recently used values
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().stats()
+    }
+    
+    // A snapshot of the size of the database, its cache, and the operations done
+    // since it was opened. Everything in it is kept up to date as the database is
+    // used, so it is cheap to call often.
+    pub fn stats(&self) -> Result<DbStats> {
+        // Check if the database is closed
+        if *self.closed.read().unwrap() {
+            return Err(KvError::DbClosed);
+        }
+        
+        // Only one of garbage collection and compactions happens, depending on the engine
+        let (gc_runs, gc_time) = self.metrics.gc();
+        let (file_size, dead_bytes, (compactions, compaction_time)) = match &self.engine {
+            Engine::Log(log) => {
+                let live_bytes = log.live_bytes.load(Ordering::Relaxed);
+                let file_size = *log.file_size.lock().unwrap();
+                (file_size, Some(file_size.saturating_sub(live_bytes)), (0, gc_time))
+            }
+            Engine::Lsm(tree) => {
+                let tree = tree.read().unwrap();
+                (tree.disk_size()?, None, tree.compactions())
+            }
+        };
+        
+        Ok(DbStats {
+            keys: self.len(),
+            file_size,
+            dead_bytes,
+            cache: self.cache_stats(),
+            gc_runs,
+            compactions,
+            compaction_time,
+            operations: self.metrics.operations(),
+        })
+    }
+    
+    // The bytes of the records the current values are made of, going through the index
+    fn count_live_bytes(&self, log: &LogStore) -> Result<u64> {
+        let index = log.index.read().unwrap();
+        let file = log.file.lock().unwrap();
+        let mut live_bytes = 0;
+        index.for_each(&mut |_, pos| {
+            live_bytes += self.value_bytes(&**file, pos)?;
+            Ok(())
+        })?;
+        Ok(live_bytes)
+    }
+    
+    // The bytes of the records a value is made of: its Set or Merge record,
+    // the records earlier in its merge chain and its chunks
+    fn value_bytes(&self, file: &dyn StorageFile, pos: ValuePos) -> Result<u64> {
+        let mut bytes = 0;
+        let mut next = Some(pos);
+        while let Some(pos) = next {
+            if pos.flags & FLAG_MERGE != 0 {
+                bytes += 1 + 8 + MERGE_LINK_SIZE + 8 + pos.size; // op_type + key + link + value_size + value
+                next = read_merge_link(file, &pos)?;
+                continue;
+            }
+            
+            bytes += 1 + 8 + 8 + pos.size; // op_type + key + value_size + value
+            if pos.flags & FLAG_CHUNKED != 0 {
+                for chunk in Manifest::decode(&read_payload(file, &pos)?)?.chunks {
+                    bytes += 1 + 8 + 8 + chunk.size;
+                }
+            }
+            next = None;
+        }
+        Ok(bytes)
+    }
+    
+    // Read the values of the keys that were cached when the hot keys were last
+    // saved into the cache, hottest first, returning the number of keys read.
+    // Stops early if the database is closed in the meantime. The reads go past
+    // the cache and aren't counted in the stats.
+    pub fn warm_cache(&self) -> Result<usize> {
+        let keys = cache::load_hot_keys(&*self.storage)?;
+        let mut warmed = 0;
+        for key in keys {
+            if *self.closed.read().unwrap() {
+                break;
+            }
+            self.read_uncached(key)?;
+            warmed += 1;
+        }
+        Ok(warmed)
+    }
+    
+    // A column family: a key space of its own within the database, with its own
+    // index, cache and garbage collection. It is created when first used, and
+    // closed along with the database.
+    pub fn cf(&self, name: &str) -> Result<Arc<KvDb>> {
+        // Hold off closing while a column family is opened, so it gets closed too
+        let closed = self.closed.read().unwrap();
+        if *closed {
+            return Err(KvError::DbClosed);
+        }
+        if !is_valid_column_family(name) {
+            return Err(KvError::InvalidColumnFamily(name.to_string()));
+        }
+        
+        if let Some(db) = self.column_families.read().unwrap().get(name) {
+            return Ok(db.clone());
+        }
+        let mut column_families = self.column_families.write().unwrap();
+        if let Some(db) = column_families.get(name) {
+            return Ok(db.clone());
+        }
+        
+        // Column families share everything but their own settings with this database
+        let storage = PrefixStorage::new(self.storage.clone(), format!("{}{}.", COLUMN_FAMILY_PREFIX, name));
+        let mut config = Config {
+            storage: Some(Arc::new(storage)),
+            column_families: HashMap::new(),
+            ..self.config.clone()
+        };
+        if let Some(options) = self.config.column_families.get(name) {
+            config.index = options.index;
+            config.engine = options.engine;
+            config.gc_threshold = options.gc_threshold;
+            config.cache_capacity_bytes = options.cache_capacity_bytes;
+            config.cache_policy = options.cache_policy;
+        }
+        
+        let db = Arc::new(KvDb::open(config)?);
+        column_families.insert(name.to_string(), db.clone());
+        Ok(db)
+    }
+    
+    // The column family with the given name if it has been created before,
+    // opening it if need be. Unlike `cf`, this doesn't create one that doesn't
+    // exist yet.
+    pub fn existing_cf(&self, name: &str) -> Result<Option<Arc<KvDb>>> {
+        if !is_valid_column_family(name) {
+            return Err(KvError::InvalidColumnFamily(name.to_string()));
+        }
+        if !self.column_families.read().unwrap().contains_key(name) && !self.column_families()?.iter().any(|cf| cf == name) {
+            return Ok(None);
+        }
+        self.cf(name).map(Some)
+    }
+    
+    // The names of the column families with files in the database, in order
+    pub fn column_families(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = self
+            .storage
+            .list()?
+            .iter()
+            .filter_map(|name| name.strip_prefix(COLUMN_FAMILY_PREFIX)?.split_once('.'))
+            .map(|(name, _)| name.to_string())
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+    
+    // The column families opened so far with `KvDb::cf`, in order of name.
+    // Unlike `column_families`, this doesn't look at the files, and leaves out
+    // column families that haven't been used since the database was opened.
+    pub fn open_column_families(&self) -> Vec<(String, Arc<KvDb>)> {
+        let mut column_families: Vec<_> = self
+            .column_families
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, db)| (name.clone(), db.clone()))
+            .collect();
+        column_families.sort_by(|a, b| a.0.cmp(&b.0));
+        column_families
+    }
+    
+    // Get the entries whose values have the given field value in a secondary
+    // index, in key order
+    pub fn query_by_index(&self, name: &str, value: &str) -> Result<Vec<(i64, String)>> {
+        self.metrics.track(Operation::QueryByIndex, || {
+            // Check if the database is closed
+            if *self.closed.read().unwrap() {
+                return Err(KvError::DbClosed);
+            }
+            
+            // Read the values while holding off writers, so they match the index
+            let indexes = self.secondary_indexes.read().unwrap();
+            let keys = indexes.query(name, value)?;
+            let values = self.get_many(&keys)?;
+            Ok(keys.into_iter().zip(values).filter_map(|(key, value)| Some((key, value?))).collect())
+        })
+    }
+    
+    // Index every value in the database. Chains of merges that can't be resolved
+    // aren't indexed.
+    fn build_secondary_indexes(&self) -> Result<()> {
+        let mut indexes = self.lock_secondary_indexes();
+        match &self.engine {
+            Engine::Log(log) => {
+                let index = log.index.read().unwrap();
+                let mut positions = Vec::new();
+                index.for_each(&mut |key, pos| {
+                    positions.push((key, pos));
+                    Ok(())
+                })?;
+                
+                let file = log.file.lock().unwrap();
+                for (key, pos) in positions {
+                    match self.read_value(&**file, key, &pos) {
+                        Ok(value) => indexes.update(key, Some(&value)),
+                        Err(KvError::Merge(_)) => {}
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+            Engine::Lsm(tree) => {
+                let tree = tree.read().unwrap();
+                for (key, (flags, payload)) in tree.scan((Bound::Unbounded, Bound::Unbounded), usize::MAX)? {
+                    indexes.update(key, Some(&self.decode_value(key, flags, payload)?));
+                }
+            }
+        }
+        Ok(())
+    }
+    
+    // Write every live entry to `writer` in key order, returning how many there were.
+    // Values that aren't valid UTF-8 are written with the invalid bytes replaced.
+    // The entries are read a batch at a time, so writes made during the export may
+    // or may not be included.
+    pub fn export(&self, writer: impl Write, format: DataFormat) -> Result<usize> {
+        let mut writer = EntryWriter::new(BufWriter::new(writer), format)?;
+        let mut cursor = self.scan_cursor(..)?;
+        let mut count = 0;
+        loop {
+            let batch = cursor.next_page(EXPORT_BATCH_SIZE, EXPORT_BATCH_BYTES)?;
+            if batch.is_empty() {
+                break;
+            }
+            for (key, value) in &batch {
+                writer.write(*key, value)?;
+            }
+            count += batch.len();
+        }
+        writer.finish()?;
+        Ok(count)
+    }
+    
+    // Set the entries read from `reader` a batch at a time, returning how many were
+    // read. Later entries for a key win. If the data turns out to be malformed, the
+    // batches before the malformed entry have been set.
+    pub fn import(&self, reader: impl Read, format: DataFormat) -> Result<usize> {
+        let mut count = 0;
+        let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+        for entry in EntryReader::new(BufReader::new(reader), format) {
+            batch.push(entry?);
+            if batch.len() == IMPORT_BATCH_SIZE {
+                count += self.import_batch(&mut batch)?;
+            }
+        }
+        count += self.import_batch(&mut batch)?;
+        Ok(count)
+    }
+    
+    fn import_batch(&self, batch: &mut Vec<(i64, String)>) -> Result<usize> {
+        let entries: Vec<(i64, &str)> = batch.iter().map(|(key, value)| (*key, value.as_str())).collect();
+        self.set_many(&entries)?;
+        let count = batch.len();
+        batch.clear();
+        Ok(count)
+    }
+    
+    // Fill an empty database with the entries read from `reader`, returning how many
+    // keys it holds. Rather than being written one by one, the entries are sorted and
+    // written out as a compacted log file, or as tables in the bottom level of the
+    // LSM tree, which replace the empty ones in one step. Later entries for a key win.
+    // Fails with `KvError::NotEmpty` unless nothing has been written since the database
+    // was created or last compacted. Large values aren't split into chunks.
+    pub fn bulk_load(&self, reader: impl Read, format: DataFormat) -> Result<usize> {
+        // Check if the database is closed
+        if *self.closed.read().unwrap() {
+            return Err(KvError::DbClosed);
+        }
+        
+        // Sort newest first within each key, so deduplication keeps the last entry
+        let mut entries = EntryReader::new(BufReader::new(reader), format).collect::<Result<Vec<_>>>()?;
+        entries.reverse();
+        entries.sort_by_key(|&(key, _)| key);
+        entries.dedup_by_key(|&mut (key, _)| key);
+        
+        let mut indexes = self.lock_secondary_indexes();
+        match &self.engine {
+            Engine::Log(log) => self.bulk_load_log(log, &entries)?,
+            Engine::Lsm(tree) => {
+                let mut values = Vec::with_capacity(entries.len());
+                for (key, value) in &entries {
+                    values.push((*key, self.encode_value(*key, value.as_bytes())?));
+                }
+                tree.write().unwrap().bulk_load(values)?;
+            }
+        }
+        
+        // The cache may remember the keys as missing
+        let mut cache = self.cache.lock().unwrap();
+        for (key, value) in &entries {
+            cache.remove(*key);
+            indexes.update(*key, Some(value.as_bytes()));
+        }
+        Ok(entries.len())
+    }
+    
+    // Close the database
+    pub fn close(&self) -> Result<()> {
+        let mut closed = self.closed.write().unwrap();
+        if !*closed {
+            *closed = true;
+            
+            // Save the hot keys one last time
+            if let Some(saver) = self.hot_key_saver.lock().unwrap().take() {
+                saver.stop();
+                if let Err(err) = cache::save_hot_keys(&self.storage, &self.cache) {
+                    log::warn!("Failed to save the hot keys: {}", err);
+                }
+            }
+            
+            match &self.engine {
+                // Persist the index so the next open doesn't have to replay the log.
+                // The log is synced first, as the index must not get ahead of it.
+                Engine::Log(log) => {
+                    let mut index = log.index.write().unwrap();
+                    let file = log.file.lock().unwrap();
+                    file.sync()?;
+                    index.flush()?;
+                    log.save_filter(&self.storage, &**index, file.size()?);
+                }
+                Engine::Lsm(tree) => tree.read().unwrap().sync()?,
+            }
+            
+            for db in self.column_families.read().unwrap().values() {
+                db.close()?;
+            }
+        }
+        Ok(())
+    }
+    
+    // Encode a value for storage, returning the record flags and the payload
+    fn encode_value(&self, key: i64, value: &[u8]) -> Result<(u8, Vec<u8>)> {
+        // Compress the value if configured and worthwhile
+        let (mut flags, mut payload) = match compression::compress(self.config.compression, value)? {
+            Some(compressed) => (FLAG_COMPRESSED, compressed),
+            None => (0, value.to_vec()),
+        };
+        
+        // Encrypt the (possibly compressed) value
+        if self.keyring.is_enabled() {
+            payload = self.keyring.encrypt(key, &payload)?;
+            flags |= FLAG_ENCRYPTED;
+        }
+        
+        Ok((flags, payload))
+    }
+    
+    // Decode a stored payload back into the original value
+    fn decode_value(&self, key: i64, flags: u8, mut payload: Vec<u8>) -> Result<Vec<u8>> {
+        if flags & FLAG_ENCRYPTED != 0 {
+            payload = self.keyring.decrypt(key, &payload)?;
+        }
+        if flags & FLAG_COMPRESSED != 0 {
+            payload = compression::decompress(&payload)?;
+        }
+        Ok(payload)
+    }
+
+    // Keep a stored payload as it is, unless it has to be re-encrypted
+    // because the encryption key was rotated
+    fn rotate_payload(&self, key: i64, flags: u8, payload: Vec<u8>) -> Result<(u8, Vec<u8>)> {
+        if self.keyring.needs_rotation(flags & FLAG_ENCRYPTED != 0, &payload) {
+            let value = self.decode_value(key, flags, payload)?;
+            self.encode_value(key, &value)
+        } else {
+            Ok((flags, payload))
+        }
+    }
+
+    // Copy the value at a position to the compacted file written by garbage collection,
+    // returning its position there. Chains of merge operands are resolved if they can be.
+    fn copy_value(&self, file: &dyn StorageFile, writer: &mut impl Write, new_offset: &mut u64, key: i64, pos: &ValuePos) -> Result<ValuePos> {
+        let (flags, value_bytes) = if pos.flags & FLAG_MERGE != 0 {
+            match self.read_value(file, key, pos) {
+                Ok(value) => self.encode_value(key, &value)?,
+                Err(KvError::Merge(err)) => {
+                    log::warn!("Keeping unresolved merges for key {}: {}", key, err);
+                    return self.copy_merge_chain(file, writer, new_offset, key, pos);
+                }
+                Err(err) => return Err(err),
+            }
+        } else if pos.flags & FLAG_CHUNKED != 0 {
+            // Copy each chunk of a large value, then write a manifest
+            // pointing at the copies
+            let manifest = Manifest::decode(&read_payload(file, pos)?)?;
+            let mut new_manifest = Manifest {
+                len: manifest.len,
+                chunks: Vec::with_capacity(manifest.chunks.len()),
+            };
+            
+            for chunk in &manifest.chunks {
+                new_manifest.chunks.push(self.copy_chunk(file, writer, new_offset, key, chunk)?);
+            }
+            
+            (FLAG_CHUNKED, new_manifest.encode())
+        } else {
+            // Read the value from the original file
+            let value_bytes = read_payload(file, pos)?;
+            self.rotate_payload(key, pos.flags, value_bytes)?
+        };
+        
+        // Write to the new file
+        write_record(writer, OpType::Set as u8 | flags, key, &value_bytes)?;
+        let new_pos = ValuePos {
+            offset: *new_offset + 1 + 8 + 8, // op_type + key + value_size
+            size: value_bytes.len() as u64,
+            flags,
+        };
+        *new_offset = new_pos.offset + new_pos.size;
+        Ok(new_pos)
+    }
+    
+    // Copy a chain of merge operands as it is, linking the copies to each other
+    fn copy_merge_chain(&self, file: &dyn StorageFile, writer: &mut impl Write, new_offset: &mut u64, key: i64, pos: &ValuePos) -> Result<ValuePos> {
+        // Collect the operands back to the value they apply to, and copy that first
+        let mut operands = Vec::new();
+        let mut next = Some(*pos);
+        while let Some(pos) = next.filter(|pos| pos.flags & FLAG_MERGE != 0) {
+            operands.push(pos);
+            next = read_merge_link(file, &pos)?;
+        }
+        let mut previous = match next {
+            Some(pos) => Some(self.copy_value(file, writer, new_offset, key, &pos)?),
+            None => None,
+        };
+        
+        for pos in operands.into_iter().rev() {
+            let payload = read_payload(file, &pos)?;
+            let (flags, payload) = self.rotate_payload(key, pos.flags & !FLAG_MERGE, payload)?;
+            write_merge_record(writer, flags, key, previous, &payload)?;
+            
+            let new_pos = ValuePos {
+                offset: *new_offset + 1 + 8 + MERGE_LINK_SIZE + 8, // op_type + key + link + value_size
+                size: payload.len() as u64,
+                flags: flags | FLAG_MERGE,
+            };
+            *new_offset = new_pos.offset + new_pos.size;
+            previous = Some(new_pos);
+        }
+        Ok(previous.expect("a merge chain has an operand"))
+    }
+    
+    // Write sorted entries with distinct keys to a new log file and swap it in for
+    // the empty one, in the same way as garbage collection
+    fn bulk_load_log(&self, log: &LogStore, entries: &[(i64, String)]) -> Result<()> {
+        let mut index = log.index.write().unwrap();
+        let mut file = log.file.lock().unwrap();
+        let mut history = log.history.as_ref().map(|history| history.lock().unwrap());
+        
+        // Removed keys with a history, and chunks of blobs being written, would be lost
+        let has_history = history.as_ref().is_some_and(|history| !history.is_empty());
+        if index.len() > 0 || has_history || !log.pending_blobs.lock().unwrap().is_empty() {
+            return Err(KvError::NotEmpty);
+        }
+        
+        let temp_file = self.storage.create("temp.db")?;
+        let mut writer = BufWriter::new(FileWriter(temp_file.clone()));
+        let mut new_index = index.rebuild()?;
+        let mut new_filter = BloomFilter::new(filter_capacity(entries.len()));
+        let mut new_offset = 0u64;
+        
+        // The entries are all written in one version
+        let version = history.as_mut().map(|history| history.next_version());
+        if let Some((version, timestamp)) = version {
+            write_version_record(&mut writer, version, timestamp, false)?;
+            new_offset += VERSION_RECORD_SIZE;
+        }
+        
+        let mut positions = Vec::with_capacity(entries.len());
+        for (key, value) in entries {
+            let (flags, payload) = self.encode_value(*key, value.as_bytes())?;
+            write_record(&mut writer, OpType::Set as u8 | flags, *key, &payload)?;
+            let pos = ValuePos {
+                offset: new_offset + 1 + 8 + 8, // op_type + key + value_size
+                size: payload.len() as u64,
+                flags,
+            };
+            new_offset = pos.offset + pos.size;
+            new_index.push(*key, pos)?;
+            new_filter.insert(*key);
+            positions.push((*key, pos));
+        }
+        
+        writer.flush()?;
+        temp_file.sync()?;
+        let new_index = new_index.finish(new_offset)?;
         
-        // Flush and sync the temporary file
-        temp_file.flush()?;
-        temp_file.sync_all()?;
-        
         // Replace the old file with the new one
-        let data_path = self.config.path.join("data.db");
-        std::fs::rename(temp_path, &data_path)?;
+        index.invalidate()?;
+        bloom::invalidate(&*self.storage)?;
+        self.storage.rename("temp.db", "data.db")?;
         
-        // Update the file and index
-        {
-            let mut file_lock = self.file.lock().unwrap();
-            *file_lock = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(&data_path)?;
-            
-            *self.file_size.lock().unwrap() = new_offset;
+        // Nothing from here on can fail, so the file and index always match
+        *file = temp_file;
+        *log.file_size.lock().unwrap() = new_offset;
+        *index = new_index;
+        index.install();
+        *log.filter.write().unwrap() = new_filter;
+        log.save_filter(&self.storage, &**index, new_offset);
+        let version_size = if version.is_some() { VERSION_RECORD_SIZE } else { 0 };
+        log.live_bytes.store(new_offset - version_size, Ordering::Relaxed);
+        if let (Some(history), Some((version, timestamp))) = (&mut history, version) {
+            for (key, pos) in positions {
+                history.record(key, VersionEntry { version, timestamp, pos: Some(pos) });
+            }
         }
         
-        {
-            let mut index_lock = self.index.write().unwrap();
-            *index_lock = new_index;
+        Ok(())
+    }
+    
+    // Garbage collect the database to reclaim space
+    fn garbage_collect(&self) -> Result<()> {
+        let started = Instant::now();
+        
+        // Hold the index and file locks for the whole collection so that
+        // no write can land in the old file after it has been copied
+        let log = self.log_store("Garbage collection")?;
+        let mut index = log.index.write().unwrap();
+        let mut file = log.file.lock().unwrap();
+        
+        // Create a temporary file for the new data
+        let temp_file = self.storage.create("temp.db")?;
+        let mut writer = BufWriter::new(FileWriter(temp_file.clone()));
+        
+        // Initialize a new index and filter for the compacted data
+        let mut new_index = index.rebuild()?;
+        let mut new_filter = BloomFilter::new(filter_capacity(index.len()));
+        
+        // Start at the beginning of the temporary file
+        let mut new_offset = 0u64;
+        
+        // The bytes of the records copied for the indexed values
+        let mut new_live_bytes = 0;
+        
+        let mut history = log.history.as_ref().map(|history| history.lock().unwrap());
+        let mut new_history = HashMap::new();
+        if let Some(history) = &history {
+            // Copy the retained versions of every key, each after a Version record.
+            // Keys removed before the retention window are left out altogether.
+            let cutoff = history.cutoff();
+            for key in history.keys() {
+                let key_history = history.get(key).unwrap();
+                let expired = cutoff.map_or(0, |cutoff| key_history.expired(cutoff));
+                if expired == key_history.entries.len() {
+                    continue;
+                }
+                let truncated = key_history.truncated || expired > 0;
+                let mut entries = VecDeque::with_capacity(key_history.entries.len() - expired);
+                let mut current_bytes = 0;
+                for (i, entry) in key_history.entries.iter().skip(expired).enumerate() {
+                    write_version_record(&mut writer, entry.version, entry.timestamp, i == 0 && truncated)?;
+                    new_offset += VERSION_RECORD_SIZE;
+                    let start = new_offset;
+                    let pos = match &entry.pos {
+                        Some(pos) => Some(self.copy_value(&**file, &mut writer, &mut new_offset, key, pos)?),
+                        None => {
+                            writer.write_u8(OpType::Remove as u8)?;
+                            writer.write_i64::<LittleEndian>(key)?;
+                            new_offset += 1 + 8; // op_type + key
+                            None
+                        }
+                    };
+                    entries.push_back(VersionEntry { pos, ..*entry });
+                    current_bytes = new_offset - start;
+                }
+                
+                // Index the current version, unless the key was removed
+                if let Some(pos) = entries.back().and_then(|entry| entry.pos) {
+                    new_index.push(key, pos)?;
+                    new_filter.insert(key);
+                    new_live_bytes += current_bytes;
+                }
+                new_history.insert(key, KeyHistory { entries, truncated });
+            }
+        } else {
+            // For each live key in the index, write it to the new file.
+            // Tombstones are not carried over: the new file holds no older values
+            // they would have to shadow.
+            index.for_each(&mut |key, pos| {
+                let start = new_offset;
+                let new_pos = self.copy_value(&**file, &mut writer, &mut new_offset, key, &pos)?;
+                new_live_bytes += new_offset - start;
+                
+                // Update the new index
+                new_index.push(key, new_pos)?;
+                new_filter.insert(key);
+                
+                Ok(())
+            })?;
+        }
+        
+        // Chunks of blobs still being written aren't in the index yet, but will be
+        // once their writers commit, so they are copied too
+        let mut pending_blobs = log.pending_blobs.lock().unwrap();
+        let mut new_pending_blobs = HashMap::with_capacity(pending_blobs.len());
+        for (&writer_id, blob) in pending_blobs.iter() {
+            let chunks = blob
+                .chunks
+                .iter()
+                .map(|chunk| self.copy_chunk(&**file, &mut writer, &mut new_offset, blob.key, chunk))
+                .collect::<Result<Vec<_>>>()?;
+            new_pending_blobs.insert(writer_id, PendingBlob { key: blob.key, chunks });
+        }
+        
+        // Flush and sync the temporary file
+        writer.flush()?;
+        temp_file.sync()?;
+        
+        let new_index = new_index.finish(new_offset)?;
+        
+        // Replace the old file with the new one. A persisted index and filter for the old
+        // file are removed first, so a crash in between can't pair them with the new one.
+        index.invalidate()?;
+        bloom::invalidate(&*self.storage)?;
+        self.storage.rename("temp.db", "data.db")?;
+        
+        // Nothing from here on can fail, so the file and index always match
+        *file = temp_file;
+        *log.file_size.lock().unwrap() = new_offset;
+        *index = new_index;
+        index.install();
+        *log.filter.write().unwrap() = new_filter;
+        log.save_filter(&self.storage, &**index, new_offset);
+        if let Some(history) = &mut history {
+            history.replace_keys(new_history);
         }
+        *pending_blobs = new_pending_blobs;
+        log.live_bytes.store(new_live_bytes, Ordering::Relaxed);
+        self.metrics.record_gc(started.elapsed());
+        
+        Ok(())
+    }
+    
+    // Copy a chunk of a large value to the compacted file, returning its position there
+    fn copy_chunk(&self, file: &dyn StorageFile, writer: &mut impl Write, new_offset: &mut u64, key: i64, chunk: &ValuePos) -> Result<ValuePos> {
+        let payload = read_payload(file, chunk)?;
+        let (flags, payload) = self.rotate_payload(key, chunk.flags, payload)?;
+        write_record(writer, OpType::Chunk as u8 | flags, key, &payload)?;
+        
+        let new_pos = ValuePos {
+            offset: *new_offset + 1 + 8 + 8, // op_type + key + value_size
+            size: payload.len() as u64,
+            flags,
+        };
+        *new_offset = new_pos.offset + new_pos.size;
+        Ok(new_pos)
+    }
+}
+
+impl LogStore {
+    // Open the data file and index of a log-structured database.
+    // The index still has to be brought up to date with `KvDb::load_index`.
+    fn open(storage: &Arc<dyn Storage>, index_kind: IndexKind, max_versions: usize, retention: Option<Duration>) -> Result<Self> {
+        // Open the data file, create it if it doesn't exist
+        let file = storage.open_or_create("data.db")?;
+        
+        // Get the current size of the file
+        let file_size = file.size()?;
+        
+        // Open the index; a persistent index may already cover part of the file
+        let index = open_index(index_kind, storage.clone())?;
+        
+        // Use the persisted filter if it covers as much of the file as the index,
+        // otherwise build one from the index. Either way the rest of the file
+        // is added by `KvDb::load_index`.
+        let filter = match bloom::load(&**storage, index.persisted_log_offset()) {
+            Ok(Some(filter)) => filter,
+            Ok(None) => filter_from_index(&*index)?,
+            Err(err) => {
+                log::warn!("Rebuilding unreadable bloom filter: {}", err);
+                filter_from_index(&*index)?
+            }
+        };
         
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+            index: Arc::new(RwLock::new(index)),
+            filter: Arc::new(RwLock::new(filter)),
+            file_size: Arc::new(Mutex::new(file_size)),
+            pending_blobs: Mutex::new(HashMap::new()),
+            next_blob_writer: AtomicU64::new(0),
+            history: (max_versions > 1).then(|| Mutex::new(History::new(max_versions, retention))),
+            live_bytes: AtomicU64::new(0),
+        })
+    }
+    
+    // Start a new version for a write if history is kept, writing its Version
+    // record to the front of the records of the write. The history stays locked
+    // until the versions of the keys written are recorded.
+    fn begin_version(&self, records: &mut Vec<u8>) -> io::Result<Option<(MutexGuard<'_, History>, u64, u64)>> {
+        let Some(history) = &self.history else {
+            return Ok(None);
+        };
+        let mut history = history.lock().unwrap();
+        let (version, timestamp) = history.next_version();
+        write_version_record(records, version, timestamp, false)?;
+        Ok(Some((history, version, timestamp)))
+    }
+    
+    // Record a version of a key read from the log, if history is kept
+    fn record_version(&self, key: i64, (version, timestamp): (u64, u64), truncated: bool, pos: Option<ValuePos>) {
+        if let Some(history) = &self.history {
+            let mut history = history.lock().unwrap();
+            if truncated {
+                history.truncate(key);
+            }
+            history.record(key, VersionEntry { version, timestamp, pos });
+        }
+    }
+    
+    // Add a newly indexed key to the filter. A filter holding more keys than it
+    // was sized for is rebuilt from the index, with room to grow.
+    fn add_to_filter(&self, index: &dyn Index, key: i64) -> Result<()> {
+        let mut filter = self.filter.write().unwrap();
+        filter.insert(key);
+        if filter.is_full() {
+            *filter = filter_from_index(index)?;
+        }
         Ok(())
     }
+    
+    // Persist the filter alongside an index that covers the file up to `log_offset`.
+    // This can't fail: a filter that isn't saved is rebuilt from the index on open.
+    fn save_filter(&self, storage: &Arc<dyn Storage>, index: &dyn Index, log_offset: u64) {
+        if index.persisted_log_offset() != log_offset {
+            return;
+        }
+        if let Err(err) = bloom::save(storage, &self.filter.read().unwrap(), log_offset) {
+            log::warn!("Failed to save the bloom filter: {}", err);
+        }
+    }
+}
+
+// Convert a stored value to a string, replacing any invalid UTF-8
+fn into_string(value: Vec<u8>) -> String {
+    String::from_utf8(value).unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned())
+}
+
+// Add to a counter stored as a decimal integer, which is zero if there is no value
+fn add_to_counter(value: Option<&[u8]>, delta: i64) -> Result<i64> {
+    let counter = match value {
+        Some(value) => std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .ok_or(KvError::NotAnInteger)?,
+        None => 0,
+    };
+    counter.checked_add(delta).ok_or(KvError::Overflow)
+}
+
+// The smallest number of keys a filter is sized for
+const MIN_FILTER_KEYS: usize = 1024;
+
+// The number of keys to size a filter for, given the number of keys now
+fn filter_capacity(len: usize) -> usize {
+    (len * 2).max(MIN_FILTER_KEYS)
+}
+
+// Build a filter of the keys in an index
+fn filter_from_index(index: &dyn Index) -> Result<BloomFilter> {
+    let mut filter = BloomFilter::new(filter_capacity(index.len()));
+    index.for_each(&mut |key, _| {
+        filter.insert(key);
+        Ok(())
+    })?;
+    Ok(filter)
 }
 
 // Implement Drop for KvDb to ensure resources are properly closed
@@ -445,6 +2419,7 @@ mod tests {
     use super::*;
     use std::fs;
     use std::path::PathBuf;
+    use std::time::SystemTime;
     
     fn setup_test_db() -> (KvDb, PathBuf) {
         let test_dir = PathBuf::from("test_db");
@@ -455,88 +2430,1265 @@ mod tests {
         let config = Config {
             path: test_dir.clone(),
             gc_threshold: 1024 * 1024, // 1MB for tests
+            ..Config::default()
+        };
+        
+        let db = KvDb::open(config).unwrap();
+        (db, test_dir)
+    }
+    
+    #[test]
+    fn test_set_get() {
+        let (db, test_dir) = setup_test_db();
+        
+        // Test setting and getting a key
+        assert_eq!(db.set(1, "value1").unwrap(), None);
+        assert_eq!(db.get(1).unwrap(), Some("value1".to_string()));
+        
+        // Test overwriting a key
+        assert_eq!(db.set(1, "value2").unwrap(), Some("value1".to_string()));
+        assert_eq!(db.get(1).unwrap(), Some("value2".to_string()));
+        
+        // Clean up
+        let _ = fs::remove_dir_all(test_dir);
+    }
+    
+    #[test]
+    fn test_remove() {
+        // Use a unique directory for this test to avoid conflicts
+        let test_dir = PathBuf::from("test_remove_db");
+        // Clean up any previous test data
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        
+        let config = Config {
+            path: test_dir.clone(),
+            gc_threshold: 1024 * 1024, // 1MB for tests
+            ..Config::default()
+        };
+        
+        let db = KvDb::open(config).unwrap();
+        
+        // Set a key
+        assert_eq!(db.set(1, "value1").unwrap(), None);
+        assert_eq!(db.get(1).unwrap(), Some("value1".to_string()));
+        
+        // Remove the key
+        let remove_result = db.remove(1).unwrap();
+        assert_eq!(remove_result, Some("value1".to_string()));
+        assert_eq!(db.get(1).unwrap(), None);
+        
+        // Remove a non-existent key
+        assert_eq!(db.remove(2).unwrap(), None);
+        
+        // Clean up
+        drop(db); // Ensure db is closed before removing the directory
+        let _ = fs::remove_dir_all(test_dir);
+    }
+    
+    #[test]
+    fn test_persistence() {
+        let test_dir = PathBuf::from("test_persistence");
+        // Clean up any previous test data
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        
+        let config = Config {
+            path: test_dir.clone(),
+            gc_threshold: 1024 * 1024, // 1MB for tests
+            ..Config::default()
+        };
+        
+        // Create a database and write some data
+        {
+            let db = KvDb::open(config.clone()).unwrap();
+            db.set(1, "value1").unwrap();
+            db.set(2, "value2").unwrap();
+            db.remove(1).unwrap();
+        }
+        
+        // Open the database again and check the data
+        {
+            let db = KvDb::open(config).unwrap();
+            assert_eq!(db.get(1).unwrap(), None);
+            assert_eq!(db.get(2).unwrap(), Some("value2".to_string()));
+        }
+        
+        // Clean up
+        let _ = fs::remove_dir_all(test_dir);
+    }
+    
+    #[test]
+    fn test_compression() {
+        let test_dir = PathBuf::from("test_compression");
+        // Clean up any previous test data
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        
+        let large_value = "{\"field\": \"some repetitive json\"}".repeat(64);
+        let config = Config {
+            path: test_dir.clone(),
+            gc_threshold: 1024 * 1024, // 1MB for tests
+            ..Config::default()
+        };
+        
+        // Write some uncompressed records
+        {
+            let db = KvDb::open(config.clone()).unwrap();
+            db.set(1, &large_value).unwrap();
+        }
+        
+        // Append compressed records with each codec to the same file
+        for compression in [Compression::Lz4, Compression::Zstd(3)] {
+            let db = KvDb::open(Config { compression, ..config.clone() }).unwrap();
+            db.set(2, &large_value).unwrap();
+            db.set(3, "short").unwrap();
+        }
+        
+        // The compressed records take less space than the raw one
+        let file_size = fs::metadata(test_dir.join("data.db")).unwrap().len();
+        assert!(file_size < 2 * large_value.len() as u64);
+        
+        // Mixed records are readable after a restart and after garbage collection
+        let db = KvDb::open(config).unwrap();
+        for _ in 0..2 {
+            assert_eq!(db.get(1).unwrap(), Some(large_value.clone()));
+            assert_eq!(db.get(2).unwrap(), Some(large_value.clone()));
+            assert_eq!(db.get(3).unwrap(), Some("short".to_string()));
+            
+            db.garbage_collect().unwrap();
+            *db.cache.lock().unwrap() = ValueCache::new(db.config.cache_policy, db.config.cache_capacity_bytes, 0);
+        }
+        
+        // Clean up
+        drop(db);
+        let _ = fs::remove_dir_all(test_dir);
+    }
+    
+    #[test]
+    fn test_encryption() {
+        let test_dir = PathBuf::from("test_encryption");
+        // Clean up any previous test data
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        
+        let old_key = EncryptionKey::new([1; 32]);
+        let new_key = EncryptionKey::new([2; 32]);
+        let config = Config {
+            path: test_dir.clone(),
+            gc_threshold: 1024 * 1024, // 1MB for tests
+            compression: Compression::Lz4,
+            encryption_key: Some(old_key.clone()),
+            ..Config::default()
+        };
+        
+        // Write some encrypted values
+        {
+            let db = KvDb::open(config.clone()).unwrap();
+            db.set(1, "top secret").unwrap();
+            db.set(2, &"regulated data ".repeat(32)).unwrap();
+        }
+        
+        // The plaintext never reaches the file
+        let contents = fs::read(test_dir.join("data.db")).unwrap();
+        assert!(!contents.windows(10).any(|window| window == b"top secret"));
+        
+        // Opening without the right key fails clearly
+        let result = KvDb::open(Config { encryption_key: None, ..config.clone() });
+        assert!(matches!(result, Err(KvError::WrongEncryptionKey)));
+        let result = KvDb::open(Config { encryption_key: Some(new_key.clone()), ..config.clone() });
+        assert!(matches!(result, Err(KvError::WrongEncryptionKey)));
+        
+        // Rotate to the new key during garbage collection
+        let rotated = Config {
+            encryption_key: Some(new_key),
+            previous_encryption_keys: vec![old_key],
+            ..config.clone()
+        };
+        {
+            let db = KvDb::open(rotated.clone()).unwrap();
+            assert_eq!(db.get(1).unwrap(), Some("top secret".to_string()));
+            db.garbage_collect().unwrap();
+        }
+        
+        // The old key is no longer needed
+        let db = KvDb::open(Config { previous_encryption_keys: Vec::new(), ..rotated }).unwrap();
+        assert_eq!(db.get(1).unwrap(), Some("top secret".to_string()));
+        assert_eq!(db.get(2).unwrap(), Some("regulated data ".repeat(32)));
+        drop(db);
+        
+        let result = KvDb::open(config);
+        assert!(matches!(result, Err(KvError::WrongEncryptionKey)));
+        
+        // Clean up
+        let _ = fs::remove_dir_all(test_dir);
+    }
+    
+    #[test]
+    fn test_large_values() {
+        let test_dir = PathBuf::from("test_large_values");
+        // Clean up any previous test data
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        
+        let config = Config {
+            path: test_dir.clone(),
+            gc_threshold: 1024 * 1024, // 1MB for tests
+            compression: Compression::Lz4,
+            encryption_key: Some(EncryptionKey::new([3; 32])),
+            chunk_size: 64,
+            ..Config::default()
+        };
+        let large_value: String = (0..1000).map(|i| format!("{},", i)).collect();
+        
+        let db = KvDb::open(config.clone()).unwrap();
+        
+        // Large values set directly are stored in chunks
+        db.set(1, &large_value).unwrap();
+        assert_eq!(db.get(1).unwrap(), Some(large_value.clone()));
+        
+        // Large values can be written piece by piece
+        let mut writer = db.blob_writer(2).unwrap();
+        for piece in large_value.as_bytes().chunks(100) {
+            writer.write_all(piece).unwrap();
+        }
+        assert_eq!(db.get(2).unwrap(), None);
+        writer.finish().unwrap();
+        
+        // And streamed back out
+        let mut value = String::new();
+        db.get_reader(2).unwrap().unwrap().read_to_string(&mut value).unwrap();
+        assert_eq!(value, large_value);
+        assert!(db.get_reader(3).unwrap().is_none());
+        
+        // An abandoned blob leaves the old value in place
+        let mut writer = db.blob_writer(1).unwrap();
+        writer.write_all(&[0; 1000]).unwrap();
+        drop(writer);
+        assert_eq!(db.get(1).unwrap(), Some(large_value.clone()));
+        
+        // A reader keeps working while garbage collection replaces the file
+        let mut reader = db.get_reader(1).unwrap().unwrap();
+        let mut start = [0; 10];
+        reader.read_exact(&mut start).unwrap();
+        db.garbage_collect().unwrap();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(format!("{}{}", String::from_utf8_lossy(&start), rest), large_value);
+        drop(reader);
+        
+        // Garbage collection runs while a blob is being written, keeping its chunks
+        let mut writer = db.blob_writer(3).unwrap();
+        writer.write_all(&large_value.as_bytes()[..500]).unwrap();
+        db.garbage_collect().unwrap();
+        assert_eq!(db.stats().unwrap().gc_runs, 2);
+        writer.write_all(&large_value.as_bytes()[500..]).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(db.get(3).unwrap(), Some(large_value.clone()));
+        drop(db);
+        
+        // Chunked values survive a restart
+        let db = KvDb::open(config.clone()).unwrap();
+        assert_eq!(db.get(1).unwrap(), Some(large_value.clone()));
+        assert_eq!(db.get(2).unwrap(), Some(large_value.clone()));
+        assert_eq!(db.get(3).unwrap(), Some(large_value));
+        drop(db);
+        
+        // Chunks have to hold something
+        let result = KvDb::open(Config { chunk_size: 0, ..config });
+        assert!(matches!(result, Err(KvError::InvalidConfig(_))));
+        
+        // Clean up
+        let _ = fs::remove_dir_all(test_dir);
+    }
+    
+    #[test]
+    fn test_tombstone_reclamation() {
+        let test_dir = PathBuf::from("test_tombstones");
+        // Clean up any previous test data
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        
+        let config = Config {
+            path: test_dir.clone(),
+            gc_threshold: 1024 * 1024, // 1MB for tests
+            ..Config::default()
         };
         
-        let db = KvDb::open(config).unwrap();
-        (db, test_dir)
-    }
-    
-    #[test]
-    fn test_set_get() {
-        let (db, test_dir) = setup_test_db();
+        // Write and delete many unique keys
+        {
+            let db = KvDb::open(config.clone()).unwrap();
+            for key in 0..1000 {
+                db.set(key, "value").unwrap();
+                db.remove(key).unwrap();
+            }
+            db.set(1000, "kept").unwrap();
+            
+            // Deleted keys don't linger in the index
+            assert_eq!(db.len(), 1);
+        }
         
-        // Test setting and getting a key
-        assert_eq!(db.set(1, "value1").unwrap(), None);
-        assert_eq!(db.get(1).unwrap(), Some("value1".to_string()));
+        // The tombstones in the log keep the keys deleted after a restart
+        {
+            let db = KvDb::open(config.clone()).unwrap();
+            assert_eq!(db.len(), 1);
+            assert_eq!(db.get(0).unwrap(), None);
+            
+            // Compaction drops them from the file as well
+            db.garbage_collect().unwrap();
+            let file_size = fs::metadata(test_dir.join("data.db")).unwrap().len();
+            assert_eq!(file_size, 1 + 8 + 8 + 4);
+        }
         
-        // Test overwriting a key
-        assert_eq!(db.set(1, "value2").unwrap(), Some("value1".to_string()));
-        assert_eq!(db.get(1).unwrap(), Some("value2".to_string()));
+        // And the keys stay deleted after compaction and a restart
+        let db = KvDb::open(config).unwrap();
+        for key in 0..1000 {
+            assert_eq!(db.get(key).unwrap(), None);
+        }
+        assert_eq!(db.get(1000).unwrap(), Some("kept".to_string()));
         
         // Clean up
+        drop(db);
         let _ = fs::remove_dir_all(test_dir);
     }
     
     #[test]
-    fn test_remove() {
-        // Use a unique directory for this test to avoid conflicts
-        let test_dir = PathBuf::from("test_remove_db");
+    fn test_index_kinds() {
+        for (kind, dir) in [
+            (IndexKind::Memory, "test_index_kinds_memory"),
+            (IndexKind::Disk { max_buffered_keys: 16 }, "test_index_kinds_disk"),
+        ] {
+            let test_dir = PathBuf::from(dir);
+            // Clean up any previous test data
+            let _ = fs::remove_dir_all(&test_dir);
+            fs::create_dir_all(&test_dir).unwrap();
+            
+            let config = Config {
+                path: test_dir.clone(),
+                gc_threshold: 1024 * 1024, // 1MB for tests
+                index: kind,
+                chunk_size: 64,
+                ..Config::default()
+            };
+            let large_value = "large".repeat(100);
+            
+            {
+                let db = KvDb::open(config.clone()).unwrap();
+                for key in 0..100 {
+                    db.set(key, &format!("value{}", key)).unwrap();
+                }
+                for key in (0..100).step_by(2) {
+                    db.remove(key).unwrap();
+                }
+                db.set(1, &large_value).unwrap();
+                assert_eq!(db.len(), 50);
+            }
+            
+            // Keys survive a restart, whether the index is rebuilt or reloaded
+            {
+                let db = KvDb::open(config.clone()).unwrap();
+                assert_eq!(db.len(), 50);
+                assert_eq!(db.get(2).unwrap(), None);
+                assert_eq!(db.get(3).unwrap(), Some("value3".to_string()));
+                
+                // Writes after the index was persisted are replayed on the next open
+                db.set(200, "late").unwrap();
+                db.remove(3).unwrap();
+                
+                // Simulate a crash by skipping close
+                std::mem::forget(db);
+            }
+            
+            // And garbage collection
+            {
+                let db = KvDb::open(config.clone()).unwrap();
+                assert_eq!(db.get(200).unwrap(), Some("late".to_string()));
+                assert_eq!(db.get(3).unwrap(), None);
+                db.garbage_collect().unwrap();
+                assert_eq!(db.len(), 50);
+            }
+            
+            let db = KvDb::open(config).unwrap();
+            assert_eq!(db.len(), 50);
+            assert_eq!(db.get(1).unwrap(), Some(large_value));
+            assert_eq!(db.get(5).unwrap(), Some("value5".to_string()));
+            assert_eq!(db.get(200).unwrap(), Some("late".to_string()));
+            
+            // Clean up
+            drop(db);
+            let _ = fs::remove_dir_all(test_dir);
+        }
+        
+        // The buffer has to hold at least one change
+        let config = Config {
+            path: PathBuf::from("test_index_kinds_invalid"),
+            index: IndexKind::Disk { max_buffered_keys: 0 },
+            ..Config::default()
+        };
+        assert!(matches!(KvDb::open(config), Err(KvError::InvalidConfig(_))));
+    }
+    
+    #[test]
+    fn test_lsm_engine() {
+        let test_dir = PathBuf::from("test_lsm_engine");
         // Clean up any previous test data
         let _ = fs::remove_dir_all(&test_dir);
         fs::create_dir_all(&test_dir).unwrap();
         
+        // Small limits, so the memtable is flushed and compacted many times over
         let config = Config {
             path: test_dir.clone(),
-            gc_threshold: 1024 * 1024, // 1MB for tests
+            engine: EngineKind::Lsm(LsmOptions {
+                memtable_size: 4 * 1024,
+                level0_tables: 2,
+                level1_size: 8 * 1024,
+                level_multiplier: 2,
+                table_size: 4 * 1024,
+            }),
+            compression: Compression::Lz4,
+            ..Config::default()
         };
         
-        let db = KvDb::open(config).unwrap();
+        {
+            let db = KvDb::open(config.clone()).unwrap();
+            for round in 0..5 {
+                for key in 0..500 {
+                    db.set(key, &format!("value{}-{}", key, round)).unwrap();
+                }
+            }
+            for key in (0..500).step_by(2) {
+                assert_eq!(db.remove(key).unwrap(), Some(format!("value{}-4", key)));
+            }
+            
+            // The key count is an estimate, as overwritten keys are only found out by a flush
+            assert_eq!(db.scan(..).unwrap().len(), 250);
+            assert!(db.len() >= 250);
+            
+            // Large values are stored inline rather than streamed
+            assert!(matches!(db.blob_writer(1), Err(KvError::Unsupported(_))));
+        }
         
-        // Set a key
-        assert_eq!(db.set(1, "value1").unwrap(), None);
-        assert_eq!(db.get(1).unwrap(), Some("value1".to_string()));
+        {
+            let db = KvDb::open(config.clone()).unwrap();
+            assert_eq!(db.scan(..).unwrap().len(), 250);
+            assert!(db.len() >= 250);
+            assert_eq!(db.get(2).unwrap(), None);
+            assert_eq!(db.get(3).unwrap(), Some("value3-4".to_string()));
+            
+            // Writes that only made it to the write-ahead log are replayed on open
+            db.set(1000, "late").unwrap();
+            db.remove(3).unwrap();
+            std::mem::forget(db);
+        }
         
-        // Remove the key
-        let remove_result = db.remove(1).unwrap();
-        assert_eq!(remove_result, Some("value1".to_string()));
-        assert_eq!(db.get(1).unwrap(), None);
+        let db = KvDb::open(config).unwrap();
+        assert_eq!(db.scan(..).unwrap().len(), 250);
+        assert!(db.len() >= 250);
+        assert_eq!(db.get(1000).unwrap(), Some("late".to_string()));
+        assert_eq!(db.get(3).unwrap(), None);
         
-        // Remove a non-existent key
-        assert_eq!(db.remove(2).unwrap(), None);
+        // Keys come back in order
+        let entries = db.scan(..10).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (1, "value1-4".to_string()),
+                (5, "value5-4".to_string()),
+                (7, "value7-4".to_string()),
+                (9, "value9-4".to_string()),
+            ]
+        );
+        assert_eq!(db.scan(495..1000).unwrap().len(), 3);
         
         // Clean up
-        drop(db); // Ensure db is closed before removing the directory
+        drop(db);
         let _ = fs::remove_dir_all(test_dir);
     }
     
     #[test]
-    fn test_persistence() {
-        let test_dir = PathBuf::from("test_persistence");
+    fn test_scan() {
+        let test_dir = PathBuf::from("test_scan");
         // Clean up any previous test data
         let _ = fs::remove_dir_all(&test_dir);
         fs::create_dir_all(&test_dir).unwrap();
         
-        let config = Config {
+        let db = KvDb::open(Config {
             path: test_dir.clone(),
-            gc_threshold: 1024 * 1024, // 1MB for tests
+            ..Config::default()
+        })
+        .unwrap();
+        
+        for key in [5, -3, 12, 0, 7] {
+            db.set(key, &format!("value{}", key)).unwrap();
+        }
+        db.remove(7).unwrap();
+        
+        let keys: Vec<i64> = db.scan(0..=12).unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![0, 5, 12]);
+        assert_eq!(db.scan(..0).unwrap(), vec![(-3, "value-3".to_string())]);
+        
+        // Clean up
+        drop(db);
+        let _ = fs::remove_dir_all(test_dir);
+    }
+    
+    #[test]
+    fn test_memory_storage() {
+        for engine in [EngineKind::Log, EngineKind::Lsm(LsmOptions::default())] {
+            // Nothing touches the filesystem, and clones of the storage share its files
+            let storage = MemoryStorage::new();
+            let config = Config {
+                path: PathBuf::from("test_memory_storage_unused"),
+                storage: Some(Arc::new(storage.clone())),
+                gc_threshold: 8 * 1024,
+                index: IndexKind::Disk { max_buffered_keys: 16 },
+                chunk_size: 64,
+                engine,
+                ..Config::default()
+            };
+            
+            {
+                let db = KvDb::open(config.clone()).unwrap();
+                for key in 0..200 {
+                    db.set(key, &format!("value{}", key).repeat(10)).unwrap();
+                }
+                for key in (0..200).step_by(2) {
+                    db.remove(key).unwrap();
+                }
+            }
+            assert!(!config.path.exists());
+            assert!(!storage.list().unwrap().is_empty());
+            
+            let db = KvDb::open(config).unwrap();
+            assert_eq!(db.len(), 100);
+            assert_eq!(db.get(2).unwrap(), None);
+            assert_eq!(db.get(3).unwrap(), Some("value3".repeat(10)));
+        }
+    }
+
+    #[test]
+    fn test_cache_capacity() {
+        // Room for about ten of the values below
+        let db = KvDb::open(Config {
+            storage: Some(Arc::new(MemoryStorage::new())),
+            cache_capacity_bytes: 10 * (8 + 100),
+            ..Config::default()
+        })
+        .unwrap();
+        
+        for key in 0..20 {
+            db.set(key, &"x".repeat(100)).unwrap();
+        }
+        let stats = db.cache_stats();
+        assert_eq!((stats.entries, stats.size_bytes, stats.evictions), (10, 1080, 10));
+        
+        // The last ten keys are cached, the first ten have to be read from the log
+        for key in (0..20).rev() {
+            assert_eq!(db.get(key).unwrap(), Some("x".repeat(100)));
+        }
+        let stats = db.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (10, 30));
+        assert!(stats.size_bytes <= stats.capacity_bytes);
+        
+        // A zero capacity disables the cache
+        let db = KvDb::open(Config {
+            storage: Some(Arc::new(MemoryStorage::new())),
+            cache_capacity_bytes: 0,
+            ..Config::default()
+        })
+        .unwrap();
+        db.set(1, "value").unwrap();
+        assert_eq!(db.get(1).unwrap(), Some("value".to_string()));
+        assert_eq!(db.cache_stats().entries, 0);
+    }
+
+    #[test]
+    fn test_negative_cache() {
+        let storage = MemoryStorage::new();
+        let config = Config {
+            storage: Some(Arc::new(storage.clone())),
+            negative_cache_keys: 2,
+            index: IndexKind::Disk { max_buffered_keys: 4 },
+            ..Config::default()
+        };
+        let db = KvDb::open(config.clone()).unwrap();
+        for key in 0..5 {
+            db.set(key, "value").unwrap();
+        }
+        db.remove(1).unwrap();
+        db.remove(2).unwrap();
+        db.remove(3).unwrap();
+        assert_eq!(db.cache_stats().absent_keys, 2);
+
+        // Only the most recently removed keys are answered from the cache
+        let before = db.cache_stats();
+        assert_eq!(db.get(3).unwrap(), None);
+        assert_eq!(db.get(1).unwrap(), None);
+        assert_eq!(db.get(1).unwrap(), None);
+        let stats = db.cache_stats();
+        assert_eq!((stats.hits - before.hits, stats.misses - before.misses), (0, 1));
+        assert_eq!(stats.negative_hits - before.negative_hits, 2);
+        assert_eq!(stats.absent_keys, 2);
+
+        // Setting a key clears its absent mark
+        db.set(100, "back").unwrap();
+        assert_eq!(db.get(100).unwrap(), Some("back".to_string()));
+        db.close().unwrap();
+
+        // The filter is saved with the index on close and loaded on open
+        assert!(storage.list().unwrap().contains(&"filter.db".to_string()));
+        let db = KvDb::open(config).unwrap();
+        for key in [0, 4, 100] {
+            assert!(db.get(key).unwrap().is_some());
+        }
+        for key in [1, 2, 3, 200] {
+            assert_eq!(db.get(key).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_cache_warm_up() {
+        let storage = MemoryStorage::new();
+        let config = Config {
+            storage: Some(Arc::new(storage.clone())),
+            cache_capacity_bytes: 10 * (8 + 5),
+            hot_keys_interval: Some(Duration::from_millis(10)),
+            ..Config::default()
         };
+        let db = KvDb::open(config.clone()).unwrap();
+        for key in 0..20 {
+            db.set(key, "value").unwrap();
+        }
         
-        // Create a database and write some data
-        {
+        // The hot keys are saved in the background
+        let started = std::time::Instant::now();
+        while !storage.list().unwrap().contains(&"hot_keys.db".to_string()) {
+            assert!(started.elapsed() < Duration::from_secs(10), "hot keys were not saved");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        
+        // and on close, to be prefetched on open
+        for key in 0..5 {
+            db.get(key).unwrap();
+        }
+        db.close().unwrap();
+        let db = KvDb::open(Config {
+            warm_cache_on_open: true,
+            ..config.clone()
+        })
+        .unwrap();
+        assert_eq!(db.cache_stats().entries, 10);
+        let before = db.cache_stats();
+        for key in (0..5).chain(15..20) {
+            assert_eq!(db.get(key).unwrap(), Some("value".to_string()));
+        }
+        assert_eq!(db.cache_stats().hits - before.hits, 10);
+        db.close().unwrap();
+        
+        // Warming up explicitly, as from a background thread
+        let db = KvDb::open(config).unwrap();
+        assert_eq!(db.cache_stats().entries, 0);
+        assert_eq!(db.warm_cache().unwrap(), 10);
+        assert_eq!(db.cache_stats().entries, 10);
+        
+        // Warming up isn't counted as lookups
+        let stats = db.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (0, 0));
+    }
+
+    #[test]
+    fn test_get_many_set_many() {
+        for engine in [EngineKind::Log, EngineKind::Lsm(LsmOptions::default())] {
+            let storage = MemoryStorage::new();
+            let config = Config {
+                storage: Some(Arc::new(storage.clone())),
+                chunk_size: 64,
+                engine,
+                ..Config::default()
+            };
             let db = KvDb::open(config.clone()).unwrap();
-            db.set(1, "value1").unwrap();
-            db.set(2, "value2").unwrap();
-            db.remove(1).unwrap();
+            db.set(1, "old").unwrap();
+            
+            // Later entries for a key win, and large values go in between the others
+            let large = "large".repeat(20);
+            let old_values = db
+                .set_many(&[(1, "one"), (2, "two"), (3, &large), (4, "four"), (2, "second")])
+                .unwrap();
+            assert_eq!(old_values, vec![Some("old".to_string()), None, None, None, None]);
+            
+            let expected = vec![
+                Some("four".to_string()),
+                None,
+                Some("one".to_string()),
+                Some("second".to_string()),
+                Some(large.clone()),
+                Some("one".to_string()),
+            ];
+            assert_eq!(db.get_many(&[4, 5, 1, 2, 3, 1]).unwrap(), expected);
+            
+            // Once again from the files, without the cache
+            drop(db);
+            let db = KvDb::open(config).unwrap();
+            assert_eq!(db.len(), 4);
+            assert_eq!(db.get_many(&[4, 5, 1, 2, 3, 1]).unwrap(), expected);
+            assert_eq!(db.get_many(&[]).unwrap(), Vec::<Option<String>>::new());
+        }
+    }
+
+    #[test]
+    fn test_counters() {
+        for engine in [EngineKind::Log, EngineKind::Lsm(LsmOptions::default())] {
+            let db = Arc::new(
+                KvDb::open(Config {
+                    storage: Some(Arc::new(MemoryStorage::new())),
+                    engine,
+                    ..Config::default()
+                })
+                .unwrap(),
+            );
+            
+            // Missing keys start at zero
+            assert_eq!(db.incr(1, 5).unwrap(), 5);
+            assert_eq!(db.decr(1, 7).unwrap(), -2);
+            assert_eq!(db.get(1).unwrap(), Some("-2".to_string()));
+            
+            // Bad values and overflows leave the value alone
+            db.set(2, "two").unwrap();
+            assert!(matches!(db.incr(2, 1), Err(KvError::NotAnInteger)));
+            db.set(3, &i64::MAX.to_string()).unwrap();
+            assert!(matches!(db.incr(3, 1), Err(KvError::Overflow)));
+            assert!(matches!(db.decr(1, i64::MIN), Err(KvError::Overflow)));
+            assert_eq!(db.get(3).unwrap(), Some(i64::MAX.to_string()));
+            
+            // No update is lost to concurrent ones
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    let db = db.clone();
+                    std::thread::spawn(move || {
+                        for _ in 0..100 {
+                            db.incr(4, 1).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(db.get(4).unwrap(), Some("400".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        for index in [IndexKind::Memory, IndexKind::Disk { max_buffered_keys: 4 }] {
+            let storage = MemoryStorage::new();
+            let config = Config {
+                storage: Some(Arc::new(storage.clone())),
+                index,
+                chunk_size: 64,
+                merge_operator: Some(Arc::new(AppendOperator { delimiter: ",".to_string() })),
+                ..Config::default()
+            };
+            let db = KvDb::open(config.clone()).unwrap();
+            db.set(1, "a").unwrap();
+            db.merge(1, "b").unwrap();
+            assert_eq!(db.get(1).unwrap(), Some("a,b".to_string()));
+            
+            // Cached values are updated as operands come in
+            db.merge(1, "c").unwrap();
+            db.merge(2, "x").unwrap();
+            db.set(3, &"large".repeat(20)).unwrap();
+            db.merge(3, "y").unwrap();
+            db.merge(4, "gone").unwrap();
+            db.remove(4).unwrap();
+            db.merge(4, "back").unwrap();
+            
+            let expected = vec![
+                Some("a,b,c".to_string()),
+                Some("x".to_string()),
+                Some(format!("{},y", "large".repeat(20))),
+                Some("back".to_string()),
+            ];
+            assert_eq!(db.get_many(&[1, 2, 3, 4]).unwrap(), expected);
+            
+            // The chains are resolved from the log after a restart
+            drop(db);
+            let db = KvDb::open(config.clone()).unwrap();
+            assert_eq!(db.get_many(&[1, 2, 3, 4]).unwrap(), expected);
+            
+            // and rewritten as plain values by garbage collection
+            db.garbage_collect().unwrap();
+            db.merge(2, "z").unwrap();
+            assert_eq!(db.scan(1..=2).unwrap(), vec![(1, "a,b,c".to_string()), (2, "x,z".to_string())]);
+            drop(db);
+            
+            // Merges can't be made or read without the operator, but can be overwritten
+            let db = KvDb::open(Config {
+                merge_operator: None,
+                ..config
+            })
+            .unwrap();
+            assert!(matches!(db.merge(1, "d"), Err(KvError::Merge(_))));
+            assert_eq!(db.get(1).unwrap(), Some("a,b,c".to_string()));
+            assert!(matches!(db.get(2), Err(KvError::Merge(_))));
+            assert_eq!(db.set(2, "plain").unwrap(), None);
+            assert_eq!(db.get(2).unwrap(), Some("plain".to_string()));
         }
         
-        // Open the database again and check the data
-        {
+        // Operands the operator rejects are kept through garbage collection
+        let storage = MemoryStorage::new();
+        let config = Config {
+            storage: Some(Arc::new(storage.clone())),
+            merge_operator: Some(Arc::new(JsonMergePatchOperator)),
+            ..Config::default()
+        };
+        let db = KvDb::open(config.clone()).unwrap();
+        db.set(1, r#"{"a":1}"#).unwrap();
+        db.merge(1, r#"{"b":2}"#).unwrap();
+        db.merge(1, "not json").unwrap();
+        db.set(2, r#"{"c":3}"#).unwrap();
+        db.merge(2, r#"{"c":null}"#).unwrap();
+        assert!(matches!(db.get(1), Err(KvError::Merge(_))));
+        db.garbage_collect().unwrap();
+        drop(db);
+        
+        let db = KvDb::open(config).unwrap();
+        assert!(matches!(db.get(1), Err(KvError::Merge(_))));
+        assert_eq!(db.get(2).unwrap(), Some("{}".to_string()));
+        assert_eq!(db.remove(1).unwrap(), None);
+        assert_eq!(db.get(1).unwrap(), None);
+        
+        // The LSM engine applies operands as they are written
+        let db = KvDb::open(Config {
+            storage: Some(Arc::new(MemoryStorage::new())),
+            engine: EngineKind::Lsm(LsmOptions::default()),
+            merge_operator: Some(Arc::new(AppendOperator { delimiter: ",".to_string() })),
+            ..Config::default()
+        })
+        .unwrap();
+        db.merge(1, "a").unwrap();
+        db.merge(1, "b").unwrap();
+        assert_eq!(db.get(1).unwrap(), Some("a,b".to_string()));
+    }
+
+    #[test]
+    fn test_column_families() {
+        let storage = MemoryStorage::new();
+        let config = Config {
+            storage: Some(Arc::new(storage.clone())),
+            column_families: HashMap::from([(
+                "small".to_string(),
+                ColumnFamilyOptions {
+                    engine: EngineKind::Lsm(LsmOptions::default()),
+                    cache_capacity_bytes: 0,
+                    ..ColumnFamilyOptions::default()
+                },
+            )]),
+            ..Config::default()
+        };
+        let db = KvDb::open(config.clone()).unwrap();
+        
+        // Each column family has a key space of its own
+        db.set(1, "default").unwrap();
+        let users = db.cf("users").unwrap();
+        users.set(1, "user").unwrap();
+        users.set(2, "other user").unwrap();
+        let small = db.cf("small").unwrap();
+        small.set(1, "small").unwrap();
+        assert_eq!(db.get(1).unwrap(), Some("default".to_string()));
+        assert_eq!(users.get(1).unwrap(), Some("user".to_string()));
+        assert_eq!(small.get(1).unwrap(), Some("small".to_string()));
+        assert_eq!((db.len(), users.len(), small.len()), (1, 2, 1));
+        
+        // with its own settings
+        assert!(Arc::ptr_eq(&users, &db.cf("users").unwrap()));
+        assert_eq!(users.cache_stats().entries, 2);
+        assert_eq!(small.cache_stats().capacity_bytes, 0);
+        assert_eq!(db.column_families().unwrap(), vec!["small".to_string(), "users".to_string()]);
+        
+        // Looking a column family up doesn't create it
+        assert!(Arc::ptr_eq(&users, &db.existing_cf("users").unwrap().unwrap()));
+        assert!(db.existing_cf("other").unwrap().is_none());
+        assert_eq!(db.column_families().unwrap().len(), 2);
+        
+        for name in ["", "a.b", "../x", &"x".repeat(65)] {
+            assert!(matches!(db.cf(name), Err(KvError::InvalidColumnFamily(_))));
+        }
+        
+        // Column families are closed with the database
+        db.close().unwrap();
+        assert!(matches!(users.get(1), Err(KvError::DbClosed)));
+        assert!(matches!(db.cf("users"), Err(KvError::DbClosed)));
+        
+        let db = KvDb::open(config).unwrap();
+        assert_eq!(db.get(1).unwrap(), Some("default".to_string()));
+        assert_eq!(db.existing_cf("users").unwrap().unwrap().get(2).unwrap(), Some("other user".to_string()));
+        assert_eq!(db.cf("small").unwrap().get(1).unwrap(), Some("small".to_string()));
+    }
+
+    #[test]
+    fn test_query_by_index() {
+        for engine in [EngineKind::Log, EngineKind::Lsm(LsmOptions::default())] {
+            let storage = MemoryStorage::new();
+            let config = Config {
+                storage: Some(Arc::new(storage.clone())),
+                engine,
+                chunk_size: 64,
+                merge_operator: Some(Arc::new(JsonMergePatchOperator)),
+                secondary_indexes: vec![SecondaryIndex::new("city", "/city"), SecondaryIndex::new("age", "/age")],
+                ..Config::default()
+            };
+            let db = KvDb::open(config.clone()).unwrap();
+            let names = |entries: Vec<(i64, String)>| -> Vec<i64> { entries.into_iter().map(|(key, _)| key).collect() };
+            
+            db.set(1, r#"{"name":"alice","city":"Paris","age":30}"#).unwrap();
+            db.set_many(&[(2, r#"{"name":"bob","city":"Paris"}"#), (3, r#"{"name":"carol","city":"Oslo"}"#)]).unwrap();
+            db.set(4, "not json").unwrap();
+            assert_eq!(db.query_by_index("city", "Paris").unwrap(), [
+                (1, r#"{"name":"alice","city":"Paris","age":30}"#.to_string()),
+                (2, r#"{"name":"bob","city":"Paris"}"#.to_string()),
+            ]);
+            assert_eq!(names(db.query_by_index("age", "30").unwrap()), [1]);
+            
+            // Every kind of write keeps the indexes up to date
+            db.set(2, r#"{"name":"bob","city":"Oslo"}"#).unwrap();
+            db.remove(3).unwrap();
+            db.merge(1, r#"{"city":"Rome"}"#).unwrap();
+            db.incr(5, 30).unwrap();
+            assert!(db.query_by_index("city", "Paris").unwrap().is_empty());
+            assert_eq!(names(db.query_by_index("city", "Oslo").unwrap()), [2]);
+            assert_eq!(names(db.query_by_index("city", "Rome").unwrap()), [1]);
+            assert_eq!(names(db.query_by_index("age", "30").unwrap()), [1]);
+            
+            // Large values are indexed too, including those the log-structured engine stores in chunks
+            let large = |city: &str| format!(r#"{{"city":"{}","padding":"{}"}}"#, city, "x".repeat(100));
+            db.set(6, &large("Oslo")).unwrap();
+            db.set_many(&[(7, &large("Oslo"))]).unwrap();
+            let expected = if engine == EngineKind::Log {
+                let mut writer = db.blob_writer(8).unwrap();
+                writer.write_all(large("Oslo").as_bytes()).unwrap();
+                writer.finish().unwrap();
+                vec![2, 6, 7, 8]
+            } else {
+                vec![2, 6, 7]
+            };
+            assert_eq!(names(db.query_by_index("city", "Oslo").unwrap()), expected);
+            assert!(matches!(db.query_by_index("name", "bob"), Err(KvError::InvalidIndex(_))));
+            
+            // The indexes are built again when the database is opened
+            db.close().unwrap();
             let db = KvDb::open(config).unwrap();
-            assert_eq!(db.get(1).unwrap(), None);
-            assert_eq!(db.get(2).unwrap(), Some("value2".to_string()));
+            assert_eq!(names(db.query_by_index("city", "Oslo").unwrap()), expected);
+            assert_eq!(names(db.query_by_index("city", "Rome").unwrap()), [1]);
+            assert_eq!(names(db.query_by_index("age", "30").unwrap()), [1]);
         }
         
-        // Clean up
-        let _ = fs::remove_dir_all(test_dir);
+        let config = Config {
+            storage: Some(Arc::new(MemoryStorage::new())),
+            secondary_indexes: vec![SecondaryIndex::new("city", "city")],
+            ..Config::default()
+        };
+        assert!(matches!(KvDb::open(config), Err(KvError::InvalidIndex(_))));
+    }
+
+    #[test]
+    fn test_history() {
+        let storage = MemoryStorage::new();
+        let config = Config {
+            storage: Some(Arc::new(storage.clone())),
+            merge_operator: Some(Arc::new(AppendOperator { delimiter: ",".to_string() })),
+            ..Config::default()
+        };
+        
+        // Values written before history is kept count as version 0
+        let db = KvDb::open(config.clone()).unwrap();
+        db.set(5, "old").unwrap();
+        assert!(matches!(db.get_at(5, AsOf::Version(0)), Err(KvError::HistoryDisabled)));
+        db.close().unwrap();
+        
+        let config = Config { max_versions: 3, ..config };
+        let db = KvDb::open(config.clone()).unwrap();
+        db.set(1, "a").unwrap();
+        db.set(1, "b").unwrap();
+        db.remove(1).unwrap();
+        db.set(1, "c").unwrap();
+        db.set_many(&[(2, "x"), (2, "y")]).unwrap();
+        db.set(3, "a").unwrap();
+        db.merge(3, "b").unwrap();
+        db.set(5, "new").unwrap();
+        assert_eq!(db.last_version().unwrap(), 8);
+        
+        let check = |db: &KvDb| {
+            let get_at = |key, version| db.get_at(key, AsOf::Version(version)).unwrap();
+            assert!(matches!(db.get_at(1, AsOf::Version(1)), Err(KvError::VersionNotRetained)));
+            assert_eq!(get_at(1, 2), Some("b".to_string()));
+            assert_eq!(get_at(1, 3), None);
+            assert_eq!(get_at(1, 4), Some("c".to_string()));
+            assert_eq!(get_at(2, 4), None);
+            assert_eq!(get_at(2, 5), Some("y".to_string()));
+            assert_eq!(get_at(3, 6), Some("a".to_string()));
+            assert_eq!(get_at(3, 7), Some("a,b".to_string()));
+            assert_eq!(get_at(5, 0), Some("old".to_string()));
+            assert_eq!(get_at(5, 7), Some("old".to_string()));
+            assert_eq!(db.get_at(1, AsOf::Time(SystemTime::now())).unwrap(), Some("c".to_string()));
+            assert_eq!(db.get_at(1, AsOf::Time(SystemTime::UNIX_EPOCH)).unwrap_err().to_string(), KvError::VersionNotRetained.to_string());
+            
+            let history = db.history(1).unwrap();
+            let versions: Vec<_> = history.versions.iter().map(|version| (version.version, version.value.as_deref())).collect();
+            assert_eq!(versions, [(2, Some("b")), (3, None), (4, Some("c"))]);
+            assert!(history.versions.windows(2).all(|pair| pair[0].timestamp <= pair[1].timestamp));
+            assert!(history.truncated);
+            assert_eq!(db.history(2).unwrap().versions.len(), 1);
+            assert!(!db.history(2).unwrap().truncated);
+            assert_eq!(db.history(9).unwrap(), VersionHistory::default());
+        };
+        check(&db);
+        
+        // Garbage collection keeps the retained versions, and so does reopening
+        db.garbage_collect().unwrap();
+        check(&db);
+        assert_eq!(db.get(1).unwrap(), Some("c".to_string()));
+        db.close().unwrap();
+        let db = KvDb::open(config.clone()).unwrap();
+        check(&db);
+        assert_eq!(db.last_version().unwrap(), 8);
+        
+        // Removed keys keep their history within the retention window, and stay removed
+        db.remove(2).unwrap();
+        db.garbage_collect().unwrap();
+        assert_eq!(db.get(2).unwrap(), None);
+        assert_eq!(db.get_at(2, AsOf::Version(5)).unwrap(), Some("y".to_string()));
+        db.close().unwrap();
+        let db = KvDb::open(config.clone()).unwrap();
+        assert_eq!(db.get(2).unwrap(), None);
+        assert_eq!(db.len(), 3);
+        db.close().unwrap();
+        
+        // Once they age out of it, garbage collection drops the history of removed
+        // keys, and the replaced versions of the others
+        let expiring_config = Config { version_retention: Some(Duration::ZERO), ..config.clone() };
+        let db = KvDb::open(expiring_config.clone()).unwrap();
+        db.garbage_collect().unwrap();
+        assert!(db.history(2).unwrap().versions.is_empty());
+        assert_eq!(db.get_at(2, AsOf::Version(5)).unwrap(), None);
+        assert_eq!(db.history(1).unwrap().versions.len(), 1);
+        assert!(matches!(db.get_at(1, AsOf::Version(2)), Err(KvError::VersionNotRetained)));
+        assert_eq!(db.get(1).unwrap(), Some("c".to_string()));
+        db.close().unwrap();
+        let db = KvDb::open(expiring_config).unwrap();
+        assert!(db.history(2).unwrap().versions.is_empty());
+        assert!(matches!(db.get_at(1, AsOf::Version(2)), Err(KvError::VersionNotRetained)));
+        assert_eq!(db.len(), 3);
+        db.close().unwrap();
+        
+        let disk_config = Config { index: IndexKind::Disk { max_buffered_keys: 4 }, ..config.clone() };
+        assert!(matches!(KvDb::open(disk_config), Err(KvError::Unsupported(_))));
+        let config = Config { engine: EngineKind::Lsm(LsmOptions::default()), ..config };
+        assert!(matches!(KvDb::open(config), Err(KvError::Unsupported(_))));
+    }
+
+    #[test]
+    fn test_version_retention() {
+        let config = Config {
+            storage: Some(Arc::new(MemoryStorage::new())),
+            max_versions: 5,
+            version_retention: Some(Duration::from_millis(500)),
+            ..Config::default()
+        };
+        let db = KvDb::open(config).unwrap();
+        db.set(1, "a").unwrap();
+        db.set(1, "b").unwrap();
+        db.set(2, "x").unwrap();
+        db.remove(2).unwrap();
+        
+        // Garbage collection keeps versions replaced within the retention window
+        db.garbage_collect().unwrap();
+        assert_eq!(db.history(1).unwrap().versions.len(), 2);
+        assert!(!db.history(1).unwrap().truncated);
+        
+        // Versions that have aged out are listed until garbage collection drops them
+        std::thread::sleep(Duration::from_millis(600));
+        assert_eq!(db.history(1).unwrap().versions.len(), 2);
+        assert_eq!(db.get_at(1, AsOf::Version(1)).unwrap(), Some("a".to_string()));
+        db.garbage_collect().unwrap();
+        let history = db.history(1).unwrap();
+        assert_eq!(history.versions.iter().map(|version| version.value.as_deref()).collect::<Vec<_>>(), [Some("b")]);
+        assert!(history.truncated);
+        assert!(matches!(db.get_at(1, AsOf::Version(1)), Err(KvError::VersionNotRetained)));
+        assert_eq!(db.get(1).unwrap(), Some("b".to_string()));
+        
+        // and the history of a key removed before the window along with it
+        assert_eq!(db.history(2).unwrap(), VersionHistory::default());
+        assert_eq!(db.get_at(2, AsOf::Version(3)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_export_import() {
+        for engine in [EngineKind::Log, EngineKind::Lsm(LsmOptions::default())] {
+            let config = || Config {
+                storage: Some(Arc::new(MemoryStorage::new())),
+                engine,
+                secondary_indexes: vec![SecondaryIndex::new("name", "/name")],
+                ..Config::default()
+            };
+            let db = KvDb::open(config()).unwrap();
+            db.set(1, "one").unwrap();
+            db.set(-5, "with, comma\nand \"quotes\"").unwrap();
+            db.set(3, r#"{"name":"carol"}"#).unwrap();
+            let expected = db.scan(..).unwrap();
+            
+            for format in [DataFormat::JsonLines, DataFormat::Csv] {
+                let mut data = Vec::new();
+                assert_eq!(db.export(&mut data, format).unwrap(), 3);
+                
+                // Import on top of existing values, and bulk load into an empty database
+                let other = KvDb::open(config()).unwrap();
+                other.set(1, "old").unwrap();
+                assert_eq!(other.import(&data[..], format).unwrap(), 3);
+                assert_eq!(other.scan(..).unwrap(), expected);
+                
+                let other = KvDb::open(config()).unwrap();
+                assert_eq!(other.get(3).unwrap(), None);
+                assert_eq!(other.bulk_load(&data[..], format).unwrap(), 3);
+                assert_eq!(other.scan(..).unwrap(), expected);
+                assert_eq!(other.get(3).unwrap(), Some(r#"{"name":"carol"}"#.to_string()));
+                assert_eq!(other.query_by_index("name", "carol").unwrap().len(), 1);
+                assert!(matches!(other.bulk_load(&data[..], format), Err(KvError::NotEmpty)));
+            }
+            
+            // Bulk loaded entries are sorted and the last one for a key wins
+            let storage = Arc::new(MemoryStorage::new());
+            let config = Config { storage: Some(storage.clone()), engine, ..Config::default() };
+            let db = KvDb::open(config.clone()).unwrap();
+            let data = "key,value\n9,a\n2,b\n9,c\n";
+            assert_eq!(db.bulk_load(data.as_bytes(), DataFormat::Csv).unwrap(), 2);
+            db.set(4, "d").unwrap();
+            db.close().unwrap();
+            let db = KvDb::open(config).unwrap();
+            assert_eq!(db.len(), 3);
+            assert_eq!(db.scan(..).unwrap(), [(2, "b".to_string()), (4, "d".to_string()), (9, "c".to_string())]);
+            
+            // Malformed data is an error
+            let data = "{\"key\":1,\"value\":\"a\"}\nnot json\n";
+            assert!(matches!(db.import(data.as_bytes(), DataFormat::JsonLines), Err(KvError::Import(_))));
+        }
+        
+        // Bulk loaded entries share a version
+        let config = Config { storage: Some(Arc::new(MemoryStorage::new())), max_versions: 2, ..Config::default() };
+        let db = KvDb::open(config).unwrap();
+        db.bulk_load("{\"key\":1,\"value\":\"a\"}\n".as_bytes(), DataFormat::JsonLines).unwrap();
+        db.set(1, "b").unwrap();
+        assert_eq!(db.get_at(1, AsOf::Version(1)).unwrap(), Some("a".to_string()));
+        assert_eq!(db.history(1).unwrap().versions.len(), 2);
+    }
+    
+    #[test]
+    fn test_scan_cursor() {
+        for engine in [EngineKind::Log, EngineKind::Lsm(LsmOptions::default())] {
+            let config = Config { storage: Some(Arc::new(MemoryStorage::new())), engine, ..Config::default() };
+            let db = KvDb::open(config).unwrap();
+            for key in 0..10 {
+                db.set(key, &"x".repeat(key as usize)).unwrap();
+            }
+            
+            // Pages are bounded by entries and by bytes, but hold at least one entry
+            let mut cursor = db.scan_cursor(2..).unwrap();
+            let keys = |page: Vec<(i64, String)>| page.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+            assert_eq!(keys(cursor.next_page(3, usize::MAX).unwrap()), [2, 3, 4]);
+            assert_eq!(keys(cursor.next_page(10, 8 + 5 + 8 + 6).unwrap()), [5, 6]);
+            assert_eq!(keys(cursor.next_page(10, 1).unwrap()), [7]);
+            
+            // Writes between pages show up in the later ones
+            db.remove(8).unwrap();
+            db.set(9, "changed").unwrap();
+            assert_eq!(cursor.next_page(10, usize::MAX).unwrap(), [(9, "changed".to_string())]);
+            assert!(cursor.next_page(10, usize::MAX).unwrap().is_empty());
+            
+            let mut cursor = db.scan_cursor(..).unwrap();
+            db.close().unwrap();
+            assert!(matches!(cursor.next_page(10, usize::MAX), Err(KvError::DbClosed)));
+            assert!(matches!(db.scan_cursor(..), Err(KvError::DbClosed)));
+        }
+    }
+    
+    #[test]
+    fn test_stats() {
+        for engine in [EngineKind::Log, EngineKind::Lsm(LsmOptions::default())] {
+            let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+            let db = KvDb::open(Config {
+                storage: Some(storage.clone()),
+                engine,
+                chunk_size: 64,
+                merge_operator: Some(Arc::new(AppendOperator { delimiter: ",".to_string() })),
+                ..Config::default()
+            })
+            .unwrap();
+            
+            for key in 0..10 {
+                db.set(key, &format!("value {}", key)).unwrap();
+            }
+            db.set(1, "updated").unwrap();
+            db.set(2, &"x".repeat(200)).unwrap();
+            db.remove(3).unwrap();
+            db.merge(4, "more").unwrap();
+            db.incr(10, 1).unwrap();
+            db.decr(10, 2).unwrap();
+            assert!(db.incr(1, 1).is_err());
+            db.get(5).unwrap();
+            db.get(5).unwrap();
+            db.get_many(&[5, 6, 100]).unwrap();
+            
+            let stats = db.stats().unwrap();
+            assert_eq!(stats.keys, 10);
+            assert!(stats.file_size > 0);
+            assert_eq!(stats.cache, db.cache_stats());
+            
+            // Only the outermost call is counted, though `set` reads the old value
+            let count = |operation: Operation| stats.operations[operation as usize].count;
+            assert_eq!(count(Operation::Set), 12);
+            assert_eq!(count(Operation::Get), 2);
+            assert_eq!(count(Operation::GetMany), 1);
+            assert_eq!(count(Operation::Remove), 1);
+            assert_eq!(count(Operation::Incr), 3);
+            assert_eq!(count(Operation::Merge), 1);
+            assert_eq!(count(Operation::Scan), 0);
+            let incr = &stats.operations[Operation::Incr as usize];
+            assert_eq!(incr.errors, 1);
+            assert_eq!(incr.latency_buckets.len(), LATENCY_BUCKETS.len());
+            assert!(incr.percentile(0.5) <= incr.max_latency && incr.max_latency <= incr.total_latency);
+            
+            match engine {
+                EngineKind::Log => {
+                    // The dead bytes are what inspecting the file finds, and collecting garbage reclaims them
+                    let file_stats = LogFile::open(&*storage).unwrap().stats().unwrap();
+                    assert_eq!(stats.file_size, file_stats.file_size);
+                    assert_eq!(stats.dead_bytes, Some(file_stats.dead_bytes));
+                    assert!(file_stats.dead_bytes > 0);
+            
+                    db.garbage_collect().unwrap();
+                    let stats = db.stats().unwrap();
+                    assert_eq!((stats.dead_bytes, stats.gc_runs), (Some(0), 1));
+                    assert!(stats.compaction_time > Duration::ZERO);
+                    assert_eq!(stats.keys, 10);
+                    
+                    // Later writes keep the count up to date, as does reopening
+                    db.set(2, "small").unwrap();
+                    db.merge(4, "again").unwrap();
+                    db.set(6, &"y".repeat(200)).unwrap();
+                    db.merge(6, "more").unwrap();
+                    db.remove(6).unwrap();
+                    db.set(4, "replaced").unwrap();
+                    let file_stats = LogFile::open(&*storage).unwrap().stats().unwrap();
+                    assert_eq!(db.stats().unwrap().dead_bytes, Some(file_stats.dead_bytes));
+                    db.close().unwrap();
+                    let db = KvDb::open(Config { storage: Some(storage.clone()), ..Config::default() }).unwrap();
+                    assert_eq!(db.stats().unwrap().dead_bytes, Some(file_stats.dead_bytes));
+                }
+                EngineKind::Lsm(_) => assert_eq!(stats.dead_bytes, None),
+            }
+            
+            db.close().unwrap();
+            assert!(matches!(db.stats(), Err(KvError::DbClosed)));
+        }
     }
-}
\ No newline at end of file
+}
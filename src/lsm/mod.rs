@@ -0,0 +1,632 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, ErrorKind, Read, Write};
+use std::iter::Peekable;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::storage::{FileReader, Storage, StorageFile};
+use crate::{parse_header, write_record, KvError, OpType, Result};
+
+mod sstable;
+
+use sstable::{SsTable, SsTableWriter};
+
+// Tuning for the LSM-tree engine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LsmOptions {
+    // The memtable is written out as a level 0 table once its entries
+    // take up this many bytes
+    pub memtable_size: usize,
+
+    // Level 0 is compacted into level 1 once it holds this many tables
+    pub level0_tables: usize,
+
+    // The size level 1 may grow to before a table is compacted into level 2.
+    // Each deeper level may grow `level_multiplier` times larger than the one above.
+    pub level1_size: u64,
+    pub level_multiplier: u64,
+
+    // Compaction output is split into tables of about this size
+    pub table_size: u64,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        Self {
+            memtable_size: 4 * 1024 * 1024, // 4MB
+            level0_tables: 4,
+            level1_size: 16 * 1024 * 1024, // 16MB
+            level_multiplier: 10,
+            table_size: 2 * 1024 * 1024, // 2MB
+        }
+    }
+}
+
+// The number of levels, including level 0
+const MAX_LEVELS: usize = 7;
+
+// Magic bytes at the start of the manifest
+const MANIFEST_MAGIC: &[u8; 8] = b"KVDBLSM1";
+
+// A value as stored in the tree: its record flags and encoded payload
+pub(crate) type StoredValue = (u8, Vec<u8>);
+
+// A value, or None for a tombstone
+pub(crate) type Entry = Option<StoredValue>;
+
+// Write an entry as a Set or Remove record, returning its length in bytes
+fn write_entry(writer: &mut impl Write, key: i64, entry: &Entry) -> io::Result<u64> {
+    match entry {
+        Some((flags, payload)) => {
+            write_record(writer, OpType::Set as u8 | flags, key, payload)?;
+            Ok(1 + 8 + 8 + payload.len() as u64) // op_type + key + value_size + value
+        }
+        None => {
+            writer.write_u8(OpType::Remove as u8)?;
+            writer.write_i64::<LittleEndian>(key)?;
+            Ok(1 + 8) // op_type + key
+        }
+    }
+}
+
+// Read an entry written by `write_entry`
+fn read_entry(reader: &mut impl Read) -> Result<(i64, Entry)> {
+    let (op_type, flags) = parse_header(reader.read_u8()?)?;
+    let key = reader.read_i64::<LittleEndian>()?;
+
+    match op_type {
+        OpType::Set => {
+            let size = reader.read_u64::<LittleEndian>()?;
+            let mut payload = Vec::new();
+            reader.take(size).read_to_end(&mut payload)?;
+            if payload.len() as u64 != size {
+                return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+            }
+            Ok((key, Some((flags, payload))))
+        }
+        OpType::Remove => Ok((key, None)),
+        // Large values are stored inline, so there are no chunks, merges are
+        // applied as they are written, and no history is kept
+        OpType::Chunk | OpType::Merge | OpType::Version => Err(KvError::InvalidFormat),
+    }
+}
+
+// The approximate memory used by an entry in the memtable
+fn entry_size(entry: &Entry) -> usize {
+    8 + 1 + entry.as_ref().map_or(0, |(_, payload)| payload.len())
+}
+
+fn wal_name(id: u64) -> String {
+    format!("{:06}.wal", id)
+}
+
+// Load the tables listed in the manifest into `levels`, returning the next
+// file id, the id of the write-ahead log and the number of live keys
+fn load_manifest(storage: &dyn Storage, levels: &mut [Vec<SsTable>]) -> Result<(u64, u64, usize)> {
+    let file = match storage.open("MANIFEST") {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok((1, 0, 0)),
+        Err(err) => return Err(err.into()),
+    };
+    let mut data = Vec::new();
+    FileReader::new(file, 0).read_to_end(&mut data)?;
+    let mut reader = &data[..];
+
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MANIFEST_MAGIC {
+        return Err(KvError::InvalidFormat);
+    }
+    let next_id = reader.read_u64::<LittleEndian>()?;
+    let wal_id = reader.read_u64::<LittleEndian>()?;
+    let len = reader.read_u64::<LittleEndian>()? as usize;
+
+    for level in levels.iter_mut() {
+        let count = reader.read_u32::<LittleEndian>()?;
+        for _ in 0..count {
+            let id = reader.read_u64::<LittleEndian>()?;
+            level.push(SsTable::open(storage, id)?);
+        }
+    }
+    Ok((next_id, wal_id, len))
+}
+
+// A log-structured merge tree.
+// Writes go to a write-ahead log and a sorted in-memory table. A full memtable
+// is written out as an immutable sorted table in level 0, and level 0 tables are
+// gradually compacted into deeper levels of non-overlapping tables. The set of
+// live tables is recorded in a manifest that is replaced atomically.
+pub(crate) struct LsmTree {
+    storage: Arc<dyn Storage>,
+    options: LsmOptions,
+    // Sync the write-ahead log after every write
+    sync_writes: bool,
+    memtable: BTreeMap<i64, Entry>,
+    memtable_size: usize,
+    wal: Arc<dyn StorageFile>,
+    wal_id: u64,
+    // Tables per level. Level 0 tables may overlap each other and are ordered
+    // oldest first; tables in deeper levels are ordered by key and don't overlap.
+    levels: Vec<Vec<SsTable>>,
+    // The id for the next table or write-ahead log file
+    next_id: u64,
+    // The number of live keys. Writes are counted without looking keys up in
+    // the tables, so a value for a key that isn't in the memtable counts as a
+    // new key until the memtable is flushed.
+    len: usize,
+    // The keys counted as new that way, which flushing checks the tables for
+    new_keys: HashSet<i64>,
+    // The largest key of the last table compacted out of each level,
+    // so compactions work their way through the whole key space
+    compact_pointers: Vec<i64>,
+    // The compactions done since the tree was opened, and the time they took
+    compactions: u64,
+    compaction_time: Duration,
+}
+
+impl LsmTree {
+    pub(crate) fn open(storage: Arc<dyn Storage>, options: LsmOptions, sync_writes: bool) -> Result<Self> {
+        let mut levels: Vec<Vec<SsTable>> = (0..MAX_LEVELS).map(|_| Vec::new()).collect();
+        let (next_id, wal_id, len) = load_manifest(&*storage, &mut levels)?;
+        let wal = storage.open_or_create(&wal_name(wal_id))?;
+
+        let mut tree = Self {
+            storage,
+            options,
+            sync_writes,
+            memtable: BTreeMap::new(),
+            memtable_size: 0,
+            new_keys: HashSet::new(),
+            wal,
+            wal_id,
+            levels,
+            next_id,
+            len,
+            compact_pointers: vec![i64::MIN; MAX_LEVELS],
+            compactions: 0,
+            compaction_time: Duration::ZERO,
+        };
+
+        tree.remove_orphans()?;
+        tree.replay_wal()?;
+
+        if tree.memtable_size >= tree.options.memtable_size {
+            tree.flush_memtable()?;
+            tree.compact()?;
+        }
+        Ok(tree)
+    }
+
+    // Replace the manifest with one describing the current tables
+    fn write_manifest(&self) -> Result<()> {
+        let mut writer = Vec::new();
+        writer.write_all(MANIFEST_MAGIC)?;
+        writer.write_u64::<LittleEndian>(self.next_id)?;
+        writer.write_u64::<LittleEndian>(self.wal_id)?;
+        writer.write_u64::<LittleEndian>(self.len as u64)?;
+        for level in &self.levels {
+            writer.write_u32::<LittleEndian>(level.len() as u32)?;
+            for table in level {
+                writer.write_u64::<LittleEndian>(table.id)?;
+            }
+        }
+
+        let file = self.storage.create("MANIFEST.tmp")?;
+        file.append(&writer)?;
+        file.sync()?;
+        self.storage.rename("MANIFEST.tmp", "MANIFEST")?;
+        Ok(())
+    }
+
+    // Delete files left behind by a flush or compaction that didn't complete
+    fn remove_orphans(&self) -> Result<()> {
+        let live: HashSet<u64> = self.levels.iter().flatten().map(|table| table.id).collect();
+
+        for name in self.storage.list()? {
+            let Some((stem, extension)) = name.rsplit_once('.') else {
+                continue;
+            };
+            let orphan = match (extension, stem.parse::<u64>()) {
+                ("sst", Ok(id)) => !live.contains(&id),
+                ("wal", Ok(id)) => id != self.wal_id,
+                _ => name == "MANIFEST.tmp",
+            };
+            if orphan {
+                self.storage.remove(&name)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Rebuild the memtable from the write-ahead log.
+    // A record cut short by a crash is discarded.
+    fn replay_wal(&mut self) -> Result<()> {
+        let mut data = Vec::new();
+        FileReader::new(self.wal.clone(), 0).read_to_end(&mut data)?;
+
+        let mut reader = &data[..];
+        let mut valid_len = 0;
+        while !reader.is_empty() {
+            match read_entry(&mut reader) {
+                Ok((key, entry)) => self.apply(key, entry)?,
+                Err(KvError::Io(err)) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            }
+            valid_len = data.len() - reader.len();
+        }
+
+        if valid_len < data.len() {
+            log::warn!("Discarding a torn record at the end of write-ahead log {}", self.wal_id);
+            self.wal.truncate(valid_len as u64)?;
+        }
+        Ok(())
+    }
+
+    // Look up the value for a key
+    pub(crate) fn get(&self, key: i64) -> Result<Option<StoredValue>> {
+        Ok(self.lookup(key)?.flatten())
+    }
+
+    // Find the newest entry for a key, which may be a tombstone
+    fn lookup(&self, key: i64) -> Result<Option<Entry>> {
+        if let Some(entry) = self.memtable.get(&key) {
+            return Ok(Some(entry.clone()));
+        }
+        self.lookup_tables(key)
+    }
+
+    // Find the newest entry for a key in the tables, leaving out the memtable
+    fn lookup_tables(&self, key: i64) -> Result<Option<Entry>> {
+        // Newer level 0 tables shadow older ones
+        for table in self.levels[0].iter().rev() {
+            if let Some(entry) = table.get(key)? {
+                return Ok(Some(entry));
+            }
+        }
+
+        // At most one table per deeper level can hold the key
+        for level in &self.levels[1..] {
+            let index = level.partition_point(|table| table.max_key < key);
+            if let Some(table) = level.get(index) {
+                if let Some(entry) = table.get(key)? {
+                    return Ok(Some(entry));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // Write a value for a key, or a tombstone if there is none
+    pub(crate) fn write(&mut self, key: i64, entry: Entry) -> Result<()> {
+        self.write_batch(vec![(key, entry)])
+    }
+
+    // Write values and tombstones for many keys, in order, logging them in one append
+    pub(crate) fn write_batch(&mut self, entries: Vec<(i64, Entry)>) -> Result<()> {
+        // Log the writes first, each in one piece
+        let mut records = Vec::with_capacity(entries.iter().map(|(_, entry)| entry_size(entry) + 8).sum());
+        for (key, entry) in &entries {
+            write_entry(&mut records, *key, entry)?;
+        }
+        self.wal.append(&records)?;
+        if self.sync_writes {
+            self.wal.sync()?;
+        }
+
+        for (key, entry) in entries {
+            self.apply(key, entry)?;
+        }
+
+        if self.memtable_size >= self.options.memtable_size {
+            self.flush_memtable()?;
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    // Apply a logged write to the memtable
+    fn apply(&mut self, key: i64, entry: Entry) -> Result<()> {
+        // Tombstones are only written for live keys
+        let existed = match self.memtable.get(&key) {
+            Some(old) => old.is_some(),
+            None if entry.is_some() => {
+                self.new_keys.insert(key);
+                false
+            }
+            None => true,
+        };
+        match (existed, entry.is_some()) {
+            (false, true) => self.len += 1,
+            (true, false) => self.len -= 1,
+            _ => {}
+        }
+
+        self.memtable_size += entry_size(&entry);
+        if let Some(old) = self.memtable.insert(key, entry) {
+            self.memtable_size -= entry_size(&old);
+        }
+        Ok(())
+    }
+
+    // The number of live keys. It is an estimate until the memtable is flushed,
+    // as keys in the tables overwritten since are counted twice.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn compactions(&self) -> (u64, Duration) {
+        (self.compactions, self.compaction_time)
+    }
+
+    // The bytes the tables and the write-ahead log take up
+    pub(crate) fn disk_size(&self) -> Result<u64> {
+        let tables: u64 = self.levels.iter().flatten().map(|table| table.size).sum();
+        Ok(tables + self.wal.size()?)
+    }
+
+    // Collect up to `limit` live entries with keys in the given range, in ascending order
+    pub(crate) fn scan(&self, range: (Bound<i64>, Bound<i64>), limit: usize) -> Result<Vec<(i64, StoredValue)>> {
+        let from = match range.0 {
+            Bound::Included(key) => key,
+            Bound::Excluded(key) => match key.checked_add(1) {
+                Some(key) => key,
+                None => return Ok(Vec::new()),
+            },
+            Bound::Unbounded => i64::MIN,
+        };
+
+        // Sources from newest to oldest
+        let memtable: Vec<_> = self
+            .memtable
+            .range(range)
+            .map(|(&key, entry)| Ok((key, entry.clone())))
+            .collect();
+        let mut sources: Vec<Source> = vec![Box::new(memtable.into_iter())];
+        for table in self.levels[0].iter().rev() {
+            sources.push(Box::new(table.iter(from)?));
+        }
+        for level in &self.levels[1..] {
+            let mut tables = Vec::new();
+            for table in level.iter().filter(|table| table.max_key >= from) {
+                tables.push(table.iter(from)?);
+            }
+            sources.push(Box::new(tables.into_iter().flatten()));
+        }
+
+        let mut entries = Vec::new();
+        for item in MergeIter::new(sources) {
+            let (key, entry) = item?;
+            if !range.contains(&key) || entries.len() == limit {
+                break;
+            }
+            if let Some(value) = entry {
+                entries.push((key, value));
+            }
+        }
+        Ok(entries)
+    }
+
+    // Make sure every write so far is on disk
+    pub(crate) fn sync(&self) -> Result<()> {
+        self.wal.sync()?;
+        Ok(())
+    }
+
+    // Write sorted entries with distinct keys straight into tables in the bottom
+    // level, split like compaction output. The tree must be empty.
+    pub(crate) fn bulk_load(&mut self, entries: Vec<(i64, StoredValue)>) -> Result<()> {
+        if !self.memtable.is_empty() || self.levels.iter().any(|level| !level.is_empty()) {
+            return Err(KvError::NotEmpty);
+        }
+
+        let len = entries.len();
+        let mut outputs = Vec::new();
+        let mut writer: Option<SsTableWriter> = None;
+        for (key, value) in entries {
+            let current = match &mut writer {
+                Some(current) => current,
+                None => {
+                    self.next_id += 1;
+                    writer.insert(SsTableWriter::create(&*self.storage, self.next_id - 1)?)
+                }
+            };
+            current.add(key, &Some(value))?;
+            if current.size() >= self.options.table_size {
+                outputs.push(writer.take().unwrap().finish()?);
+            }
+        }
+        if let Some(current) = writer {
+            outputs.push(current.finish()?);
+        }
+
+        // The tables only become part of the tree once the manifest lists them
+        self.levels[MAX_LEVELS - 1] = outputs;
+        self.len = len;
+        self.write_manifest()?;
+        Ok(())
+    }
+
+    // Write the memtable out as a level 0 table and start a new write-ahead log
+    fn flush_memtable(&mut self) -> Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+
+        // Keys counted as new when written may have been in the tables all along
+        for key in std::mem::take(&mut self.new_keys) {
+            if matches!(self.lookup_tables(key)?, Some(Some(_))) {
+                self.len -= 1;
+            }
+        }
+
+        // Tombstones are kept, as older tables may still hold the keys
+        let mut writer = SsTableWriter::create(&*self.storage, self.next_id)?;
+        self.next_id += 1;
+        for (&key, entry) in &self.memtable {
+            writer.add(key, entry)?;
+        }
+        self.levels[0].push(writer.finish()?);
+
+        // The old log is only deleted once the manifest no longer refers to it
+        let old_wal_id = self.wal_id;
+        self.wal_id = self.next_id;
+        self.next_id += 1;
+        self.wal = self.storage.create(&wal_name(self.wal_id))?;
+        self.write_manifest()?;
+        self.storage.remove(&wal_name(old_wal_id))?;
+
+        self.memtable.clear();
+        self.memtable_size = 0;
+        Ok(())
+    }
+
+    // Compact levels until they are all within their limits
+    fn compact(&mut self) -> Result<()> {
+        loop {
+            if self.levels[0].len() >= self.options.level0_tables {
+                self.compact_level(0)?;
+                continue;
+            }
+
+            let over_limit = (1..MAX_LEVELS - 1).find(|&level| {
+                let size: u64 = self.levels[level].iter().map(|table| table.size).sum();
+                size > self.max_level_size(level)
+            });
+            match over_limit {
+                Some(level) => self.compact_level(level)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // The size a level may grow to before it is compacted
+    fn max_level_size(&self, level: usize) -> u64 {
+        (1..level).fold(self.options.level1_size, |size, _| size.saturating_mul(self.options.level_multiplier))
+    }
+
+    // Merge tables from a level into the overlapping tables of the next one.
+    // All of level 0 is compacted at once; from deeper levels one table is.
+    fn compact_level(&mut self, level: usize) -> Result<()> {
+        let started = Instant::now();
+        let inputs: Vec<SsTable> = if level == 0 {
+            // Newest first, as they may overlap
+            self.levels[0].drain(..).rev().collect()
+        } else {
+            let pointer = self.compact_pointers[level];
+            let tables = &mut self.levels[level];
+            let index = tables.iter().position(|table| table.min_key > pointer).unwrap_or(0);
+            vec![tables.remove(index)]
+        };
+
+        let min_key = inputs.iter().map(|table| table.min_key).min().unwrap();
+        let max_key = inputs.iter().map(|table| table.max_key).max().unwrap();
+        self.compact_pointers[level] = max_key;
+
+        // The inputs are newer than anything in the next level
+        let (overlapping, rest): (Vec<SsTable>, Vec<SsTable>) = std::mem::take(&mut self.levels[level + 1])
+            .into_iter()
+            .partition(|table| table.overlaps(min_key, max_key));
+        self.levels[level + 1] = rest;
+
+        let mut sources: Vec<Source> = Vec::new();
+        for table in inputs.iter().chain(&overlapping) {
+            sources.push(Box::new(table.iter(i64::MIN)?));
+        }
+
+        // Write the merged entries out, split into tables of about `table_size`
+        let mut outputs = Vec::new();
+        let mut writer: Option<SsTableWriter> = None;
+        for item in MergeIter::new(sources) {
+            let (key, entry) = item?;
+
+            // A tombstone is only needed while deeper levels may hold the key
+            if entry.is_none() && !self.deeper_levels_contain(level + 1, key) {
+                continue;
+            }
+
+            let current = match &mut writer {
+                Some(current) => current,
+                None => {
+                    self.next_id += 1;
+                    writer.insert(SsTableWriter::create(&*self.storage, self.next_id - 1)?)
+                }
+            };
+            current.add(key, &entry)?;
+            if current.size() >= self.options.table_size {
+                outputs.push(writer.take().unwrap().finish()?);
+            }
+        }
+        if let Some(current) = writer {
+            outputs.push(current.finish()?);
+        }
+
+        let next = &mut self.levels[level + 1];
+        next.extend(outputs);
+        next.sort_by_key(|table| table.min_key);
+
+        // Only delete the inputs once the manifest no longer refers to them
+        self.write_manifest()?;
+        for table in inputs.into_iter().chain(overlapping) {
+            table.delete(&*self.storage)?;
+        }
+        self.compactions += 1;
+        self.compaction_time += started.elapsed();
+        Ok(())
+    }
+
+    // Whether a table below the given level may hold the key
+    fn deeper_levels_contain(&self, level: usize, key: i64) -> bool {
+        self.levels[level + 1..]
+            .iter()
+            .flatten()
+            .any(|table| table.overlaps(key, key))
+    }
+}
+
+type Source = Box<dyn Iterator<Item = Result<(i64, Entry)>>>;
+
+// Merges sorted sources into one sorted sequence.
+// When several sources hold the same key, the entry from the earliest source wins.
+struct MergeIter {
+    sources: Vec<Peekable<Source>>,
+}
+
+impl MergeIter {
+    fn new(sources: Vec<Source>) -> Self {
+        Self {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<(i64, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Find the source with the smallest next key
+        let mut smallest: Option<(usize, i64)> = None;
+        for (index, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                Some(Ok((key, _))) if smallest.is_none_or(|(_, smallest_key)| *key < smallest_key) => {
+                    smallest = Some((index, *key));
+                }
+                Some(Err(_)) => return source.next(),
+                _ => {}
+            }
+        }
+
+        let (index, key) = smallest?;
+        let item = self.sources[index].next();
+
+        // Skip the older entries for the same key
+        for source in &mut self.sources[index + 1..] {
+            if matches!(source.peek(), Some(Ok((other, _))) if *other == key) {
+                source.next();
+            }
+        }
+        item
+    }
+}
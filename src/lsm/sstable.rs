@@ -0,0 +1,282 @@
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::{read_entry, write_entry, Entry};
+use crate::bloom::BloomFilter;
+use crate::storage::{FileReader, FileWriter, Storage, StorageFile};
+use crate::{KvError, Result};
+
+// Magic bytes at the end of every table file
+const MAGIC: &[u8; 8] = b"KVDBSST2";
+
+// Length of the footer: index offset, entry count, largest key, filter offset, magic
+const FOOTER_LEN: u64 = 8 + 8 + 8 + 8 + 8;
+
+// Target size of the blocks the sparse index points into.
+// A lookup reads at most one block from disk.
+const BLOCK_SIZE: u64 = 4096;
+
+// The file name of the table with the given id
+pub(crate) fn table_name(id: u64) -> String {
+    format!("{:06}.sst", id)
+}
+
+// An immutable file of entries sorted by key.
+// The data section holds one record per key, in the same format as the log,
+// followed by a sparse index of the first key and offset of every block
+// and a bloom filter of the keys.
+pub(crate) struct SsTable {
+    pub(crate) id: u64,
+    file: Arc<dyn StorageFile>,
+    // The first key and offset of each block
+    blocks: Vec<(i64, u64)>,
+    // Rules out most keys the table doesn't hold without reading from it
+    filter: BloomFilter,
+    // The length of the data section
+    data_len: u64,
+    pub(crate) min_key: i64,
+    pub(crate) max_key: i64,
+    // The size of the file in bytes
+    pub(crate) size: u64,
+}
+
+impl SsTable {
+    pub(crate) fn open(storage: &dyn Storage, id: u64) -> Result<Self> {
+        let file = storage.open(&table_name(id))?;
+        let size = file.size()?;
+        if size < FOOTER_LEN {
+            return Err(KvError::InvalidFormat);
+        }
+
+        // Read the footer
+        let mut footer = [0; FOOTER_LEN as usize];
+        file.read_at(size - FOOTER_LEN, &mut footer)?;
+        let mut reader = &footer[..];
+        let data_len = reader.read_u64::<LittleEndian>()?;
+        let count = reader.read_u64::<LittleEndian>()?;
+        let max_key = reader.read_i64::<LittleEndian>()?;
+        let filter_offset = reader.read_u64::<LittleEndian>()?;
+        if reader != MAGIC || data_len > filter_offset || filter_offset > size - FOOTER_LEN {
+            return Err(KvError::InvalidFormat);
+        }
+
+        // Read the filter
+        let mut data = vec![0; (size - FOOTER_LEN - filter_offset) as usize];
+        file.read_at(filter_offset, &mut data)?;
+        let filter = BloomFilter::decode(&data)?;
+
+        // Read the sparse index
+        let mut reader = BufReader::new(FileReader::new(file.clone(), data_len));
+        let mut blocks = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let key = reader.read_i64::<LittleEndian>()?;
+            let offset = reader.read_u64::<LittleEndian>()?;
+            blocks.push((key, offset));
+        }
+        let min_key = blocks.first().ok_or(KvError::InvalidFormat)?.0;
+
+        Ok(Self {
+            id,
+            file,
+            blocks,
+            filter,
+            data_len,
+            min_key,
+            max_key,
+            size,
+        })
+    }
+
+    // Whether the table's key range overlaps the given one
+    pub(crate) fn overlaps(&self, min_key: i64, max_key: i64) -> bool {
+        self.min_key <= max_key && self.max_key >= min_key
+    }
+
+    // Look up the entry for a key, which may be a tombstone
+    pub(crate) fn get(&self, key: i64) -> Result<Option<Entry>> {
+        if key < self.min_key || key > self.max_key {
+            return Ok(None);
+        }
+        if !self.filter.may_contain(key) {
+            return Ok(None);
+        }
+
+        // Read the only block that can hold the key
+        let block = self.blocks.partition_point(|&(first, _)| first <= key) - 1;
+        let start = self.blocks[block].1;
+        let end = self.blocks.get(block + 1).map_or(self.data_len, |&(_, offset)| offset);
+        let mut data = vec![0; (end - start) as usize];
+        self.file.read_at(start, &mut data)?;
+
+        let mut reader = &data[..];
+        while !reader.is_empty() {
+            let (entry_key, entry) = read_entry(&mut reader)?;
+            if entry_key == key {
+                return Ok(Some(entry));
+            }
+            if entry_key > key {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    // Iterate over the entries with keys from `from` on, in ascending order
+    pub(crate) fn iter(&self, from: i64) -> Result<SsTableIter> {
+        let block = self.blocks.partition_point(|&(first, _)| first <= from).saturating_sub(1);
+        let start = self.blocks[block].1;
+
+        Ok(SsTableIter {
+            reader: BufReader::new(FileReader::new(self.file.clone(), start)),
+            remaining: self.data_len - start,
+            from,
+        })
+    }
+
+    // Delete the table's file
+    pub(crate) fn delete(self, storage: &dyn Storage) -> Result<()> {
+        storage.remove(&table_name(self.id))?;
+        Ok(())
+    }
+}
+
+// Iterates over the entries of a table
+pub(crate) struct SsTableIter {
+    reader: BufReader<FileReader>,
+    // Bytes left in the data section
+    remaining: u64,
+    // Entries with smaller keys are skipped
+    from: i64,
+}
+
+impl Iterator for SsTableIter {
+    type Item = Result<(i64, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.remaining > 0 {
+            let mut reader = (&mut self.reader).take(self.remaining);
+            let result = read_entry(&mut reader);
+            self.remaining = reader.limit();
+
+            match result {
+                Ok((key, _)) if key < self.from => continue,
+                Ok(item) => return Some(Ok(item)),
+                Err(err) => {
+                    self.remaining = 0;
+                    return Some(Err(err));
+                }
+            }
+        }
+        None
+    }
+}
+
+// Writes a new table. Entries have to be added in ascending key order.
+pub(crate) struct SsTableWriter<'a> {
+    storage: &'a dyn Storage,
+    id: u64,
+    file: Arc<dyn StorageFile>,
+    writer: BufWriter<FileWriter>,
+    blocks: Vec<(i64, u64)>,
+    // Every key added, for the filter
+    keys: Vec<i64>,
+    offset: u64,
+    max_key: i64,
+}
+
+impl<'a> SsTableWriter<'a> {
+    pub(crate) fn create(storage: &'a dyn Storage, id: u64) -> Result<Self> {
+        let file = storage.create(&table_name(id))?;
+        Ok(Self {
+            storage,
+            id,
+            writer: BufWriter::new(FileWriter(file.clone())),
+            file,
+            blocks: Vec::new(),
+            keys: Vec::new(),
+            offset: 0,
+            max_key: i64::MIN,
+        })
+    }
+
+    pub(crate) fn add(&mut self, key: i64, entry: &Entry) -> Result<()> {
+        // Start a new block once the current one is full
+        let block_full = self
+            .blocks
+            .last()
+            .is_none_or(|&(_, start)| self.offset - start >= BLOCK_SIZE);
+        if block_full {
+            self.blocks.push((key, self.offset));
+        }
+
+        self.offset += write_entry(&mut self.writer, key, entry)?;
+        self.keys.push(key);
+        self.max_key = key;
+        Ok(())
+    }
+
+    // The number of bytes written so far
+    pub(crate) fn size(&self) -> u64 {
+        self.offset
+    }
+
+    // Write out the index, filter and footer, sync the file and open it as a table
+    pub(crate) fn finish(mut self) -> Result<SsTable> {
+        for &(key, offset) in &self.blocks {
+            self.writer.write_i64::<LittleEndian>(key)?;
+            self.writer.write_u64::<LittleEndian>(offset)?;
+        }
+
+        let mut filter = BloomFilter::new(self.keys.len());
+        for &key in &self.keys {
+            filter.insert(key);
+        }
+        let filter_offset = self.offset + self.blocks.len() as u64 * (8 + 8);
+        self.writer.write_all(&filter.encode())?;
+
+        self.writer.write_u64::<LittleEndian>(self.offset)?;
+        self.writer.write_u64::<LittleEndian>(self.blocks.len() as u64)?;
+        self.writer.write_i64::<LittleEndian>(self.max_key)?;
+        self.writer.write_u64::<LittleEndian>(filter_offset)?;
+        self.writer.write_all(MAGIC)?;
+        self.writer.flush()?;
+        self.file.sync()?;
+
+        SsTable::open(self.storage, self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_sstable() {
+        let storage = MemoryStorage::new();
+
+        // Enough entries to span several blocks, with a tombstone every 10 keys
+        let mut writer = SsTableWriter::create(&storage, 1).unwrap();
+        for key in (0..2000).map(|n| n * 2) {
+            let entry = (key % 20 != 0).then(|| (0, format!("value{}", key).into_bytes()));
+            writer.add(key, &entry).unwrap();
+        }
+        let table = writer.finish().unwrap();
+        assert_eq!((table.min_key, table.max_key), (0, 3998));
+        assert!(table.filter.may_contain(42));
+
+        assert_eq!(table.get(42).unwrap(), Some(Some((0, b"value42".to_vec()))));
+        assert_eq!(table.get(40).unwrap(), Some(None));
+        assert_eq!(table.get(41).unwrap(), None);
+        assert_eq!(table.get(-1).unwrap(), None);
+        assert_eq!(table.get(4000).unwrap(), None);
+
+        let keys: Vec<i64> = table.iter(3001).unwrap().map(|item| item.unwrap().0).collect();
+        assert_eq!(keys, (1501..2000).map(|n| n * 2).collect::<Vec<_>>());
+
+        table.delete(&storage).unwrap();
+        assert!(storage.list().unwrap().is_empty());
+    }
+}
@@ -14,6 +14,8 @@ fn main() {
     println!("  cargo run --bin kvdb-client -- --server http://[::1]:50051 set <key> <value>");
     println!("  cargo run --bin kvdb-client -- --server http://[::1]:50051 get <key>");
     println!("  cargo run --bin kvdb-client -- --server http://[::1]:50051 remove <key>");
+    println!("  cargo run --bin kvdb-client -- --server http://[::1]:50051 put-blob <key> <file>");
+    println!("  cargo run --bin kvdb-client -- --server http://[::1]:50051 get-blob <key> --output <file>");
     println!("\nExample:");
     println!("  cargo run --bin kvdb-client -- set 1 \"Hello, World!\"");
     println!("  cargo run --bin kvdb-client -- get 1");
@@ -0,0 +1,94 @@
+use std::fmt;
+
+use serde_json::Value;
+
+// Combines merge operands with the value of a key, for updates that don't need
+// to read the value first. Merge records are written as they come and resolved
+// when the key is read, or rewritten as a plain value by garbage collection.
+//
+// Operands may be applied in several batches, each on the result of the previous
+// one, so merging [a, b] must give the same result as merging [b] on the result
+// of merging [a].
+pub trait MergeOperator: Send + Sync + fmt::Debug {
+    // Apply operands, oldest first, to the existing value of a key, if there is one.
+    // An error fails the read that resolves the merge.
+    fn merge(&self, key: i64, existing: Option<&str>, operands: &[&str]) -> std::result::Result<String, String>;
+}
+
+// Appends each operand to the value, separated by a delimiter
+#[derive(Debug, Clone)]
+pub struct AppendOperator {
+    pub delimiter: String,
+}
+
+impl MergeOperator for AppendOperator {
+    fn merge(&self, _key: i64, existing: Option<&str>, operands: &[&str]) -> std::result::Result<String, String> {
+        let mut parts = existing.into_iter().chain(operands.iter().copied());
+        let mut value = parts.next().unwrap_or_default().to_string();
+        for part in parts {
+            value.push_str(&self.delimiter);
+            value.push_str(part);
+        }
+        Ok(value)
+    }
+}
+
+// Applies each operand to a JSON value as a merge patch (RFC 7386): objects are
+// merged key by key, a null removes a key, and anything else replaces the value
+#[derive(Debug, Clone, Default)]
+pub struct JsonMergePatchOperator;
+
+impl MergeOperator for JsonMergePatchOperator {
+    fn merge(&self, _key: i64, existing: Option<&str>, operands: &[&str]) -> std::result::Result<String, String> {
+        let parse = |json: &str| serde_json::from_str::<Value>(json).map_err(|err| format!("invalid JSON: {}", err));
+        let mut value = match existing {
+            Some(existing) => parse(existing)?,
+            None => Value::Null,
+        };
+        for operand in operands {
+            apply_patch(&mut value, parse(operand)?);
+        }
+        Ok(value.to_string())
+    }
+}
+
+fn apply_patch(value: &mut Value, patch: Value) {
+    let Value::Object(patch) = patch else {
+        *value = patch;
+        return;
+    };
+    if !value.is_object() {
+        *value = Value::Object(Default::default());
+    }
+    let object = value.as_object_mut().unwrap();
+    for (name, patch) in patch {
+        if patch.is_null() {
+            object.remove(&name);
+        } else {
+            apply_patch(object.entry(name).or_insert(Value::Null), patch);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append() {
+        let operator = AppendOperator { delimiter: ",".to_string() };
+        assert_eq!(operator.merge(1, None, &["a", "b"]).unwrap(), "a,b");
+        assert_eq!(operator.merge(1, Some("a,b"), &["c"]).unwrap(), "a,b,c");
+    }
+
+    #[test]
+    fn test_json_merge_patch() {
+        let operator = JsonMergePatchOperator;
+        let value = operator
+            .merge(1, Some(r#"{"a":1,"b":{"c":2,"d":3}}"#), &[r#"{"b":{"c":null,"e":4}}"#, r#"{"a":[1]}"#])
+            .unwrap();
+        assert_eq!(value, r#"{"a":[1],"b":{"d":3,"e":4}}"#);
+        assert_eq!(operator.merge(1, None, &[r#"{"a":null,"b":1}"#]).unwrap(), r#"{"b":1}"#);
+        assert!(operator.merge(1, Some("{"), &["{}"]).is_err());
+    }
+}
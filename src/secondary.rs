@@ -0,0 +1,137 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::RwLockWriteGuard;
+
+use serde_json::Value;
+
+use crate::{KvError, Result};
+
+// A secondary index on a field of JSON values, found by a JSON pointer (RFC 6901)
+// such as "/user/name". Values where the field is a string, number or boolean
+// are indexed under it, with strings as they are and other fields as JSON text,
+// so the string "30" and the number 30 are indexed the same. Values that aren't
+// JSON, or where the field is missing, null, an array or an object, aren't
+// indexed. An empty path indexes whole values that are scalars.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecondaryIndex {
+    pub name: String,
+    pub path: String,
+}
+
+impl SecondaryIndex {
+    pub fn new(name: impl Into<String>, path: impl Into<String>) -> Self {
+        Self { name: name.into(), path: path.into() }
+    }
+}
+
+// The entries of one secondary index
+#[derive(Default)]
+struct Entries {
+    // The keys with each indexed field value
+    keys: BTreeMap<String, BTreeSet<i64>>,
+    // The indexed field value of each key, to find its entry when the value changes
+    fields: HashMap<i64, String>,
+}
+
+// The secondary indexes of a database, kept in memory and built when it is opened
+pub(crate) struct SecondaryIndexes {
+    indexes: Vec<(SecondaryIndex, Entries)>,
+}
+
+impl SecondaryIndexes {
+    pub(crate) fn new(indexes: &[SecondaryIndex]) -> Result<Self> {
+        for (i, index) in indexes.iter().enumerate() {
+            if !index.path.is_empty() && !index.path.starts_with('/') {
+                return Err(KvError::InvalidIndex(format!("path {:?} of index {:?} must start with '/'", index.path, index.name)));
+            }
+            if indexes[..i].iter().any(|other| other.name == index.name) {
+                return Err(KvError::InvalidIndex(format!("index {:?} is defined twice", index.name)));
+            }
+        }
+        Ok(Self { indexes: indexes.iter().map(|index| (index.clone(), Entries::default())).collect() })
+    }
+
+    // Index the new value of a key, or drop the key if it was removed
+    pub(crate) fn update(&mut self, key: i64, value: Option<&[u8]>) {
+        let document = value.and_then(|value| serde_json::from_slice::<Value>(value).ok());
+        for (index, entries) in &mut self.indexes {
+            let field = document.as_ref().and_then(|document| indexed_field(document, &index.path));
+            if entries.fields.get(&key) == field.as_ref() {
+                continue;
+            }
+            if let Some(old) = entries.fields.remove(&key) {
+                let keys = entries.keys.get_mut(&old).unwrap();
+                keys.remove(&key);
+                if keys.is_empty() {
+                    entries.keys.remove(&old);
+                }
+            }
+            if let Some(field) = field {
+                entries.keys.entry(field.clone()).or_default().insert(key);
+                entries.fields.insert(key, field);
+            }
+        }
+    }
+
+    // The keys with the given field value in an index, in order
+    pub(crate) fn query(&self, name: &str, value: &str) -> Result<Vec<i64>> {
+        let (_, entries) = self
+            .indexes
+            .iter()
+            .find(|(index, _)| index.name == name)
+            .ok_or_else(|| KvError::InvalidIndex(format!("no index named {:?}", name)))?;
+        Ok(entries.keys.get(value).map(|keys| keys.iter().copied().collect()).unwrap_or_default())
+    }
+}
+
+// The value a document is indexed under, if any
+fn indexed_field(document: &Value, path: &str) -> Option<String> {
+    match document.pointer(path)? {
+        Value::String(field) => Some(field.clone()),
+        field @ (Value::Number(_) | Value::Bool(_)) => Some(field.to_string()),
+        Value::Null | Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+// Exclusive access to the secondary indexes for a write, if the database has any.
+// Writers hold it from before they write until they have updated the indexes, so
+// queries never see an index out of step with the values.
+pub(crate) struct IndexWriter<'a>(pub(crate) Option<RwLockWriteGuard<'a, SecondaryIndexes>>);
+
+impl IndexWriter<'_> {
+    pub(crate) fn is_active(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub(crate) fn update(&mut self, key: i64, value: Option<&[u8]>) {
+        if let Some(indexes) = &mut self.0 {
+            indexes.update(key, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secondary_indexes() {
+        let mut indexes = SecondaryIndexes::new(&[SecondaryIndex::new("name", "/name"), SecondaryIndex::new("city", "/address/city")]).unwrap();
+        indexes.update(1, Some(br#"{"name":"alice","address":{"city":"Paris"}}"#));
+        indexes.update(2, Some(br#"{"name":"bob","address":{"city":"Paris"}}"#));
+        indexes.update(3, Some(br#"{"name":30}"#));
+        indexes.update(4, Some(b"not json"));
+        assert_eq!(indexes.query("city", "Paris").unwrap(), [1, 2]);
+        assert_eq!(indexes.query("name", "30").unwrap(), [3]);
+
+        // Changing or removing a value moves or drops its entries
+        indexes.update(2, Some(br#"{"name":"bob","address":{"city":"Oslo"}}"#));
+        indexes.update(1, None);
+        assert!(indexes.query("city", "Paris").unwrap().is_empty());
+        assert_eq!(indexes.query("city", "Oslo").unwrap(), [2]);
+        assert!(indexes.query("name", "alice").unwrap().is_empty());
+        assert!(matches!(indexes.query("age", "1"), Err(KvError::InvalidIndex(_))));
+
+        assert!(SecondaryIndexes::new(&[SecondaryIndex::new("name", "name")]).is_err());
+        assert!(SecondaryIndexes::new(&[SecondaryIndex::new("a", "/a"), SecondaryIndex::new("a", "/b")]).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use std::cell::Cell;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::{CacheStats, Result};
+
+// The upper bounds of the buckets operation latencies are counted in
+pub const LATENCY_BUCKETS: [Duration; 16] = [
+    Duration::from_micros(5),
+    Duration::from_micros(10),
+    Duration::from_micros(25),
+    Duration::from_micros(50),
+    Duration::from_micros(100),
+    Duration::from_micros(250),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_micros(2500),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_secs(1),
+];
+
+// The operations the database keeps counts and latencies for. Variants of an
+// operation, such as `get_bytes` for `get` or `decr` for `incr`, count as it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Get,
+    GetMany,
+    Set,
+    SetMany,
+    Remove,
+    Incr,
+    Merge,
+    Scan,
+    QueryByIndex,
+}
+
+impl Operation {
+    pub const ALL: [Operation; 9] = [
+        Operation::Get,
+        Operation::GetMany,
+        Operation::Set,
+        Operation::SetMany,
+        Operation::Remove,
+        Operation::Incr,
+        Operation::Merge,
+        Operation::Scan,
+        Operation::QueryByIndex,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Get => "get",
+            Operation::GetMany => "get_many",
+            Operation::Set => "set",
+            Operation::SetMany => "set_many",
+            Operation::Remove => "remove",
+            Operation::Incr => "incr",
+            Operation::Merge => "merge",
+            Operation::Scan => "scan",
+            Operation::QueryByIndex => "query_by_index",
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// How often an operation was called since the database was opened, and how long it took
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperationStats {
+    pub operation: Operation,
+    // Calls, including those that failed
+    pub count: u64,
+    pub errors: u64,
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+    // For each of `LATENCY_BUCKETS`, the number of calls that took at most that long
+    pub latency_buckets: Vec<(Duration, u64)>,
+}
+
+impl OperationStats {
+    pub fn mean_latency(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => Duration::from_nanos((self.total_latency.as_nanos() / count as u128) as u64),
+        }
+    }
+
+    // An upper bound on the latency of the given fraction of calls, such as 0.99,
+    // as precise as the buckets allow
+    pub fn percentile(&self, fraction: f64) -> Duration {
+        let rank = (self.count as f64 * fraction).ceil() as u64;
+        self.latency_buckets
+            .iter()
+            .find(|&&(_, count)| count >= rank)
+            .map_or(self.max_latency, |&(bound, _)| bound.min(self.max_latency))
+    }
+}
+
+// A snapshot of what the database holds and how it has been used, as returned
+// by `KvDb::stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbStats {
+    pub keys: usize,
+    // The bytes the database takes up on disk: the log, or the tables and
+    // write-ahead log of the LSM engine
+    pub file_size: u64,
+    // The bytes of the log that no current value needs, which garbage collection
+    // would reclaim. Retained versions count as dead. Not known for the LSM engine.
+    pub dead_bytes: Option<u64>,
+    pub cache: CacheStats,
+    // Garbage collections of the log, and compactions of the LSM engine
+    pub gc_runs: u64,
+    pub compactions: u64,
+    // The time spent in either
+    pub compaction_time: Duration,
+    // One entry for each of `Operation::ALL`, in that order
+    pub operations: Vec<OperationStats>,
+}
+
+#[derive(Default)]
+struct OperationCounters {
+    count: AtomicU64,
+    errors: AtomicU64,
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+    // Calls per bucket, the last one for those slower than every bound
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+}
+
+// The counters behind `DbStats`, updated without taking any lock
+#[derive(Default)]
+pub(crate) struct Metrics {
+    operations: [OperationCounters; Operation::ALL.len()],
+    gc_runs: AtomicU64,
+    gc_nanos: AtomicU64,
+}
+
+thread_local! {
+    // Whether an operation is being tracked on this thread, so that the
+    // operations it calls in turn aren't counted as well
+    static TRACKING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Clears `TRACKING` when the outermost tracked operation ends, even by panicking
+struct TrackingGuard;
+
+impl Drop for TrackingGuard {
+    fn drop(&mut self) {
+        TRACKING.with(|tracking| tracking.set(false));
+    }
+}
+
+impl Metrics {
+    // Run an operation, counting it and how long it took unless it is called by
+    // another tracked operation
+    pub(crate) fn track<T>(&self, operation: Operation, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        if TRACKING.with(|tracking| tracking.replace(true)) {
+            return f();
+        }
+        let guard = TrackingGuard;
+        let started = Instant::now();
+        let result = f();
+        let elapsed = started.elapsed();
+        drop(guard);
+        self.record(operation, elapsed, result.is_ok());
+        result
+    }
+
+    pub(crate) fn record_gc(&self, elapsed: Duration) {
+        self.gc_runs.fetch_add(1, Ordering::Relaxed);
+        self.gc_nanos.fetch_add(elapsed.as_nanos().min(u64::MAX as u128) as u64, Ordering::Relaxed);
+    }
+
+    // The garbage collections done, and the time they took
+    pub(crate) fn gc(&self) -> (u64, Duration) {
+        (self.gc_runs.load(Ordering::Relaxed), Duration::from_nanos(self.gc_nanos.load(Ordering::Relaxed)))
+    }
+
+    fn record(&self, operation: Operation, elapsed: Duration, ok: bool) {
+        let counters = &self.operations[operation as usize];
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        counters.count.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            counters.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        counters.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        counters.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+        let bucket = LATENCY_BUCKETS.iter().position(|&bound| elapsed <= bound).unwrap_or(LATENCY_BUCKETS.len());
+        counters.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn operations(&self) -> Vec<OperationStats> {
+        Operation::ALL
+            .iter()
+            .map(|&operation| {
+                let counters = &self.operations[operation as usize];
+                let mut below = 0;
+                let latency_buckets = LATENCY_BUCKETS
+                    .iter()
+                    .zip(&counters.buckets)
+                    .map(|(&bound, count)| {
+                        below += count.load(Ordering::Relaxed);
+                        (bound, below)
+                    })
+                    .collect();
+                OperationStats {
+                    operation,
+                    count: counters.count.load(Ordering::Relaxed),
+                    errors: counters.errors.load(Ordering::Relaxed),
+                    total_latency: Duration::from_nanos(counters.total_nanos.load(Ordering::Relaxed)),
+                    max_latency: Duration::from_nanos(counters.max_nanos.load(Ordering::Relaxed)),
+                    latency_buckets,
+                }
+            })
+            .collect()
+    }
+}
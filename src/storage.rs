@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+// A flat namespace of files the database keeps its data in
+pub trait Storage: fmt::Debug + Send + Sync {
+    // Open an existing file
+    fn open(&self, name: &str) -> io::Result<Arc<dyn StorageFile>>;
+
+    // Create an empty file, replacing any file with the same name.
+    // The new file may be lost in a crash until it is synced.
+    fn create(&self, name: &str) -> io::Result<Arc<dyn StorageFile>>;
+
+    // Atomically replace the file `to` with the file `from`, durably once this
+    // returns, along with the creates and removes before it.
+    // Handles that are already open keep referring to the file they opened.
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    // Remove a file. The removal may be undone by a crash until a later rename
+    // or sync of a new file.
+    fn remove(&self, name: &str) -> io::Result<()>;
+
+    // The names of all files
+    fn list(&self) -> io::Result<Vec<String>>;
+
+    // Open a file, creating it empty if it doesn't exist
+    fn open_or_create(&self, name: &str) -> io::Result<Arc<dyn StorageFile>> {
+        match self.open(name) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => self.create(name),
+            result => result,
+        }
+    }
+}
+
+// An open file. Data is only ever appended to a file.
+pub trait StorageFile: Send + Sync {
+    // The length of the file in bytes
+    fn size(&self) -> io::Result<u64>;
+
+    // Fill `buf` with the data at `offset`
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    // Append data to the end of the file.
+    // Appended data may be lost in a crash until `sync` is called.
+    fn append(&self, data: &[u8]) -> io::Result<()>;
+
+    // Make everything appended so far durable
+    fn sync(&self) -> io::Result<()>;
+
+    // Cut the file down to the given length
+    fn truncate(&self, len: u64) -> io::Result<()>;
+}
+
+// Files in a directory on disk
+#[derive(Debug)]
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    // Use the given directory, creating it if it doesn't exist
+    pub fn new(dir: impl AsRef<Path>) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+}
+
+impl Storage for FileStorage {
+    fn open(&self, name: &str) -> io::Result<Arc<dyn StorageFile>> {
+        let file = OpenOptions::new().read(true).append(true).open(self.dir.join(name))?;
+        Ok(Arc::new(DiskFile {
+            file: Mutex::new(file),
+            new_in: Mutex::new(None),
+        }))
+    }
+
+    fn create(&self, name: &str) -> io::Result<Arc<dyn StorageFile>> {
+        // Remove the old file rather than truncating it, as it may still be open
+        match fs::remove_file(self.dir.join(name)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        let file = OpenOptions::new().read(true).append(true).create_new(true).open(self.dir.join(name))?;
+        Ok(Arc::new(DiskFile {
+            file: Mutex::new(file),
+            new_in: Mutex::new(Some(self.dir.clone())),
+        }))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(self.dir.join(from), self.dir.join(to))?;
+        sync_dir(&self.dir)
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.dir.join(name))
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        Ok(names)
+    }
+}
+
+// Make the entries of a directory durable: the files created, renamed and
+// removed in it. Directories can only be synced on Unix.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// A file on disk. Opened in append mode, so writes always land at the end.
+struct DiskFile {
+    file: Mutex<File>,
+    // For a file created since it was last synced, its directory, which is
+    // synced along with it the next time so the file survives a crash
+    new_in: Mutex<Option<PathBuf>>,
+}
+
+impl StorageFile for DiskFile {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.file.lock().unwrap().metadata()?.len())
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+
+    fn append(&self, data: &[u8]) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.write_all(data)?;
+        file.flush()
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.lock().unwrap().sync_all()?;
+
+        let mut new_in = self.new_in.lock().unwrap();
+        if let Some(dir) = &*new_in {
+            sync_dir(dir)?;
+            *new_in = None;
+        }
+        Ok(())
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        self.file.lock().unwrap().set_len(len)
+    }
+}
+
+// Files held in memory, for tests and databases that don't need to outlive
+// the process. Clones share the same files.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStorage {
+    files: Arc<Mutex<HashMap<String, Arc<MemoryFile>>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open(&self, name: &str) -> io::Result<Arc<dyn StorageFile>> {
+        match self.files.lock().unwrap().get(name) {
+            Some(file) => Ok(file.clone()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn create(&self, name: &str) -> io::Result<Arc<dyn StorageFile>> {
+        let file = Arc::new(MemoryFile::default());
+        self.files.lock().unwrap().insert(name.to_string(), file.clone());
+        Ok(file)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let file = files.remove(from).ok_or(io::ErrorKind::NotFound)?;
+        files.insert(to.to_string(), file);
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        match self.files.lock().unwrap().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        Ok(self.files.lock().unwrap().keys().cloned().collect())
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemoryFile {
+    data: RwLock<Vec<u8>>,
+}
+
+impl StorageFile for MemoryFile {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data.read().unwrap().len() as u64)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let data = self.data.read().unwrap();
+        let start = offset.min(data.len() as u64) as usize;
+        match data[start..].get(..buf.len()) {
+            Some(slice) => {
+                buf.copy_from_slice(slice);
+                Ok(())
+            }
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    fn append(&self, data: &[u8]) -> io::Result<()> {
+        self.data.write().unwrap().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        self.data.write().unwrap().truncate(len as usize);
+        Ok(())
+    }
+}
+
+// The files of another storage whose names start with a prefix, which is
+// left out of the names seen through this one
+#[derive(Debug)]
+pub(crate) struct PrefixStorage {
+    inner: Arc<dyn Storage>,
+    prefix: String,
+}
+
+impl PrefixStorage {
+    pub(crate) fn new(inner: Arc<dyn Storage>, prefix: String) -> Self {
+        Self { inner, prefix }
+    }
+
+    fn name(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+}
+
+impl Storage for PrefixStorage {
+    fn open(&self, name: &str) -> io::Result<Arc<dyn StorageFile>> {
+        self.inner.open(&self.name(name))
+    }
+
+    fn create(&self, name: &str) -> io::Result<Arc<dyn StorageFile>> {
+        self.inner.create(&self.name(name))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.inner.rename(&self.name(from), &self.name(to))
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        self.inner.remove(&self.name(name))
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let names = self.inner.list()?;
+        Ok(names
+            .into_iter()
+            .filter_map(|name| name.strip_prefix(&self.prefix).map(str::to_string))
+            .collect())
+    }
+}
+
+// Reads a storage file sequentially
+pub(crate) struct FileReader {
+    file: Arc<dyn StorageFile>,
+    position: u64,
+}
+
+impl FileReader {
+    pub(crate) fn new(file: Arc<dyn StorageFile>, position: u64) -> Self {
+        Self { file, position }
+    }
+}
+
+impl Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (self.file.size()?.saturating_sub(self.position)).min(buf.len() as u64) as usize;
+        self.file.read_at(self.position, &mut buf[..len])?;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for FileReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.file.size()?.checked_add_signed(delta),
+        };
+        self.position = position.ok_or(io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
+
+// Writes to a storage file by appending
+pub(crate) struct FileWriter(pub(crate) Arc<dyn StorageFile>);
+
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.append(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_storage(storage: &dyn Storage) {
+        let file = storage.create("a").unwrap();
+        file.append(b"hello ").unwrap();
+        file.append(b"world").unwrap();
+        file.sync().unwrap();
+        assert_eq!(file.size().unwrap(), 11);
+
+        let mut buf = [0; 5];
+        file.read_at(6, &mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+        assert!(file.read_at(8, &mut buf).is_err());
+
+        // Renaming replaces the target, while open handles keep their file
+        let other = storage.create("b").unwrap();
+        other.append(b"other").unwrap();
+        storage.rename("a", "b").unwrap();
+        assert_eq!(other.size().unwrap(), 5);
+        assert_eq!(storage.open("b").unwrap().size().unwrap(), 11);
+        assert!(storage.open("a").is_err());
+        assert_eq!(storage.list().unwrap(), vec!["b".to_string()]);
+
+        let mut contents = String::new();
+        FileReader::new(storage.open("b").unwrap(), 0).read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "hello world");
+
+        file.truncate(5).unwrap();
+        assert_eq!(storage.open_or_create("b").unwrap().size().unwrap(), 5);
+        storage.remove("b").unwrap();
+        assert_eq!(storage.open_or_create("b").unwrap().size().unwrap(), 0);
+        storage.remove("b").unwrap();
+    }
+
+    #[test]
+    fn test_file_storage() {
+        let dir = PathBuf::from("test_file_storage");
+        let _ = fs::remove_dir_all(&dir);
+
+        check_storage(&FileStorage::new(&dir).unwrap());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        check_storage(&MemoryStorage::new());
+    }
+
+    #[test]
+    fn test_prefix_storage() {
+        let inner = MemoryStorage::new();
+        inner.create("a").unwrap();
+        check_storage(&PrefixStorage::new(Arc::new(inner.clone()), "p.".to_string()));
+
+        let storage = PrefixStorage::new(Arc::new(inner.clone()), "p.".to_string());
+        storage.create("a").unwrap();
+        let mut names = inner.list().unwrap();
+        names.sort();
+        assert_eq!(names, vec!["a".to_string(), "p.a".to_string()]);
+        assert_eq!(storage.list().unwrap(), vec!["a".to_string()]);
+    }
+}
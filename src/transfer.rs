@@ -0,0 +1,242 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{KvError, Result};
+
+// A text format for exporting and importing entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFormat {
+    // A JSON object per line, such as {"key":1,"value":"one"}. On import a value
+    // that isn't a JSON string is stored as its JSON text.
+    JsonLines,
+    // A "key,value" header row, then a row per entry, with fields quoted as in RFC 4180
+    Csv,
+}
+
+impl FromStr for DataFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> std::result::Result<Self, String> {
+        match name {
+            "jsonl" | "json" => Ok(DataFormat::JsonLines),
+            "csv" => Ok(DataFormat::Csv),
+            _ => Err(format!("unknown format {:?}, expected jsonl or csv", name)),
+        }
+    }
+}
+
+impl fmt::Display for DataFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataFormat::JsonLines => write!(f, "jsonl"),
+            DataFormat::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    key: i64,
+    value: &'a str,
+}
+
+// Writes entries in a data format, one at a time
+pub struct EntryWriter<W: Write> {
+    writer: W,
+    format: DataFormat,
+}
+
+impl<W: Write> EntryWriter<W> {
+    pub fn new(mut writer: W, format: DataFormat) -> io::Result<Self> {
+        if format == DataFormat::Csv {
+            writer.write_all(b"key,value\n")?;
+        }
+        Ok(Self { writer, format })
+    }
+
+    pub fn write(&mut self, key: i64, value: &str) -> io::Result<()> {
+        match self.format {
+            DataFormat::JsonLines => {
+                serde_json::to_writer(&mut self.writer, &JsonEntry { key, value })?;
+                self.writer.write_all(b"\n")
+            }
+            DataFormat::Csv => writeln!(self.writer, "{},{}", key, csv_field(value)),
+        }
+    }
+
+    // Flush the entries written, returning the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+// Quote a CSV field if it has to be
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\r', '\n']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+// Reads entries written in a data format, one at a time. Blank lines are skipped,
+// as is a CSV header row.
+pub struct EntryReader<R: BufRead> {
+    reader: R,
+    format: DataFormat,
+    // The number of lines read so far, and the line the current entry starts on
+    line: usize,
+    entry_line: usize,
+    buffer: String,
+}
+
+impl<R: BufRead> EntryReader<R> {
+    pub fn new(reader: R, format: DataFormat) -> Self {
+        Self { reader, format, line: 0, entry_line: 0, buffer: String::new() }
+    }
+
+    // Add the next line to the buffer, returning false at the end of the input
+    fn read_line(&mut self) -> Result<bool> {
+        if self.reader.read_line(&mut self.buffer)? == 0 {
+            return Ok(false);
+        }
+        self.line += 1;
+        Ok(true)
+    }
+
+    fn error(&self, message: impl fmt::Display) -> KvError {
+        KvError::Import(format!("line {}: {}", self.entry_line, message))
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(i64, String)>> {
+        loop {
+            self.buffer.clear();
+            if !self.read_line()? {
+                return Ok(None);
+            }
+            self.entry_line = self.line;
+            if self.buffer.trim().is_empty() {
+                continue;
+            }
+
+            match self.format {
+                DataFormat::JsonLines => {
+                    let entry: Value = serde_json::from_str(&self.buffer).map_err(|err| self.error(err))?;
+                    let key = entry.get("key").and_then(Value::as_i64).ok_or_else(|| self.error("expected an integer \"key\""))?;
+                    let value = match entry.get("value") {
+                        Some(Value::String(value)) => value.clone(),
+                        Some(value) => value.to_string(),
+                        None => return Err(self.error("expected a \"value\"")),
+                    };
+                    return Ok(Some((key, value)));
+                }
+                DataFormat::Csv => {
+                    // A quoted field may go on over several lines
+                    let fields = loop {
+                        if let Some(fields) = parse_csv_record(&self.buffer) {
+                            break fields;
+                        }
+                        if !self.read_line()? {
+                            return Err(self.error("quoted field is never closed"));
+                        }
+                    };
+                    if self.entry_line == 1 && fields == ["key", "value"] {
+                        continue;
+                    }
+                    let [key, value]: [String; 2] = fields
+                        .try_into()
+                        .map_err(|fields: Vec<String>| self.error(format!("expected 2 fields, found {}", fields.len())))?;
+                    let key = key.parse().map_err(|err| self.error(format!("invalid key {:?}: {}", key, err)))?;
+                    return Ok(Some((key, value)));
+                }
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for EntryReader<R> {
+    type Item = Result<(i64, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+// Split a CSV record into its fields, or return None if it ends inside a quoted field
+fn parse_csv_record(record: &str) -> Option<Vec<String>> {
+    let record = record.strip_suffix('\n').map_or(record, |record| record.strip_suffix('\r').unwrap_or(record));
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = record.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (false, '"') => quoted = true,
+            (false, ',') => fields.push(std::mem::take(&mut field)),
+            (_, c) => field.push(c),
+        }
+    }
+    if quoted {
+        return None;
+    }
+    fields.push(field);
+    Some(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(format: DataFormat, entries: &[(i64, &str)]) -> Vec<u8> {
+        let mut writer = EntryWriter::new(Vec::new(), format).unwrap();
+        for &(key, value) in entries {
+            writer.write(key, value).unwrap();
+        }
+        let data = writer.finish().unwrap();
+        let read: Vec<(i64, String)> = EntryReader::new(&data[..], format).collect::<Result<_>>().unwrap();
+        assert_eq!(read.iter().map(|(key, value)| (*key, value.as_str())).collect::<Vec<_>>(), entries);
+        data
+    }
+
+    #[test]
+    fn test_formats() {
+        let entries = [(1, "plain"), (-2, "with, comma"), (3, "\"quoted\"\nand\r\nlines"), (4, ""), (5, "ünïcode")];
+        let csv = round_trip(DataFormat::Csv, &entries);
+        assert!(csv.starts_with(b"key,value\n1,plain\n-2,\"with, comma\"\n"));
+        let json = round_trip(DataFormat::JsonLines, &entries);
+        assert!(json.starts_with(b"{\"key\":1,\"value\":\"plain\"}\n"));
+
+        // JSON values that aren't strings are read as JSON text, and blank lines are skipped
+        let read: Vec<_> = EntryReader::new(&b"{\"key\":7,\"value\":{\"a\":1}}\n\n{\"key\":8,\"value\":2}\n"[..], DataFormat::JsonLines)
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(read, [(7, "{\"a\":1}".to_string()), (8, "2".to_string())]);
+
+        // Errors name the line they are on
+        let errors = [
+            (DataFormat::Csv, &b"key,value\n1,a\nx,b\n"[..], "line 3: invalid key"),
+            (DataFormat::Csv, b"1,a,b\n", "line 1: expected 2 fields"),
+            (DataFormat::Csv, b"1,\"open\n2,b\n", "line 1: quoted field"),
+            (DataFormat::JsonLines, b"{\"key\":1}\n", "line 1: expected a \"value\""),
+            (DataFormat::JsonLines, b"{\"key\":\"1\",\"value\":\"a\"}\n", "line 1: expected an integer"),
+        ];
+        for (format, data, message) in errors {
+            let err = EntryReader::new(data, format).collect::<Result<Vec<_>>>().unwrap_err();
+            assert!(err.to_string().contains(message), "{}", err);
+        }
+
+        assert_eq!("csv".parse::<DataFormat>().unwrap(), DataFormat::Csv);
+        assert!("xml".parse::<DataFormat>().is_err());
+    }
+}
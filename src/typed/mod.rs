@@ -0,0 +1,214 @@
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{KvDb, KvError, Result};
+
+mod binary;
+
+// A key type that maps onto the database's i64 keys. The mapping must preserve
+// order, so a range of typed keys is a range of database keys.
+pub trait TypedKey: Sized {
+    fn to_key(&self) -> i64;
+
+    // Get the typed key back, or None for a database key outside its range
+    fn from_key(key: i64) -> Option<Self>;
+}
+
+impl TypedKey for i64 {
+    fn to_key(&self) -> i64 {
+        *self
+    }
+
+    fn from_key(key: i64) -> Option<Self> {
+        Some(key)
+    }
+}
+
+macro_rules! impl_typed_key {
+    ($($t:ty),*) => {
+        $(
+            impl TypedKey for $t {
+                fn to_key(&self) -> i64 {
+                    *self as i64
+                }
+
+                fn from_key(key: i64) -> Option<Self> {
+                    <$t>::try_from(key).ok()
+                }
+            }
+        )*
+    };
+}
+
+impl_typed_key!(i32, i16, i8, u32, u16, u8);
+
+// Turns values into the bytes stored in the database and back
+pub trait Codec: Send + Sync {
+    fn encode<V: Serialize>(&self, value: &V) -> Result<Vec<u8>>;
+
+    fn decode<V: DeserializeOwned>(&self, bytes: &[u8]) -> Result<V>;
+}
+
+// Stores values as JSON, which other clients can read as plain strings
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<V: Serialize>(&self, value: &V) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|err| KvError::Serialization(err.to_string()))
+    }
+
+    fn decode<V: DeserializeOwned>(&self, bytes: &[u8]) -> Result<V> {
+        serde_json::from_slice(bytes).map_err(|err| KvError::Serialization(err.to_string()))
+    }
+}
+
+// Stores values in a compact binary format in the style of bincode. The format
+// has no field names, so adding, removing or reordering the fields of a type
+// makes values stored before the change unreadable.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BinaryCodec;
+
+impl Codec for BinaryCodec {
+    fn encode<V: Serialize>(&self, value: &V) -> Result<Vec<u8>> {
+        binary::to_vec(value).map_err(|err| KvError::Serialization(err.to_string()))
+    }
+
+    fn decode<V: DeserializeOwned>(&self, bytes: &[u8]) -> Result<V> {
+        binary::from_slice(bytes).map_err(|err| KvError::Serialization(err.to_string()))
+    }
+}
+
+// A view of a database with typed keys and values, which are serialized with a
+// codec. Every value stored through a tree must be readable by its codec as V:
+// reading anything else fails with a serialization error.
+pub struct TypedTree<K, V, C = JsonCodec> {
+    db: Arc<KvDb>,
+    codec: C,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: TypedKey, V: Serialize + DeserializeOwned> TypedTree<K, V, JsonCodec> {
+    pub fn new(db: Arc<KvDb>) -> Self {
+        Self::with_codec(db, JsonCodec)
+    }
+}
+
+impl<K: TypedKey, V: Serialize + DeserializeOwned, C: Codec> TypedTree<K, V, C> {
+    pub fn with_codec(db: Arc<KvDb>, codec: C) -> Self {
+        TypedTree { db, codec, _types: PhantomData }
+    }
+
+    pub fn db(&self) -> &Arc<KvDb> {
+        &self.db
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.db.get_bytes(key.to_key())? {
+            Some(bytes) => Ok(Some(self.codec.decode(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert(&self, key: &K, value: &V) -> Result<()> {
+        let bytes = self.codec.encode(value)?;
+        self.db.set_bytes(key.to_key(), &bytes)
+    }
+
+    // Remove a key, returning whether it was present
+    pub fn remove(&self, key: &K) -> Result<bool> {
+        Ok(self.db.remove(key.to_key())?.is_some())
+    }
+
+    // Get the entries with keys in the given range, in key order. Database keys
+    // that aren't valid keys of type K are skipped.
+    pub fn scan(&self, range: impl RangeBounds<K>) -> Result<Vec<(K, V)>> {
+        let map = |bound: Bound<&K>| match bound {
+            Bound::Included(key) => Bound::Included(key.to_key()),
+            Bound::Excluded(key) => Bound::Excluded(key.to_key()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let range = (map(range.start_bound()), map(range.end_bound()));
+        let mut entries = Vec::new();
+        for (key, bytes) in self.db.scan_bytes(range)? {
+            if let Some(key) = K::from_key(key) {
+                entries.push((key, self.codec.decode(&bytes)?));
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{Config, EngineKind, LsmOptions, MemoryStorage};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u32,
+        email: Option<String>,
+    }
+
+    fn user(name: &str, age: u32) -> User {
+        User { name: name.to_string(), age, email: None }
+    }
+
+    fn check_tree<C: Codec>(db: Arc<KvDb>, codec: C) {
+        let tree: TypedTree<u16, User, C> = TypedTree::with_codec(db.clone(), codec);
+        assert_eq!(tree.get(&1).unwrap(), None);
+        tree.insert(&1, &user("alice", 30)).unwrap();
+        tree.insert(&2, &User { email: Some("bob@example.com".to_string()), ..user("bob", 25) }).unwrap();
+        tree.insert(&3, &user("carol", 41)).unwrap();
+        assert_eq!(tree.get(&1).unwrap(), Some(user("alice", 30)));
+        tree.insert(&1, &user("alice", 31)).unwrap();
+        assert_eq!(tree.get(&1).unwrap(), Some(user("alice", 31)));
+
+        // Keys outside the range of u16 are skipped by scans
+        db.set(-1, "other").unwrap();
+        let names: Vec<_> = tree.scan(..).unwrap().into_iter().map(|(key, user)| (key, user.name)).collect();
+        assert_eq!(names, [(1, "alice".to_string()), (2, "bob".to_string()), (3, "carol".to_string())]);
+        assert_eq!(tree.scan(2..3).unwrap().len(), 1);
+
+        assert!(tree.remove(&2).unwrap());
+        assert!(!tree.remove(&2).unwrap());
+        assert_eq!(tree.get(&2).unwrap(), None);
+
+        // A value that isn't a User is an error
+        db.set(4, "not a user").unwrap();
+        assert!(matches!(tree.get(&4), Err(KvError::Serialization(_))));
+    }
+
+    #[test]
+    fn test_typed_tree() {
+        for engine in [EngineKind::Log, EngineKind::Lsm(LsmOptions::default())] {
+            let config = || Config { storage: Some(Arc::new(MemoryStorage::new())), engine, ..Config::default() };
+            check_tree(Arc::new(KvDb::open(config()).unwrap()), JsonCodec);
+            check_tree(Arc::new(KvDb::open(config()).unwrap()), BinaryCodec);
+        }
+
+        // JSON values are readable as strings
+        let db = Arc::new(KvDb::open(Config { storage: Some(Arc::new(MemoryStorage::new())), ..Config::default() }).unwrap());
+        TypedTree::<i64, User>::new(db.clone()).insert(&5, &user("dave", 50)).unwrap();
+        assert_eq!(db.get(5).unwrap().unwrap(), r#"{"name":"dave","age":50,"email":null}"#);
+    }
+
+    #[test]
+    fn test_binary_values() {
+        // Binary values aren't UTF-8, so they bypass the string cache but read back intact
+        let db = Arc::new(KvDb::open(Config { storage: Some(Arc::new(MemoryStorage::new())), ..Config::default() }).unwrap());
+        let tree: TypedTree<i64, Vec<u8>, BinaryCodec> = TypedTree::with_codec(db.clone(), BinaryCodec);
+        let value = vec![0xff, 0xfe, 0x00, 0x80];
+        tree.insert(&1, &value).unwrap();
+        assert_eq!(tree.get(&1).unwrap(), Some(value.clone()));
+        assert_eq!(tree.get(&1).unwrap(), Some(value.clone()));
+        assert_eq!(db.get_bytes(1).unwrap().unwrap()[8..], value[..]);
+    }
+}